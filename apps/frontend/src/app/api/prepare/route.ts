@@ -0,0 +1,41 @@
+import { NextResponse } from "next/server";
+import { z } from "zod";
+import { Principal } from "@dfinity/principal";
+import { getRelayerActor } from "@/lib/relayer-client";
+
+export const dynamic = "force-dynamic";
+
+const payloadSchema = z.object({
+  assetPrincipal: z.string().min(1),
+  from: z.string().regex(/^0x[a-fA-F0-9]{40}$/),
+  to: z.string().regex(/^0x[a-fA-F0-9]{40}$/),
+  value: z.string().min(1),
+});
+
+export async function POST(request: Request) {
+  const json = await request.json();
+  const parsed = payloadSchema.safeParse(json);
+
+  if (!parsed.success) {
+    const message = parsed.error.issues
+      .map((issue) => `${issue.path.join(".")}: ${issue.message}`)
+      .join("; ");
+    return NextResponse.json({ error: message }, { status: 400 });
+  }
+
+  const payload = parsed.data;
+  const actor = await getRelayerActor();
+
+  const result = await actor.prepare_authorization(
+    Principal.fromText(payload.assetPrincipal),
+    payload.from,
+    payload.to,
+    BigInt(payload.value),
+  );
+
+  if ("Err" in result) {
+    return NextResponse.json({ error: result.Err }, { status: 502 });
+  }
+
+  return NextResponse.json(JSON.parse(result.Ok));
+}
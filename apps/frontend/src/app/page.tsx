@@ -14,8 +14,6 @@ import {
   formatUnits,
   parseUnits,
   hexToBytes,
-  bytesToHex,
-  type TypedDataDomain,
 } from "viem";
 import {
   Card,
@@ -57,6 +55,23 @@ interface SubmitResponse {
   txHash: string;
 }
 
+interface PreparedAuthorization {
+  domain: {
+    name: string;
+    version: string;
+    chainId: number;
+    verifyingContract: `0x${string}`;
+  };
+  message: {
+    from: `0x${string}`;
+    to: `0x${string}`;
+    value: string;
+    validAfter: string;
+    validBefore: string;
+    nonce: `0x${string}`;
+  };
+}
+
 export default function HomePage() {
   const queryClient = useQueryClient();
   const { address, isConnected, chainId } = useAccount();
@@ -132,34 +147,41 @@ export default function HomePage() {
         throw new Error("トークン情報の取得を待っています");
       }
 
-      const { name, decimals } = tokenMetaQuery.data;
+      const { decimals } = tokenMetaQuery.data;
       const value = parseUnits(amount, decimals);
-      const now = Math.floor(Date.now() / 1000);
-      const validBefore = BigInt(
-        now + clientEnv.NEXT_PUBLIC_AUTH_VALIDITY_SECONDS,
-      );
-      const nonceBytes = crypto.getRandomValues(new Uint8Array(32));
-      const nonceHex = bytesToHex(nonceBytes);
+
+      // The relayer picks the domain, validity window and nonce.
+      const prepareResponse = await fetch("/api/prepare", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+          assetPrincipal: clientEnv.NEXT_PUBLIC_JPYC_ASSET_PRINCIPAL,
+          from: address,
+          to: toAddress,
+          value: value.toString(),
+        }),
+      });
+      if (!prepareResponse.ok) {
+        const { error } = await prepareResponse.json();
+        throw new Error(error ?? "署名データの取得に失敗しました");
+      }
+      const prepared = (await prepareResponse.json()) as PreparedAuthorization;
 
       const message = {
-        from: address as `0x${string}`,
-        to: toAddress as `0x${string}`,
-        value,
-        validAfter: BigInt(0),
-        validBefore,
-        nonce: nonceHex,
+        from: prepared.message.from,
+        to: prepared.message.to,
+        value: BigInt(prepared.message.value),
+        validAfter: BigInt(prepared.message.validAfter),
+        validBefore: BigInt(prepared.message.validBefore),
+        nonce: prepared.message.nonce,
       } as const;
 
-      const domain: TypedDataDomain = {
-        name,
-        chainId: BigInt(clientEnv.NEXT_PUBLIC_CHAIN_ID),
-        verifyingContract: clientEnv.NEXT_PUBLIC_JPYC_ADDRESS as `0x${string}`,
-      };
-      domain.version = "1";
-
       const signature = await walletClient.signTypedData({
         account: address,
-        domain,
+        domain: {
+          ...prepared.domain,
+          chainId: BigInt(prepared.domain.chainId),
+        },
         primaryType: "TransferWithAuthorization",
         types: {
           TransferWithAuthorization: [
@@ -186,10 +208,10 @@ export default function HomePage() {
           assetPrincipal: clientEnv.NEXT_PUBLIC_JPYC_ASSET_PRINCIPAL,
           from: message.from,
           to: message.to,
-          value: value.toString(),
+          value: message.value.toString(),
           validAfter: message.validAfter.toString(),
           validBefore: message.validBefore.toString(),
-          nonce: nonceHex,
+          nonce: message.nonce,
           signature: { v, r, s },
         }),
       });
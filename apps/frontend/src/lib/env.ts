@@ -31,11 +31,6 @@ const clientSchema = z.object({
     .string()
     .regex(/^0x[a-fA-F0-9]{40}$/)
     .optional(),
-  NEXT_PUBLIC_AUTH_VALIDITY_SECONDS: z.coerce
-    .number()
-    .int()
-    .min(60)
-    .default(900),
 });
 
 export const clientEnv = (() => {
@@ -48,8 +43,6 @@ export const clientEnv = (() => {
     NEXT_PUBLIC_JPYC_ASSET_PRINCIPAL:
       process.env.NEXT_PUBLIC_JPYC_ASSET_PRINCIPAL ?? "",
     NEXT_PUBLIC_RELAYER_ADDRESS: process.env.NEXT_PUBLIC_RELAYER_ADDRESS,
-    NEXT_PUBLIC_AUTH_VALIDITY_SECONDS:
-      process.env.NEXT_PUBLIC_AUTH_VALIDITY_SECONDS,
   });
 
   if (!parsed.success) {
@@ -38,7 +38,7 @@ export const idlFactory = ({ IDL }: { IDL: CandidIDL }) => {
   });
 
   return IDL.Service({
-    add_asset: IDL.Func([IDL.Principal, IDL.Text, IDL.Nat], [], []),
+    add_asset: IDL.Func([IDL.Principal, IDL.Text, IDL.Nat, IDL.Nat8], [], []),
     deprecate_asset: IDL.Func([IDL.Principal], [], []),
     disable_asset: IDL.Func([IDL.Principal], [], []),
     derive_relayer_address: IDL.Func([], [Result_1], []),
@@ -46,6 +46,11 @@ export const idlFactory = ({ IDL }: { IDL: CandidIDL }) => {
     info: IDL.Func([], [InfoResponse], ["query"]),
     logs: IDL.Func([IDL.Opt(IDL.Nat64), IDL.Nat32], [IDL.Vec(LogEntry)], ["query"]),
     pause: IDL.Func([IDL.Bool], [], []),
+    prepare_authorization: IDL.Func(
+      [IDL.Principal, IDL.Text, IDL.Text, IDL.Nat],
+      [Result_1],
+      ["query"],
+    ),
     refresh_gas_balance: IDL.Func([], [Result], []),
     set_chain_id: IDL.Func([IDL.Nat], [], []),
     set_ecdsa_derivation_path: IDL.Func([IDL.Vec(IDL.Vec(IDL.Nat8))], [], []),
@@ -103,7 +108,7 @@ export interface InitArgs {
 }
 
 export interface _SERVICE {
-  add_asset: (arg_0: Principal, arg_1: string, arg_2: bigint) => Promise<void>;
+  add_asset: (arg_0: Principal, arg_1: string, arg_2: bigint, arg_3: number) => Promise<void>;
   deprecate_asset: (arg_0: Principal) => Promise<void>;
   disable_asset: (arg_0: Principal) => Promise<void>;
   derive_relayer_address: () => Promise<Result_1>;
@@ -111,6 +116,12 @@ export interface _SERVICE {
   info: () => Promise<InfoResponse>;
   logs: (arg_0: [] | [bigint], arg_1: number) => Promise<Array<LogEntry>>;
   pause: (arg_0: boolean) => Promise<void>;
+  prepare_authorization: (
+    arg_0: Principal,
+    arg_1: string,
+    arg_2: string,
+    arg_3: bigint,
+  ) => Promise<Result_1>;
   refresh_gas_balance: () => Promise<Result>;
   set_chain_id: (arg_0: bigint) => Promise<void>;
   set_ecdsa_derivation_path: (arg_0: Uint8Array[]) => Promise<void>;
@@ -3,11 +3,16 @@
 //! entry point to submit authorizations. RPC interactions are issued via
 //! direct HTTP outcalls to the configured Ethereum RPC endpoint.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
+use std::future::Future;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use candid::{CandidType, Nat, Principal};
+use hmac::{Hmac, Mac};
 use ic_cdk::api::{msg_caller, stable_size, time};
 use ic_cdk::call::Call;
 use ic_cdk::management_canister::{
@@ -18,19 +23,67 @@ use ic_cdk::management_canister::{
 use ic_cdk::storage::{stable_restore, stable_save};
 use ic_cdk::trap;
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
 use k256::ecdsa::{RecoveryId, Signature as K256Signature, VerifyingKey};
 use num_bigint::BigUint;
 use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha2::Sha256;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 type InternalResult<T> = std::result::Result<T, RelayError>;
 
 const WASM_PAGE_BYTES: u64 = 65_536;
 
 thread_local! {
-    static STATE: RefCell<Option<RelayerState>> = RefCell::new(None);
+    static STATE: RefCell<Option<RelayerState>> = const { RefCell::new(None) };
+    /// Cycles attached by the task currently being polled; see `Metered`.
+    static CYCLE_METER: Cell<Option<u64>> = const { Cell::new(None) };
+    /// Updates from drop guards that ran while `STATE` was borrowed; applied
+    /// by the next `state_mut`.
+    static DEFERRED: RefCell<Vec<DeferredUpdate>> = const { RefCell::new(Vec::new()) };
+}
+
+enum DeferredUpdate {
+    ReleaseWallet(u32),
+    StageTimings(u64, Vec<StageTiming>),
+    RefundQuota(QuotaRefund),
+}
+
+fn apply_deferred_update(state: &mut RelayerState, update: DeferredUpdate) {
+    match update {
+        DeferredUpdate::ReleaseWallet(id) => release_wallet(state, id),
+        DeferredUpdate::RefundQuota(refund) => refund_quota(state, &refund),
+        DeferredUpdate::StageTimings(log_id, stages) => {
+            if let Ok(index) = state.logs.binary_search_by_key(&log_id, |log| log.id) {
+                state
+                    .log_metrics
+                    .get_or_insert_with(LogMetrics::default)
+                    .observe_stages(&stages);
+                state.logs[index].stages = Some(stages);
+            }
+        }
+    }
+}
+
+fn apply_deferred_updates(state: &mut RelayerState) {
+    for update in DEFERRED.with(|queue| std::mem::take(&mut *queue.borrow_mut())) {
+        apply_deferred_update(state, update);
+    }
+}
+
+/// Applies `update` now if the state is free, otherwise queues it.
+fn defer_state_update(update: DeferredUpdate) {
+    STATE.with(|cell| match cell.try_borrow_mut() {
+        Ok(mut guard) => {
+            if let Some(state) = guard.as_mut() {
+                apply_deferred_update(state, update);
+            }
+        }
+        Err(_) => DEFERRED.with(|queue| queue.borrow_mut().push(update)),
+    });
 }
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
@@ -43,6 +96,30 @@ struct RelayerState {
     logs: Vec<PaymentLog>,
     next_log_id: u64,
     last_known_gas: Nat,
+    wallet_pool: Option<WalletPool>,
+    audit: Option<AuditTrail>,
+    rotations: Option<RotationRegistry>,
+    withdrawals: Option<WithdrawalRegistry>,
+    payouts: Option<PayoutRegistry>,
+    fee_settings: Option<FeeSettings>,
+    pricing: Option<PricingState>,
+    limiter: Option<RateLimiter>,
+    screening: Option<ScreeningState>,
+    kyc: Option<KycState>,
+    rollout: Option<RolloutState>,
+    breaker: Option<BreakerState>,
+    gas_monitor: Option<GasMonitor>,
+    receipt_poll: Option<ReceiptPollStatus>,
+    webhooks: Option<WebhookRegistry>,
+    subscriptions: Option<SubscriptionRegistry>,
+    invoices: Option<InvoiceRegistry>,
+    log_index: Option<LogIndex>,
+    log_schema: Option<u32>,
+    relay_outcomes: Option<BTreeMap<String, u64>>,
+    log_metrics: Option<LogMetrics>,
+    rpc_health: Option<RpcHealth>,
+    http_gateway: Option<HttpGatewayConfig>,
+    nonce_seed: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
@@ -56,6 +133,291 @@ struct RelayerConfig {
     max_fee_multiplier: f64,
     priority_multiplier: f64,
     paused: bool,
+    /// Lifetime of authorizations handed out by `prepare_authorization`.
+    auth_validity_sec: Option<u64>,
+}
+
+/// Hot wallets used to broadcast relays. Wallet `PRIMARY_WALLET_ID` mirrors
+/// `RelayerConfig::ecdsa_derivation_path` / `evm_addr`; the others are added
+/// by admins with their own derivation paths.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct WalletPool {
+    wallets: BTreeMap<u32, RelayerWallet>,
+    next_wallet_id: u32,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RelayerWallet {
+    derivation_path: Vec<Vec<u8>>,
+    address: Option<String>,
+    status: WalletStatus,
+    gas_wei: Nat,
+    gas_updated_sec: u64,
+    next_nonce: Option<Nat>,
+    /// Pending nonce reported by the chain when `next_nonce` was last
+    /// allocated.
+    chain_nonce: Option<Nat>,
+    in_flight: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum WalletStatus {
+    #[default]
+    Active,
+    Draining,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct AuditTrail {
+    entries: Vec<AuditEntry>,
+    next_id: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct AuditEntry {
+    id: u64,
+    ts_sec: u64,
+    caller: Principal,
+    action: String,
+    detail: String,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RotationRegistry {
+    rotations: Vec<KeyRotation>,
+    next_id: u64,
+}
+
+/// Moves a pool wallet to a new derivation path: the old address is drained,
+/// its native balance swept to the new address, and the wallet switches over
+/// once the sweep transaction is confirmed.
+///
+/// The sweep reserves `max_fee_per_gas * 21000` for gas, so the difference to
+/// the price actually paid stays on the old address. It is not re-swept;
+/// `residual_wei` records the old balance read after confirmation.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct KeyRotation {
+    id: u64,
+    wallet_id: u32,
+    started_sec: u64,
+    old_path: Vec<Vec<u8>>,
+    old_address: Option<String>,
+    new_path: Vec<Vec<u8>>,
+    new_address: String,
+    phase: RotationPhase,
+    sweep_tx: Option<String>,
+    swept_wei: Option<Nat>,
+    residual_wei: Option<Nat>,
+    last_error: Option<String>,
+    finished_sec: Option<u64>,
+    /// When the rotation entered `Sweeping` or `SweepPending`.
+    phase_sec: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum RotationPhase {
+    AwaitingSettlement,
+    Sweeping,
+    SweepPending,
+    Completed,
+    Aborted,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct WithdrawalRegistry {
+    policy: WithdrawalPolicy,
+    requests: Vec<WithdrawalRequest>,
+    next_id: u64,
+    daily_counter: BTreeMap<String, RateWindowCounter>,
+}
+
+/// Daily caps are in the smallest unit of each asset, keyed by `"native"` or
+/// the token address. Assets without a cap cannot be withdrawn.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct WithdrawalPolicy {
+    timelock_sec: u64,
+    daily_cap_native_wei: Nat,
+    daily_cap_erc20: BTreeMap<String, Nat>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct WithdrawalRequest {
+    id: u64,
+    kind: WithdrawalKind,
+    to: String,
+    amount: Nat,
+    requested_by: Principal,
+    requested_sec: u64,
+    executable_after_sec: u64,
+    status: WithdrawalStatus,
+    tx_hash: Option<String>,
+    fail_reason: Option<String>,
+    finished_sec: Option<u64>,
+    /// Pool wallet that signs the withdrawal; `None` is the primary wallet.
+    wallet_id: Option<u32>,
+    claimed_sec: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum WithdrawalKind {
+    Native,
+    Erc20 { token: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum WithdrawalStatus {
+    Pending,
+    Executing,
+    /// Executing did not finish within `WITHDRAWAL_EXECUTING_TIMEOUT_SEC`;
+    /// the transaction may or may not have been broadcast. Resolved by the
+    /// owner with `resolve_stalled_withdrawal`.
+    Stalled,
+    Executed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct PayoutRegistry {
+    callers: BTreeMap<Principal, PayoutCaller>,
+    payouts: Vec<Payout>,
+    next_id: u64,
+}
+
+/// Allowlisted canister that may request payouts, with the remaining budget
+/// per asset in the asset's smallest unit.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct PayoutCaller {
+    budgets: BTreeMap<Principal, Nat>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct Payout {
+    id: u64,
+    caller: Principal,
+    asset: Principal,
+    to: String,
+    amount: Nat,
+    memo: Option<String>,
+    status: PayoutStatus,
+    log_id: u64,
+    tx_hash: Option<String>,
+    fail_reason: Option<String>,
+    created_sec: u64,
+    updated_sec: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum PayoutStatus {
+    Queued,
+    Sending,
+    /// Sending did not finish within `PAYOUT_SENDING_TIMEOUT_SEC`; the
+    /// transaction may or may not have been broadcast. Resolved by an admin
+    /// with `resolve_stalled_payout`.
+    Stalled,
+    Broadcasted,
+    Confirmed,
+    Failed,
+}
+
+/// Where relay fees go. The payment and the fee transfer are broadcast as one
+/// atomic transaction through `batch_contract` (a Multicall3 deployment);
+/// relays carrying a fee are rejected until it is set.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct FeeSettings {
+    recipient: Option<String>,
+    batch_contract: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct FeeQuote {
+    asset: Principal,
+    value: Nat,
+    fee_bps: u16,
+    fee_value: Nat,
+    fee_recipient: Option<String>,
+    batched: bool,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct PricingState {
+    config: PricingConfig,
+    last_price: Option<PriceSample>,
+    gas_estimates: BTreeMap<Principal, u64>,
+    quotes: BTreeMap<u64, IssuedQuote>,
+    next_quote_id: u64,
+}
+
+/// Gas-based fee quoting. Assets are assumed to be yen-pegged (1 token =
+/// 1 JPY), so the POL/JPY rate converts gas cost straight into token units.
+/// When enabled, every relay must reference an unexpired quote and its fee
+/// authorization must cover the quoted fee; `fee_bps` acts as a floor.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct PricingConfig {
+    enabled: bool,
+    source: PriceSource,
+    margin_bps: u16,
+    quote_ttl_sec: u64,
+    price_cache_sec: u64,
+    default_gas_units: u64,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            source: PriceSource::Fixed {
+                jpy_per_pol_micro: 0,
+            },
+            margin_bps: 2_000,
+            quote_ttl_sec: 120,
+            price_cache_sec: 60,
+            default_gas_units: 120_000,
+        }
+    }
+}
+
+/// `Http` reads a JSON number (or numeric string) at `json_path` from a GET
+/// to `url`, e.g. CoinGecko `simple/price` with `["matic-network", "jpy"]`.
+/// `Fixed` is the local stand-in used when no outcalls are available.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+enum PriceSource {
+    Http { url: String, json_path: Vec<String> },
+    Fixed { jpy_per_pol_micro: u64 },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct PriceSample {
+    jpy_per_pol_micro: u64,
+    fetched_sec: u64,
+}
+
+/// A quote is bound to the payer it was issued for and is held by the relay
+/// referencing it (`log_id`) until that relay is broadcast or fails.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct IssuedQuote {
+    asset: Principal,
+    value: Nat,
+    fee_value: Nat,
+    expires_sec: u64,
+    from: Option<String>,
+    log_id: Option<u64>,
+    requested_by: Option<Principal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct DynamicFeeQuote {
+    quote_id: u64,
+    asset: Principal,
+    value: Nat,
+    fee_value: Nat,
+    gas_fee: Nat,
+    bps_fee: Nat,
+    gas_units: u64,
+    gas_price_wei: Nat,
+    jpy_per_pol_micro: u64,
+    expires_at_sec: u64,
+    fee_recipient: Option<String>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -64,21 +426,37 @@ struct AssetConfig {
     status: AssetStatus,
     fee_bps: u16,
     version: u32,
+    decimals: Option<u8>,
+    limits: Option<AssetLimits>,
+    domain: Option<TokenDomain>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+/// EIP-712 domain `name` and `version` of the token contract.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+struct TokenDomain {
+    name: String,
+    version: String,
+}
+
+/// Per-asset amount limits. Daily caps are whole tokens scaled by the asset's
+/// decimals (`Some(0)` disables one, `None` on the sender cap falls back to
+/// the global `daily_cap_token`); `min_value`/`max_value` are smallest units.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct AssetLimits {
+    sender_daily_cap_tokens: Option<u64>,
+    recipient_daily_cap_tokens: Option<u64>,
+    min_value: Option<Nat>,
+    max_value: Option<Nat>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
 enum AssetStatus {
+    #[default]
     Active,
     Deprecated,
     Disabled,
 }
 
-impl Default for AssetStatus {
-    fn default() -> Self {
-        AssetStatus::Active
-    }
-}
-
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
 struct RateLimitConfig {
     per_addr_per_min: u32,
@@ -87,21 +465,143 @@ struct RateLimitConfig {
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
 struct RateLimitState {
-    per_min_counter: BTreeMap<String, RateWindowCounter>,
     daily_counter: BTreeMap<String, RateWindowCounter>,
 }
 
+/// Compliance screening of relay senders and recipients. Denylist entries
+/// always apply; an allowlist only applies once enforced for that side.
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
-struct RateWindowCounter {
-    window_start_sec: u64,
+struct ScreeningState {
+    deny_from: BTreeMap<String, ScreeningEntry>,
+    deny_to: BTreeMap<String, ScreeningEntry>,
+    allow_from: BTreeMap<String, ScreeningEntry>,
+    allow_to: BTreeMap<String, ScreeningEntry>,
+    enforce_allow_from: bool,
+    enforce_allow_to: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum ScreeningList {
+    DenyFrom,
+    DenyTo,
+    AllowFrom,
+    AllowTo,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ScreeningEntry {
+    reason: String,
+    expires_sec: Option<u64>,
+    added_sec: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ScreeningEntryInput {
+    address: String,
+    reason: String,
+    expires_sec: Option<u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct ScreeningEntryView {
+    address: String,
+    entry: ScreeningEntry,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize)]
+enum ImportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct InvoiceRegistry {
+    invoices: BTreeMap<u64, Invoice>,
+    by_nonce: BTreeMap<Vec<u8>, u64>,
+    next_id: u64,
+    /// Relay log id -> the pending invoice it pays.
+    by_log: Option<BTreeMap<u64, u64>>,
+    /// Principals an admin has allowed to create invoices.
+    merchants: Option<BTreeSet<Principal>>,
+}
+
+/// A merchant's request for payment. The payer signs the EIP-3009
+/// authorization with `nonce`, which is how a submission finds its invoice.
+/// The nonce is fixed by the relayer, so invoice payments cannot use
+/// `memo_salt`; the invoice's `reference` plays the memo's role.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct Invoice {
+    id: u64,
+    merchant: Principal,
+    asset: Principal,
+    recipient: String,
     amount: Nat,
-    hits: u32,
+    expires_sec: u64,
+    reference: String,
+    nonce: Vec<u8>,
+    status: InvoiceStatus,
+    log_id: Option<u64>,
+    tx_hash: Option<String>,
+    created_sec: u64,
+    paid_sec: Option<u64>,
+}
+
+/// `Pending` means a relay for the invoice is in flight; if it fails the
+/// invoice goes back to `Open`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum InvoiceStatus {
+    Open,
+    Pending,
+    Paid,
+    Expired,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct SubscriptionRegistry {
+    subscriptions: BTreeMap<u64, Subscription>,
+    queue: Vec<QueuedEvent>,
+    next_id: u64,
+    /// Canisters an admin has allowed to subscribe.
+    allowed: Option<BTreeSet<Principal>>,
 }
 
+/// A canister subscribed to payment events. The relayer calls its
+/// `on_payment_event : (PaymentEvent) -> ()` method with a bounded wait of
+/// `SUBSCRIBER_CALL_TIMEOUT_SEC`. A reject or timeout is retried with
+/// backoff, so an event may arrive more than once; after
+/// `MAX_SUBSCRIBER_FAILURES` failures in a row the subscription is dropped.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct PaymentLog {
+struct Subscription {
     id: u64,
-    ts_sec: u64,
+    subscriber: Principal,
+    filter: SubscriptionFilter,
+    created_sec: u64,
+    delivered: u64,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+}
+
+/// Unset fields match everything.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct SubscriptionFilter {
+    asset: Option<Principal>,
+    recipient: Option<String>,
+    memo: Option<Memo>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct QueuedEvent {
+    subscription_id: u64,
+    event: PaymentEvent,
+    attempts: u32,
+    next_attempt_sec: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct PaymentEvent {
+    log_id: u64,
+    event: String,
     asset: Principal,
     from: String,
     to: String,
@@ -109,83 +609,699 @@ struct PaymentLog {
     status: PaymentStatus,
     tx_hash: Option<String>,
     fail_reason: Option<String>,
+    memo: Option<Memo>,
+    ts_sec: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct WebhookRegistry {
+    config: WebhookConfig,
+    endpoints: BTreeMap<String, WebhookEndpoint>,
+    deliveries: Vec<WebhookDelivery>,
+    next_id: u64,
 }
 
+/// `allow_insecure_localhost` admits `http://localhost` / `http://127.0.0.1`
+/// URLs so a local replica can deliver to `scripts/webhook_sink.js`.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-enum PaymentStatus {
-    Accepted,
-    Broadcasted,
-    Failed,
+struct WebhookConfig {
+    max_attempts: u32,
+    base_backoff_sec: u64,
+    allow_insecure_localhost: bool,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_backoff_sec: 30,
+            allow_insecure_localhost: false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct InfoResponse {
-    relayer_addr: String,
-    gas_wei: Nat,
-    threshold_wei: Nat,
-    cycles_balance: Nat,
-    assets: Vec<AssetInfo>,
+struct WebhookEndpoint {
+    url: String,
+    secret: String,
+    created_sec: u64,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct SubmitAuthorizationRequest {
-    asset: Principal,
-    from: Vec<u8>,
-    to: Vec<u8>,
-    value: Nat,
-    valid_after: Nat,
-    valid_before: Nat,
-    nonce: Vec<u8>,
-    sig_v: u8,
-    sig_r: Vec<u8>,
-    sig_s: Vec<u8>,
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct WebhookInfo {
+    recipient: String,
+    url: String,
+    created_sec: u64,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct LogEntry {
+struct WebhookDelivery {
     id: u64,
-    ts: u64,
-    from: String,
-    to: String,
-    value: Nat,
-    tx: Option<String>,
-    status: String,
-    fail_reason: Option<String>,
+    log_id: u64,
+    recipient: String,
+    event: String,
+    payload: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    next_attempt_sec: u64,
+    last_status_code: Option<u16>,
+    last_error: Option<String>,
+    created_sec: u64,
+    delivered_sec: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
 }
 
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct GasMonitor {
+    config: GasMonitorConfig,
+    health: GasHealth,
+    avg_relay_cost_wei: Option<Nat>,
+    last_refresh_sec: u64,
+    last_error: Option<String>,
+    auto_paused: bool,
+}
+
+/// Balances of all pool wallets are refreshed every `refresh_interval_sec`.
+/// Below `soft_threshold_wei` the monitor reports `Warning`; below
+/// `hard_threshold_wei` it reports `Critical` and, with `auto_pause`, pauses
+/// relaying until the balance is back above the soft threshold.
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-struct AssetInfo {
-    asset: Principal,
-    evm_address: String,
-    status: AssetStatus,
-    fee_bps: u16,
+struct GasMonitorConfig {
+    refresh_interval_sec: u64,
+    soft_threshold_wei: Option<Nat>,
+    hard_threshold_wei: Option<Nat>,
+    auto_pause: bool,
+}
+
+impl Default for GasMonitorConfig {
+    fn default() -> Self {
+        Self {
+            refresh_interval_sec: 300,
+            soft_threshold_wei: None,
+            hard_threshold_wei: None,
+            auto_pause: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum GasHealth {
+    #[default]
+    Healthy,
+    Warning,
+    Critical,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-enum RelayError {
-    NotAuthorized,
-    NotInitialized,
-    Paused,
-    ConfigurationMissing {
-        field: String,
-    },
-    RelayerAddressMissing,
-    AssetNotRegistered,
-    AssetNotActive,
-    AuthorizationExpired,
-    AuthorizationAlreadyUsed,
-    InvalidAddressLength {
-        field: String,
-        expected: usize,
-        actual: usize,
-    },
-    InvalidNonceLength {
-        expected: usize,
-        actual: usize,
-    },
-    InvalidSignatureLength {
-        field: String,
-        expected: usize,
+struct GasMonitorInfo {
+    health: GasHealth,
+    total_gas_wei: Nat,
+    avg_relay_cost_wei: Option<Nat>,
+    relays_last_hour: u64,
+    runway_relays: Option<u64>,
+    runway_hours: Option<f64>,
+    soft_threshold_wei: Option<Nat>,
+    hard_threshold_wei: Option<Nat>,
+    last_refresh_sec: u64,
+    last_error: Option<String>,
+    auto_paused: bool,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct BreakerState {
+    config: BreakerConfig,
+    circuits: BTreeMap<String, Circuit>,
+}
+
+/// Failure-rate circuit breaker. RPC and gas failures count against the
+/// `global` circuit, simulation failures against the asset's own circuit.
+/// A circuit trips when one class exceeds its threshold (in bps of all
+/// outcomes within `window_sec`, once `min_samples` are seen), or at once on
+/// low gas. After `cooldown_sec` it lets single probe relays through and
+/// closes again after `probe_successes` of them succeed.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct BreakerConfig {
+    enabled: bool,
+    window_sec: u64,
+    min_samples: u32,
+    thresholds_bps: BTreeMap<ErrorClass, u16>,
+    trip_on_low_gas: bool,
+    cooldown_sec: u64,
+    probe_successes: u32,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window_sec: 300,
+            min_samples: 10,
+            thresholds_bps: BTreeMap::from([
+                (ErrorClass::Simulation, 5_000),
+                (ErrorClass::Rpc, 5_000),
+            ]),
+            trip_on_low_gas: true,
+            cooldown_sec: 300,
+            probe_successes: 3,
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize, Serialize,
+)]
+enum ErrorClass {
+    Simulation,
+    Rpc,
+    Gas,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct Circuit {
+    phase: CircuitPhase,
+    reason: Option<String>,
+    tripped_sec: u64,
+    probe_started_sec: Option<u64>,
+    probe_successes: u32,
+    buckets: Vec<OutcomeBucket>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum CircuitPhase {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct OutcomeBucket {
+    minute: u64,
+    successes: u32,
+    failures: BTreeMap<ErrorClass, u32>,
+}
+
+/// Outcome of recent RPC outcalls. Only transport-level failures count; a
+/// JSON-RPC error response means the provider answered.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RpcHealth {
+    last_success_sec: Option<u64>,
+    last_failure_sec: Option<u64>,
+    consecutive_failures: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct Readiness {
+    ready: bool,
+    reasons: Vec<String>,
+}
+
+/// Public health snapshot behind `/health`, `/status` and `readiness()`.
+/// Carries no endpoints, keys or error messages.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct HealthReport {
+    ready: bool,
+    reasons: Vec<String>,
+    paused: bool,
+    gas_auto_paused: bool,
+    breakers: Vec<BreakerInfo>,
+    gas_health: GasHealth,
+    runway_relays: Option<u64>,
+    runway_hours: Option<f64>,
+    last_success_sec: Option<u64>,
+    rpc: RpcHealth,
+    now_sec: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct BreakerInfo {
+    scope: String,
+    phase: CircuitPhase,
+    reason: Option<String>,
+    tripped_sec: u64,
+}
+
+/// Gradual opening on top of the global `paused` switch. Allowlisted senders
+/// pass in every mode except `Paused`; `Percentage` admits senders whose
+/// `keccak256(from)` bucket falls below `bps` out of 10_000.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct RolloutState {
+    mode: RolloutMode,
+    asset_overrides: BTreeMap<Principal, RolloutMode>,
+    allowlist: BTreeSet<String>,
+    schedule: Vec<RampStep>,
+}
+
+impl Default for RolloutState {
+    fn default() -> Self {
+        Self {
+            mode: RolloutMode::Open,
+            asset_overrides: BTreeMap::new(),
+            allowlist: BTreeSet::new(),
+            schedule: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum RolloutMode {
+    Paused,
+    AllowlistOnly,
+    Percentage { bps: u16 },
+    Open,
+}
+
+/// Switches the default mode (or `asset`'s override) to `mode` at `at_sec`.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct RampStep {
+    at_sec: u64,
+    asset: Option<Principal>,
+    mode: RolloutMode,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct RolloutStatus {
+    mode: RolloutMode,
+    asset_overrides: Vec<(Principal, RolloutMode)>,
+    allowlist_size: u64,
+    schedule: Vec<RampStep>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct KycState {
+    policy: Option<KycPolicy>,
+    attestors: BTreeMap<String, KycAttestorKey>,
+    verified: BTreeMap<String, VerifiedKyc>,
+}
+
+/// Relays above `threshold_tokens` need a verified sender; each tier caps a
+/// single relay at `tier_max_tokens[tier]`. Amounts are whole tokens scaled by
+/// the asset's decimals; a tier without an entry gets no extra allowance.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct KycPolicy {
+    threshold_tokens: u64,
+    tier_max_tokens: BTreeMap<u8, u64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+enum KycAttestorKey {
+    Secp256k1(Vec<u8>),
+    Ed25519(Vec<u8>),
+}
+
+/// Attestor signature over
+/// `kyc_attestation_digest(chain_id, relayer, from, tier, expires_sec)`, where
+/// `relayer` is this canister's id. secp256k1 signatures are 64-byte
+/// `r || s` (or 65 with a trailing `v`) over the digest; ed25519 signs the
+/// 32-byte digest as its message.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct KycAttestation {
+    attestor: String,
+    tier: u8,
+    expires_sec: u64,
+    signature: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct VerifiedKyc {
+    attestor: String,
+    tier: u8,
+    expires_sec: u64,
+    verified_sec: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RateLimiter {
+    config: RateLimiterConfig,
+    buckets: BTreeMap<String, TokenBucket>,
+    gc: Option<RateLimitGc>,
+}
+
+/// Eviction bookkeeping. Full buckets and past daily windows carry no
+/// information, so dropping them never changes a limit decision.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct RateLimitGc {
+    max_tracked_keys: u64,
+    evicted_total: u64,
+    last_evicted: u64,
+    last_run_sec: u64,
+}
+
+impl Default for RateLimitGc {
+    fn default() -> Self {
+        Self {
+            max_tracked_keys: DEFAULT_MAX_TRACKED_KEYS,
+            evicted_total: 0,
+            last_evicted: 0,
+            last_run_sec: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct RateLimitMetrics {
+    bucket_entries: u64,
+    daily_entries: u64,
+    max_tracked_keys: u64,
+    evicted_total: u64,
+    last_evicted: u64,
+    last_run_sec: u64,
+}
+
+/// Token-bucket limits applied to every relay; a `None` scope is unlimited.
+/// A relay needs one token from each configured bucket.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RateLimiterConfig {
+    /// Anonymous Candid callers share one bucket.
+    per_principal: Option<BucketLimit>,
+    per_from: Option<BucketLimit>,
+    per_to: Option<BucketLimit>,
+    per_asset: Option<BucketLimit>,
+    global: Option<BucketLimit>,
+    /// One bucket for all `POST /relay` calls, in place of `per_principal`;
+    /// falls back to the `per_principal` limit when unset.
+    per_http: Option<BucketLimit>,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize)]
+struct BucketLimit {
+    capacity: u32,
+    refill_per_min: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct TokenBucket {
+    tokens_milli: u64,
+    updated_sec: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct RateWindowCounter {
+    window_start_sec: u64,
+    amount: Nat,
+    hits: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct PaymentLog {
+    id: u64,
+    ts_sec: u64,
+    asset: Principal,
+    from: String,
+    to: String,
+    value: Nat,
+    status: PaymentStatus,
+    tx_hash: Option<String>,
+    fail_reason: Option<String>,
+    kind: Option<PaymentKind>,
+    fee_value: Option<Nat>,
+    fee_tx_hash: Option<String>,
+    memo: Option<Memo>,
+    memo_bound: Option<bool>,
+    chain_id: Option<Nat>,
+    valid_after: Option<Nat>,
+    valid_before: Option<Nat>,
+    auth_nonce: Option<String>,
+    tx_nonce: Option<Nat>,
+    gas_limit: Option<Nat>,
+    max_fee_per_gas: Option<Nat>,
+    max_priority_fee_per_gas: Option<Nat>,
+    caller: Option<Principal>,
+    stages: Option<Vec<StageTiming>>,
+}
+
+/// Wall time and cycles attached to management canister calls for one
+/// stage of a relay.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+struct StageTiming {
+    stage: String,
+    duration_ms: u64,
+    cycles: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct StagePercentiles {
+    stage: String,
+    samples: u32,
+    p50_ms: u64,
+    p90_ms: u64,
+    p99_ms: u64,
+    max_ms: u64,
+    p50_cycles: u64,
+    p90_cycles: u64,
+    p99_cycles: u64,
+    total_cycles: Nat,
+}
+
+/// Log ids by the immutable or rarely changing log fields. Only `by_tx`
+/// entries may be stale; `query_logs` re-checks every filter against the log
+/// itself and takes single-filter totals from the set sizes.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct LogIndex {
+    by_asset: BTreeMap<Principal, BTreeSet<u64>>,
+    by_from: BTreeMap<String, BTreeSet<u64>>,
+    by_to: BTreeMap<String, BTreeSet<u64>>,
+    by_tx: BTreeMap<String, BTreeSet<u64>>,
+    /// Status label -> ids, moved by `set_log_status`.
+    by_status: Option<BTreeMap<String, BTreeSet<u64>>>,
+}
+
+/// Running log totals for `/metrics`, kept in step with the logs so a
+/// scrape does not walk them.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct LogMetrics {
+    /// (kind, status) -> logs.
+    by_status: BTreeMap<(String, String), u64>,
+    /// (asset, kind) -> confirmed value.
+    confirmed_volume: BTreeMap<(String, String), Nat>,
+    stage_durations: BTreeMap<String, StageHistogram>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct StageHistogram {
+    /// Cumulative counts per `LATENCY_BUCKETS_MS` bound.
+    buckets: Vec<u64>,
+    count: u64,
+    sum_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct LogQuery {
+    asset: Option<Principal>,
+    from: Option<String>,
+    to: Option<String>,
+    status: Option<PaymentStatus>,
+    tx_hash: Option<String>,
+    since_sec: Option<u64>,
+    until_sec: Option<u64>,
+    min_value: Option<Nat>,
+    max_value: Option<Nat>,
+    /// Defaults to newest first.
+    direction: Option<LogDirection>,
+    /// Id of the last entry of the previous page.
+    cursor: Option<u64>,
+    limit: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum LogDirection {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct LogPage {
+    entries: Vec<LogEntry>,
+    /// Matches across all pages.
+    total: u64,
+    next_cursor: Option<u64>,
+}
+
+/// Caller-supplied reference (e.g. an order id), at most `MAX_MEMO_BYTES`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum Memo {
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum PaymentStatus {
+    Accepted,
+    Broadcasted,
+    Confirmed,
+    Failed,
+}
+
+/// Logs written before payouts existed carry no kind and are relays.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum PaymentKind {
+    #[default]
+    Relay,
+    Payout,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct InfoResponse {
+    relayer_addr: String,
+    gas_wei: Nat,
+    threshold_wei: Nat,
+    cycles_balance: Nat,
+    assets: Vec<AssetInfo>,
+    wallets: Vec<WalletInfo>,
+    breakers: Vec<BreakerInfo>,
+    gas_monitor: GasMonitorInfo,
+    receipt_poll: Option<ReceiptPollStatus>,
+}
+
+/// Outcome of the last timer-driven receipt poll.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct ReceiptPollStatus {
+    last_run_sec: u64,
+    last_error: Option<String>,
+}
+
+/// Request and response of the HTTP gateway interface.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: serde_bytes::ByteBuf,
+}
+
+/// Origins allowed to call the HTTP interface from a browser; `"*"` allows
+/// any origin.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+struct HttpGatewayConfig {
+    cors_allowed_origins: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: serde_bytes::ByteBuf,
+    upgrade: Option<bool>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct WalletInfo {
+    id: u32,
+    address: Option<String>,
+    status: WalletStatus,
+    gas_wei: Nat,
+    gas_updated_sec: u64,
+    in_flight: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct SubmitAuthorizationRequest {
+    asset: Principal,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    value: Nat,
+    valid_after: Nat,
+    valid_before: Nat,
+    nonce: Vec<u8>,
+    sig_v: u8,
+    sig_r: Vec<u8>,
+    sig_s: Vec<u8>,
+    fee: Option<FeeAuthorization>,
+    quote_id: Option<u64>,
+    kyc: Option<KycAttestation>,
+    memo: Option<Memo>,
+    /// When set, `nonce` must equal `memo_nonce(memo, memo_salt)`, which
+    /// binds the memo to the signed authorization. At most
+    /// `MAX_MEMO_SALT_BYTES`. An invoice payment must sign the invoice's own
+    /// nonce, so it cannot also bind a memo this way.
+    memo_salt: Option<Vec<u8>>,
+}
+
+/// Second EIP-3009 authorization from the same `from`, paying the relay fee
+/// to the configured fee recipient.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct FeeAuthorization {
+    value: Nat,
+    valid_after: Nat,
+    valid_before: Nat,
+    nonce: Vec<u8>,
+    sig_v: u8,
+    sig_r: Vec<u8>,
+    sig_s: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct LogEntry {
+    id: u64,
+    ts: u64,
+    from: String,
+    to: String,
+    value: Nat,
+    tx: Option<String>,
+    status: String,
+    fail_reason: Option<String>,
+    kind: String,
+    fee: Option<Nat>,
+    fee_tx: Option<String>,
+    memo: Option<Memo>,
+    memo_bound: bool,
+    chain_id: Option<Nat>,
+    valid_after: Option<Nat>,
+    valid_before: Option<Nat>,
+    auth_nonce: Option<String>,
+    tx_nonce: Option<Nat>,
+    gas_limit: Option<Nat>,
+    max_fee_per_gas: Option<Nat>,
+    max_priority_fee_per_gas: Option<Nat>,
+    /// Only returned to admins.
+    caller: Option<Principal>,
+    stages: Vec<StageTiming>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct AssetInfo {
+    asset: Principal,
+    evm_address: String,
+    status: AssetStatus,
+    fee_bps: u16,
+    decimals: u8,
+    limits: Option<AssetLimits>,
+    domain: Option<TokenDomain>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+enum RelayError {
+    NotAuthorized,
+    NotInitialized,
+    Paused,
+    ConfigurationMissing {
+        field: String,
+    },
+    RelayerAddressMissing,
+    AssetNotRegistered,
+    AssetNotActive,
+    NoHealthyWallet,
+    AuthorizationExpired,
+    AuthorizationAlreadyUsed,
+    InvalidAddressLength {
+        field: String,
+        expected: usize,
+        actual: usize,
+    },
+    InvalidNonceLength {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidSignatureLength {
+        field: String,
+        expected: usize,
         actual: usize,
     },
     SignatureRecoveryFailed {
@@ -218,18 +1334,84 @@ enum RelayError {
         required: Nat,
         actual: Nat,
     },
-    RateLimited,
-    JsonError {
+    FeeAuthorizationMissing {
+        required: Nat,
+    },
+    FeeTooLow {
+        required: Nat,
+        actual: Nat,
+    },
+    InvalidFeeAuthorization {
         message: String,
     },
-    NotImplemented {
-        feature: String,
+    QuoteRequired,
+    QuoteInvalid {
+        message: String,
     },
-}
-
-struct SignatureParts {
-    y_parity: u8,
-    r: Vec<u8>,
+    PriceUnavailable {
+        message: String,
+    },
+    RateLimited {
+        scope: String,
+    },
+    AddressScreened {
+        side: String,
+        reason: String,
+    },
+    RolloutRestricted {
+        mode: String,
+    },
+    CircuitOpen {
+        scope: String,
+        reason: String,
+    },
+    KycRequired {
+        threshold: Nat,
+    },
+    InvalidKycAttestation {
+        message: String,
+    },
+    KycTierLimitExceeded {
+        tier: u8,
+        max: Nat,
+    },
+    AmountBelowMinimum {
+        min: Nat,
+        actual: Nat,
+    },
+    AmountAboveMaximum {
+        max: Nat,
+        actual: Nat,
+    },
+    SenderDailyCapExceeded {
+        cap: Nat,
+        spent: Nat,
+    },
+    RecipientDailyCapExceeded {
+        cap: Nat,
+        spent: Nat,
+    },
+    PayoutBudgetExceeded,
+    InvoiceNotPayable {
+        id: u64,
+        status: String,
+    },
+    InvoiceMismatch {
+        id: u64,
+        field: String,
+    },
+    MemoNotBound,
+    JsonError {
+        message: String,
+    },
+    NotImplemented {
+        feature: String,
+    },
+}
+
+struct SignatureParts {
+    y_parity: u8,
+    r: Vec<u8>,
     s: Vec<u8>,
 }
 
@@ -245,6 +1427,7 @@ impl std::fmt::Display for RelayError {
             RelayError::RelayerAddressMissing => write!(f, "relayer address not configured"),
             RelayError::AssetNotRegistered => write!(f, "asset not registered"),
             RelayError::AssetNotActive => write!(f, "asset not active"),
+            RelayError::NoHealthyWallet => write!(f, "no healthy relayer wallet available"),
             RelayError::AuthorizationExpired => write!(f, "authorization expired"),
             RelayError::AuthorizationAlreadyUsed => write!(f, "authorization already used"),
             RelayError::InvalidAddressLength {
@@ -293,7 +1476,63 @@ impl std::fmt::Display for RelayError {
                 "gas balance low: required {}, actual {}",
                 required, actual
             ),
-            RelayError::RateLimited => write!(f, "rate limit exceeded"),
+            RelayError::FeeAuthorizationMissing { required } => {
+                write!(f, "fee authorization missing: required {}", required)
+            }
+            RelayError::FeeTooLow { required, actual } => {
+                write!(f, "fee too low: required {}, actual {}", required, actual)
+            }
+            RelayError::InvalidFeeAuthorization { message } => {
+                write!(f, "invalid fee authorization: {}", message)
+            }
+            RelayError::QuoteRequired => write!(f, "fee quote required"),
+            RelayError::QuoteInvalid { message } => write!(f, "invalid fee quote: {}", message),
+            RelayError::PriceUnavailable { message } => {
+                write!(f, "price unavailable: {}", message)
+            }
+            RelayError::RateLimited { scope } => write!(f, "rate limit exceeded ({})", scope),
+            RelayError::AddressScreened { side, reason } => {
+                write!(f, "screening: {} address blocked ({})", side, reason)
+            }
+            RelayError::CircuitOpen { scope, reason } => {
+                write!(f, "circuit breaker open for {}: {}", scope, reason)
+            }
+            RelayError::RolloutRestricted { mode } => {
+                write!(f, "sender not enabled by rollout ({})", mode)
+            }
+            RelayError::KycRequired { threshold } => {
+                write!(f, "kyc attestation required above {}", threshold)
+            }
+            RelayError::InvalidKycAttestation { message } => {
+                write!(f, "invalid kyc attestation: {}", message)
+            }
+            RelayError::KycTierLimitExceeded { tier, max } => {
+                write!(f, "kyc tier {} allows at most {}", tier, max)
+            }
+            RelayError::AmountBelowMinimum { min, actual } => {
+                write!(f, "amount {} below minimum {}", actual, min)
+            }
+            RelayError::AmountAboveMaximum { max, actual } => {
+                write!(f, "amount {} above maximum {}", actual, max)
+            }
+            RelayError::SenderDailyCapExceeded { cap, spent } => write!(
+                f,
+                "sender daily cap exceeded (cap={}, spent={})",
+                cap, spent
+            ),
+            RelayError::RecipientDailyCapExceeded { cap, spent } => write!(
+                f,
+                "recipient daily cap exceeded (cap={}, spent={})",
+                cap, spent
+            ),
+            RelayError::PayoutBudgetExceeded => write!(f, "payout budget exceeded"),
+            RelayError::InvoiceNotPayable { id, status } => {
+                write!(f, "invoice {} is not payable (status={})", id, status)
+            }
+            RelayError::InvoiceMismatch { id, field } => {
+                write!(f, "authorization does not match invoice {} ({})", id, field)
+            }
+            RelayError::MemoNotBound => write!(f, "nonce does not bind the memo"),
             RelayError::JsonError { message } => write!(f, "json error: {}", message),
             RelayError::NotImplemented { feature } => {
                 write!(f, "feature not implemented: {}", feature)
@@ -337,6 +1576,7 @@ fn state_mut<T>(f: impl FnOnce(&mut RelayerState) -> T) -> T {
     STATE.with(|cell| {
         let mut guard = cell.borrow_mut();
         let state = guard.as_mut().expect("relayer state not initialized");
+        apply_deferred_updates(state);
         f(state)
     })
 }
@@ -349,6 +1589,16 @@ fn state_ref<T>(f: impl FnOnce(&RelayerState) -> T) -> T {
     })
 }
 
+/// Owners are the canister controllers; they alone may move funds out of
+/// the relayer addresses.
+fn ensure_owner() -> InternalResult<()> {
+    if ic_cdk::api::is_controller(&msg_caller()) {
+        Ok(())
+    } else {
+        Err(RelayError::NotAuthorized)
+    }
+}
+
 fn ensure_admin() -> InternalResult<()> {
     let caller = msg_caller();
     state_ref(|state| {
@@ -376,6 +1626,7 @@ fn init(args: Option<InitArgs>) {
         max_fee_multiplier: args.max_fee_multiplier.unwrap_or(2.0),
         priority_multiplier: args.priority_multiplier.unwrap_or(1.2),
         paused: true,
+        auth_validity_sec: None,
     };
 
     let rate_limit = RateLimitConfig {
@@ -383,7 +1634,7 @@ fn init(args: Option<InitArgs>) {
         daily_cap_token: args.daily_cap_token.unwrap_or(10_000),
     };
 
-    let state = RelayerState {
+    let mut state = RelayerState {
         admins,
         config,
         assets: BTreeMap::new(),
@@ -392,19 +1643,51 @@ fn init(args: Option<InitArgs>) {
         logs: Vec::new(),
         next_log_id: 1,
         last_known_gas: Nat::from(0_u32),
+        wallet_pool: None,
+        audit: None,
+        rotations: None,
+        withdrawals: None,
+        payouts: None,
+        fee_settings: None,
+        pricing: None,
+        limiter: None,
+        screening: None,
+        kyc: None,
+        rollout: None,
+        breaker: None,
+        gas_monitor: None,
+        receipt_poll: None,
+        webhooks: None,
+        subscriptions: None,
+        invoices: None,
+        log_index: Some(LogIndex::default()),
+        log_schema: Some(LOG_SCHEMA_VERSION),
+        relay_outcomes: None,
+        log_metrics: Some(LogMetrics::default()),
+        rpc_health: None,
+        http_gateway: None,
+        nonce_seed: None,
     };
+    sync_primary_wallet(&mut state);
 
     STATE.with(|cell| {
         *cell.borrow_mut() = Some(state);
     });
+    start_timers();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let snapshot = STATE.with(|cell| cell.borrow().clone());
+    let snapshot = STATE.with(|cell| {
+        let mut guard = cell.borrow_mut();
+        if let Some(state) = guard.as_mut() {
+            apply_deferred_updates(state);
+        }
+        guard.clone()
+    });
     let stable_pages_before = stable_size();
     if let Err(e) = stable_save((snapshot,)) {
-        trap(&format!("failed to save state: {}", e));
+        trap(format!("failed to save state: {}", e));
     }
     let stable_pages_after = stable_size();
     let written_pages = stable_pages_after.saturating_sub(stable_pages_before);
@@ -421,7 +1704,7 @@ fn pre_upgrade() {
 fn post_upgrade() {
     let stable_pages_before = stable_size();
     let (snapshot,): (Option<RelayerState>,) =
-        stable_restore().unwrap_or_else(|e| trap(&format!("failed to restore state: {}", e)));
+        stable_restore().unwrap_or_else(|e| trap(format!("failed to restore state: {}", e)));
     let stable_pages_after = stable_size();
     ic_cdk::println!(
         "[relayer] post_upgrade: stable_pages_before={}, stable_pages_after={}, restored_logs={}, restored_assets={}",
@@ -436,8 +1719,139 @@ fn post_upgrade() {
             .map(|state| state.assets.len())
             .unwrap_or(0)
     );
+    let mut state = snapshot.unwrap_or_default();
+    sync_primary_wallet(&mut state);
+    migrate_logs(&mut state);
+    migrate_daily_counters(&mut state);
+    migrate_invoices(&mut state);
+    if state
+        .log_index
+        .as_ref()
+        .is_none_or(|index| index.by_status.is_none())
+    {
+        let ids: Vec<u64> = state.logs.iter().map(|log| log.id).collect();
+        for id in ids {
+            index_log(&mut state, id);
+        }
+    }
+    if state.log_metrics.is_none() {
+        state.log_metrics = Some(rebuild_log_metrics(&state.logs));
+    }
     STATE.with(|cell| {
-        *cell.borrow_mut() = Some(snapshot.unwrap_or_default());
+        *cell.borrow_mut() = Some(state);
+    });
+    start_timers();
+}
+
+const RATE_LIMIT_GC_INTERVAL_SEC: u64 = 300;
+const ROLLOUT_TICK_SEC: u64 = 60;
+const GAS_MONITOR_TICK_SEC: u64 = 60;
+const NOTIFY_TICK_SEC: u64 = 30;
+const PAYOUT_TICK_SEC: u64 = 15;
+const RECEIPT_POLL_TICK_SEC: u64 = 60;
+const RECEIPTS_PER_TICK: usize = 20;
+const NONCE_SEED_RETRY_SEC: u64 = 30;
+
+/// Draws the secret seed behind invoice nonces, retrying until `raw_rand`
+/// succeeds.
+fn draw_nonce_seed(delay: Duration) {
+    ic_cdk_timers::set_timer(delay, async {
+        match ic_cdk::management_canister::raw_rand().await {
+            Ok(seed) => state_mut(|state| state.nonce_seed = Some(seed)),
+            Err(_) => draw_nonce_seed(Duration::from_secs(NONCE_SEED_RETRY_SEC)),
+        }
+    });
+}
+
+fn start_timers() {
+    if state_ref(|state| state.nonce_seed.is_none()) {
+        draw_nonce_seed(Duration::ZERO);
+    }
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(RATE_LIMIT_GC_INTERVAL_SEC), || async {
+        let now_sec = time() / 1_000_000_000;
+        state_mut(|state| {
+            gc_rate_limit_state(state, now_sec);
+            prune_expired_screening(state, now_sec);
+            if let Some(registry) = state.invoices.as_mut() {
+                prune_invoices(registry, now_sec);
+            }
+            if let Some(kyc) = state.kyc.as_mut() {
+                kyc.verified
+                    .retain(|_, verified| verified.expires_sec > now_sec);
+            }
+        });
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(ROLLOUT_TICK_SEC), || async {
+        let now_sec = time() / 1_000_000_000;
+        state_mut(|state| {
+            for step in advance_rollout(state, now_sec) {
+                record_audit(state, "rollout_ramp", format!("{:?}", step));
+            }
+        });
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(GAS_MONITOR_TICK_SEC), || async {
+        let now_sec = time() / 1_000_000_000;
+        let due = state_ref(|state| {
+            let monitor = state.gas_monitor.clone().unwrap_or_default();
+            state.config.rpc_endpoint.is_some()
+                && state.config.evm_addr.is_some()
+                && now_sec.saturating_sub(monitor.last_refresh_sec)
+                    >= monitor.config.refresh_interval_sec
+        });
+        if due {
+            let result = refresh_wallet_balances().await;
+            let now_sec = time() / 1_000_000_000;
+            state_mut(|state| finish_gas_refresh(state, result.as_ref().err(), now_sec));
+        }
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(RECEIPT_POLL_TICK_SEC), || async {
+        let pending = state_ref(|state| {
+            state.config.rpc_endpoint.is_some()
+                && state
+                    .logs
+                    .iter()
+                    .any(|log| log.status == PaymentStatus::Broadcasted)
+        });
+        if pending {
+            let result = poll_broadcasted_receipts(RECEIPTS_PER_TICK).await;
+            let now_sec = time() / 1_000_000_000;
+            state_mut(|state| {
+                let status = state
+                    .receipt_poll
+                    .get_or_insert_with(ReceiptPollStatus::default);
+                status.last_run_sec = now_sec;
+                status.last_error = result.err().map(|err| err.to_string());
+            });
+        }
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(PAYOUT_TICK_SEC), || async {
+        let now_sec = time() / 1_000_000_000;
+        let queued = state_mut(|state| {
+            for payout_id in stall_stuck_payouts(state, now_sec) {
+                record_audit(state, "payout_stalled", format!("payout={}", payout_id));
+            }
+            for withdrawal_id in stall_stuck_withdrawals(state, now_sec) {
+                record_audit(
+                    state,
+                    "withdrawal_stalled",
+                    format!("withdrawal={}", withdrawal_id),
+                );
+            }
+            state.config.rpc_endpoint.is_some()
+                && state.payouts.as_ref().is_some_and(|registry| {
+                    registry
+                        .payouts
+                        .iter()
+                        .any(|payout| payout.status == PayoutStatus::Queued)
+                })
+        });
+        if queued {
+            process_payout_queue(MAX_PAYOUTS_PER_RUN).await;
+        }
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(NOTIFY_TICK_SEC), || async {
+        process_webhook_queue(MAX_WEBHOOKS_PER_RUN).await;
+        process_subscription_queue(MAX_EVENTS_PER_RUN).await;
     });
 }
 
@@ -457,51 +1871,168 @@ fn info() -> InfoResponse {
             .assets
             .iter()
             .map(|(principal, cfg)| AssetInfo {
-                asset: principal.clone(),
+                asset: *principal,
                 evm_address: cfg.evm_address.clone(),
                 status: cfg.status.clone(),
                 fee_bps: cfg.fee_bps,
+                decimals: cfg.decimals.unwrap_or(DEFAULT_TOKEN_DECIMALS),
+                limits: cfg.limits.clone(),
+                domain: cfg.domain.clone(),
             })
             .collect(),
+        wallets: state
+            .wallet_pool
+            .iter()
+            .flat_map(|pool| pool.wallets.iter())
+            .map(|(id, wallet)| wallet_info(*id, wallet))
+            .collect(),
+        breakers: breaker_infos(state),
+        gas_monitor: gas_monitor_info(state, time() / 1_000_000_000),
+        receipt_poll: state.receipt_poll.clone(),
     })
 }
 
+#[query]
+fn readiness() -> Readiness {
+    let report = state_ref(|state| health_report(state, time() / 1_000_000_000));
+    Readiness {
+        ready: report.ready,
+        reasons: report.reasons,
+    }
+}
+
 #[query]
 fn logs(start_after: Option<u64>, limit: u32) -> Vec<LogEntry> {
-    state_ref(|state| {
+    let is_admin = ensure_admin().is_ok();
+    let mut entries = state_ref(|state| {
         let mut entries = Vec::new();
         for log in state.logs.iter().rev() {
             if let Some(cursor) = start_after {
-                if log.id <= cursor {
+                if log.id >= cursor {
                     continue;
                 }
             }
             if entries.len() as u32 >= limit.max(1) {
                 break;
             }
-            entries.push(LogEntry {
-                id: log.id,
-                ts: log.ts_sec,
-                from: log.from.clone(),
-                to: log.to.clone(),
-                value: log.value.clone(),
-                tx: log.tx_hash.clone(),
-                status: match log.status {
-                    PaymentStatus::Accepted => "accepted".to_string(),
-                    PaymentStatus::Broadcasted => "broadcasted".to_string(),
-                    PaymentStatus::Failed => "failed".to_string(),
-                },
-                fail_reason: log.fail_reason.clone(),
-            });
+            entries.push(log_entry(log));
         }
         entries
-    })
+    });
+    if !is_admin {
+        redact_callers(&mut entries);
+    }
+    entries
+}
+
+#[query]
+fn query_logs(query: LogQuery) -> Result<LogPage, String> {
+    let query = LogQuery {
+        from: query
+            .from
+            .as_deref()
+            .map(normalize_evm_address)
+            .transpose()
+            .map_err(|err| err.to_string())?,
+        to: query
+            .to
+            .as_deref()
+            .map(normalize_evm_address)
+            .transpose()
+            .map_err(|err| err.to_string())?,
+        tx_hash: query.tx_hash.map(|hash| hash.trim().to_lowercase()),
+        ..query
+    };
+    let mut page = state_ref(|state| query_log_page(&state.logs, state.log_index.as_ref(), &query));
+    if ensure_admin().is_err() {
+        redact_callers(&mut page.entries);
+    }
+    Ok(page)
+}
+
+/// Logs carrying exactly this memo, newest first.
+#[query]
+fn find_by_memo(memo: Memo, limit: u32) -> Vec<LogEntry> {
+    let mut entries: Vec<LogEntry> = state_ref(|state| {
+        state
+            .logs
+            .iter()
+            .rev()
+            .filter(|log| log.memo.as_ref() == Some(&memo))
+            .take(limit.clamp(1, 100) as usize)
+            .map(log_entry)
+            .collect()
+    });
+    if ensure_admin().is_err() {
+        redact_callers(&mut entries);
+    }
+    entries
+}
+
+fn redact_callers(entries: &mut [LogEntry]) {
+    for entry in entries {
+        entry.caller = None;
+    }
+}
+
+/// Stage latency and cycle percentiles over the most recent `window` relays
+/// (default 1000).
+#[query]
+fn relay_stage_stats(window: Option<u32>) -> Result<Vec<StagePercentiles>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let window = window.unwrap_or(1_000).clamp(1, 10_000) as usize;
+    Ok(state_ref(|state| {
+        let recent: Vec<&[StageTiming]> = state
+            .logs
+            .iter()
+            .rev()
+            .filter_map(|log| log.stages.as_deref())
+            .take(window)
+            .collect();
+        stage_percentiles(&recent)
+    }))
+}
+
+fn log_entry(log: &PaymentLog) -> LogEntry {
+    LogEntry {
+        id: log.id,
+        ts: log.ts_sec,
+        from: log.from.clone(),
+        to: log.to.clone(),
+        value: log.value.clone(),
+        tx: log.tx_hash.clone(),
+        status: match log.status {
+            PaymentStatus::Accepted => "accepted".to_string(),
+            PaymentStatus::Broadcasted => "broadcasted".to_string(),
+            PaymentStatus::Confirmed => "confirmed".to_string(),
+            PaymentStatus::Failed => "failed".to_string(),
+        },
+        fail_reason: log.fail_reason.clone(),
+        kind: match log.kind.clone().unwrap_or_default() {
+            PaymentKind::Relay => "relay".to_string(),
+            PaymentKind::Payout => "payout".to_string(),
+        },
+        fee: log.fee_value.clone(),
+        fee_tx: log.fee_tx_hash.clone(),
+        memo: log.memo.clone(),
+        memo_bound: log.memo_bound.unwrap_or(false),
+        chain_id: log.chain_id.clone(),
+        valid_after: log.valid_after.clone(),
+        valid_before: log.valid_before.clone(),
+        auth_nonce: log.auth_nonce.clone(),
+        tx_nonce: log.tx_nonce.clone(),
+        gas_limit: log.gas_limit.clone(),
+        max_fee_per_gas: log.max_fee_per_gas.clone(),
+        max_priority_fee_per_gas: log.max_priority_fee_per_gas.clone(),
+        caller: log.caller,
+        stages: log.stages.clone().unwrap_or_default(),
+    }
 }
 
 #[update]
 fn set_rpc_endpoint(url: String) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
     let trimmed = url.trim();
     if trimmed.is_empty() {
@@ -516,7 +2047,7 @@ fn set_rpc_endpoint(url: String) {
 #[update]
 fn set_threshold(value: Nat) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
     state_mut(|state| state.config.threshold_wei = value);
 }
@@ -524,7 +2055,7 @@ fn set_threshold(value: Nat) {
 #[update]
 fn set_chain_id(chain_id: Nat) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
     state_mut(|state| state.config.chain_id = Some(chain_id));
 }
@@ -532,21 +2063,46 @@ fn set_chain_id(chain_id: Nat) {
 #[update]
 fn set_ecdsa_derivation_path(path: Vec<Vec<u8>>) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
-    state_mut(|state| state.config.ecdsa_derivation_path = path);
+    state_mut(|state| {
+        if state.config.evm_addr.is_some() {
+            ic_cdk::trap(
+                "relayer address already configured; use start_key_rotation to change the derivation path",
+            );
+        }
+        record_audit(
+            state,
+            "set_ecdsa_derivation_path",
+            format!("path={}", format_derivation_path(&path)),
+        );
+        state.config.ecdsa_derivation_path = path;
+        sync_primary_wallet(state);
+    });
 }
 
 #[update]
 fn set_relayer_address(address: String) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
     let normalized = match normalize_evm_address(&address) {
         Ok(addr) => addr,
-        Err(err) => ic_cdk::trap(&err.to_string()),
+        Err(err) => ic_cdk::trap(err.to_string()),
     };
-    state_mut(|state| state.config.evm_addr = Some(normalized));
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_relayer_address",
+            format!(
+                "old={} new={}",
+                state.config.evm_addr.as_deref().unwrap_or("-"),
+                normalized
+            ),
+        );
+        state.config.evm_addr = Some(normalized);
+        sync_primary_wallet(state);
+    });
 }
 
 #[query]
@@ -565,18 +2121,35 @@ async fn derive_relayer_address() -> Result<String, String> {
             state.config.ecdsa_derivation_path.clone(),
         )
     });
+    let address = derive_evm_address(&key_name, &derivation_path).await?;
+    state_mut(|state| {
+        record_audit(
+            state,
+            "derive_relayer_address",
+            format!(
+                "path={} address={}",
+                format_derivation_path(&derivation_path),
+                address
+            ),
+        );
+        state.config.evm_addr = Some(address.clone());
+        sync_primary_wallet(state);
+    });
+    Ok(address)
+}
+
+async fn derive_evm_address(key_name: &str, derivation_path: &[Vec<u8>]) -> Result<String, String> {
     let arg = EcdsaPublicKeyArgs {
         canister_id: Some(ic_cdk::api::canister_self()),
-        derivation_path: derivation_path.clone(),
+        derivation_path: derivation_path.to_vec(),
         key_id: EcdsaKeyId {
             curve: EcdsaCurve::Secp256k1,
-            name: key_name,
+            name: key_name.to_string(),
         },
     };
-    let EcdsaPublicKeyResult { public_key, .. } =
-        ecdsa_public_key(&arg)
-            .await
-            .map_err(|err| format!("ecdsa_public_key failed: {}", err))?;
+    let EcdsaPublicKeyResult { public_key, .. } = ecdsa_public_key(&arg)
+        .await
+        .map_err(|err| format!("ecdsa_public_key failed: {}", err))?;
     let verifying_key = VerifyingKey::from_sec1_bytes(&public_key)
         .map_err(|err| format!("invalid public key bytes: {}", err))?;
     let encoded = verifying_key.to_encoded_point(false);
@@ -587,1297 +2160,9647 @@ async fn derive_relayer_address() -> Result<String, String> {
     let hash = keccak256(&pubkey_bytes[1..]);
     let mut addr = [0u8; 20];
     addr.copy_from_slice(&hash[12..]);
-    let address = format!("0x{}", hex::encode(addr));
-    state_mut(|state| state.config.evm_addr = Some(address.clone()));
-    Ok(address)
+    Ok(format!("0x{}", hex::encode(addr)))
 }
 
 #[update]
 async fn refresh_gas_balance() -> Result<Nat, String> {
+    let result = refresh_wallet_balances().await;
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| finish_gas_refresh(state, result.as_ref().err(), now_sec));
+    result.map_err(|e| e.to_string())
+}
+
+#[update]
+fn set_gas_monitor_config(config: GasMonitorConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        record_audit(state, "set_gas_monitor_config", format!("{:?}", config));
+        state
+            .gas_monitor
+            .get_or_insert_with(GasMonitor::default)
+            .config = config;
+    });
+}
+
+async fn refresh_wallet_balances() -> InternalResult<Nat> {
     let (address_opt, chain_id_opt) =
         state_ref(|state| (state.config.evm_addr.clone(), state.config.chain_id.clone()));
 
-    let address = address_opt.ok_or_else(|| RelayError::RelayerAddressMissing.to_string())?;
-    let chain_id_nat = chain_id_opt.ok_or_else(|| {
-        RelayError::ConfigurationMissing {
-            field: "chain_id".into(),
-        }
-        .to_string()
+    let address = address_opt.ok_or(RelayError::RelayerAddressMissing)?;
+    let chain_id_nat = chain_id_opt.ok_or_else(|| RelayError::ConfigurationMissing {
+        field: "chain_id".into(),
     })?;
 
-    let chain_id_u64 = nat_to_u64(&chain_id_nat).map_err(|e| e.to_string())?;
+    let chain_id_u64 = nat_to_u64(&chain_id_nat)?;
 
-    let balance = fetch_balance(chain_id_u64, &address)
-        .await
-        .map_err(|e| e.to_string())?;
+    let balance = fetch_balance(chain_id_u64, &address).await?;
 
-    state_mut(|state| state.last_known_gas = balance.clone());
+    state_mut(|state| record_wallet_balance(state, PRIMARY_WALLET_ID, &balance));
+
+    let secondary: Vec<(u32, String)> = state_ref(|state| {
+        state
+            .wallet_pool
+            .iter()
+            .flat_map(|pool| pool.wallets.iter())
+            .filter(|(id, _)| **id != PRIMARY_WALLET_ID)
+            .filter_map(|(id, wallet)| wallet.address.clone().map(|addr| (*id, addr)))
+            .collect()
+    });
+    // One unreachable wallet should not keep the rest of the pool stale.
+    let mut first_error = None;
+    for (wallet_id, wallet_addr) in secondary {
+        match fetch_balance(chain_id_u64, &wallet_addr).await {
+            Ok(wallet_balance) => {
+                state_mut(|state| record_wallet_balance(state, wallet_id, &wallet_balance))
+            }
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
 
-    Ok(balance)
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(balance),
+    }
 }
 
 #[update]
-fn add_asset(asset: Principal, evm_address: String, fee_bps: Nat) {
+async fn add_wallet(derivation_path: Vec<Vec<u8>>) -> Result<WalletInfo, String> {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        return Err(err.to_string());
     }
-    let fee = match nat_to_u32(&fee_bps) {
-        Ok(v) if v <= u16::MAX as u32 => v as u16,
-        _ => ic_cdk::trap("fee_bps out of range"),
-    };
-    let normalized = match normalize_evm_address(&evm_address) {
-        Ok(addr) => addr,
-        Err(err) => ic_cdk::trap(&err.to_string()),
-    };
-    state_mut(|state| {
-        state.assets.insert(
-            asset,
-            AssetConfig {
-                evm_address: normalized,
-                status: AssetStatus::Active,
-                fee_bps: fee,
-                version: 1,
-            },
-        );
+    let (key_name, duplicate) = state_ref(|state| {
+        let duplicate = state
+            .wallet_pool
+            .iter()
+            .flat_map(|pool| pool.wallets.values())
+            .any(|wallet| wallet.derivation_path == derivation_path);
+        (state.config.ecdsa_key_name.clone(), duplicate)
     });
+    if duplicate {
+        return Err("derivation path already in wallet pool".into());
+    }
+    let address = derive_evm_address(&key_name, &derivation_path).await?;
+    Ok(state_mut(|state| {
+        let pool = wallet_pool_mut(state);
+        let id = pool.next_wallet_id.max(PRIMARY_WALLET_ID + 1);
+        pool.next_wallet_id = id + 1;
+        let wallet = RelayerWallet {
+            derivation_path,
+            address: Some(address),
+            ..RelayerWallet::default()
+        };
+        let detail = format!(
+            "wallet={} path={} address={}",
+            id,
+            format_derivation_path(&wallet.derivation_path),
+            wallet.address.as_deref().unwrap_or("-")
+        );
+        let info = wallet_info(id, &wallet);
+        pool.wallets.insert(id, wallet);
+        record_audit(state, "add_wallet", detail);
+        info
+    }))
 }
 
 #[update]
-fn deprecate_asset(asset: Principal) {
+fn drain_wallet(wallet_id: u32) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
-    state_mut(|state| {
-        if let Some(cfg) = state.assets.get_mut(&asset) {
-            cfg.status = AssetStatus::Deprecated;
-        }
-    });
+    set_wallet_status(wallet_id, WalletStatus::Draining);
 }
 
 #[update]
-fn disable_asset(asset: Principal) {
+fn activate_wallet(wallet_id: u32) {
     if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+        ic_cdk::trap(err.to_string());
     }
+    set_wallet_status(wallet_id, WalletStatus::Active);
+}
+
+fn set_wallet_status(wallet_id: u32, status: WalletStatus) {
     state_mut(|state| {
-        if let Some(cfg) = state.assets.get_mut(&asset) {
-            cfg.status = AssetStatus::Disabled;
+        if active_rotation_for_wallet(state, wallet_id).is_some() {
+            ic_cdk::trap("wallet is being rotated");
+        }
+        match wallet_pool_mut(state).wallets.get_mut(&wallet_id) {
+            Some(wallet) => wallet.status = status.clone(),
+            None => ic_cdk::trap("unknown wallet id"),
         }
+        record_audit(
+            state,
+            "set_wallet_status",
+            format!("wallet={} status={:?}", wallet_id, status),
+        );
     });
 }
 
+#[query]
+fn audit_log(start_after: Option<u64>, limit: u32) -> Result<Vec<AuditEntry>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(state_ref(|state| {
+        state
+            .audit
+            .iter()
+            .flat_map(|trail| trail.entries.iter().rev())
+            .filter(|entry| start_after.is_none_or(|cursor| entry.id < cursor))
+            .take(limit.max(1) as usize)
+            .cloned()
+            .collect()
+    }))
+}
+
+#[query]
+fn key_rotations() -> Result<Vec<KeyRotation>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(state_ref(|state| {
+        state
+            .rotations
+            .iter()
+            .flat_map(|registry| registry.rotations.iter().rev())
+            .cloned()
+            .collect()
+    }))
+}
+
+/// Derives the address for `new_path` and stops assigning relays to the
+/// wallet. Call `advance_key_rotation` until the rotation completes.
 #[update]
-fn pause(flag: bool) {
-    if let Err(err) = ensure_admin() {
-        ic_cdk::trap(&err.to_string());
+async fn start_key_rotation(wallet_id: u32, new_path: Vec<Vec<u8>>) -> Result<KeyRotation, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let key_name = state_ref(|state| {
+        if active_rotation_for_wallet(state, wallet_id).is_some() {
+            return Err("rotation already in progress for wallet".to_string());
+        }
+        let wallet = state
+            .wallet_pool
+            .as_ref()
+            .and_then(|pool| pool.wallets.get(&wallet_id))
+            .ok_or_else(|| "unknown wallet id".to_string())?;
+        if wallet.derivation_path == new_path {
+            return Err("new derivation path equals the current one".to_string());
+        }
+        Ok(state.config.ecdsa_key_name.clone())
+    })?;
+    let new_address = derive_evm_address(&key_name, &new_path).await?;
+
+    state_mut(|state| {
+        let rotation = begin_rotation(
+            state,
+            wallet_id,
+            new_path,
+            new_address,
+            time() / 1_000_000_000,
+        )?;
+        record_audit(
+            state,
+            "rotation_started",
+            format!(
+                "rotation={} wallet={} old={} new={} new_path={}",
+                rotation.id,
+                wallet_id,
+                rotation.old_address.as_deref().unwrap_or("-"),
+                rotation.new_address,
+                format_derivation_path(&rotation.new_path)
+            ),
+        );
+        Ok(rotation)
+    })
+}
+
+/// Waits for the old address to settle (no relays in flight, no pending
+/// nonces) and sweeps its native balance minus gas. While the rotation is
+/// `SweepPending`, checks the sweep receipt and switches the wallet to the
+/// new key once it is confirmed; a reverted sweep is retried.
+#[update]
+async fn advance_key_rotation(rotation_id: u64) -> Result<KeyRotation, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let rotation =
+        state_mut(|state| claim_rotation_step(state, rotation_id, time() / 1_000_000_000))?;
+    if rotation.phase == RotationPhase::SweepPending {
+        return confirm_rotation_sweep(&rotation).await;
+    }
+
+    match sweep_rotated_wallet(&rotation).await {
+        Ok(Some(sweep)) => Ok(state_mut(|state| {
+            let rotation = record_rotation_sweep(state, rotation_id, sweep, time() / 1_000_000_000);
+            if rotation.phase == RotationPhase::Completed {
+                audit_rotation_completed(state, &rotation);
+            } else {
+                record_audit(
+                    state,
+                    "rotation_sweep_sent",
+                    format!(
+                        "rotation={} sweep_tx={}",
+                        rotation.id,
+                        rotation.sweep_tx.as_deref().unwrap_or("-")
+                    ),
+                );
+            }
+            rotation
+        })),
+        Ok(None) => Ok(state_mut(|state| {
+            retry_rotation_sweep(state, rotation_id, None)
+        })),
+        Err(err) => {
+            let message = err.to_string();
+            state_mut(|state| {
+                retry_rotation_sweep(state, rotation_id, Some(message.clone()));
+                record_audit(
+                    state,
+                    "rotation_sweep_failed",
+                    format!("rotation={} error={}", rotation_id, message),
+                );
+            });
+            Err(message)
+        }
     }
-    state_mut(|state| state.config.paused = flag);
 }
 
+/// Aborts a rotation and returns the wallet to its old key. A rotation stuck
+/// in `Sweeping` or `SweepPending` (a trapped sweep, a dropped or replaced
+/// transaction) can be aborted once `ROTATION_STUCK_SEC` has passed; a sweep
+/// that still lands leaves its funds on the new address, which this canister
+/// controls.
 #[update]
-async fn submit_authorization(req: SubmitAuthorizationRequest) -> Result<String, String> {
-    match submit_authorization_internal(req).await {
-        Ok(tx_hash) => Ok(tx_hash),
-        Err(err) => Err(err.to_string()),
+fn abort_key_rotation(rotation_id: u64) -> Result<KeyRotation, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        let rotation = abort_rotation(state, rotation_id, time() / 1_000_000_000)?;
+        record_audit(
+            state,
+            "rotation_aborted",
+            format!("rotation={} wallet={}", rotation.id, rotation.wallet_id),
+        );
+        Ok(rotation)
+    })
+}
+
+#[update]
+fn set_withdrawal_policy(
+    timelock_sec: u64,
+    daily_cap_native_wei: Nat,
+    daily_cap_erc20: Vec<(String, Nat)>,
+) -> Result<(), String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    let mut caps = BTreeMap::new();
+    for (token, cap) in daily_cap_erc20 {
+        caps.insert(
+            normalize_evm_address(&token).map_err(|e| e.to_string())?,
+            cap,
+        );
     }
+    state_mut(|state| {
+        let detail = format!(
+            "timelock_sec={} native_cap={} erc20_caps={}",
+            timelock_sec,
+            daily_cap_native_wei,
+            caps.len()
+        );
+        let registry = state
+            .withdrawals
+            .get_or_insert_with(WithdrawalRegistry::default);
+        registry.policy = WithdrawalPolicy {
+            timelock_sec,
+            daily_cap_native_wei,
+            daily_cap_erc20: caps,
+        };
+        record_audit(state, "set_withdrawal_policy", detail);
+    });
+    Ok(())
 }
 
-async fn submit_authorization_internal(req: SubmitAuthorizationRequest) -> InternalResult<String> {
-    if state_ref(|state| state.config.paused) {
-        return Err(RelayError::Paused);
+#[query]
+fn withdrawal_policy() -> WithdrawalPolicy {
+    state_ref(|state| {
+        state
+            .withdrawals
+            .as_ref()
+            .map(|registry| registry.policy.clone())
+            .unwrap_or_default()
+    })
+}
+
+#[query]
+fn withdrawals(start_after: Option<u64>, limit: u32) -> Result<Vec<WithdrawalRequest>, String> {
+    let caller = msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        ensure_admin().map_err(|err| err.to_string())?;
     }
+    Ok(state_ref(|state| {
+        state
+            .withdrawals
+            .iter()
+            .flat_map(|registry| registry.requests.iter().rev())
+            .filter(|request| start_after.is_none_or(|cursor| request.id < cursor))
+            .take(limit.max(1) as usize)
+            .cloned()
+            .collect()
+    }))
+}
 
-    if req.from.len() != 20 {
-        return Err(RelayError::InvalidAddressLength {
-            field: "from".into(),
-            expected: 20,
-            actual: req.from.len(),
-        });
-    }
-    if req.to.len() != 20 {
-        return Err(RelayError::InvalidAddressLength {
-            field: "to".into(),
-            expected: 20,
-            actual: req.to.len(),
-        });
-    }
+/// Queues a withdrawal of native POL from a pool wallet. It executes
+/// immediately when no timelock is configured.
+#[update]
+async fn withdraw_native(
+    wallet_id: u32,
+    to: String,
+    amount: Nat,
+) -> Result<WithdrawalRequest, String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    queue_withdrawal(wallet_id, WithdrawalKind::Native, to, amount).await
+}
 
-    let asset_cfg = state_ref(|state| state.assets.get(&req.asset).cloned());
-    let asset_cfg = asset_cfg.ok_or(RelayError::AssetNotRegistered)?;
-    if !matches!(
-        asset_cfg.status,
-        AssetStatus::Active | AssetStatus::Deprecated
-    ) {
-        return Err(RelayError::AssetNotActive);
-    }
+/// Queues an ERC-20 `transfer` from a pool wallet, e.g. to recover tokens
+/// sent there by mistake.
+#[update]
+async fn withdraw_erc20(
+    wallet_id: u32,
+    token: String,
+    to: String,
+    amount: Nat,
+) -> Result<WithdrawalRequest, String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    let token = normalize_evm_address(&token).map_err(|err| err.to_string())?;
+    queue_withdrawal(wallet_id, WithdrawalKind::Erc20 { token }, to, amount).await
+}
 
-    let config_snapshot = state_ref(|state| state.config.clone());
-    let threshold_wei = config_snapshot.threshold_wei.clone();
-    let max_fee_multiplier = config_snapshot.max_fee_multiplier;
-    let priority_multiplier = config_snapshot.priority_multiplier;
-    let relayer_addr_opt = config_snapshot.evm_addr.clone();
-    let chain_id_opt = config_snapshot.chain_id.clone();
-    let ecdsa_key_name = config_snapshot.ecdsa_key_name.clone();
-    let derivation_path = config_snapshot.ecdsa_derivation_path.clone();
+#[update]
+async fn execute_withdrawal(withdrawal_id: u64) -> Result<WithdrawalRequest, String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    run_withdrawal(withdrawal_id).await
+}
+
+#[update]
+fn cancel_withdrawal(withdrawal_id: u64) -> Result<WithdrawalRequest, String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        let request = cancel_withdrawal_request(state, withdrawal_id, time() / 1_000_000_000)?;
+        record_audit(
+            state,
+            "withdrawal_cancelled",
+            format!("withdrawal={}", withdrawal_id),
+        );
+        Ok(request)
+    })
+}
+
+/// Settles a `Stalled` withdrawal after checking the wallet on chain: with
+/// the transaction hash it is executed, without one it fails and its amount
+/// goes back to the daily cap.
+#[update]
+fn resolve_stalled_withdrawal(
+    withdrawal_id: u64,
+    tx_hash: Option<String>,
+) -> Result<WithdrawalRequest, String> {
+    ensure_owner().map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        let status = withdrawal_mut(state, withdrawal_id)?.status.clone();
+        if status != WithdrawalStatus::Stalled {
+            return Err(format!("withdrawal is {:?}", status));
+        }
+        let result = tx_hash
+            .clone()
+            .ok_or_else(|| RelayError::RpcTransportError {
+                code: "stalled".into(),
+                message: "resolved by owner as not sent".into(),
+            });
+        let request = finish_withdrawal(state, withdrawal_id, &result, time() / 1_000_000_000);
+        record_audit(
+            state,
+            "withdrawal_resolved",
+            format!(
+                "withdrawal={} tx={}",
+                withdrawal_id,
+                tx_hash.as_deref().unwrap_or("-")
+            ),
+        );
+        Ok(request)
+    })
+}
 
+async fn queue_withdrawal(
+    wallet_id: u32,
+    kind: WithdrawalKind,
+    to: String,
+    amount: Nat,
+) -> Result<WithdrawalRequest, String> {
+    let to = normalize_evm_address(&to).map_err(|err| err.to_string())?;
+    if amount == 0u64 {
+        return Err("amount must be positive".into());
+    }
     let now_sec = time() / 1_000_000_000;
-    let valid_before = nat_to_u64(&req.valid_before).map_err(|_| RelayError::NumberOutOfRange {
-        field: "valid_before".to_string(),
+    let request = state_mut(|state| {
+        let request =
+            new_withdrawal_request(state, wallet_id, kind, to, amount, msg_caller(), now_sec)?;
+        record_audit(
+            state,
+            "withdrawal_requested",
+            format!(
+                "withdrawal={} wallet={} asset={} to={} amount={} executable_after={}",
+                request.id,
+                wallet_id,
+                withdrawal_asset_key(&request.kind),
+                request.to,
+                request.amount,
+                request.executable_after_sec
+            ),
+        );
+        Ok::<_, String>(request)
     })?;
-    if valid_before <= now_sec {
-        return Err(RelayError::AuthorizationExpired);
+    if request.executable_after_sec <= now_sec {
+        run_withdrawal(request.id).await
+    } else {
+        Ok(request)
     }
+}
 
-    let from_hex = to_hex_address(&req.from)?;
-    let to_hex = to_hex_address(&req.to)?;
+async fn run_withdrawal(withdrawal_id: u64) -> Result<WithdrawalRequest, String> {
+    let now_sec = time() / 1_000_000_000;
+    let (wallet_id, outbound) = state_mut(|state| {
+        let outbound = claim_withdrawal(state, withdrawal_id, now_sec)?;
+        let wallet_id = withdrawal_mut(state, withdrawal_id)?
+            .wallet_id
+            .unwrap_or(PRIMARY_WALLET_ID);
+        Ok::<_, String>((wallet_id, outbound))
+    })?;
 
-    state_mut(|state| enforce_rate_limits(state, &from_hex, &req.value))?;
+    let result = send_wallet_transaction(wallet_id, outbound).await;
+    state_mut(|state| {
+        let request = finish_withdrawal(state, withdrawal_id, &result, time() / 1_000_000_000);
+        record_audit(
+            state,
+            "withdrawal_executed",
+            format!(
+                "withdrawal={} asset={} to={} amount={} tx={} error={}",
+                request.id,
+                withdrawal_asset_key(&request.kind),
+                request.to,
+                request.amount,
+                request.tx_hash.as_deref().unwrap_or("-"),
+                request.fail_reason.as_deref().unwrap_or("-")
+            ),
+        );
+        match result {
+            Ok(_) => Ok(request),
+            Err(err) => Err(err.to_string()),
+        }
+    })
+}
 
-    let log_id = state_mut(|state| {
-        let id = state.next_log_id;
-        state.next_log_id += 1;
-        state.logs.push(PaymentLog {
-            id,
-            ts_sec: now_sec,
-            asset: req.asset,
-            from: from_hex.clone(),
-            to: to_hex.clone(),
-            value: req.value.clone(),
-            status: PaymentStatus::Accepted,
-            tx_hash: None,
-            fail_reason: None,
-        });
-        id
+#[update]
+fn set_payout_budget(caller: Principal, asset: Principal, budget: Nat) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        state
+            .payouts
+            .get_or_insert_with(PayoutRegistry::default)
+            .callers
+            .entry(caller)
+            .or_default()
+            .budgets
+            .insert(asset, budget.clone());
+        record_audit(
+            state,
+            "set_payout_budget",
+            format!("caller={} asset={} budget={}", caller, asset, budget),
+        );
     });
+}
 
-    if state_ref(|state| state.config.rpc_endpoint.is_none()) {
-        mark_log_failure(log_id, "rpc endpoint not configured");
-        return Err(RelayError::ConfigurationMissing {
-            field: "rpc_endpoint".into(),
-        });
+#[update]
+fn remove_payout_caller(caller: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        if let Some(registry) = state.payouts.as_mut() {
+            registry.callers.remove(&caller);
+        }
+        record_audit(state, "remove_payout_caller", format!("caller={}", caller));
+    });
+}
 
-    let relayer_addr = relayer_addr_opt.ok_or_else(|| {
-        mark_log_failure(log_id, "relayer address not configured");
-        RelayError::RelayerAddressMissing
-    })?;
+#[query]
+fn payout_budgets(caller: Principal) -> Vec<(Principal, Nat)> {
+    state_ref(|state| {
+        state
+            .payouts
+            .as_ref()
+            .and_then(|registry| registry.callers.get(&caller))
+            .map(|entry| {
+                entry
+                    .budgets
+                    .iter()
+                    .map(|(asset, budget)| (*asset, budget.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
 
-    let relayer_addr_bytes = match evm_address_bytes(&relayer_addr) {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
+/// Inter-canister entry point: pays `amount` of `asset` from the primary
+/// relayer address to `to`. The payout is charged against the caller's
+/// budget and queued; a timer sends queued payouts in id order. Poll
+/// `get_payout` for the outcome.
+#[update]
+fn request_payout(
+    asset: Principal,
+    to: String,
+    amount: Nat,
+    memo: Option<String>,
+) -> Result<Payout, String> {
+    let caller = msg_caller();
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let payout_id = enqueue_payout(state, caller, asset, to, amount, memo, now_sec)
+            .map_err(|err| err.to_string())?;
+        payout_by_id(state, payout_id)
+            .cloned()
+            .ok_or_else(|| "unknown payout id".to_string())
+    })
+}
 
-    let chain_id_nat = match chain_id_opt {
-        Some(ref id) => id.clone(),
-        None => {
-            mark_log_failure(log_id, "chain id not configured");
-            return Err(RelayError::ConfigurationMissing {
-                field: "chain_id".into(),
-            });
-        }
-    };
+#[query]
+fn get_payout(payout_id: u64) -> Result<Payout, String> {
+    let caller = msg_caller();
+    let payout = state_ref(|state| payout_by_id(state, payout_id).cloned())
+        .ok_or_else(|| "unknown payout id".to_string())?;
+    if payout.caller != caller {
+        ensure_admin().map_err(|err| err.to_string())?;
+    }
+    Ok(payout)
+}
 
-    let chain_id_u64 = match nat_to_u64(&chain_id_nat) {
-        Ok(value) => value,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
+#[update]
+async fn run_payout_queue() -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(process_payout_queue(MAX_PAYOUTS_PER_RUN).await)
+}
 
-    if let Err(err) =
-        ensure_authorization_unused(chain_id_u64, &asset_cfg.evm_address, &req.from, &req.nonce)
-            .await
-    {
-        mark_log_failure(log_id, &err.to_string());
-        return Err(err);
-    }
-
-    let call_data = match encode_transfer_with_authorization_call(
-        &req.from,
-        &req.to,
-        &req.value,
-        &req.valid_after,
-        &req.valid_before,
-        &req.nonce,
-        req.sig_v,
-        &req.sig_r,
-        &req.sig_s,
-    ) {
-        Ok(data) => data,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+/// Settles a `Stalled` payout after checking the relayer address on chain:
+/// with the transaction hash it is tracked as broadcast, without one it
+/// fails and the budget is returned.
+#[update]
+fn resolve_stalled_payout(payout_id: u64, tx_hash: Option<String>) -> Result<Payout, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let payout =
+            payout_by_id(state, payout_id).ok_or_else(|| "unknown payout id".to_string())?;
+        if payout.status != PayoutStatus::Stalled {
+            return Err(format!("payout is {:?}", payout.status));
         }
-    };
+        let result = tx_hash
+            .clone()
+            .ok_or_else(|| RelayError::RpcTransportError {
+                code: "stalled".into(),
+                message: "resolved by admin as not sent".into(),
+            });
+        finish_payout(state, payout_id, result, now_sec);
+        record_audit(
+            state,
+            "payout_resolved",
+            format!(
+                "payout={} tx={}",
+                payout_id,
+                tx_hash.as_deref().unwrap_or("-")
+            ),
+        );
+        payout_by_id(state, payout_id)
+            .cloned()
+            .ok_or_else(|| "unknown payout id".to_string())
+    })
+}
 
-    if let Err(err) = simulate_transfer_call(
-        chain_id_u64,
-        &asset_cfg.evm_address,
-        &relayer_addr,
-        &call_data,
-    )
-    .await
-    {
-        mark_log_failure(log_id, &err.to_string());
-        return Err(err);
+#[update]
+fn allow_merchant(merchant: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        state
+            .invoices
+            .get_or_insert_with(InvoiceRegistry::default)
+            .merchants
+            .get_or_insert_with(BTreeSet::new)
+            .insert(merchant);
+        record_audit(state, "allow_merchant", merchant.to_string());
+    });
+}
 
-    let gas_estimate = match estimate_gas(
-        chain_id_u64,
-        &asset_cfg.evm_address,
-        &relayer_addr,
-        &call_data,
-    )
-    .await
-    {
-        Ok(value) => value,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+/// Revokes a merchant. Invoices it already opened stay payable.
+#[update]
+fn remove_merchant(merchant: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        if let Some(merchants) = state
+            .invoices
+            .as_mut()
+            .and_then(|registry| registry.merchants.as_mut())
+        {
+            merchants.remove(&merchant);
         }
-    };
+        record_audit(state, "remove_merchant", merchant.to_string());
+    });
+}
 
-    let mut gas_limit = gas_estimate.clone();
-    let minimum_limit = Nat::from(50_000u64);
-    if gas_limit < minimum_limit {
-        gas_limit = minimum_limit;
+/// Opens an invoice for the caller, which must be an allowed merchant or an
+/// admin. The returned invoice carries the nonce the payer must sign; relays
+/// using it are checked against the invoice.
+#[update]
+fn create_invoice(
+    asset: Principal,
+    recipient: String,
+    amount: Nat,
+    expires_at: u64,
+    reference: String,
+) -> Result<Invoice, String> {
+    let merchant = msg_caller();
+    let is_admin = ensure_admin().is_ok();
+    let recipient = normalize_evm_address(&recipient).map_err(|err| err.to_string())?;
+    if amount == 0u64 {
+        return Err("invoice amount must be positive".into());
     }
-    gas_limit = match scale_nat(&gas_limit, 1.2) {
-        Ok(val) => {
-            if val < gas_estimate {
-                gas_estimate.clone()
-            } else {
-                val
-            }
-        }
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+    if reference.len() > MAX_INVOICE_REFERENCE_LEN {
+        return Err("invoice reference too long".into());
+    }
+    let now_sec = time() / 1_000_000_000;
+    if expires_at <= now_sec {
+        return Err("invoice already expired".into());
+    }
+    state_mut(|state| {
+        match state.assets.get(&asset) {
+            Some(cfg) if matches!(cfg.status, AssetStatus::Active) => {}
+            Some(_) => return Err(RelayError::AssetNotActive.to_string()),
+            None => return Err(RelayError::AssetNotRegistered.to_string()),
         }
-    };
-
-    let base_fee = match fetch_base_fee(chain_id_u64).await {
-        Ok(val) => val,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+        let seed = state
+            .nonce_seed
+            .clone()
+            .ok_or_else(|| "nonce seed not initialised yet".to_string())?;
+        let registry = state.invoices.get_or_insert_with(InvoiceRegistry::default);
+        let allowed = registry
+            .merchants
+            .as_ref()
+            .is_some_and(|merchants| merchants.contains(&merchant));
+        if !allowed && !is_admin {
+            return Err(RelayError::NotAuthorized.to_string());
         }
-    };
+        open_invoice(
+            registry,
+            Invoice {
+                id: 0,
+                merchant,
+                asset,
+                recipient,
+                amount,
+                expires_sec: expires_at,
+                reference,
+                nonce: Vec::new(),
+                status: InvoiceStatus::Open,
+                log_id: None,
+                tx_hash: None,
+                created_sec: now_sec,
+                paid_sec: None,
+            },
+            &seed,
+            now_sec,
+        )
+    })
+}
 
-    let priority_fee = match fetch_max_priority_fee(chain_id_u64).await {
-        Ok(val) => val,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+#[update]
+fn cancel_invoice(id: u64) -> Result<(), String> {
+    let caller = msg_caller();
+    let is_admin = ensure_admin().is_ok();
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let invoice = state
+            .invoices
+            .as_mut()
+            .and_then(|registry| registry.invoices.get_mut(&id))
+            .ok_or_else(|| "unknown invoice".to_string())?;
+        if invoice.merchant != caller && !is_admin {
+            return Err(RelayError::NotAuthorized.to_string());
         }
-    };
-
-    let priority_fee_effective = match scale_nat(&priority_fee, priority_multiplier) {
-        Ok(val) => {
-            if val < priority_fee {
-                priority_fee.clone()
-            } else {
-                val
+        match invoice_status(invoice, now_sec) {
+            InvoiceStatus::Open | InvoiceStatus::Expired => {
+                invoice.status = InvoiceStatus::Cancelled;
+                Ok(())
             }
+            status => Err(format!("invoice is {:?}", status)),
         }
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
+    })
+}
 
-    let priority_fee_effective = if priority_fee_effective == Nat::from(0u64) {
-        Nat::from(1_000_000_000u64)
-    } else {
-        priority_fee_effective
-    };
+/// Invoices are public by id so payers can fetch the nonce to sign.
+#[query]
+fn get_invoice(id: u64) -> Result<Invoice, String> {
+    let now_sec = time() / 1_000_000_000;
+    state_ref(|state| {
+        state
+            .invoices
+            .as_ref()
+            .and_then(|registry| registry.invoices.get(&id))
+            .map(|invoice| with_current_status(invoice, now_sec))
+            .ok_or_else(|| "unknown invoice".to_string())
+    })
+}
 
-    let base_fee_scaled = match scale_nat(&base_fee, max_fee_multiplier) {
-        Ok(val) => {
-            if val < base_fee {
-                base_fee.clone()
-            } else {
-                val
-            }
-        }
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
+/// Lists a merchant's invoices, newest first. Defaults to the caller;
+/// other merchants' invoices require admin.
+#[query]
+fn invoices(
+    merchant: Option<Principal>,
+    status: Option<InvoiceStatus>,
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<Vec<Invoice>, String> {
+    let caller = msg_caller();
+    let merchant = merchant.unwrap_or(caller);
+    if merchant != caller {
+        ensure_admin().map_err(|err| err.to_string())?;
+    }
+    let now_sec = time() / 1_000_000_000;
+    Ok(state_ref(|state| {
+        state
+            .invoices
+            .iter()
+            .flat_map(|registry| registry.invoices.values().rev())
+            .filter(|invoice| start_after.is_none_or(|cursor| invoice.id < cursor))
+            .filter(|invoice| invoice.merchant == merchant)
+            .map(|invoice| with_current_status(invoice, now_sec))
+            .filter(|invoice| status.is_none_or(|status| invoice.status == status))
+            .take(limit.clamp(1, 100) as usize)
+            .collect()
+    }))
+}
 
-    let max_fee_per_gas = base_fee_scaled.clone() + priority_fee_effective.clone();
+#[update]
+fn set_webhook(recipient: String, url: String, secret: String) -> Result<(), String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let recipient = normalize_evm_address(&recipient).map_err(|err| err.to_string())?;
+    if secret.len() < 16 {
+        return Err("webhook secret must be at least 16 bytes".into());
+    }
+    let allow_local = state_ref(|state| {
+        state
+            .webhooks
+            .as_ref()
+            .is_some_and(|registry| registry.config.allow_insecure_localhost)
+    });
+    validate_webhook_url(&url, allow_local)?;
+    state_mut(|state| {
+        record_audit(state, "set_webhook", format!("{} {}", recipient, url));
+        state
+            .webhooks
+            .get_or_insert_with(WebhookRegistry::default)
+            .endpoints
+            .insert(
+                recipient,
+                WebhookEndpoint {
+                    url,
+                    secret,
+                    created_sec: time() / 1_000_000_000,
+                },
+            );
+    });
+    Ok(())
+}
 
-    let balance = match fetch_balance(chain_id_u64, &relayer_addr).await {
-        Ok(val) => val,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
+#[update]
+fn remove_webhook(recipient: String) -> Result<(), String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let recipient = normalize_evm_address(&recipient).map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        record_audit(state, "remove_webhook", recipient.clone());
+        if let Some(registry) = state.webhooks.as_mut() {
+            registry.endpoints.remove(&recipient);
         }
-    };
-
-    state_mut(|state| state.last_known_gas = balance.clone());
+    });
+    Ok(())
+}
 
-    if balance < threshold_wei {
-        mark_log_failure(log_id, "relayer gas below threshold");
-        return Err(RelayError::GasBalanceLow {
-            required: threshold_wei,
-            actual: balance,
-        });
+#[update]
+fn set_webhook_config(config: WebhookConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-
-    let chain_id = chain_id_nat;
-
-    let nonce = match fetch_nonce(chain_id_u64, &relayer_addr).await {
-        Ok(val) => val,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
-
-    let asset_address_bytes = match evm_address_bytes(&asset_cfg.evm_address) {
-        Ok(bytes) => bytes,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
-
-    let mut unsigned_items = Vec::new();
-    unsigned_items.push(rlp_encode_nat_value(&chain_id));
-    unsigned_items.push(rlp_encode_nat_value(&nonce));
-    unsigned_items.push(rlp_encode_nat_value(&priority_fee_effective));
-    unsigned_items.push(rlp_encode_nat_value(&max_fee_per_gas));
-    unsigned_items.push(rlp_encode_nat_value(&gas_limit));
-    unsigned_items.push(rlp_encode_bytes(&asset_address_bytes));
-    unsigned_items.push(rlp_encode_nat_value(&Nat::from(0u64))); // value = 0
-    unsigned_items.push(rlp_encode_bytes(&call_data));
-    unsigned_items.push(rlp_encode_list(&[])); // access list
-
-    let unsigned_rlp = rlp_encode_list(&unsigned_items);
-    let mut signing_payload = Vec::with_capacity(1 + unsigned_rlp.len());
-    signing_payload.push(0x02);
-    signing_payload.extend_from_slice(&unsigned_rlp);
-    let sighash = keccak256(&signing_payload);
-
-    let signature = match sign_prehashed_message(
-        &ecdsa_key_name,
-        &derivation_path,
-        &sighash,
-        &relayer_addr_bytes,
-    )
-    .await
-    {
-        Ok(sig) => sig,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
-
-    let mut signed_items = unsigned_items;
-    signed_items.push(rlp_encode_nat_value(&Nat::from(signature.y_parity as u64)));
-    signed_items.push(rlp_encode_bytes(&signature.r));
-    signed_items.push(rlp_encode_bytes(&signature.s));
-
-    let signed_rlp = rlp_encode_list(&signed_items);
-    let mut raw_tx = Vec::with_capacity(1 + signed_rlp.len());
-    raw_tx.push(0x02);
-    raw_tx.extend_from_slice(&signed_rlp);
-
-    let tx_hash = match send_raw_transaction(chain_id_u64, &raw_tx).await {
-        Ok(hash) => hash,
-        Err(err) => {
-            mark_log_failure(log_id, &err.to_string());
-            return Err(err);
-        }
-    };
-
-    mark_log_success(log_id, &tx_hash);
-    Ok(tx_hash)
+    state_mut(|state| {
+        record_audit(state, "set_webhook_config", format!("{:?}", config));
+        state
+            .webhooks
+            .get_or_insert_with(WebhookRegistry::default)
+            .config = config;
+    });
 }
 
-fn nat_to_u32(value: &Nat) -> InternalResult<u32> {
-    value
-        .0
-        .to_u32()
-        .ok_or_else(|| RelayError::NumberOutOfRange {
-            field: "u32".to_string(),
-        })
+#[query]
+fn webhooks() -> Result<Vec<WebhookInfo>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(state_ref(|state| {
+        state
+            .webhooks
+            .iter()
+            .flat_map(|registry| registry.endpoints.iter())
+            .map(|(recipient, endpoint)| WebhookInfo {
+                recipient: recipient.clone(),
+                url: endpoint.url.clone(),
+                created_sec: endpoint.created_sec,
+            })
+            .collect()
+    }))
 }
 
-fn nat_to_u64(value: &Nat) -> InternalResult<u64> {
-    value
-        .0
-        .to_u64()
-        .ok_or_else(|| RelayError::NumberOutOfRange {
-            field: "u64".to_string(),
-        })
+#[query]
+fn webhook_deliveries(
+    start_after: Option<u64>,
+    limit: u32,
+) -> Result<Vec<WebhookDelivery>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(state_ref(|state| {
+        state
+            .webhooks
+            .iter()
+            .flat_map(|registry| registry.deliveries.iter().rev())
+            .filter(|delivery| start_after.is_none_or(|cursor| delivery.id < cursor))
+            .take(limit.max(1) as usize)
+            .cloned()
+            .collect()
+    }))
 }
 
-fn to_hex_address(bytes: &[u8]) -> InternalResult<String> {
-    if bytes.len() != 20 {
-        return Err(RelayError::InvalidAddressLength {
-            field: "address".into(),
-            expected: 20,
-            actual: bytes.len(),
-        });
+#[update]
+fn allow_subscriber(subscriber: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-    Ok(format!("0x{}", hex::encode(bytes)))
+    state_mut(|state| {
+        state
+            .subscriptions
+            .get_or_insert_with(SubscriptionRegistry::default)
+            .allowed
+            .get_or_insert_with(BTreeSet::new)
+            .insert(subscriber);
+        record_audit(state, "allow_subscriber", subscriber.to_string());
+    });
 }
 
-fn mark_log_failure(log_id: u64, reason: &str) {
+/// Revokes a subscriber and drops its subscriptions.
+#[update]
+fn remove_subscriber(subscriber: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
     state_mut(|state| {
-        if let Some(log) = state.logs.iter_mut().find(|l| l.id == log_id) {
-            log.status = PaymentStatus::Failed;
-            log.fail_reason = Some(reason.to_string());
+        if let Some(registry) = state.subscriptions.as_mut() {
+            if let Some(allowed) = registry.allowed.as_mut() {
+                allowed.remove(&subscriber);
+            }
+            let owned: Vec<u64> = registry
+                .subscriptions
+                .values()
+                .filter(|sub| sub.subscriber == subscriber)
+                .map(|sub| sub.id)
+                .collect();
+            for id in owned {
+                drop_subscription(registry, id);
+            }
         }
+        record_audit(state, "remove_subscriber", subscriber.to_string());
     });
 }
 
-fn mark_log_success(log_id: u64, tx_hash: &str) {
+/// Subscribes the caller, which must have been allowed by an admin.
+#[update]
+fn subscribe(filter: SubscriptionFilter) -> Result<u64, String> {
+    let caller = msg_caller();
+    let recipient = filter
+        .recipient
+        .as_deref()
+        .map(normalize_evm_address)
+        .transpose()
+        .map_err(|err| err.to_string())?;
+    let filter = SubscriptionFilter {
+        recipient,
+        ..filter
+    };
+    let now_sec = time() / 1_000_000_000;
     state_mut(|state| {
-        if let Some(log) = state.logs.iter_mut().find(|l| l.id == log_id) {
-            log.status = PaymentStatus::Broadcasted;
-            log.tx_hash = Some(tx_hash.to_string());
-            log.fail_reason = None;
-        }
-    });
+        let registry = state
+            .subscriptions
+            .get_or_insert_with(SubscriptionRegistry::default);
+        register_subscription(registry, caller, filter, now_sec)
+    })
 }
 
-fn normalize_evm_address(address: &str) -> InternalResult<String> {
-    let trimmed = address.trim();
-    if trimmed.len() != 42 || !trimmed.starts_with("0x") {
-        return Err(RelayError::InvalidAddressLength {
-            field: "evm_address".into(),
-            expected: 42,
-            actual: trimmed.len(),
-        });
+fn register_subscription(
+    registry: &mut SubscriptionRegistry,
+    caller: Principal,
+    filter: SubscriptionFilter,
+    now_sec: u64,
+) -> Result<u64, String> {
+    if !registry
+        .allowed
+        .as_ref()
+        .is_some_and(|allowed| allowed.contains(&caller))
+    {
+        return Err(RelayError::NotAuthorized.to_string());
     }
-    let bytes = hex::decode(&trimmed[2..]).map_err(|_| RelayError::HexDecodeFailed {
-        value: trimmed.to_string(),
-    })?;
-    if bytes.len() != 20 {
-        return Err(RelayError::InvalidAddressLength {
-            field: "evm_address".into(),
-            expected: 20,
-            actual: bytes.len(),
-        });
+    let owned = registry
+        .subscriptions
+        .values()
+        .filter(|sub| sub.subscriber == caller)
+        .count();
+    if owned >= MAX_SUBSCRIPTIONS_PER_CALLER || registry.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+        return Err("subscription limit reached".to_string());
     }
-    Ok(format!("0x{}", hex::encode(bytes)))
+    registry.next_id += 1;
+    let id = registry.next_id;
+    registry.subscriptions.insert(
+        id,
+        Subscription {
+            id,
+            subscriber: caller,
+            filter,
+            created_sec: now_sec,
+            delivered: 0,
+            consecutive_failures: 0,
+            last_error: None,
+        },
+    );
+    Ok(id)
 }
 
-fn pad_left(value: &[u8], len: usize) -> Vec<u8> {
-    if value.len() >= len {
-        return value[value.len() - len..].to_vec();
-    }
-    let mut out = vec![0u8; len - value.len()];
-    out.extend_from_slice(value);
-    out
+/// Removes a subscription; allowed for its subscriber and for admins.
+#[update]
+fn unsubscribe(id: u64) -> Result<(), String> {
+    let caller = msg_caller();
+    let is_admin = ensure_admin().is_ok();
+    state_mut(|state| {
+        let registry = state
+            .subscriptions
+            .as_mut()
+            .ok_or_else(|| "unknown subscription".to_string())?;
+        match registry.subscriptions.get(&id) {
+            Some(sub) if sub.subscriber == caller || is_admin => {}
+            Some(_) => return Err(RelayError::NotAuthorized.to_string()),
+            None => return Err("unknown subscription".into()),
+        }
+        drop_subscription(registry, id);
+        Ok(())
+    })
 }
 
-fn encode_uint_nat(value: &Nat) -> InternalResult<Vec<u8>> {
-    let bytes = value.0.to_bytes_be();
-    if bytes.len() > 32 {
-        return Err(RelayError::NumberOutOfRange {
-            field: "uint256".into(),
-        });
-    }
-    Ok(pad_left(&bytes, 32))
+/// The caller's own subscriptions, or all of them for admins.
+#[query]
+fn subscriptions() -> Vec<Subscription> {
+    let caller = msg_caller();
+    let is_admin = ensure_admin().is_ok();
+    state_ref(|state| {
+        state
+            .subscriptions
+            .iter()
+            .flat_map(|registry| registry.subscriptions.values())
+            .filter(|sub| is_admin || sub.subscriber == caller)
+            .cloned()
+            .collect()
+    })
 }
 
-fn encode_uint_u8(value: u8) -> Vec<u8> {
-    pad_left(&[value], 32)
+#[update]
+async fn run_webhook_queue() -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(process_webhook_queue(MAX_WEBHOOKS_PER_RUN).await)
 }
 
-fn encode_bytes32(value: &[u8]) -> InternalResult<Vec<u8>> {
-    if value.len() != 32 {
-        return Err(RelayError::InvalidNonceLength {
-            expected: 32,
-            actual: value.len(),
-        });
-    }
-    Ok(value.to_vec())
+/// Checks receipts of broadcasted payments (relays and payouts) and moves
+/// them to `Confirmed` or `Failed`. Returns how many logs changed state.
+#[update]
+async fn poll_receipts(limit: u32) -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    poll_broadcasted_receipts(limit.max(1) as usize)
+        .await
+        .map_err(|err| err.to_string())
 }
 
-fn to_hex_prefixed(bytes: &[u8]) -> String {
-    format!("0x{}", hex::encode(bytes))
+#[update]
+fn set_fee_recipient(recipient: Option<String>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    let recipient = recipient.map(|addr| match normalize_evm_address(&addr) {
+        Ok(addr) => addr,
+        Err(err) => ic_cdk::trap(err.to_string()),
+    });
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_fee_recipient",
+            format!("recipient={}", recipient.as_deref().unwrap_or("-")),
+        );
+        state
+            .fee_settings
+            .get_or_insert_with(FeeSettings::default)
+            .recipient = recipient;
+    });
 }
 
-fn parse_hex_bytes(value: &str) -> InternalResult<Vec<u8>> {
-    let trimmed = value.trim();
-    if !trimmed.starts_with("0x") {
-        return Err(RelayError::HexDecodeFailed {
-            value: trimmed.to_string(),
-        });
-    }
-    let mut body = trimmed[2..].to_string();
-    if body.is_empty() {
-        return Ok(vec![]);
-    }
-    if body.len() % 2 != 0 {
-        body = format!("0{}", body);
+#[update]
+fn set_batch_contract(contract: Option<String>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-    hex::decode(&body).map_err(|_| RelayError::HexDecodeFailed {
-        value: trimmed.to_string(),
-    })
+    let contract = contract.map(|addr| match normalize_evm_address(&addr) {
+        Ok(addr) => addr,
+        Err(err) => ic_cdk::trap(err.to_string()),
+    });
+    state_mut(|state| {
+        state
+            .fee_settings
+            .get_or_insert_with(FeeSettings::default)
+            .batch_contract = contract;
+    });
 }
 
-fn decode_bool_abi(bytes: &[u8]) -> InternalResult<bool> {
-    if bytes.is_empty() {
-        return Ok(false);
-    }
-    Ok(bytes[bytes.len() - 1] != 0)
+/// Fee the wallet must authorize (as a second EIP-3009 transfer to
+/// `fee_recipient`) for a payment of `value`.
+#[query]
+fn quote_fee(asset: Principal, value: Nat) -> Result<FeeQuote, String> {
+    state_ref(|state| {
+        let asset_cfg = state
+            .assets
+            .get(&asset)
+            .ok_or_else(|| RelayError::AssetNotRegistered.to_string())?;
+        let settings = state.fee_settings.clone().unwrap_or_default();
+        Ok(FeeQuote {
+            asset,
+            fee_bps: asset_cfg.fee_bps,
+            fee_value: required_fee(&value, asset_cfg.fee_bps),
+            value,
+            fee_recipient: settings.recipient,
+            batched: settings.batch_contract.is_some(),
+        })
+    })
 }
 
-fn nat_from_hex(value: &str) -> InternalResult<Nat> {
-    let bytes = parse_hex_bytes(value)?;
-    Ok(Nat::from(BigUint::from_bytes_be(&bytes)))
+#[update]
+fn add_screening_entries(
+    list: ScreeningList,
+    entries: Vec<ScreeningEntryInput>,
+) -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let count = insert_screening_entries(state, list, entries, now_sec)?;
+        record_audit(
+            state,
+            "add_screening_entries",
+            format!("{:?} count={}", list, count),
+        );
+        Ok(count)
+    })
+    .map_err(|err: RelayError| err.to_string())
 }
 
-fn nat_from_hex_with_zero_default(value: &str) -> InternalResult<Nat> {
-    if value == "0x" {
-        Ok(Nat::from(0u32))
-    } else {
-        nat_from_hex(value)
-    }
+/// Bulk import. CSV rows are `address,reason[,expires_sec]` (a header row
+/// starting with `address` is skipped); JSON is an array of
+/// `{"address", "reason", "expires_sec"}` objects.
+#[update]
+fn import_screening_list(
+    list: ScreeningList,
+    format: ImportFormat,
+    data: String,
+) -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let entries = parse_screening_import(format, &data).map_err(|err| err.to_string())?;
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let count = insert_screening_entries(state, list, entries, now_sec)?;
+        record_audit(
+            state,
+            "add_screening_entries",
+            format!("{:?} count={}", list, count),
+        );
+        Ok(count)
+    })
+    .map_err(|err: RelayError| err.to_string())
 }
 
-fn nat_to_u128(value: &Nat) -> InternalResult<u128> {
-    value
-        .0
-        .to_u128()
-        .ok_or_else(|| RelayError::NumberOutOfRange {
-            field: "u128".into(),
-        })
+#[update]
+fn remove_screening_entries(list: ScreeningList, addresses: Vec<String>) -> Result<u32, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let normalized = addresses
+        .iter()
+        .map(|address| normalize_evm_address(address))
+        .collect::<InternalResult<Vec<_>>>()
+        .map_err(|err| err.to_string())?;
+    Ok(state_mut(|state| {
+        let entries = screening_list_mut(state, list);
+        let removed = normalized
+            .iter()
+            .filter(|address| entries.remove(*address).is_some())
+            .count() as u32;
+        record_audit(
+            state,
+            "remove_screening_entries",
+            format!("{:?} removed={}", list, removed),
+        );
+        removed
+    }))
 }
 
-fn scale_nat(value: &Nat, multiplier: f64) -> InternalResult<Nat> {
-    let base = nat_to_u128(value)?;
-    let scaled = (base as f64 * multiplier).ceil();
-    if scaled.is_nan() || scaled.is_infinite() || scaled < 0.0 {
-        return Err(RelayError::NumberOutOfRange {
-            field: "scaled nat".into(),
-        });
+#[update]
+fn set_allowlist_enforcement(from: bool, to: bool) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-    Ok(Nat::from(scaled as u128))
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_allowlist_enforcement",
+            format!("from={} to={}", from, to),
+        );
+        let screening = state.screening.get_or_insert_with(ScreeningState::default);
+        screening.enforce_allow_from = from;
+        screening.enforce_allow_to = to;
+    });
 }
 
-const JPYC_UNIT_MULTIPLIER: u128 = 1_000_000_000_000_000_000;
-const RPC_RESPONSE_MAX_BYTES: u64 = 64 * 1024;
-static JSON_RPC_ID: AtomicU64 = AtomicU64::new(1);
+#[query]
+fn screening_entries(
+    list: ScreeningList,
+    start_after: Option<String>,
+    limit: u32,
+) -> Result<Vec<ScreeningEntryView>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    Ok(state_ref(|state| {
+        state
+            .screening
+            .iter()
+            .flat_map(|screening| screening_list(screening, list).iter())
+            .filter(|(address, _)| start_after.as_ref().is_none_or(|cursor| *address > cursor))
+            .take(limit.max(1) as usize)
+            .map(|(address, entry)| ScreeningEntryView {
+                address: address.clone(),
+                entry: entry.clone(),
+            })
+            .collect()
+    }))
+}
 
-fn daily_cap_in_smallest_unit(config: &RateLimitConfig) -> Option<Nat> {
-    if config.daily_cap_token == 0 {
-        None
-    } else {
-        Some(Nat::from(config.daily_cap_token) * Nat::from(JPYC_UNIT_MULTIPLIER))
+/// Sets the default rollout mode, or `asset`'s override; `mode = None` with
+/// an asset removes the override.
+#[update]
+fn set_rollout_mode(asset: Option<Principal>, mode: Option<RolloutMode>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    if let Some(RolloutMode::Percentage { bps }) = &mode {
+        if *bps > 10_000 {
+            ic_cdk::trap("bps must be <= 10000");
+        }
     }
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_rollout_mode",
+            format!("asset={:?} mode={:?}", asset, mode),
+        );
+        let rollout = state.rollout.get_or_insert_with(RolloutState::default);
+        match (asset, mode) {
+            (None, Some(mode)) => rollout.mode = mode,
+            (None, None) => ic_cdk::trap("default rollout mode is required"),
+            (Some(asset), Some(mode)) => {
+                rollout.asset_overrides.insert(asset, mode);
+            }
+            (Some(asset), None) => {
+                rollout.asset_overrides.remove(&asset);
+            }
+        }
+    });
 }
 
-fn enforce_rate_limits(state: &mut RelayerState, from: &str, amount: &Nat) -> InternalResult<()> {
-    let now_sec = time() / 1_000_000_000;
-    if state.rate_limit.per_addr_per_min > 0 {
-        let window = now_sec / 60;
-        let counter = state
-            .rate_state
-            .per_min_counter
-            .entry(from.to_string())
-            .or_default();
-        if counter.window_start_sec != window {
-            counter.window_start_sec = window;
-            counter.amount = Nat::from(0u32);
-            counter.hits = 0;
-        }
-        if counter.hits >= state.rate_limit.per_addr_per_min {
-            return Err(RelayError::RateLimited);
+#[update]
+fn update_rollout_allowlist(add: Vec<String>, remove: Vec<String>) -> Result<u64, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let normalize = |addresses: Vec<String>| {
+        addresses
+            .iter()
+            .map(|address| normalize_evm_address(address))
+            .collect::<InternalResult<Vec<_>>>()
+            .map_err(|err| err.to_string())
+    };
+    let (add, remove) = (normalize(add)?, normalize(remove)?);
+    Ok(state_mut(|state| {
+        record_audit(
+            state,
+            "update_rollout_allowlist",
+            format!("add={} remove={}", add.len(), remove.len()),
+        );
+        let rollout = state.rollout.get_or_insert_with(RolloutState::default);
+        rollout.allowlist.extend(add);
+        for address in &remove {
+            rollout.allowlist.remove(address);
         }
-        counter.hits += 1;
-        counter.amount = counter.amount.clone() + amount.clone();
+        rollout.allowlist.len() as u64
+    }))
+}
+
+/// Replaces the ramp schedule; steps are applied by the rollout timer once
+/// `at_sec` has passed.
+#[update]
+fn schedule_rollout(steps: Vec<RampStep>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    if steps
+        .iter()
+        .any(|step| matches!(step.mode, RolloutMode::Percentage { bps } if bps > 10_000))
+    {
+        ic_cdk::trap("bps must be <= 10000");
+    }
+    let mut steps = steps;
+    steps.sort_by_key(|step| step.at_sec);
+    state_mut(|state| {
+        record_audit(state, "schedule_rollout", format!("steps={}", steps.len()));
+        state
+            .rollout
+            .get_or_insert_with(RolloutState::default)
+            .schedule = steps;
+    });
+}
 
-    if let Some(cap) = daily_cap_in_smallest_unit(&state.rate_limit) {
-        let window = now_sec / 86_400;
-        let counter = state
-            .rate_state
-            .daily_counter
-            .entry(from.to_string())
-            .or_default();
-        if counter.window_start_sec != window {
-            counter.window_start_sec = window;
-            counter.amount = Nat::from(0u32);
-            counter.hits = 0;
+#[query]
+fn rollout_status() -> RolloutStatus {
+    state_ref(|state| {
+        let rollout = state.rollout.clone().unwrap_or_default();
+        RolloutStatus {
+            mode: rollout.mode,
+            asset_overrides: rollout.asset_overrides.into_iter().collect(),
+            allowlist_size: rollout.allowlist.len() as u64,
+            schedule: rollout.schedule,
         }
-        counter.hits += 1;
-        counter.amount = counter.amount.clone() + amount.clone();
-        if counter.amount > cap {
-            return Err(RelayError::RateLimited);
+    })
+}
+
+#[update]
+fn set_kyc_attestor(id: String, key: KycAttestorKey) -> Result<(), String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    parse_attestor_key(&key).map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        record_audit(state, "set_kyc_attestor", id.clone());
+        state
+            .kyc
+            .get_or_insert_with(KycState::default)
+            .attestors
+            .insert(id, key);
+    });
+    Ok(())
+}
+
+/// Removes an attestor and every cached verification it issued.
+#[update]
+fn remove_kyc_attestor(id: String) -> Result<(), String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    state_mut(|state| {
+        record_audit(state, "remove_kyc_attestor", id.clone());
+        if let Some(kyc) = state.kyc.as_mut() {
+            kyc.attestors.remove(&id);
+            kyc.verified.retain(|_, verified| verified.attestor != id);
         }
-    }
+    });
     Ok(())
 }
 
-fn trim_leading_zeroes(data: &[u8]) -> Vec<u8> {
-    let mut index = 0;
-    while index < data.len() && data[index] == 0 {
-        index += 1;
-    }
-    if index >= data.len() {
-        Vec::new()
-    } else {
-        data[index..].to_vec()
+#[update]
+fn set_kyc_policy(policy: Option<KycPolicy>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        record_audit(state, "set_kyc_policy", format!("{:?}", policy));
+        state.kyc.get_or_insert_with(KycState::default).policy = policy;
+    });
 }
 
-fn nat_to_be_bytes(value: &Nat) -> Vec<u8> {
-    let bytes = value.0.to_bytes_be();
-    trim_leading_zeroes(&bytes)
+#[query]
+fn kyc_status(address: String) -> Result<Option<VerifiedKyc>, String> {
+    ensure_admin().map_err(|err| err.to_string())?;
+    let address = normalize_evm_address(&address).map_err(|err| err.to_string())?;
+    let now_sec = time() / 1_000_000_000;
+    Ok(state_ref(|state| {
+        state
+            .kyc
+            .as_ref()
+            .and_then(|kyc| kyc.verified.get(&address))
+            .filter(|verified| verified.expires_sec > now_sec)
+            .cloned()
+    }))
 }
 
-fn length_to_bytes(len: usize) -> Vec<u8> {
-    let mut value = len;
-    let mut bytes = Vec::new();
-    while value > 0 {
-        bytes.push((value & 0xff) as u8);
-        value >>= 8;
-    }
-    bytes.reverse();
-    if bytes.is_empty() {
-        vec![0]
-    } else {
-        bytes
+#[update]
+fn set_rate_limits(config: RateLimiterConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        record_audit(state, "set_rate_limits", format!("{:?}", config));
+        limiter_mut(state).config = config;
+    });
 }
 
-fn rlp_encode_bytes(data: &[u8]) -> Vec<u8> {
-    match data.len() {
-        0 => vec![0x80],
-        1 if data[0] < 0x80 => vec![data[0]],
-        len if len <= 55 => {
-            let mut out = Vec::with_capacity(1 + len);
-            out.push(0x80 + len as u8);
-            out.extend_from_slice(data);
-            out
-        }
-        len => {
-            let len_bytes = length_to_bytes(len);
-            let mut out = Vec::with_capacity(1 + len_bytes.len() + len);
-            out.push(0xB7 + len_bytes.len() as u8);
-            out.extend_from_slice(&len_bytes);
-            out.extend_from_slice(data);
-            out
-        }
+#[update]
+fn set_daily_cap_token(cap: u64) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        record_audit(state, "set_daily_cap_token", cap.to_string());
+        state.rate_limit.daily_cap_token = cap;
+    });
 }
 
-fn rlp_encode_nat_value(value: &Nat) -> Vec<u8> {
-    let bytes = nat_to_be_bytes(value);
-    rlp_encode_bytes(&bytes)
+#[update]
+fn set_rate_limit_max_keys(max_tracked_keys: u64) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_rate_limit_max_keys",
+            max_tracked_keys.to_string(),
+        );
+        limiter_mut(state)
+            .gc
+            .get_or_insert_with(RateLimitGc::default)
+            .max_tracked_keys = max_tracked_keys;
+    });
 }
 
-fn rlp_encode_list(items: &[Vec<u8>]) -> Vec<u8> {
-    let total_len: usize = items.iter().map(|item| item.len()).sum();
-    if total_len <= 55 {
-        let mut out = Vec::with_capacity(1 + total_len);
-        out.push(0xC0 + total_len as u8);
-        for item in items {
-            out.extend_from_slice(item);
-        }
-        out
-    } else {
-        let len_bytes = length_to_bytes(total_len);
-        let mut out = Vec::with_capacity(1 + len_bytes.len() + total_len);
-        out.push(0xF7 + len_bytes.len() as u8);
-        out.extend_from_slice(&len_bytes);
-        for item in items {
-            out.extend_from_slice(item);
+#[query]
+fn rate_limit_metrics() -> RateLimitMetrics {
+    state_ref(|state| {
+        let gc = state
+            .limiter
+            .as_ref()
+            .and_then(|limiter| limiter.gc.clone())
+            .unwrap_or_default();
+        RateLimitMetrics {
+            bucket_entries: state
+                .limiter
+                .as_ref()
+                .map(|limiter| limiter.buckets.len() as u64)
+                .unwrap_or(0),
+            daily_entries: state.rate_state.daily_counter.len() as u64,
+            max_tracked_keys: gc.max_tracked_keys,
+            evicted_total: gc.evicted_total,
+            last_evicted: gc.last_evicted,
+            last_run_sec: gc.last_run_sec,
         }
-        out
-    }
+    })
 }
 
-const KECCAKF_ROUND_CONSTANTS: [u64; 24] = [
-    0x0000000000000001,
-    0x0000000000008082,
-    0x800000000000808a,
-    0x8000000080008000,
-    0x000000000000808b,
-    0x0000000080000001,
-    0x8000000080008081,
-    0x8000000000008009,
-    0x000000000000008a,
-    0x0000000000000088,
-    0x0000000080008009,
-    0x000000008000000a,
-    0x000000008000808b,
-    0x800000000000008b,
-    0x8000000000008089,
-    0x8000000000008003,
-    0x8000000000008002,
-    0x8000000000000080,
-    0x000000000000800a,
-    0x800000008000000a,
-    0x8000000080008081,
-    0x8000000000008080,
-    0x0000000080000001,
-    0x8000000080008008,
-];
-
-const KECCAKF_ROTATION: [u32; 24] = [
-    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
-];
-
-const KECCAKF_PERMUTATION: [usize; 24] = [
-    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
-];
+#[query]
+fn rate_limits() -> RateLimiterConfig {
+    state_ref(|state| match &state.limiter {
+        Some(limiter) => limiter.config.clone(),
+        None => legacy_limiter_config(&state.rate_limit),
+    })
+}
 
-fn keccak_f1600(state: &mut [u64; 25]) {
-    let mut bc = [0u64; 5];
-    for &rc in KECCAKF_ROUND_CONSTANTS.iter() {
-        for i in 0..5 {
-            bc[i] = state[i] ^ state[i + 5] ^ state[i + 10] ^ state[i + 15] ^ state[i + 20];
-        }
-        for i in 0..5 {
-            let t = bc[(i + 4) % 5] ^ bc[(i + 1) % 5].rotate_left(1);
-            for j in (0..25).step_by(5) {
-                state[i + j] ^= t;
-            }
-        }
-        let mut t = state[1];
-        for i in 0..24 {
-            let j = KECCAKF_PERMUTATION[i];
-            let tmp = state[j];
-            state[j] = t.rotate_left(KECCAKF_ROTATION[i]);
-            t = tmp;
-        }
-        for j in (0..25).step_by(5) {
-            for i in 0..5 {
-                bc[i] = state[j + i];
-            }
-            for i in 0..5 {
-                state[j + i] ^= (!bc[(i + 1) % 5]) & bc[(i + 2) % 5];
-            }
+#[update]
+fn set_pricing_config(config: PricingConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    if let PriceSource::Http { url, .. } = &config.source {
+        if !url.starts_with("https://") {
+            ic_cdk::trap("price source url must start with https://");
         }
-        state[0] ^= rc;
     }
+    state_mut(|state| {
+        record_audit(
+            state,
+            "set_pricing_config",
+            format!(
+                "enabled={} margin_bps={} quote_ttl_sec={}",
+                config.enabled, config.margin_bps, config.quote_ttl_sec
+            ),
+        );
+        let pricing = state.pricing.get_or_insert_with(PricingState::default);
+        pricing.config = config;
+        pricing.last_price = None;
+    });
 }
 
-fn absorb_block(state: &mut [u64; 25], block: &[u8]) {
-    for (i, chunk) in block.chunks(8).enumerate() {
-        let mut lane_bytes = [0u8; 8];
-        lane_bytes[..chunk.len()].copy_from_slice(chunk);
-        state[i] ^= u64::from_le_bytes(lane_bytes);
-    }
+#[query]
+fn pricing_config() -> PricingConfig {
+    state_ref(|state| {
+        state
+            .pricing
+            .as_ref()
+            .map(|pricing| pricing.config.clone())
+            .unwrap_or_default()
+    })
 }
 
-fn state_to_bytes(state: &[u64; 25]) -> [u8; 200] {
-    let mut out = [0u8; 200];
-    for (i, lane) in state.iter().enumerate() {
-        out[i * 8..(i + 1) * 8].copy_from_slice(&lane.to_le_bytes());
+#[update]
+fn set_asset_decimals(asset: Principal, decimals: u8) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-    out
+    state_mut(|state| match state.assets.get_mut(&asset) {
+        Some(cfg) => cfg.decimals = Some(decimals),
+        None => ic_cdk::trap("asset not registered"),
+    });
 }
 
-fn keccak256(input: &[u8]) -> [u8; 32] {
-    const RATE: usize = 136;
-    let mut state = [0u64; 25];
-    let mut offset = 0;
-    while offset + RATE <= input.len() {
-        absorb_block(&mut state, &input[offset..offset + RATE]);
-        keccak_f1600(&mut state);
-        offset += RATE;
+#[update]
+fn set_asset_domain(asset: Principal, domain: TokenDomain) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-
-    let mut block = [0u8; RATE];
-    let remainder = &input[offset..];
-    block[..remainder.len()].copy_from_slice(remainder);
-    block[remainder.len()] = 0x01;
-    block[RATE - 1] |= 0x80;
-    absorb_block(&mut state, &block);
-    keccak_f1600(&mut state);
-
-    let mut output = [0u8; 32];
-    let mut out_offset = 0;
-    loop {
-        let state_bytes = state_to_bytes(&state);
-        let take = std::cmp::min(32 - out_offset, RATE);
-        output[out_offset..out_offset + take].copy_from_slice(&state_bytes[..take]);
-        out_offset += take;
-        if out_offset >= 32 {
-            break;
+    state_mut(|state| {
+        match state.assets.get_mut(&asset) {
+            Some(cfg) => cfg.domain = Some(domain.clone()),
+            None => ic_cdk::trap("asset not registered"),
         }
-        keccak_f1600(&mut state);
-    }
-    output
+        record_audit(
+            state,
+            "set_asset_domain",
+            format!("{} {} v{}", asset, domain.name, domain.version),
+        );
+    });
 }
 
-fn function_selector(signature: &str) -> [u8; 4] {
-    let hash = keccak256(signature.as_bytes());
-    [hash[0], hash[1], hash[2], hash[3]]
+#[update]
+fn set_auth_validity(seconds: u64) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    if !(60..=86_400).contains(&seconds) {
+        ic_cdk::trap("validity must be between 60 and 86400 seconds");
+    }
+    state_mut(|state| state.config.auth_validity_sec = Some(seconds));
 }
 
-fn encode_authorization_state_call(from: &[u8], nonce: &[u8]) -> InternalResult<Vec<u8>> {
-    let mut data = Vec::with_capacity(4 + 32 * 2);
-    let selector = function_selector("authorizationState(address,bytes32)");
-    data.extend_from_slice(&selector);
-    data.extend_from_slice(&pad_left(from, 32));
-    data.extend_from_slice(&encode_bytes32(nonce)?);
-    Ok(data)
+/// Returns the `eth_signTypedData_v4` payload for a relay: domain from the
+/// asset's stored metadata, the relayer's chain id and validity window and a
+/// fresh nonce. The signed result can be posted as-is to `/relay` with an
+/// added `signature`.
+#[query]
+fn prepare_authorization(
+    asset: Principal,
+    from: String,
+    to: String,
+    value: Nat,
+) -> Result<String, String> {
+    let from = normalize_evm_address(&from).map_err(|err| err.to_string())?;
+    let to = normalize_evm_address(&to).map_err(|err| err.to_string())?;
+    let caller = msg_caller();
+    let now_ns = time();
+    state_ref(|state| {
+        let cfg = state
+            .assets
+            .get(&asset)
+            .ok_or_else(|| RelayError::AssetNotRegistered.to_string())?;
+        if !matches!(cfg.status, AssetStatus::Active) {
+            return Err(RelayError::AssetNotActive.to_string());
+        }
+        check_value_bounds(cfg, &value).map_err(|err| err.to_string())?;
+        let domain = cfg
+            .domain
+            .as_ref()
+            .ok_or_else(|| "asset EIP-712 domain not configured".to_string())?;
+        let chain_id = state
+            .config
+            .chain_id
+            .as_ref()
+            .and_then(|id| nat_to_u64(id).ok())
+            .ok_or_else(|| "chain id not configured".to_string())?;
+        let seed = state
+            .nonce_seed
+            .as_ref()
+            .ok_or_else(|| "nonce seed not initialised yet".to_string())?;
+        let nonce = authorization_nonce(seed, caller, &from, &to, &value, now_ns);
+        let validity = state
+            .config
+            .auth_validity_sec
+            .unwrap_or(DEFAULT_AUTH_VALIDITY_SEC);
+        let typed_data = transfer_authorization_typed_data(
+            domain,
+            chain_id,
+            &cfg.evm_address,
+            TypedTransfer {
+                from: &from,
+                to: &to,
+                value: &value,
+                valid_after: 0,
+                valid_before: now_ns / 1_000_000_000 + validity,
+                nonce: &nonce,
+            },
+        );
+        Ok(typed_data.to_string())
+    })
 }
 
-#[allow(clippy::too_many_arguments)]
-fn encode_transfer_with_authorization_call(
-    from: &[u8],
-    to: &[u8],
-    value: &Nat,
-    valid_after: &Nat,
-    valid_before: &Nat,
-    nonce: &[u8],
-    sig_v: u8,
-    sig_r: &[u8],
-    sig_s: &[u8],
-) -> InternalResult<Vec<u8>> {
-    if sig_r.len() != 32 {
-        return Err(RelayError::InvalidSignatureLength {
-            field: "sig_r".into(),
-            expected: 32,
-            actual: sig_r.len(),
-        });
+#[update]
+fn set_asset_limits(asset: Principal, limits: AssetLimits) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-    if sig_s.len() != 32 {
-        return Err(RelayError::InvalidSignatureLength {
-            field: "sig_s".into(),
-            expected: 32,
-            actual: sig_s.len(),
-        });
+    if let (Some(min), Some(max)) = (&limits.min_value, &limits.max_value) {
+        if min > max {
+            ic_cdk::trap("min_value exceeds max_value");
+        }
     }
-    let mut data = Vec::with_capacity(4 + 32 * 9);
-    let selector = function_selector("transferWithAuthorization(address,address,uint256,uint256,uint256,bytes32,uint8,bytes32,bytes32)");
-    data.extend_from_slice(&selector);
-    data.extend_from_slice(&pad_left(from, 32));
-    data.extend_from_slice(&pad_left(to, 32));
-    data.extend_from_slice(&encode_uint_nat(value)?);
-    data.extend_from_slice(&encode_uint_nat(valid_after)?);
-    data.extend_from_slice(&encode_uint_nat(valid_before)?);
-    data.extend_from_slice(&encode_bytes32(nonce)?);
-    data.extend_from_slice(&encode_uint_u8(sig_v));
-    data.extend_from_slice(sig_r);
-    data.extend_from_slice(sig_s);
-    Ok(data)
+    state_mut(|state| {
+        record_audit(state, "set_asset_limits", format!("{} {:?}", asset, limits));
+        match state.assets.get_mut(&asset) {
+            Some(cfg) => cfg.limits = Some(limits),
+            None => ic_cdk::trap("asset not registered"),
+        }
+    });
 }
 
-fn evm_address_bytes(address: &str) -> InternalResult<[u8; 20]> {
-    let bytes = parse_hex_bytes(address)?;
-    if bytes.len() != 20 {
-        return Err(RelayError::InvalidAddressLength {
-            field: "evm_address".into(),
-            expected: 20,
-            actual: bytes.len(),
-        });
-    }
-    let mut out = [0u8; 20];
-    out.copy_from_slice(&bytes);
-    Ok(out)
+/// Prices a relay of `value` by `from` from the current fee market, the
+/// asset's recent gas usage and the POL/JPY rate. Pass the returned
+/// `quote_id` with that payer's submission; the fee authorization must cover
+/// `fee_value`. Each caller draws from its own `quote` token bucket.
+#[update]
+async fn request_fee_quote(
+    asset: Principal,
+    from: String,
+    value: Nat,
+) -> Result<DynamicFeeQuote, String> {
+    build_fee_quote(msg_caller(), asset, from, value)
+        .await
+        .map_err(|err| err.to_string())
 }
 
-async fn sign_prehashed_message(
-    key_name: &str,
-    derivation_path: &[Vec<u8>],
-    message_hash: &[u8; 32],
-    expected_address: &[u8; 20],
-) -> InternalResult<SignatureParts> {
-    let arg = SignWithEcdsaArgs {
-        message_hash: message_hash.to_vec(),
-        derivation_path: derivation_path.to_vec(),
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            name: key_name.to_string(),
-        },
+#[update]
+fn add_asset(asset: Principal, evm_address: String, fee_bps: Nat, decimals: u8) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    let fee = match nat_to_u32(&fee_bps) {
+        Ok(v) if v <= u16::MAX as u32 => v as u16,
+        _ => ic_cdk::trap("fee_bps out of range"),
     };
-
-    let SignWithEcdsaResult { signature } = sign_with_ecdsa(&arg)
-        .await
-        .map_err(|err| RelayError::RpcTransportError {
-            code: "sign_with_ecdsa".into(),
-            message: err.to_string(),
-        })?;
-    let (r_bytes, s_bytes, y_parity) = match signature.len() {
-        65 => (
-            signature[0..32].to_vec(),
-            signature[32..64].to_vec(),
-            signature[64],
-        ),
-        64 => {
-            let mut rs = [0u8; 64];
-            rs.copy_from_slice(&signature);
-            let y = derive_y_parity(message_hash, &rs, expected_address)?;
-            (rs[0..32].to_vec(), rs[32..64].to_vec(), y)
-        }
-        actual => {
-            return Err(RelayError::InvalidSignatureLength {
-                field: "signature".into(),
-                expected: 64,
-                actual,
-            });
-        }
+    let normalized = match normalize_evm_address(&evm_address) {
+        Ok(addr) => addr,
+        Err(err) => ic_cdk::trap(err.to_string()),
     };
+    state_mut(|state| {
+        state.assets.insert(
+            asset,
+            AssetConfig {
+                evm_address: normalized,
+                status: AssetStatus::Active,
+                fee_bps: fee,
+                version: 1,
+                decimals: Some(decimals),
+                limits: None,
+                domain: None,
+            },
+        );
+    });
+}
 
-    let mut r = trim_leading_zeroes(&r_bytes);
-    if r.is_empty() {
-        r.push(0);
-    }
-    let mut s = trim_leading_zeroes(&s_bytes);
-    if s.is_empty() {
-        s.push(0);
+#[update]
+fn deprecate_asset(asset: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
-
-    Ok(SignatureParts { y_parity, r, s })
+    state_mut(|state| {
+        if let Some(cfg) = state.assets.get_mut(&asset) {
+            cfg.status = AssetStatus::Deprecated;
+        }
+    });
 }
 
-fn derive_y_parity(
-    message_hash: &[u8; 32],
-    signature_rs: &[u8; 64],
-    expected_address: &[u8; 20],
-) -> InternalResult<u8> {
-    let sig = K256Signature::try_from(signature_rs.as_slice()).map_err(|err| {
-        RelayError::SignatureRecoveryFailed {
-            message: format!("invalid signature bytes: {}", err),
+#[update]
+fn disable_asset(asset: Principal) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        if let Some(cfg) = state.assets.get_mut(&asset) {
+            cfg.status = AssetStatus::Disabled;
         }
-    })?;
+    });
+}
 
-    for recovery_byte in 0u8..=3u8 {
-        let recovery_id = RecoveryId::try_from(recovery_byte).map_err(|err| {
-            RelayError::SignatureRecoveryFailed {
-                message: format!("invalid recovery id candidate {}: {}", recovery_byte, err),
-            }
-        })?;
+#[update]
+fn pause(flag: bool) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| set_paused(state, flag));
+}
 
-        if let Ok(verifying_key) =
-            VerifyingKey::recover_from_prehash(message_hash, &sig, recovery_id)
-        {
-            let encoded = verifying_key.to_encoded_point(false);
-            let pubkey_bytes = encoded.as_bytes();
-            if pubkey_bytes.len() != 65 {
-                continue;
-            }
-            let hashed = keccak256(&pubkey_bytes[1..]);
-            if &hashed[12..] == expected_address.as_ref() {
-                return Ok(u8::from(recovery_id.is_y_odd()));
-            }
-        }
+/// An operator's pause or resume takes over from the gas monitor, so a later
+/// recovery never lifts a pause the operator set.
+fn set_paused(state: &mut RelayerState, flag: bool) {
+    state.config.paused = flag;
+    if let Some(monitor) = state.gas_monitor.as_mut() {
+        monitor.auto_paused = false;
     }
+}
 
-    Err(RelayError::SignatureRecoveryFailed {
-        message: "no recovery id produced expected relayer address".into(),
-    })
+#[update]
+async fn submit_authorization(req: SubmitAuthorizationRequest) -> Result<String, String> {
+    relay_authorization(req, false).await.1
 }
 
-async fn ensure_authorization_unused(
-    chain_id: u64,
-    asset_address: &str,
-    from: &[u8],
-    nonce: &[u8],
-) -> InternalResult<()> {
-    let data = encode_authorization_state_call(from, nonce)?;
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_call",
-        "params": [
-            {
-                "to": asset_address,
-                "data": to_hex_prefixed(&data),
-            },
-            "latest"
-        ],
+/// Runs a relay through the circuit breaker. Also returns the id of the log
+/// it wrote, if it got that far.
+async fn relay_authorization(
+    req: SubmitAuthorizationRequest,
+    via_http: bool,
+) -> (Option<u64>, Result<String, String>) {
+    let asset = req.asset;
+    let probes = match state_mut(|state| {
+        admit_through_breaker(state, asset, time() / 1_000_000_000).inspect_err(|_| {
+            count_relay_outcome(state, "circuit_open");
+        })
+    }) {
+        Ok(probes) => probes,
+        Err(err) => return (None, Err(err.to_string())),
+    };
+    let (log_id, result) = match submit_authorization_internal(req, via_http).await {
+        Ok((log_id, result)) => (Some(log_id), result),
+        Err(err) => (None, Err(err)),
+    };
+    let outcome = match &result {
+        Ok(_) => Some(Ok(())),
+        Err(err) => error_class(err).map(Err),
+    };
+    state_mut(|state| {
+        let label = match &outcome {
+            Some(Ok(())) => "ok".to_string(),
+            Some(Err(class)) => format!("{:?}", class).to_lowercase(),
+            None => "rejected".to_string(),
+        };
+        count_relay_outcome(state, &label);
+        record_breaker_outcome(state, asset, &probes, outcome, time() / 1_000_000_000)
     });
-    let value = rpc_request(chain_id, payload).await?;
-    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
-        expected: "hex string",
-    })?;
-    let bytes = parse_hex_bytes(hex)?;
-    let used = decode_bool_abi(&bytes)?;
-    if used {
-        Err(RelayError::AuthorizationAlreadyUsed)
-    } else {
-        Ok(())
+    (log_id, result.map_err(|err| err.to_string()))
+}
+
+#[update]
+fn set_breaker_config(config: BreakerConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    state_mut(|state| {
+        record_audit(state, "set_breaker_config", format!("{:?}", config));
+        state
+            .breaker
+            .get_or_insert_with(BreakerState::default)
+            .config = config;
+    });
 }
 
-async fn simulate_transfer_call(
-    chain_id: u64,
-    asset_address: &str,
-    from_address: &str,
-    call_data: &[u8],
-) -> InternalResult<()> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_call",
-        "params": [
-            {
-                "from": from_address,
-                "to": asset_address,
-                "data": to_hex_prefixed(call_data),
-            },
-            "latest"
-        ],
-    });
-    match rpc_request(chain_id, payload).await {
-        Ok(_) => Ok(()),
-        Err(RelayError::RpcError { message, .. }) => Err(RelayError::SimulationFailed { message }),
-        Err(other) => Err(other),
+#[query]
+fn breaker_config() -> BreakerConfig {
+    state_ref(|state| {
+        state
+            .breaker
+            .as_ref()
+            .map(|breaker| breaker.config.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Closes a tripped circuit by hand; `asset = None` resets the global one.
+#[update]
+fn reset_breaker(asset: Option<Principal>) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
     }
+    let scope = breaker_scope(asset);
+    state_mut(|state| {
+        record_audit(state, "reset_breaker", scope.clone());
+        if let Some(breaker) = state.breaker.as_mut() {
+            breaker.circuits.remove(&scope);
+        }
+    });
 }
 
-async fn estimate_gas(
-    chain_id: u64,
-    asset_address: &str,
-    from_address: &str,
-    call_data: &[u8],
-) -> InternalResult<Nat> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_estimateGas",
-        "params": [
+/// Relays `req`. Fails outright if it is rejected before a log is written;
+/// otherwise returns the log id along with the outcome of the relay.
+async fn submit_authorization_internal(
+    req: SubmitAuthorizationRequest,
+    via_http: bool,
+) -> InternalResult<(u64, InternalResult<String>)> {
+    let caller = msg_caller();
+    if state_ref(|state| state.config.paused) {
+        return Err(RelayError::Paused);
+    }
+
+    if req.from.len() != 20 {
+        return Err(RelayError::InvalidAddressLength {
+            field: "from".into(),
+            expected: 20,
+            actual: req.from.len(),
+        });
+    }
+    if req.to.len() != 20 {
+        return Err(RelayError::InvalidAddressLength {
+            field: "to".into(),
+            expected: 20,
+            actual: req.to.len(),
+        });
+    }
+
+    check_memo(&req)?;
+
+    let asset_cfg = state_ref(|state| state.assets.get(&req.asset).cloned());
+    let asset_cfg = asset_cfg.ok_or(RelayError::AssetNotRegistered)?;
+    if !matches!(
+        asset_cfg.status,
+        AssetStatus::Active | AssetStatus::Deprecated
+    ) {
+        return Err(RelayError::AssetNotActive);
+    }
+    check_value_bounds(&asset_cfg, &req.value)?;
+
+    let config_snapshot = state_ref(|state| state.config.clone());
+    let threshold_wei = config_snapshot.threshold_wei.clone();
+    let max_fee_multiplier = config_snapshot.max_fee_multiplier;
+    let priority_multiplier = config_snapshot.priority_multiplier;
+    let chain_id_opt = config_snapshot.chain_id.clone();
+    let ecdsa_key_name = config_snapshot.ecdsa_key_name.clone();
+
+    let now_sec = time() / 1_000_000_000;
+    let valid_before = nat_to_u64(&req.valid_before).map_err(|_| RelayError::NumberOutOfRange {
+        field: "valid_before".to_string(),
+    })?;
+    if valid_before <= now_sec {
+        return Err(RelayError::AuthorizationExpired);
+    }
+
+    let from_hex = to_hex_address(&req.from)?;
+    let to_hex = to_hex_address(&req.to)?;
+
+    state_ref(|state| check_rollout(state.rollout.as_ref(), req.asset, &req.from, &from_hex))?;
+
+    let decimals = asset_cfg.decimals.unwrap_or(DEFAULT_TOKEN_DECIMALS);
+    state_mut(|state| {
+        check_kyc(
+            state,
+            ic_cdk::api::canister_self(),
+            &req,
+            &from_hex,
+            decimals,
+            now_sec,
+        )
+    })?;
+
+    let fee_settings = state_ref(|state| state.fee_settings.clone().unwrap_or_default());
+    let fee_required = state_ref(|state| {
+        resolve_required_fee(state, &req, &from_hex, asset_cfg.fee_bps, now_sec)
+    })?;
+    validate_fee_authorization(&req, &fee_required, now_sec)?;
+    let fee_recipient = fee_recipient_for(req.fee.is_some(), &fee_settings)?;
+
+    let quota = state_mut(|state| {
+        enforce_rate_limits(
+            state,
+            RateLimitSubject {
+                caller,
+                from: &from_hex,
+                to: &to_hex,
+                asset: req.asset,
+                via_http,
+            },
+            &req.value,
+            now_sec,
+        )
+    })?;
+
+    // Screened relays are checked after the rate limits so they spend quota
+    // like any other attempt, and are logged as failed.
+    if let Err(err) =
+        state_ref(|state| screen_addresses(state.screening.as_ref(), &from_hex, &to_hex, now_sec))
+    {
+        let log_id = state_mut(|state| {
+            log_screened_relay(state, caller, &req, &from_hex, &to_hex, &err, now_sec)
+        });
+        quota.commit();
+        return Ok((log_id, Err(err)));
+    }
+
+    let log_id = state_mut(|state| {
+        let invoice_id = match state.invoices.as_ref() {
+            Some(registry) => invoice_for_payment(registry, &req, &to_hex, now_sec)?,
+            None => None,
+        };
+        let log_id = push_relay_log(state, caller, &req, &from_hex, &to_hex, now_sec);
+        if let (Some(pricing), Some(quote_id)) = (state.pricing.as_mut(), req.quote_id) {
+            if let Some(quote) = pricing.quotes.get_mut(&quote_id) {
+                quote.log_id = Some(log_id);
+            }
+        }
+        if let (Some(registry), Some(invoice_id)) = (state.invoices.as_mut(), invoice_id) {
+            mark_invoice_pending(registry, invoice_id, log_id);
+        }
+        Ok::<_, RelayError>(log_id)
+    })?;
+    let result = async move {
+        let mut stages = StageRecorder::new(log_id);
+
+        if state_ref(|state| state.config.rpc_endpoint.is_none()) {
+            mark_log_failure(log_id, "rpc endpoint not configured");
+            return Err(RelayError::ConfigurationMissing {
+                field: "rpc_endpoint".into(),
+            });
+        }
+
+        let wallet = match state_mut(acquire_wallet) {
+            Ok(lease) => lease,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+        let relayer_addr = wallet.address.clone();
+
+        let relayer_addr_bytes = match evm_address_bytes(&relayer_addr) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        let chain_id_nat = match chain_id_opt {
+            Some(ref id) => id.clone(),
+            None => {
+                mark_log_failure(log_id, "chain id not configured");
+                return Err(RelayError::ConfigurationMissing {
+                    field: "chain_id".into(),
+                });
+            }
+        };
+
+        let chain_id_u64 = match nat_to_u64(&chain_id_nat) {
+            Ok(value) => value,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        if let Err(err) = stages
+            .run(
+                "authorization_state",
+                ensure_authorization_unused(
+                    chain_id_u64,
+                    &asset_cfg.evm_address,
+                    &req.from,
+                    &req.nonce,
+                ),
+            )
+            .await
+        {
+            mark_log_failure(log_id, &err.to_string());
+            return Err(err);
+        }
+
+        if let Some(fee) = &req.fee {
+            if let Err(err) = stages
+                .run(
+                    "authorization_state",
+                    ensure_authorization_unused(
+                        chain_id_u64,
+                        &asset_cfg.evm_address,
+                        &req.from,
+                        &fee.nonce,
+                    ),
+                )
+                .await
             {
-                "from": from_address,
-                "to": asset_address,
-                "data": to_hex_prefixed(call_data),
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
             }
-        ],
-    });
-    match rpc_request(chain_id, payload).await {
-        Ok(value) => {
-            let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
-                expected: "hex string",
-            })?;
-            nat_from_hex(hex).map_err(|err| match err {
-                RelayError::HexDecodeFailed { value } => {
-                    RelayError::GasEstimateFailed { message: value }
+        }
+
+        let payment_call_data = match encode_transfer_with_authorization_call(
+            &req.from,
+            &req.to,
+            &req.value,
+            &req.valid_after,
+            &req.valid_before,
+            &req.nonce,
+            req.sig_v,
+            &req.sig_r,
+            &req.sig_s,
+        ) {
+            Ok(data) => data,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        let fee_call_data = match (&req.fee, &fee_recipient) {
+            (Some(fee), Some(recipient)) => match encode_transfer_with_authorization_call(
+                &req.from,
+                recipient,
+                &fee.value,
+                &fee.valid_after,
+                &fee.valid_before,
+                &fee.nonce,
+                fee.sig_v,
+                &fee.sig_r,
+                &fee.sig_s,
+            ) {
+                Ok(data) => Some(data),
+                Err(err) => {
+                    mark_log_failure(log_id, &err.to_string());
+                    return Err(err);
                 }
-                other => other,
-            })
+            },
+            _ => None,
+        };
+
+        let asset_address_bytes = match evm_address_bytes(&asset_cfg.evm_address) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        // The payment and fee go out in one Multicall3 transaction so a relay
+        // never lands without its fee.
+        let (tx_target, call_data) = match (&fee_call_data, &fee_settings.batch_contract) {
+            (Some(fee_data), Some(batch)) => {
+                let batch_bytes = match evm_address_bytes(batch) {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        mark_log_failure(log_id, &err.to_string());
+                        return Err(err);
+                    }
+                };
+                let batched = encode_multicall3_aggregate3(&[
+                    (asset_address_bytes, payment_call_data.as_slice()),
+                    (asset_address_bytes, fee_data.as_slice()),
+                ]);
+                (batch_bytes, batched)
+            }
+            _ => (asset_address_bytes, payment_call_data),
+        };
+        let tx_target_hex = to_hex_prefixed(&tx_target);
+
+        if let Err(err) = stages
+            .run(
+                "simulate",
+                simulate_transfer_call(chain_id_u64, &tx_target_hex, &relayer_addr, &call_data),
+            )
+            .await
+        {
+            mark_log_failure(log_id, &err.to_string());
+            return Err(err);
         }
-        Err(RelayError::RpcError { message, .. }) => Err(RelayError::GasEstimateFailed { message }),
-        Err(other) => Err(other),
+
+        let gas_estimate = match stages
+            .run(
+                "estimate",
+                estimate_gas(chain_id_u64, &tx_target_hex, &relayer_addr, &call_data),
+            )
+            .await
+        {
+            Ok(value) => value,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+        if let Some(units) = gas_estimate.0.to_u64() {
+            state_mut(|state| {
+                state
+                    .pricing
+                    .get_or_insert_with(PricingState::default)
+                    .gas_estimates
+                    .insert(req.asset, units);
+            });
+        }
+
+        let mut gas_limit = gas_estimate.clone();
+        let minimum_limit = Nat::from(50_000u64);
+        if gas_limit < minimum_limit {
+            gas_limit = minimum_limit;
+        }
+        gas_limit = match scale_nat(&gas_limit, 1.2) {
+            Ok(val) => {
+                if val < gas_estimate {
+                    gas_estimate.clone()
+                } else {
+                    val
+                }
+            }
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        let fees = match stages
+            .run(
+                "fees",
+                fetch_fee_params(chain_id_u64, priority_multiplier, max_fee_multiplier),
+            )
+            .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        let balance = match stages
+            .run("balance", fetch_balance(chain_id_u64, &relayer_addr))
+            .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        state_mut(|state| record_wallet_balance(state, wallet.id, &balance));
+
+        if balance < threshold_wei {
+            mark_log_failure(log_id, "relayer gas below threshold");
+            return Err(RelayError::GasBalanceLow {
+                required: threshold_wei,
+                actual: balance,
+            });
+        }
+
+        let chain_id = chain_id_nat;
+
+        let pending_nonce = match stages
+            .run("nonce", fetch_nonce(chain_id_u64, &relayer_addr))
+            .await
+        {
+            Ok(val) => val,
+            Err(err) => {
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+        let nonce = state_mut(|state| allocate_wallet_nonce(state, wallet.id, &pending_nonce));
+        let expected_cost = gas_estimate.clone()
+            * (fees.base_fee_per_gas.clone() + fees.max_priority_fee_per_gas.clone());
+
+        let tx = Eip1559Tx {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+            max_fee_per_gas: fees.max_fee_per_gas,
+            gas_limit,
+            to: tx_target,
+            value: Nat::from(0u64),
+            data: call_data,
+        };
+        state_mut(|state| {
+            if let Some(log) = find_log_mut(&mut state.logs, log_id) {
+                log.tx_nonce = Some(tx.nonce.clone());
+                log.gas_limit = Some(tx.gas_limit.clone());
+                log.max_fee_per_gas = Some(tx.max_fee_per_gas.clone());
+                log.max_priority_fee_per_gas = Some(tx.max_priority_fee_per_gas.clone());
+            }
+        });
+
+        let raw_tx = match stages
+            .run(
+                "sign",
+                sign_eip1559_transaction(
+                    &tx,
+                    &ecdsa_key_name,
+                    &wallet.derivation_path,
+                    &relayer_addr_bytes,
+                ),
+            )
+            .await
+        {
+            Ok(raw) => raw,
+            Err(err) => {
+                state_mut(|state| reset_wallet_nonce(state, wallet.id));
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        let tx_hash = match stages
+            .run("send", send_raw_transaction(chain_id_u64, &raw_tx))
+            .await
+        {
+            Ok(hash) => hash,
+            Err(err) => {
+                state_mut(|state| reset_wallet_nonce(state, wallet.id));
+                mark_log_failure(log_id, &err.to_string());
+                return Err(err);
+            }
+        };
+
+        mark_log_success(log_id, &tx_hash);
+        quota.commit();
+        state_mut(|state| record_relay_cost(state, &expected_cost));
+
+        if fee_call_data.is_some() {
+            mark_log_fee(log_id, &tx_hash);
+        }
+        Ok(tx_hash)
     }
+    .await;
+    Ok((log_id, result))
 }
 
-async fn fetch_balance(chain_id: u64, address: &str) -> InternalResult<Nat> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_getBalance",
-        "params": [address, "latest"],
-    });
-    let value = rpc_request(chain_id, payload).await?;
-    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
-        expected: "hex string",
-    })?;
-    nat_from_hex_with_zero_default(hex)
+const DEFAULT_TOKEN_DECIMALS: u8 = 18;
+const DEFAULT_AUTH_VALIDITY_SEC: u64 = 900;
+
+/// Nonce for a prepared authorization. Queries cannot draw randomness, so it
+/// mixes the secret seed drawn at install with the request and the time; the
+/// same request in the same round gets the same, equally valid, payload.
+fn authorization_nonce(
+    seed: &[u8],
+    caller: Principal,
+    from: &str,
+    to: &str,
+    value: &Nat,
+    now_ns: u64,
+) -> [u8; 32] {
+    let mut preimage = b"jpycpay-authorization-nonce-v1".to_vec();
+    preimage.extend_from_slice(seed);
+    preimage.extend_from_slice(caller.as_slice());
+    preimage.extend_from_slice(from.as_bytes());
+    preimage.extend_from_slice(to.as_bytes());
+    preimage.extend_from_slice(&value.0.to_bytes_be());
+    preimage.extend_from_slice(&now_ns.to_be_bytes());
+    keccak256(&preimage)
 }
 
-async fn fetch_nonce(chain_id: u64, address: &str) -> InternalResult<Nat> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_getTransactionCount",
-        "params": [address, "pending"],
-    });
-    let value = rpc_request(chain_id, payload).await?;
-    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
-        expected: "hex string",
-    })?;
-    nat_from_hex_with_zero_default(hex)
+struct TypedTransfer<'a> {
+    from: &'a str,
+    to: &'a str,
+    value: &'a Nat,
+    valid_after: u64,
+    valid_before: u64,
+    nonce: &'a [u8],
 }
 
-async fn fetch_max_priority_fee(chain_id: u64) -> InternalResult<Nat> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_maxPriorityFeePerGas",
-        "params": [],
+fn transfer_authorization_typed_data(
+    domain: &TokenDomain,
+    chain_id: u64,
+    verifying_contract: &str,
+    transfer: TypedTransfer<'_>,
+) -> Value {
+    json!({
+        "types": {
+            "EIP712Domain": [
+                { "name": "name", "type": "string" },
+                { "name": "version", "type": "string" },
+                { "name": "chainId", "type": "uint256" },
+                { "name": "verifyingContract", "type": "address" },
+            ],
+            "TransferWithAuthorization": [
+                { "name": "from", "type": "address" },
+                { "name": "to", "type": "address" },
+                { "name": "value", "type": "uint256" },
+                { "name": "validAfter", "type": "uint256" },
+                { "name": "validBefore", "type": "uint256" },
+                { "name": "nonce", "type": "bytes32" },
+            ],
+        },
+        "primaryType": "TransferWithAuthorization",
+        "domain": {
+            "name": domain.name,
+            "version": domain.version,
+            "chainId": chain_id,
+            "verifyingContract": verifying_contract,
+        },
+        "message": {
+            "from": transfer.from,
+            "to": transfer.to,
+            "value": transfer.value.0.to_string(),
+            "validAfter": transfer.valid_after.to_string(),
+            "validBefore": transfer.valid_before.to_string(),
+            "nonce": to_hex_prefixed(transfer.nonce),
+        },
+    })
+}
+
+async fn build_fee_quote(
+    caller: Principal,
+    asset: Principal,
+    from: String,
+    value: Nat,
+) -> InternalResult<DynamicFeeQuote> {
+    let from = normalize_evm_address(&from)?;
+    // Rate-limit the caller before paying for outcalls.
+    state_mut(|state| take_quote_token(state, caller, time() / 1_000_000_000))?;
+    let (asset_cfg, config, chain_id_opt, fee_recipient, gas_units) = state_ref(|state| {
+        let pricing = state.pricing.clone().unwrap_or_default();
+        (
+            state.assets.get(&asset).cloned(),
+            pricing.config,
+            state.config.chain_id.clone(),
+            state
+                .fee_settings
+                .as_ref()
+                .and_then(|settings| settings.recipient.clone()),
+            pricing.gas_estimates.get(&asset).copied(),
+        )
     });
-    let value = rpc_request(chain_id, payload).await?;
-    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
-        expected: "hex string",
+    let asset_cfg = asset_cfg.ok_or(RelayError::AssetNotRegistered)?;
+    if !config.enabled {
+        return Err(RelayError::QuoteInvalid {
+            message: "dynamic pricing disabled".into(),
+        });
+    }
+    let chain_id = nat_to_u64(&chain_id_opt.ok_or(RelayError::ConfigurationMissing {
+        field: "chain_id".into(),
+    })?)?;
+    let (priority_multiplier, max_fee_multiplier) = state_ref(|state| {
+        (
+            state.config.priority_multiplier,
+            state.config.max_fee_multiplier,
+        )
+    });
+
+    let jpy_per_pol_micro = current_pol_jpy_rate(&config).await?;
+    let fees = fetch_fee_params(chain_id, priority_multiplier, max_fee_multiplier).await?;
+    let gas_price_wei = fees.base_fee_per_gas + fees.max_priority_fee_per_gas;
+    let gas_units = gas_units.unwrap_or(config.default_gas_units);
+    let gas_fee = gas_fee_in_token_units(
+        gas_units,
+        &gas_price_wei,
+        jpy_per_pol_micro,
+        asset_cfg.decimals.unwrap_or(DEFAULT_TOKEN_DECIMALS),
+        config.margin_bps,
+    );
+    let bps_fee = required_fee(&value, asset_cfg.fee_bps);
+    let fee_value = if gas_fee > bps_fee {
+        gas_fee.clone()
+    } else {
+        bps_fee.clone()
+    };
+
+    let now_sec = time() / 1_000_000_000;
+    let expires_at_sec = now_sec + config.quote_ttl_sec;
+    let quote_id = state_mut(|state| {
+        let pricing = state.pricing.get_or_insert_with(PricingState::default);
+        issue_quote(
+            pricing,
+            IssuedQuote {
+                asset,
+                value: value.clone(),
+                fee_value: fee_value.clone(),
+                expires_sec: expires_at_sec,
+                from: Some(from),
+                log_id: None,
+                requested_by: Some(caller),
+            },
+            now_sec,
+        )
     })?;
-    nat_from_hex_with_zero_default(hex)
+
+    Ok(DynamicFeeQuote {
+        quote_id,
+        asset,
+        value,
+        fee_value,
+        gas_fee,
+        bps_fee,
+        gas_units,
+        gas_price_wei,
+        jpy_per_pol_micro,
+        expires_at_sec,
+        fee_recipient,
+    })
 }
 
-async fn fetch_base_fee(chain_id: u64) -> InternalResult<Nat> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_getBlockByNumber",
-        "params": ["latest", false],
-    });
-    let value = rpc_request(chain_id, payload).await?;
-    match value {
-        Value::Object(map) => {
-            if let Some(base_fee) = map.get("baseFeePerGas").and_then(Value::as_str) {
-                nat_from_hex_with_zero_default(base_fee)
-            } else {
-                Err(RelayError::RpcResultTypeMismatch {
-                    expected: "baseFeePerGas",
-                })
+/// Cached POL/JPY rate in micro-yen, refreshed from the price source once it
+/// is older than `price_cache_sec`.
+async fn current_pol_jpy_rate(config: &PricingConfig) -> InternalResult<u64> {
+    let now_sec = time() / 1_000_000_000;
+    let cached = state_ref(|state| {
+        state
+            .pricing
+            .as_ref()
+            .and_then(|pricing| pricing.last_price.clone())
+    });
+    if let Some(sample) = cached {
+        if now_sec.saturating_sub(sample.fetched_sec) < config.price_cache_sec {
+            return Ok(sample.jpy_per_pol_micro);
+        }
+    }
+    let rate = match &config.source {
+        PriceSource::Fixed { jpy_per_pol_micro } => *jpy_per_pol_micro,
+        PriceSource::Http { url, json_path } => {
+            let body = http_get_json(url)
+                .await
+                .map_err(|err| RelayError::PriceUnavailable {
+                    message: err.to_string(),
+                })?;
+            parse_price_micro(&body, json_path)?
+        }
+    };
+    if rate == 0 {
+        return Err(RelayError::PriceUnavailable {
+            message: "price source returned zero".into(),
+        });
+    }
+    state_mut(|state| {
+        state
+            .pricing
+            .get_or_insert_with(PricingState::default)
+            .last_price = Some(PriceSample {
+            jpy_per_pol_micro: rate,
+            fetched_sec: now_sec,
+        });
+    });
+    Ok(rate)
+}
+
+fn parse_price_micro(body: &Value, json_path: &[String]) -> InternalResult<u64> {
+    let mut node = body;
+    for key in json_path {
+        node = node.get(key).ok_or_else(|| RelayError::PriceUnavailable {
+            message: format!("missing field {}", key),
+        })?;
+    }
+    let price = match node {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+    .filter(|price| price.is_finite() && *price > 0.0)
+    .ok_or_else(|| RelayError::PriceUnavailable {
+        message: "price is not a positive number".into(),
+    })?;
+    Ok((price * 1_000_000.0).round() as u64)
+}
+
+/// `gas_units * gas_price` wei converted to token units at `jpy_per_pol_micro`
+/// and marked up by `margin_bps`, rounded up.
+fn gas_fee_in_token_units(
+    gas_units: u64,
+    gas_price_wei: &Nat,
+    jpy_per_pol_micro: u64,
+    decimals: u8,
+    margin_bps: u16,
+) -> Nat {
+    let numerator = BigUint::from(gas_units)
+        * gas_price_wei.0.clone()
+        * BigUint::from(jpy_per_pol_micro)
+        * BigUint::from(10u32).pow(decimals as u32)
+        * BigUint::from(10_000u32 + margin_bps as u32);
+    let denominator =
+        BigUint::from(10u32).pow(18) * BigUint::from(1_000_000u32) * BigUint::from(10_000u32);
+    Nat::from((numerator + &denominator - BigUint::from(1u32)) / denominator)
+}
+
+const MAX_OPEN_QUOTES: usize = 10_000;
+const MAX_QUOTES_PER_CALLER: usize = 5;
+/// Applied to the `quote` scope when no per-principal limit is configured.
+const DEFAULT_QUOTE_LIMIT: BucketLimit = BucketLimit {
+    capacity: 10,
+    refill_per_min: 10,
+};
+
+fn take_quote_token(
+    state: &mut RelayerState,
+    caller: Principal,
+    now_sec: u64,
+) -> InternalResult<()> {
+    let limit = limiter_mut(state)
+        .config
+        .per_principal
+        .unwrap_or(DEFAULT_QUOTE_LIMIT);
+    let limits = [(format!("quote:{caller}"), limit)];
+    ensure_tracking_capacity(state, &limits, &[], now_sec)?;
+    take_bucket_tokens(&mut limiter_mut(state).buckets, &limits, now_sec)
+}
+
+/// Drops expired quotes and makes room for one more quote by `caller`. A
+/// full cap evicts the oldest quote no relay holds, first among the caller's
+/// own and then globally, so nobody can lock other payers out.
+fn make_quote_room(
+    pricing: &mut PricingState,
+    caller: Option<Principal>,
+    now_sec: u64,
+) -> InternalResult<()> {
+    pricing
+        .quotes
+        .retain(|_, quote| quote.expires_sec > now_sec);
+    let open_for_caller = pricing
+        .quotes
+        .values()
+        .filter(|quote| quote.requested_by == caller)
+        .count();
+    if open_for_caller >= MAX_QUOTES_PER_CALLER {
+        evict_oldest_quote(pricing, |quote| quote.requested_by == caller)?;
+    }
+    if pricing.quotes.len() >= MAX_OPEN_QUOTES {
+        evict_oldest_quote(pricing, |_| true)?;
+    }
+    Ok(())
+}
+
+fn evict_oldest_quote(
+    pricing: &mut PricingState,
+    matches: impl Fn(&IssuedQuote) -> bool,
+) -> InternalResult<()> {
+    let oldest = pricing
+        .quotes
+        .iter()
+        .find(|(_, quote)| quote.log_id.is_none() && matches(quote))
+        .map(|(id, _)| *id)
+        .ok_or_else(|| RelayError::QuoteInvalid {
+            message: "too many open quotes".into(),
+        })?;
+    pricing.quotes.remove(&oldest);
+    Ok(())
+}
+
+fn issue_quote(
+    pricing: &mut PricingState,
+    quote: IssuedQuote,
+    now_sec: u64,
+) -> InternalResult<u64> {
+    make_quote_room(pricing, quote.requested_by, now_sec)?;
+    pricing.next_quote_id += 1;
+    pricing.quotes.insert(pricing.next_quote_id, quote);
+    Ok(pricing.next_quote_id)
+}
+
+/// Settles the quote held by `log_id`: a broadcast relay uses it up, a failed
+/// one hands it back for a retry.
+fn settle_quote(state: &mut RelayerState, log_id: u64, broadcast: bool) {
+    let Some(pricing) = state.pricing.as_mut() else {
+        return;
+    };
+    let Some(quote_id) = pricing
+        .quotes
+        .iter()
+        .find(|(_, quote)| quote.log_id == Some(log_id))
+        .map(|(id, _)| *id)
+    else {
+        return;
+    };
+    if broadcast {
+        pricing.quotes.remove(&quote_id);
+    } else if let Some(quote) = pricing.quotes.get_mut(&quote_id) {
+        quote.log_id = None;
+    }
+}
+
+/// Fee the relay must carry: the referenced quote when dynamic pricing is on,
+/// otherwise `fee_bps` of the value.
+fn resolve_required_fee(
+    state: &RelayerState,
+    req: &SubmitAuthorizationRequest,
+    from: &str,
+    fee_bps: u16,
+    now_sec: u64,
+) -> InternalResult<Nat> {
+    let Some(pricing) = state.pricing.as_ref().filter(|p| p.config.enabled) else {
+        return Ok(required_fee(&req.value, fee_bps));
+    };
+    let quote_id = req.quote_id.ok_or(RelayError::QuoteRequired)?;
+    let quote = pricing
+        .quotes
+        .get(&quote_id)
+        .ok_or_else(|| RelayError::QuoteInvalid {
+            message: "unknown or already used quote".into(),
+        })?;
+    if quote.expires_sec <= now_sec {
+        return Err(RelayError::QuoteInvalid {
+            message: "quote expired".into(),
+        });
+    }
+    if quote.asset != req.asset || quote.value != req.value {
+        return Err(RelayError::QuoteInvalid {
+            message: "quote does not match asset and value".into(),
+        });
+    }
+    if quote.from.as_deref() != Some(from) {
+        return Err(RelayError::QuoteInvalid {
+            message: "quote was issued for another payer".into(),
+        });
+    }
+    if quote.log_id.is_some() {
+        return Err(RelayError::QuoteInvalid {
+            message: "quote is in use by another relay".into(),
+        });
+    }
+    Ok(quote.fee_value.clone())
+}
+
+/// `ceil(value * fee_bps / 10_000)`.
+fn required_fee(value: &Nat, fee_bps: u16) -> Nat {
+    let numerator = value.0.clone() * BigUint::from(fee_bps);
+    let denominator = BigUint::from(10_000u32);
+    Nat::from((numerator + &denominator - BigUint::from(1u32)) / denominator)
+}
+
+fn validate_fee_authorization(
+    req: &SubmitAuthorizationRequest,
+    required: &Nat,
+    now_sec: u64,
+) -> InternalResult<()> {
+    let Some(fee) = &req.fee else {
+        if *required > 0u64 {
+            return Err(RelayError::FeeAuthorizationMissing {
+                required: required.clone(),
+            });
+        }
+        return Ok(());
+    };
+    if fee.value < *required {
+        return Err(RelayError::FeeTooLow {
+            required: required.clone(),
+            actual: fee.value.clone(),
+        });
+    }
+    if fee.nonce == req.nonce {
+        return Err(RelayError::InvalidFeeAuthorization {
+            message: "fee nonce must differ from payment nonce".into(),
+        });
+    }
+    let fee_valid_before =
+        nat_to_u64(&fee.valid_before).map_err(|_| RelayError::NumberOutOfRange {
+            field: "fee.valid_before".to_string(),
+        })?;
+    if fee_valid_before <= now_sec {
+        return Err(RelayError::AuthorizationExpired);
+    }
+    Ok(())
+}
+
+/// Fees are only relayed through the batch contract, so a fee without one
+/// is a configuration error rather than a second, separately failing tx.
+fn fee_recipient_for(has_fee: bool, settings: &FeeSettings) -> InternalResult<Option<[u8; 20]>> {
+    if !has_fee {
+        return Ok(None);
+    }
+    let recipient = settings
+        .recipient
+        .as_ref()
+        .ok_or(RelayError::ConfigurationMissing {
+            field: "fee_recipient".into(),
+        })?;
+    if settings.batch_contract.is_none() {
+        return Err(RelayError::ConfigurationMissing {
+            field: "batch_contract".into(),
+        });
+    }
+    Ok(Some(evm_address_bytes(recipient)?))
+}
+
+fn mark_log_fee(log_id: u64, tx_hash: &str) {
+    state_mut(|state| {
+        if let Some(log) = find_log_mut(&mut state.logs, log_id) {
+            log.fee_tx_hash = Some(tx_hash.to_string());
+        }
+        index_log(state, log_id);
+    });
+}
+
+const PRIMARY_WALLET_ID: u32 = 0;
+
+/// In-flight reservation on a pool wallet; releases the slot when dropped so
+/// every early return in the relay pipeline frees it.
+struct WalletLease {
+    id: u32,
+    address: String,
+    derivation_path: Vec<Vec<u8>>,
+}
+
+impl Drop for WalletLease {
+    fn drop(&mut self) {
+        defer_state_update(DeferredUpdate::ReleaseWallet(self.id));
+    }
+}
+
+fn release_wallet(state: &mut RelayerState, id: u32) {
+    if let Some(wallet) = state
+        .wallet_pool
+        .as_mut()
+        .and_then(|pool| pool.wallets.get_mut(&id))
+    {
+        wallet.in_flight = wallet.in_flight.saturating_sub(1);
+    }
+}
+
+fn wallet_pool_mut(state: &mut RelayerState) -> &mut WalletPool {
+    state.wallet_pool.get_or_insert_with(WalletPool::default)
+}
+
+/// Keeps the primary wallet in line with `config.ecdsa_derivation_path` and
+/// `config.evm_addr`. A path change invalidates the cached nonce and balance.
+fn sync_primary_wallet(state: &mut RelayerState) {
+    let path = state.config.ecdsa_derivation_path.clone();
+    let address = state.config.evm_addr.clone();
+    let pool = wallet_pool_mut(state);
+    pool.next_wallet_id = pool.next_wallet_id.max(PRIMARY_WALLET_ID + 1);
+    let wallet = pool.wallets.entry(PRIMARY_WALLET_ID).or_default();
+    if wallet.derivation_path != path || wallet.address != address {
+        wallet.next_nonce = None;
+        wallet.gas_wei = Nat::from(0u32);
+        wallet.gas_updated_sec = 0;
+    }
+    wallet.derivation_path = path;
+    wallet.address = address;
+}
+
+fn wallet_info(id: u32, wallet: &RelayerWallet) -> WalletInfo {
+    WalletInfo {
+        id,
+        address: wallet.address.clone(),
+        status: wallet.status.clone(),
+        gas_wei: wallet.gas_wei.clone(),
+        gas_updated_sec: wallet.gas_updated_sec,
+        in_flight: wallet.in_flight,
+    }
+}
+
+/// Picks the active wallet with the fewest in-flight relays, preferring the
+/// larger gas balance on ties. Wallets last seen below `threshold_wei` are
+/// skipped until a refresh shows they have been topped up.
+fn acquire_wallet(state: &mut RelayerState) -> InternalResult<WalletLease> {
+    let threshold = state.config.threshold_wei.clone();
+    let pool = wallet_pool_mut(state);
+    if pool.wallets.values().all(|wallet| wallet.address.is_none()) {
+        return Err(RelayError::RelayerAddressMissing);
+    }
+    let chosen = pool
+        .wallets
+        .iter()
+        .filter(|(_, wallet)| wallet.status == WalletStatus::Active && wallet.address.is_some())
+        .filter(|(_, wallet)| wallet.gas_updated_sec == 0 || wallet.gas_wei >= threshold)
+        .min_by(|(_, a), (_, b)| {
+            a.in_flight
+                .cmp(&b.in_flight)
+                .then_with(|| b.gas_wei.cmp(&a.gas_wei))
+        })
+        .map(|(id, _)| *id)
+        .ok_or(RelayError::NoHealthyWallet)?;
+    lease_wallet(state, chosen)
+}
+
+/// Reserves a specific wallet regardless of its status.
+fn lease_wallet(state: &mut RelayerState, wallet_id: u32) -> InternalResult<WalletLease> {
+    let wallet = wallet_pool_mut(state)
+        .wallets
+        .get_mut(&wallet_id)
+        .ok_or(RelayError::NoHealthyWallet)?;
+    let address = wallet
+        .address
+        .clone()
+        .ok_or(RelayError::RelayerAddressMissing)?;
+    wallet.in_flight += 1;
+    Ok(WalletLease {
+        id: wallet_id,
+        address,
+        derivation_path: wallet.derivation_path.clone(),
+    })
+}
+
+fn record_wallet_balance(state: &mut RelayerState, wallet_id: u32, balance: &Nat) {
+    if wallet_id == PRIMARY_WALLET_ID {
+        state.last_known_gas = balance.clone();
+    }
+    if let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&wallet_id) {
+        wallet.gas_wei = balance.clone();
+        wallet.gas_updated_sec = time() / 1_000_000_000;
+    }
+}
+
+/// Hands out the next nonce for a wallet. The local counter covers relays
+/// that are signed but not yet visible in the node's pending pool.
+fn allocate_wallet_nonce(state: &mut RelayerState, wallet_id: u32, pending_nonce: &Nat) -> Nat {
+    let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&wallet_id) else {
+        return pending_nonce.clone();
+    };
+    let nonce = match &wallet.next_nonce {
+        Some(local) if local > pending_nonce => local.clone(),
+        _ => pending_nonce.clone(),
+    };
+    wallet.next_nonce = Some(nonce.clone() + Nat::from(1u32));
+    wallet.chain_nonce = Some(pending_nonce.clone());
+    nonce
+}
+
+fn reset_wallet_nonce(state: &mut RelayerState, wallet_id: u32) {
+    if let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&wallet_id) {
+        wallet.next_nonce = None;
+    }
+}
+
+const MAX_AUDIT_ENTRIES: usize = 5_000;
+const NATIVE_TRANSFER_GAS: u64 = 21_000;
+
+fn record_audit(state: &mut RelayerState, action: &str, detail: String) {
+    let trail = state.audit.get_or_insert_with(AuditTrail::default);
+    trail.next_id += 1;
+    trail.entries.push(AuditEntry {
+        id: trail.next_id,
+        ts_sec: time() / 1_000_000_000,
+        caller: msg_caller(),
+        action: action.to_string(),
+        detail,
+    });
+    if trail.entries.len() > MAX_AUDIT_ENTRIES {
+        let excess = trail.entries.len() - MAX_AUDIT_ENTRIES;
+        trail.entries.drain(..excess);
+    }
+}
+
+fn format_derivation_path(path: &[Vec<u8>]) -> String {
+    let parts: Vec<String> = path.iter().map(hex::encode).collect();
+    format!("[{}]", parts.join(","))
+}
+
+fn active_rotation_for_wallet(state: &RelayerState, wallet_id: u32) -> Option<&KeyRotation> {
+    state.rotations.as_ref().and_then(|registry| {
+        registry.rotations.iter().find(|rotation| {
+            rotation.wallet_id == wallet_id
+                && matches!(
+                    rotation.phase,
+                    RotationPhase::AwaitingSettlement
+                        | RotationPhase::Sweeping
+                        | RotationPhase::SweepPending
+                )
+        })
+    })
+}
+
+fn begin_rotation(
+    state: &mut RelayerState,
+    wallet_id: u32,
+    new_path: Vec<Vec<u8>>,
+    new_address: String,
+    now_sec: u64,
+) -> Result<KeyRotation, String> {
+    if active_rotation_for_wallet(state, wallet_id).is_some() {
+        return Err("rotation already in progress for wallet".to_string());
+    }
+    let wallet = wallet_pool_mut(state)
+        .wallets
+        .get_mut(&wallet_id)
+        .ok_or_else(|| "unknown wallet id".to_string())?;
+    wallet.status = WalletStatus::Draining;
+    let old_path = wallet.derivation_path.clone();
+    let old_address = wallet.address.clone();
+
+    let registry = state
+        .rotations
+        .get_or_insert_with(RotationRegistry::default);
+    registry.next_id += 1;
+    let rotation = KeyRotation {
+        id: registry.next_id,
+        wallet_id,
+        started_sec: now_sec,
+        old_path,
+        old_address,
+        new_path,
+        new_address,
+        phase: RotationPhase::AwaitingSettlement,
+        sweep_tx: None,
+        swept_wei: None,
+        residual_wei: None,
+        last_error: None,
+        finished_sec: None,
+        phase_sec: Some(now_sec),
+    };
+    registry.rotations.push(rotation.clone());
+    Ok(rotation)
+}
+
+/// Claims the next step of a rotation: a settled rotation moves to
+/// `Sweeping`, a `SweepPending` one is returned as is for a receipt check.
+fn claim_rotation_step(
+    state: &mut RelayerState,
+    rotation_id: u64,
+    now_sec: u64,
+) -> Result<KeyRotation, String> {
+    let rotation = rotation_mut(state, rotation_id)?;
+    match rotation.phase {
+        RotationPhase::AwaitingSettlement => {
+            rotation.phase = RotationPhase::Sweeping;
+            rotation.phase_sec = Some(now_sec);
+        }
+        RotationPhase::SweepPending => {}
+        _ => return Err(format!("rotation is {:?}", rotation.phase)),
+    }
+    Ok(rotation.clone())
+}
+
+/// How long a rotation may sit in `Sweeping` or `SweepPending` before an
+/// admin can abort it.
+const ROTATION_STUCK_SEC: u64 = 60 * 60;
+
+fn abort_rotation(
+    state: &mut RelayerState,
+    rotation_id: u64,
+    now_sec: u64,
+) -> Result<KeyRotation, String> {
+    let rotation = rotation_mut(state, rotation_id)?;
+    match rotation.phase {
+        RotationPhase::AwaitingSettlement => {}
+        RotationPhase::Sweeping | RotationPhase::SweepPending => {
+            let since = rotation.phase_sec.unwrap_or(rotation.started_sec);
+            if now_sec < since.saturating_add(ROTATION_STUCK_SEC) {
+                return Err(format!(
+                    "rotation is {:?}; it can be aborted after {}",
+                    rotation.phase,
+                    since.saturating_add(ROTATION_STUCK_SEC)
+                ));
+            }
+        }
+        _ => return Err(format!("rotation is {:?}", rotation.phase)),
+    }
+    rotation.phase = RotationPhase::Aborted;
+    rotation.finished_sec = Some(now_sec);
+    let rotation = rotation.clone();
+    if let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&rotation.wallet_id) {
+        wallet.status = WalletStatus::Active;
+    }
+    Ok(rotation)
+}
+
+fn retry_rotation_sweep(
+    state: &mut RelayerState,
+    rotation_id: u64,
+    error: Option<String>,
+) -> KeyRotation {
+    let rotation = rotation_mut(state, rotation_id).expect("rotation exists");
+    rotation.phase = RotationPhase::AwaitingSettlement;
+    rotation.last_error = error;
+    rotation.clone()
+}
+
+/// Records a sweep. Without a transaction (nothing to sweep) the rotation
+/// completes right away; otherwise it waits in `SweepPending`.
+fn record_rotation_sweep(
+    state: &mut RelayerState,
+    rotation_id: u64,
+    sweep: SweepResult,
+    now_sec: u64,
+) -> KeyRotation {
+    let rotation = rotation_mut(state, rotation_id).expect("rotation exists");
+    rotation.sweep_tx = sweep.tx_hash;
+    rotation.swept_wei = Some(sweep.swept_wei);
+    rotation.last_error = None;
+    if rotation.sweep_tx.is_some() {
+        rotation.phase = RotationPhase::SweepPending;
+        rotation.phase_sec = Some(now_sec);
+        return rotation.clone();
+    }
+    rotation.phase = RotationPhase::Sweeping;
+    complete_rotation(state, rotation_id, now_sec)
+}
+
+/// Applies the sweep receipt to a `SweepPending` rotation: a confirmed sweep
+/// completes it, a reverted one sends it back to settlement for another try.
+fn apply_sweep_receipt(
+    state: &mut RelayerState,
+    rotation_id: u64,
+    confirmed: bool,
+    residual_wei: Option<Nat>,
+    now_sec: u64,
+) -> Result<KeyRotation, String> {
+    let rotation = rotation_mut(state, rotation_id)?;
+    if rotation.phase != RotationPhase::SweepPending {
+        return Err(format!("rotation is {:?}", rotation.phase));
+    }
+    if !confirmed {
+        let message = format!(
+            "sweep transaction {} reverted",
+            rotation.sweep_tx.as_deref().unwrap_or("-")
+        );
+        return Ok(retry_rotation_sweep(state, rotation_id, Some(message)));
+    }
+    rotation.residual_wei = residual_wei;
+    Ok(complete_rotation(state, rotation_id, now_sec))
+}
+
+async fn confirm_rotation_sweep(rotation: &KeyRotation) -> Result<KeyRotation, String> {
+    let chain_id = state_ref(|state| state.config.chain_id.clone())
+        .ok_or_else(|| "chain_id not configured".to_string())?;
+    let chain_id = nat_to_u64(&chain_id).map_err(|err| err.to_string())?;
+    let tx_hash = rotation.sweep_tx.clone().unwrap_or_default();
+    let receipt = match fetch_transaction_receipt(chain_id, &tx_hash).await {
+        Ok(receipt) => receipt,
+        Err(err) => {
+            let message = err.to_string();
+            state_mut(|state| {
+                if let Ok(rotation) = rotation_mut(state, rotation.id) {
+                    rotation.last_error = Some(message.clone());
+                }
+            });
+            return Err(message);
+        }
+    };
+    let Some(confirmed) = receipt else {
+        return Ok(rotation.clone());
+    };
+    let residual_wei = match (&rotation.old_address, confirmed) {
+        (Some(old_address), true) => fetch_balance(chain_id, old_address).await.ok(),
+        _ => None,
+    };
+    state_mut(|state| {
+        let updated = apply_sweep_receipt(
+            state,
+            rotation.id,
+            confirmed,
+            residual_wei,
+            time() / 1_000_000_000,
+        )?;
+        if updated.phase == RotationPhase::Completed {
+            audit_rotation_completed(state, &updated);
+        } else {
+            record_audit(
+                state,
+                "rotation_sweep_failed",
+                format!(
+                    "rotation={} error={}",
+                    updated.id,
+                    updated.last_error.as_deref().unwrap_or("-")
+                ),
+            );
+        }
+        Ok(updated)
+    })
+}
+
+fn rotation_mut(state: &mut RelayerState, rotation_id: u64) -> Result<&mut KeyRotation, String> {
+    state
+        .rotations
+        .as_mut()
+        .and_then(|registry| registry.rotations.iter_mut().find(|r| r.id == rotation_id))
+        .ok_or_else(|| "unknown rotation id".to_string())
+}
+
+struct SweepResult {
+    tx_hash: Option<String>,
+    swept_wei: Nat,
+}
+
+/// Returns `Ok(None)` while the old address still has relays in flight or
+/// unconfirmed transactions.
+async fn sweep_rotated_wallet(rotation: &KeyRotation) -> InternalResult<Option<SweepResult>> {
+    let (in_flight, chain_id_opt, key_name, priority_multiplier, max_fee_multiplier) =
+        state_ref(|state| {
+            (
+                state
+                    .wallet_pool
+                    .as_ref()
+                    .and_then(|pool| pool.wallets.get(&rotation.wallet_id))
+                    .map(|wallet| wallet.in_flight)
+                    .unwrap_or(0),
+                state.config.chain_id.clone(),
+                state.config.ecdsa_key_name.clone(),
+                state.config.priority_multiplier,
+                state.config.max_fee_multiplier,
+            )
+        });
+    if in_flight > 0 {
+        return Ok(None);
+    }
+    let Some(old_address) = rotation.old_address.clone() else {
+        return Ok(Some(SweepResult {
+            tx_hash: None,
+            swept_wei: Nat::from(0u32),
+        }));
+    };
+    let chain_id_nat = chain_id_opt.ok_or(RelayError::ConfigurationMissing {
+        field: "chain_id".into(),
+    })?;
+    let chain_id_u64 = nat_to_u64(&chain_id_nat)?;
+
+    let pending_nonce = fetch_nonce(chain_id_u64, &old_address).await?;
+    let latest_nonce = fetch_transaction_count(chain_id_u64, &old_address, "latest").await?;
+    if pending_nonce != latest_nonce {
+        return Ok(None);
+    }
+
+    let balance = fetch_balance(chain_id_u64, &old_address).await?;
+    let fees = fetch_fee_params(chain_id_u64, priority_multiplier, max_fee_multiplier).await?;
+    let gas_limit = Nat::from(NATIVE_TRANSFER_GAS);
+    let Some(swept_wei) = sweep_amount(balance, &fees.max_fee_per_gas, &gas_limit) else {
+        return Ok(Some(SweepResult {
+            tx_hash: None,
+            swept_wei: Nat::from(0u32),
+        }));
+    };
+
+    let tx = Eip1559Tx {
+        chain_id: chain_id_nat,
+        nonce: pending_nonce,
+        max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+        max_fee_per_gas: fees.max_fee_per_gas,
+        gas_limit,
+        to: evm_address_bytes(&rotation.new_address)?,
+        value: swept_wei.clone(),
+        data: Vec::new(),
+    };
+    let signer = evm_address_bytes(&old_address)?;
+    let raw_tx = sign_eip1559_transaction(&tx, &key_name, &rotation.old_path, &signer).await?;
+    let tx_hash = send_raw_transaction(chain_id_u64, &raw_tx).await?;
+    Ok(Some(SweepResult {
+        tx_hash: Some(tx_hash),
+        swept_wei,
+    }))
+}
+
+/// Balance left after reserving gas for the sweep at `max_fee_per_gas`, or
+/// `None` when the balance does not cover it.
+fn sweep_amount(balance: Nat, max_fee_per_gas: &Nat, gas_limit: &Nat) -> Option<Nat> {
+    let gas_cost = max_fee_per_gas.clone() * gas_limit.clone();
+    if balance <= gas_cost {
+        None
+    } else {
+        Some(balance - gas_cost)
+    }
+}
+
+fn complete_rotation(state: &mut RelayerState, rotation_id: u64, now_sec: u64) -> KeyRotation {
+    let rotation = {
+        let rotation = rotation_mut(state, rotation_id).expect("rotation exists");
+        rotation.phase = RotationPhase::Completed;
+        rotation.last_error = None;
+        rotation.finished_sec = Some(now_sec);
+        rotation.clone()
+    };
+    if rotation.wallet_id == PRIMARY_WALLET_ID {
+        state.config.ecdsa_derivation_path = rotation.new_path.clone();
+        state.config.evm_addr = Some(rotation.new_address.clone());
+        sync_primary_wallet(state);
+    } else if let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&rotation.wallet_id) {
+        wallet.derivation_path = rotation.new_path.clone();
+        wallet.address = Some(rotation.new_address.clone());
+        wallet.next_nonce = None;
+        wallet.gas_wei = Nat::from(0u32);
+        wallet.gas_updated_sec = 0;
+    }
+    if let Some(wallet) = wallet_pool_mut(state).wallets.get_mut(&rotation.wallet_id) {
+        wallet.status = WalletStatus::Active;
+    }
+    rotation
+}
+
+fn audit_rotation_completed(state: &mut RelayerState, rotation: &KeyRotation) {
+    record_audit(
+        state,
+        "rotation_completed",
+        format!(
+            "rotation={} wallet={} new={} sweep_tx={} swept_wei={} residual_wei={}",
+            rotation.id,
+            rotation.wallet_id,
+            rotation.new_address,
+            rotation.sweep_tx.as_deref().unwrap_or("-"),
+            rotation
+                .swept_wei
+                .as_ref()
+                .map(Nat::to_string)
+                .unwrap_or_else(|| "-".to_string()),
+            rotation
+                .residual_wei
+                .as_ref()
+                .map(Nat::to_string)
+                .unwrap_or_else(|| "-".to_string())
+        ),
+    );
+}
+
+fn withdrawal_mut(
+    state: &mut RelayerState,
+    withdrawal_id: u64,
+) -> Result<&mut WithdrawalRequest, String> {
+    state
+        .withdrawals
+        .as_mut()
+        .and_then(|registry| registry.requests.iter_mut().find(|r| r.id == withdrawal_id))
+        .ok_or_else(|| "unknown withdrawal id".to_string())
+}
+
+fn new_withdrawal_request(
+    state: &mut RelayerState,
+    wallet_id: u32,
+    kind: WithdrawalKind,
+    to: String,
+    amount: Nat,
+    requested_by: Principal,
+    now_sec: u64,
+) -> Result<WithdrawalRequest, String> {
+    let has_address = state
+        .wallet_pool
+        .as_ref()
+        .and_then(|pool| pool.wallets.get(&wallet_id))
+        .is_some_and(|wallet| wallet.address.is_some());
+    if !has_address {
+        return Err("unknown wallet id".to_string());
+    }
+    check_withdrawal_cap(state, &kind, &amount, now_sec)?;
+    let registry = state
+        .withdrawals
+        .get_or_insert_with(WithdrawalRegistry::default);
+    registry.next_id += 1;
+    let request = WithdrawalRequest {
+        id: registry.next_id,
+        kind,
+        to,
+        amount,
+        requested_by,
+        requested_sec: now_sec,
+        executable_after_sec: now_sec.saturating_add(registry.policy.timelock_sec),
+        status: WithdrawalStatus::Pending,
+        tx_hash: None,
+        fail_reason: None,
+        finished_sec: None,
+        wallet_id: Some(wallet_id),
+        claimed_sec: None,
+    };
+    registry.requests.push(request.clone());
+    Ok(request)
+}
+
+fn cancel_withdrawal_request(
+    state: &mut RelayerState,
+    withdrawal_id: u64,
+    now_sec: u64,
+) -> Result<WithdrawalRequest, String> {
+    let request = withdrawal_mut(state, withdrawal_id)?;
+    if request.status != WithdrawalStatus::Pending {
+        return Err(format!("withdrawal is {:?}", request.status));
+    }
+    request.status = WithdrawalStatus::Cancelled;
+    request.finished_sec = Some(now_sec);
+    Ok(request.clone())
+}
+
+fn withdrawal_outbound(request: &WithdrawalRequest) -> InternalResult<OutboundTx> {
+    let to = evm_address_bytes(&request.to)?;
+    Ok(match &request.kind {
+        WithdrawalKind::Native => OutboundTx {
+            to,
+            value: request.amount.clone(),
+            data: Vec::new(),
+        },
+        WithdrawalKind::Erc20 { token } => OutboundTx {
+            to: evm_address_bytes(token)?,
+            value: Nat::from(0u64),
+            data: encode_erc20_transfer_call(&to, &request.amount)?,
+        },
+    })
+}
+
+/// Checks timelock and cap and builds the transaction; only then is the
+/// amount counted against the cap and the request marked `Executing`.
+fn claim_withdrawal(
+    state: &mut RelayerState,
+    withdrawal_id: u64,
+    now_sec: u64,
+) -> Result<OutboundTx, String> {
+    let request = withdrawal_mut(state, withdrawal_id)?.clone();
+    if request.status != WithdrawalStatus::Pending {
+        return Err(format!("withdrawal is {:?}", request.status));
+    }
+    if request.executable_after_sec > now_sec {
+        return Err(format!(
+            "withdrawal timelocked until {}",
+            request.executable_after_sec
+        ));
+    }
+    let wallet_id = request.wallet_id.unwrap_or(PRIMARY_WALLET_ID);
+    if active_rotation_for_wallet(state, wallet_id).is_some() {
+        return Err("wallet is being rotated".into());
+    }
+    check_withdrawal_cap(state, &request.kind, &request.amount, now_sec)?;
+    let outbound = withdrawal_outbound(&request).map_err(|err| err.to_string())?;
+    record_withdrawal_amount(state, &request.kind, &request.amount, now_sec);
+    let request = withdrawal_mut(state, withdrawal_id)?;
+    request.status = WithdrawalStatus::Executing;
+    request.claimed_sec = Some(now_sec);
+    Ok(outbound)
+}
+
+const WITHDRAWAL_EXECUTING_TIMEOUT_SEC: u64 = 600;
+
+/// Moves withdrawals stuck in `Executing` (e.g. the call trapped) to
+/// `Stalled`. The amount stays charged since the transaction may have been
+/// broadcast.
+fn stall_stuck_withdrawals(state: &mut RelayerState, now_sec: u64) -> Vec<u64> {
+    let Some(registry) = state.withdrawals.as_mut() else {
+        return Vec::new();
+    };
+    let mut stalled = Vec::new();
+    for request in registry.requests.iter_mut() {
+        let claimed_sec = request.claimed_sec.unwrap_or(request.executable_after_sec);
+        if request.status == WithdrawalStatus::Executing
+            && now_sec.saturating_sub(claimed_sec) >= WITHDRAWAL_EXECUTING_TIMEOUT_SEC
+        {
+            request.status = WithdrawalStatus::Stalled;
+            request.fail_reason = Some("send did not complete".to_string());
+            stalled.push(request.id);
+        }
+    }
+    stalled
+}
+
+/// Records the broadcast result of an `Executing` or `Stalled` withdrawal;
+/// a failed one gives its amount back to the cap of the day it was claimed.
+fn finish_withdrawal(
+    state: &mut RelayerState,
+    withdrawal_id: u64,
+    result: &InternalResult<String>,
+    now_sec: u64,
+) -> WithdrawalRequest {
+    let request = withdrawal_mut(state, withdrawal_id).expect("withdrawal exists");
+    if !matches!(
+        request.status,
+        WithdrawalStatus::Executing | WithdrawalStatus::Stalled
+    ) {
+        return request.clone();
+    }
+    request.finished_sec = Some(now_sec);
+    match result {
+        Ok(tx_hash) => {
+            request.status = WithdrawalStatus::Executed;
+            request.tx_hash = Some(tx_hash.clone());
+        }
+        Err(err) => {
+            request.status = WithdrawalStatus::Failed;
+            request.fail_reason = Some(err.to_string());
+        }
+    }
+    let request = request.clone();
+    if result.is_err() {
+        refund_withdrawal_amount(
+            state,
+            &request.kind,
+            &request.amount,
+            request_day(request.claimed_sec.unwrap_or(now_sec)),
+        );
+    }
+    request
+}
+
+fn withdrawal_asset_key(kind: &WithdrawalKind) -> String {
+    match kind {
+        WithdrawalKind::Native => "native".to_string(),
+        WithdrawalKind::Erc20 { token } => token.clone(),
+    }
+}
+
+fn request_day(now_sec: u64) -> u64 {
+    now_sec / 86_400
+}
+
+/// Checks `amount` against the remaining daily cap without recording it.
+fn check_withdrawal_cap(
+    state: &RelayerState,
+    kind: &WithdrawalKind,
+    amount: &Nat,
+    now_sec: u64,
+) -> Result<(), String> {
+    let registry = state.withdrawals.as_ref();
+    let key = withdrawal_asset_key(kind);
+    let cap = registry.and_then(|registry| match kind {
+        WithdrawalKind::Native => Some(registry.policy.daily_cap_native_wei.clone()),
+        WithdrawalKind::Erc20 { token } => registry.policy.daily_cap_erc20.get(token).cloned(),
+    });
+    let cap = match cap {
+        Some(cap) if cap > 0u64 => cap,
+        _ => return Err(format!("no withdrawal cap configured for {}", key)),
+    };
+    let used = registry
+        .and_then(|registry| registry.daily_counter.get(&key))
+        .filter(|counter| counter.window_start_sec == request_day(now_sec))
+        .map(|counter| counter.amount.clone())
+        .unwrap_or_else(|| Nat::from(0u32));
+    if used + amount.clone() > cap {
+        return Err(format!("withdrawal daily cap exceeded for {}", key));
+    }
+    Ok(())
+}
+
+fn record_withdrawal_amount(
+    state: &mut RelayerState,
+    kind: &WithdrawalKind,
+    amount: &Nat,
+    now_sec: u64,
+) {
+    let registry = state
+        .withdrawals
+        .get_or_insert_with(WithdrawalRegistry::default);
+    let counter = registry
+        .daily_counter
+        .entry(withdrawal_asset_key(kind))
+        .or_default();
+    let day = request_day(now_sec);
+    if counter.window_start_sec != day {
+        counter.window_start_sec = day;
+        counter.amount = Nat::from(0u32);
+        counter.hits = 0;
+    }
+    counter.hits += 1;
+    counter.amount = counter.amount.clone() + amount.clone();
+}
+
+fn refund_withdrawal_amount(
+    state: &mut RelayerState,
+    kind: &WithdrawalKind,
+    amount: &Nat,
+    day: u64,
+) {
+    if let Some(counter) = state
+        .withdrawals
+        .as_mut()
+        .and_then(|registry| registry.daily_counter.get_mut(&withdrawal_asset_key(kind)))
+    {
+        if counter.window_start_sec == day && counter.amount >= *amount {
+            counter.amount = counter.amount.clone() - amount.clone();
+            counter.hits = counter.hits.saturating_sub(1);
+        }
+    }
+}
+
+/// Transaction sent from a pool wallet on the canister's own behalf.
+#[derive(Debug)]
+struct OutboundTx {
+    to: [u8; 20],
+    value: Nat,
+    data: Vec<u8>,
+}
+
+/// Builds, signs and broadcasts `tx` from `wallet_id`, sharing the wallet's
+/// nonce tracker with the relay pipeline.
+async fn send_wallet_transaction(wallet_id: u32, tx: OutboundTx) -> InternalResult<String> {
+    let wallet = state_mut(|state| lease_wallet(state, wallet_id))?;
+    let (chain_id_opt, key_name, priority_multiplier, max_fee_multiplier) = state_ref(|state| {
+        (
+            state.config.chain_id.clone(),
+            state.config.ecdsa_key_name.clone(),
+            state.config.priority_multiplier,
+            state.config.max_fee_multiplier,
+        )
+    });
+    let chain_id = chain_id_opt.ok_or(RelayError::ConfigurationMissing {
+        field: "chain_id".into(),
+    })?;
+    let chain_id_u64 = nat_to_u64(&chain_id)?;
+    let signer = evm_address_bytes(&wallet.address)?;
+
+    let gas_limit = if tx.data.is_empty() {
+        Nat::from(NATIVE_TRANSFER_GAS)
+    } else {
+        let estimate = estimate_gas(
+            chain_id_u64,
+            &to_hex_prefixed(&tx.to),
+            &wallet.address,
+            &tx.data,
+        )
+        .await?;
+        let scaled = scale_nat(&estimate, 1.2)?;
+        if scaled < estimate {
+            estimate
+        } else {
+            scaled
+        }
+    };
+    let fees = fetch_fee_params(chain_id_u64, priority_multiplier, max_fee_multiplier).await?;
+
+    let balance = fetch_balance(chain_id_u64, &wallet.address).await?;
+    state_mut(|state| record_wallet_balance(state, wallet.id, &balance));
+    let required = fees.max_fee_per_gas.clone() * gas_limit.clone() + tx.value.clone();
+    if balance < required {
+        return Err(RelayError::GasBalanceLow {
+            required,
+            actual: balance,
+        });
+    }
+
+    let pending_nonce = fetch_nonce(chain_id_u64, &wallet.address).await?;
+    let nonce = state_mut(|state| allocate_wallet_nonce(state, wallet.id, &pending_nonce));
+    let unsigned = Eip1559Tx {
+        chain_id,
+        nonce,
+        max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+        max_fee_per_gas: fees.max_fee_per_gas,
+        gas_limit,
+        to: tx.to,
+        value: tx.value,
+        data: tx.data,
+    };
+    let sent = match sign_eip1559_transaction(
+        &unsigned,
+        &key_name,
+        &wallet.derivation_path,
+        &signer,
+    )
+    .await
+    {
+        Ok(raw_tx) => send_raw_transaction(chain_id_u64, &raw_tx).await,
+        Err(err) => Err(err),
+    };
+    if sent.is_err() {
+        state_mut(|state| reset_wallet_nonce(state, wallet.id));
+    }
+    sent
+}
+
+const MAX_PAYOUTS_PER_RUN: u32 = 10;
+const MAX_PAYOUT_MEMO_LEN: usize = 256;
+const PAYOUT_SENDING_TIMEOUT_SEC: u64 = 600;
+
+fn payout_by_id(state: &RelayerState, payout_id: u64) -> Option<&Payout> {
+    state
+        .payouts
+        .as_ref()
+        .and_then(|registry| registry.payouts.iter().find(|p| p.id == payout_id))
+}
+
+fn payout_mut(state: &mut RelayerState, payout_id: u64) -> Option<&mut Payout> {
+    state
+        .payouts
+        .as_mut()
+        .and_then(|registry| registry.payouts.iter_mut().find(|p| p.id == payout_id))
+}
+
+fn enqueue_payout(
+    state: &mut RelayerState,
+    caller: Principal,
+    asset: Principal,
+    to: String,
+    amount: Nat,
+    memo: Option<String>,
+    now_sec: u64,
+) -> InternalResult<u64> {
+    let to = normalize_evm_address(&to)?;
+    if amount == 0u64 {
+        return Err(RelayError::NumberOutOfRange {
+            field: "amount".into(),
+        });
+    }
+    if memo
+        .as_ref()
+        .is_some_and(|memo| memo.len() > MAX_PAYOUT_MEMO_LEN)
+    {
+        return Err(RelayError::NumberOutOfRange {
+            field: "memo".into(),
+        });
+    }
+    let asset_cfg = state
+        .assets
+        .get(&asset)
+        .ok_or(RelayError::AssetNotRegistered)?;
+    if !matches!(asset_cfg.status, AssetStatus::Active) {
+        return Err(RelayError::AssetNotActive);
+    }
+    let registry = state.payouts.get_or_insert_with(PayoutRegistry::default);
+    let budget = registry
+        .callers
+        .get_mut(&caller)
+        .ok_or(RelayError::NotAuthorized)?
+        .budgets
+        .get_mut(&asset)
+        .ok_or(RelayError::PayoutBudgetExceeded)?;
+    if *budget < amount {
+        return Err(RelayError::PayoutBudgetExceeded);
+    }
+    *budget = budget.clone() - amount.clone();
+
+    let log_id = state.next_log_id;
+    state.next_log_id += 1;
+    state.logs.push(PaymentLog {
+        id: log_id,
+        ts_sec: now_sec,
+        asset,
+        from: state.config.evm_addr.clone().unwrap_or_default(),
+        to: to.clone(),
+        value: amount.clone(),
+        status: PaymentStatus::Accepted,
+        tx_hash: None,
+        fail_reason: None,
+        kind: Some(PaymentKind::Payout),
+        fee_value: None,
+        fee_tx_hash: None,
+        memo: memo.clone().map(Memo::Text),
+        memo_bound: None,
+        chain_id: state.config.chain_id.clone(),
+        valid_after: None,
+        valid_before: None,
+        auth_nonce: None,
+        tx_nonce: None,
+        gas_limit: None,
+        max_fee_per_gas: None,
+        max_priority_fee_per_gas: None,
+        caller: Some(caller),
+        stages: None,
+    });
+    count_new_log(state);
+    index_log(state, log_id);
+
+    let registry = state.payouts.get_or_insert_with(PayoutRegistry::default);
+    registry.next_id += 1;
+    let payout_id = registry.next_id;
+    registry.payouts.push(Payout {
+        id: payout_id,
+        caller,
+        asset,
+        to,
+        amount,
+        memo,
+        status: PayoutStatus::Queued,
+        log_id,
+        tx_hash: None,
+        fail_reason: None,
+        created_sec: now_sec,
+        updated_sec: now_sec,
+    });
+    Ok(payout_id)
+}
+
+/// Marks the oldest queued payout `Sending` and returns it with its asset's
+/// token address.
+fn claim_next_payout(state: &mut RelayerState, now_sec: u64) -> Option<(Payout, Option<String>)> {
+    let asset_addresses: BTreeMap<Principal, String> = state
+        .assets
+        .iter()
+        .map(|(asset, cfg)| (*asset, cfg.evm_address.clone()))
+        .collect();
+    let payout = state
+        .payouts
+        .as_mut()?
+        .payouts
+        .iter_mut()
+        .find(|p| p.status == PayoutStatus::Queued)?;
+    payout.status = PayoutStatus::Sending;
+    payout.updated_sec = now_sec;
+    Some((payout.clone(), asset_addresses.get(&payout.asset).cloned()))
+}
+
+/// Moves payouts stuck in `Sending` (e.g. the send was interrupted by an
+/// upgrade) to `Stalled`. The budget stays charged since the transaction
+/// may have been broadcast.
+fn stall_stuck_payouts(state: &mut RelayerState, now_sec: u64) -> Vec<u64> {
+    let Some(registry) = state.payouts.as_mut() else {
+        return Vec::new();
+    };
+    let mut stalled = Vec::new();
+    for payout in registry.payouts.iter_mut() {
+        if payout.status == PayoutStatus::Sending
+            && now_sec.saturating_sub(payout.updated_sec) >= PAYOUT_SENDING_TIMEOUT_SEC
+        {
+            payout.status = PayoutStatus::Stalled;
+            payout.updated_sec = now_sec;
+            payout.fail_reason = Some("send did not complete".to_string());
+            stalled.push(payout.id);
+        }
+    }
+    stalled
+}
+
+/// Sends queued payouts oldest first. Each payout is marked `Sending` before
+/// the first await so overlapping runs never pick it twice.
+async fn process_payout_queue(max: u32) -> u32 {
+    let mut processed = 0;
+    while processed < max {
+        let next = state_mut(|state| claim_next_payout(state, time() / 1_000_000_000));
+        let Some((payout, asset_address)) = next else {
+            break;
+        };
+        processed += 1;
+
+        let result = match asset_address {
+            Some(asset_address) => send_payout(&payout, &asset_address).await,
+            None => Err(RelayError::AssetNotRegistered),
+        };
+        state_mut(|state| finish_payout(state, payout.id, result, time() / 1_000_000_000));
+    }
+    processed
+}
+
+async fn send_payout(payout: &Payout, asset_address: &str) -> InternalResult<String> {
+    let to = evm_address_bytes(&payout.to)?;
+    let data = encode_erc20_transfer_call(&to, &payout.amount)?;
+    send_wallet_transaction(
+        PRIMARY_WALLET_ID,
+        OutboundTx {
+            to: evm_address_bytes(asset_address)?,
+            value: Nat::from(0u64),
+            data,
+        },
+    )
+    .await
+}
+
+/// Records the send result of a `Sending` or `Stalled` payout. A failed send
+/// returns the amount to the caller's budget.
+fn finish_payout(
+    state: &mut RelayerState,
+    payout_id: u64,
+    result: InternalResult<String>,
+    now_sec: u64,
+) {
+    let Some(payout) = payout_mut(state, payout_id) else {
+        return;
+    };
+    if !matches!(payout.status, PayoutStatus::Sending | PayoutStatus::Stalled) {
+        return;
+    }
+    payout.updated_sec = now_sec;
+    let payout = match &result {
+        Ok(tx_hash) => {
+            payout.status = PayoutStatus::Broadcasted;
+            payout.tx_hash = Some(tx_hash.clone());
+            payout.clone()
+        }
+        Err(err) => {
+            payout.status = PayoutStatus::Failed;
+            payout.fail_reason = Some(err.to_string());
+            payout.clone()
+        }
+    };
+    match result {
+        Ok(tx_hash) => {
+            if let Some(log) = find_log_mut(&mut state.logs, payout.log_id) {
+                let from = state.config.evm_addr.clone().unwrap_or_default();
+                if let Some(ids) = state
+                    .log_index
+                    .as_mut()
+                    .and_then(|index| index.by_from.get_mut(&log.from))
+                {
+                    ids.remove(&log.id);
+                }
+                log.from = from;
+                set_log_status(
+                    &mut state.log_metrics,
+                    &mut state.log_index,
+                    log,
+                    PaymentStatus::Broadcasted,
+                );
+                log.tx_hash = Some(tx_hash);
+                log.fail_reason = None;
+            }
+            index_log(state, payout.log_id);
+        }
+        Err(err) => {
+            // Nothing left the wallet, so the budget is returned.
+            if let Some(budget) = state
+                .payouts
+                .as_mut()
+                .and_then(|registry| registry.callers.get_mut(&payout.caller))
+                .and_then(|caller| caller.budgets.get_mut(&payout.asset))
+            {
+                *budget = budget.clone() + payout.amount.clone();
+            }
+            if let Some(log) = find_log_mut(&mut state.logs, payout.log_id) {
+                set_log_status(
+                    &mut state.log_metrics,
+                    &mut state.log_index,
+                    log,
+                    PaymentStatus::Failed,
+                );
+                log.fail_reason = Some(err.to_string());
+            }
+        }
+    }
+}
+
+async fn poll_broadcasted_receipts(limit: usize) -> InternalResult<u32> {
+    let chain_id = state_ref(|state| state.config.chain_id.clone()).ok_or(
+        RelayError::ConfigurationMissing {
+            field: "chain_id".into(),
+        },
+    )?;
+    let chain_id_u64 = nat_to_u64(&chain_id)?;
+    let pending: Vec<(u64, String)> = state_ref(|state| {
+        state
+            .logs
+            .iter()
+            .filter(|log| log.status == PaymentStatus::Broadcasted)
+            .filter_map(|log| log.tx_hash.clone().map(|tx| (log.id, tx)))
+            .take(limit)
+            .collect()
+    });
+    let mut updated = 0;
+    for (log_id, tx_hash) in pending {
+        if let Some(success) = fetch_transaction_receipt(chain_id_u64, &tx_hash).await? {
+            state_mut(|state| mark_log_confirmed(state, log_id, success));
+            updated += 1;
+        }
+    }
+    Ok(updated)
+}
+
+fn mark_log_confirmed(state: &mut RelayerState, log_id: u64, success: bool) {
+    if let Some(log) = find_log_mut(&mut state.logs, log_id) {
+        if success {
+            set_log_status(
+                &mut state.log_metrics,
+                &mut state.log_index,
+                log,
+                PaymentStatus::Confirmed,
+            );
+        } else {
+            set_log_status(
+                &mut state.log_metrics,
+                &mut state.log_index,
+                log,
+                PaymentStatus::Failed,
+            );
+            log.fail_reason = Some("transaction reverted".to_string());
+        }
+    }
+    publish_log_event(state, log_id);
+    if let Some(payout) = state
+        .payouts
+        .as_mut()
+        .and_then(|registry| registry.payouts.iter_mut().find(|p| p.log_id == log_id))
+    {
+        payout.updated_sec = time() / 1_000_000_000;
+        if success {
+            payout.status = PayoutStatus::Confirmed;
+        } else {
+            payout.status = PayoutStatus::Failed;
+            payout.fail_reason = Some("transaction reverted".to_string());
+        }
+    }
+}
+
+fn nat_to_u32(value: &Nat) -> InternalResult<u32> {
+    value
+        .0
+        .to_u32()
+        .ok_or_else(|| RelayError::NumberOutOfRange {
+            field: "u32".to_string(),
+        })
+}
+
+fn nat_to_u64(value: &Nat) -> InternalResult<u64> {
+    value
+        .0
+        .to_u64()
+        .ok_or_else(|| RelayError::NumberOutOfRange {
+            field: "u64".to_string(),
+        })
+}
+
+fn to_hex_address(bytes: &[u8]) -> InternalResult<String> {
+    if bytes.len() != 20 {
+        return Err(RelayError::InvalidAddressLength {
+            field: "address".into(),
+            expected: 20,
+            actual: bytes.len(),
+        });
+    }
+    Ok(format!("0x{}", hex::encode(bytes)))
+}
+
+const LOG_SCHEMA_VERSION: u32 = 2;
+
+/// Brings logs written by older versions up to `LOG_SCHEMA_VERSION`. Logs
+/// from before schema 2 get an explicit kind. Their chain id, authorization
+/// and transaction details were never recorded and stay empty; the chain
+/// configured now need not be the one they were sent on.
+fn migrate_logs(state: &mut RelayerState) {
+    if state.log_schema.unwrap_or(1) >= LOG_SCHEMA_VERSION {
+        return;
+    }
+    for log in state.logs.iter_mut() {
+        log.kind.get_or_insert(PaymentKind::Relay);
+    }
+    state.log_schema = Some(LOG_SCHEMA_VERSION);
+}
+
+fn find_log(logs: &[PaymentLog], id: u64) -> Option<&PaymentLog> {
+    logs.binary_search_by_key(&id, |log| log.id)
+        .ok()
+        .map(|index| &logs[index])
+}
+
+fn find_log_mut(logs: &mut [PaymentLog], id: u64) -> Option<&mut PaymentLog> {
+    logs.binary_search_by_key(&id, |log| log.id)
+        .ok()
+        .map(|index| &mut logs[index])
+}
+
+/// Adds a log's current fields to the index. Safe to call repeatedly.
+fn index_log(state: &mut RelayerState, log_id: u64) {
+    let Some(log) = find_log(&state.logs, log_id) else {
+        return;
+    };
+    let index = state.log_index.get_or_insert_with(LogIndex::default);
+    index.by_asset.entry(log.asset).or_default().insert(log.id);
+    index
+        .by_from
+        .entry(log.from.clone())
+        .or_default()
+        .insert(log.id);
+    index
+        .by_to
+        .entry(log.to.clone())
+        .or_default()
+        .insert(log.id);
+    for hash in [&log.tx_hash, &log.fee_tx_hash].into_iter().flatten() {
+        index
+            .by_tx
+            .entry(hash.to_lowercase())
+            .or_default()
+            .insert(log.id);
+    }
+    index
+        .by_status
+        .get_or_insert_with(BTreeMap::new)
+        .entry(log_status_label(&log.status).to_string())
+        .or_default()
+        .insert(log.id);
+}
+
+fn log_matches(log: &PaymentLog, query: &LogQuery) -> bool {
+    query.asset.is_none_or(|asset| asset == log.asset)
+        && query.from.as_ref().is_none_or(|from| *from == log.from)
+        && query.to.as_ref().is_none_or(|to| *to == log.to)
+        && query
+            .status
+            .as_ref()
+            .is_none_or(|status| *status == log.status)
+        && query.tx_hash.as_ref().is_none_or(|hash| {
+            [&log.tx_hash, &log.fee_tx_hash]
+                .into_iter()
+                .flatten()
+                .any(|tx| tx.eq_ignore_ascii_case(hash))
+        })
+        && query.since_sec.is_none_or(|since| log.ts_sec >= since)
+        && query.until_sec.is_none_or(|until| log.ts_sec <= until)
+        && query.min_value.as_ref().is_none_or(|min| log.value >= *min)
+        && query.max_value.as_ref().is_none_or(|max| log.value <= *max)
+}
+
+/// Serves `query_logs`. Candidates come from the narrowest index that
+/// applies, or from the time window of the id-ordered log otherwise.
+fn query_log_page(logs: &[PaymentLog], index: Option<&LogIndex>, query: &LogQuery) -> LogPage {
+    let indexed: Vec<&BTreeSet<u64>> = match index {
+        Some(index) => {
+            let sets = [
+                query.asset.map(|asset| index.by_asset.get(&asset)),
+                query.from.as_ref().map(|from| index.by_from.get(from)),
+                query.to.as_ref().map(|to| index.by_to.get(to)),
+                query.tx_hash.as_ref().map(|hash| index.by_tx.get(hash)),
+                query.status.as_ref().and_then(|status| {
+                    let by_status = index.by_status.as_ref()?;
+                    Some(by_status.get(log_status_label(status)))
+                }),
+            ];
+            if sets.iter().any(|set| matches!(set, Some(None))) {
+                return LogPage {
+                    entries: Vec::new(),
+                    total: 0,
+                    next_cursor: None,
+                };
+            }
+            sets.into_iter().flatten().flatten().collect()
+        }
+        None => Vec::new(),
+    };
+    let exact_filters = [
+        query.asset.is_some(),
+        query.from.is_some(),
+        query.to.is_some(),
+        query.status.is_some(),
+    ]
+    .into_iter()
+    .filter(|set| *set)
+    .count();
+    let exact_only = query.tx_hash.is_none()
+        && query.since_sec.is_none()
+        && query.until_sec.is_none()
+        && query.min_value.is_none()
+        && query.max_value.is_none();
+    let exact_total = match (exact_only, exact_filters, indexed.as_slice()) {
+        (true, 0, _) => Some(logs.len() as u64),
+        (true, 1, [ids]) => Some(ids.len() as u64),
+        _ => None,
+    };
+    let ids = indexed.into_iter().min_by_key(|set| set.len());
+
+    let total = exact_total.unwrap_or_else(|| {
+        log_candidates(logs, ids, query, None)
+            .filter(|log| log_matches(log, query))
+            .count() as u64
+    });
+    let limit = query.limit.clamp(1, 100) as usize;
+    let mut matches =
+        log_candidates(logs, ids, query, query.cursor).filter(|log| log_matches(log, query));
+    let entries: Vec<LogEntry> = matches.by_ref().take(limit).map(log_entry).collect();
+    let next_cursor = if matches.next().is_some() {
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    LogPage {
+        entries,
+        total,
+        next_cursor,
+    }
+}
+
+/// Candidate logs in the query's direction, past `cursor` if given.
+fn log_candidates<'a>(
+    logs: &'a [PaymentLog],
+    ids: Option<&'a BTreeSet<u64>>,
+    query: &LogQuery,
+    cursor: Option<u64>,
+) -> Box<dyn Iterator<Item = &'a PaymentLog> + 'a> {
+    let direction = query.direction.unwrap_or_default();
+    let range = match (direction, cursor) {
+        (_, None) => (Bound::Unbounded, Bound::Unbounded),
+        (LogDirection::NewestFirst, Some(cursor)) => (Bound::Unbounded, Bound::Excluded(cursor)),
+        (LogDirection::OldestFirst, Some(cursor)) => (Bound::Excluded(cursor), Bound::Unbounded),
+    };
+    let candidates: Box<dyn DoubleEndedIterator<Item = &'a PaymentLog> + 'a> = match ids {
+        Some(ids) => Box::new(ids.range(range).filter_map(move |id| find_log(logs, *id))),
+        None => {
+            // Logs are appended in id order with non-decreasing timestamps.
+            let mut start = query
+                .since_sec
+                .map_or(0, |since| logs.partition_point(|log| log.ts_sec < since));
+            let mut end = query.until_sec.map_or(logs.len(), |until| {
+                logs.partition_point(|log| log.ts_sec <= until)
+            });
+            if let Bound::Excluded(cursor) = range.0 {
+                start = start.max(logs.partition_point(|log| log.id <= cursor));
+            }
+            if let Bound::Excluded(cursor) = range.1 {
+                end = end.min(logs.partition_point(|log| log.id < cursor));
+            }
+            Box::new(logs[start..end.max(start)].iter())
+        }
+    };
+    match direction {
+        LogDirection::NewestFirst => Box::new(candidates.rev()),
+        LogDirection::OldestFirst => candidates,
+    }
+}
+
+fn push_relay_log(
+    state: &mut RelayerState,
+    caller: Principal,
+    req: &SubmitAuthorizationRequest,
+    from_hex: &str,
+    to_hex: &str,
+    now_sec: u64,
+) -> u64 {
+    let id = state.next_log_id;
+    state.next_log_id += 1;
+    state.logs.push(PaymentLog {
+        id,
+        ts_sec: now_sec,
+        asset: req.asset,
+        from: from_hex.to_string(),
+        to: to_hex.to_string(),
+        value: req.value.clone(),
+        status: PaymentStatus::Accepted,
+        tx_hash: None,
+        fail_reason: None,
+        kind: Some(PaymentKind::Relay),
+        fee_value: req.fee.as_ref().map(|fee| fee.value.clone()),
+        fee_tx_hash: None,
+        memo: req.memo.clone(),
+        memo_bound: Some(req.memo_salt.is_some()),
+        chain_id: state.config.chain_id.clone(),
+        valid_after: Some(req.valid_after.clone()),
+        valid_before: Some(req.valid_before.clone()),
+        auth_nonce: Some(to_hex_prefixed(&req.nonce)),
+        tx_nonce: None,
+        gas_limit: None,
+        max_fee_per_gas: None,
+        max_priority_fee_per_gas: None,
+        caller: Some(caller),
+        stages: None,
+    });
+    count_new_log(state);
+    index_log(state, id);
+    id
+}
+
+/// Logs a relay rejected by screening as failed. No webhook or subscriber
+/// hears about it.
+fn log_screened_relay(
+    state: &mut RelayerState,
+    caller: Principal,
+    req: &SubmitAuthorizationRequest,
+    from_hex: &str,
+    to_hex: &str,
+    err: &RelayError,
+    now_sec: u64,
+) -> u64 {
+    let log_id = push_relay_log(state, caller, req, from_hex, to_hex, now_sec);
+    if let Some(log) = state.logs.last_mut() {
+        set_log_status(
+            &mut state.log_metrics,
+            &mut state.log_index,
+            log,
+            PaymentStatus::Failed,
+        );
+        log.fail_reason = Some(err.to_string());
+    }
+    log_id
+}
+
+fn mark_log_failure(log_id: u64, reason: &str) {
+    state_mut(|state| {
+        if let Some(log) = find_log_mut(&mut state.logs, log_id) {
+            set_log_status(
+                &mut state.log_metrics,
+                &mut state.log_index,
+                log,
+                PaymentStatus::Failed,
+            );
+            log.fail_reason = Some(reason.to_string());
+        }
+        settle_quote(state, log_id, false);
+        index_log(state, log_id);
+        publish_log_event(state, log_id);
+    });
+}
+
+fn mark_log_success(log_id: u64, tx_hash: &str) {
+    state_mut(|state| {
+        if let Some(log) = find_log_mut(&mut state.logs, log_id) {
+            set_log_status(
+                &mut state.log_metrics,
+                &mut state.log_index,
+                log,
+                PaymentStatus::Broadcasted,
+            );
+            log.tx_hash = Some(tx_hash.to_string());
+            log.fail_reason = None;
+        }
+        settle_quote(state, log_id, true);
+        index_log(state, log_id);
+        publish_log_event(state, log_id);
+    });
+}
+
+/// Fans a log's new status out to its invoice, the recipient's webhook and
+/// matching subscribers.
+fn publish_log_event(state: &mut RelayerState, log_id: u64) {
+    let Some(log) = state.logs.iter().find(|log| log.id == log_id) else {
+        return;
+    };
+    let log = log.clone();
+    let now_sec = time() / 1_000_000_000;
+    if let Some(registry) = state.invoices.as_mut() {
+        settle_invoice(registry, &log, now_sec);
+    }
+    enqueue_webhook(state, &log, now_sec);
+    enqueue_subscription_events(state, &log, now_sec);
+}
+
+fn normalize_evm_address(address: &str) -> InternalResult<String> {
+    let trimmed = address.trim();
+    if trimmed.len() != 42 || !trimmed.starts_with("0x") {
+        return Err(RelayError::InvalidAddressLength {
+            field: "evm_address".into(),
+            expected: 42,
+            actual: trimmed.len(),
+        });
+    }
+    let bytes = hex::decode(&trimmed[2..]).map_err(|_| RelayError::HexDecodeFailed {
+        value: trimmed.to_string(),
+    })?;
+    if bytes.len() != 20 {
+        return Err(RelayError::InvalidAddressLength {
+            field: "evm_address".into(),
+            expected: 20,
+            actual: bytes.len(),
+        });
+    }
+    Ok(format!("0x{}", hex::encode(bytes)))
+}
+
+fn pad_left(value: &[u8], len: usize) -> Vec<u8> {
+    if value.len() >= len {
+        return value[value.len() - len..].to_vec();
+    }
+    let mut out = vec![0u8; len - value.len()];
+    out.extend_from_slice(value);
+    out
+}
+
+fn encode_uint_nat(value: &Nat) -> InternalResult<Vec<u8>> {
+    let bytes = value.0.to_bytes_be();
+    if bytes.len() > 32 {
+        return Err(RelayError::NumberOutOfRange {
+            field: "uint256".into(),
+        });
+    }
+    Ok(pad_left(&bytes, 32))
+}
+
+fn encode_uint_u8(value: u8) -> Vec<u8> {
+    pad_left(&[value], 32)
+}
+
+fn encode_bytes32(value: &[u8]) -> InternalResult<Vec<u8>> {
+    if value.len() != 32 {
+        return Err(RelayError::InvalidNonceLength {
+            expected: 32,
+            actual: value.len(),
+        });
+    }
+    Ok(value.to_vec())
+}
+
+fn to_hex_prefixed(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn parse_hex_bytes(value: &str) -> InternalResult<Vec<u8>> {
+    let trimmed = value.trim();
+    if !trimmed.starts_with("0x") {
+        return Err(RelayError::HexDecodeFailed {
+            value: trimmed.to_string(),
+        });
+    }
+    let mut body = trimmed[2..].to_string();
+    if body.is_empty() {
+        return Ok(vec![]);
+    }
+    if !body.len().is_multiple_of(2) {
+        body = format!("0{}", body);
+    }
+    hex::decode(&body).map_err(|_| RelayError::HexDecodeFailed {
+        value: trimmed.to_string(),
+    })
+}
+
+fn decode_bool_abi(bytes: &[u8]) -> InternalResult<bool> {
+    if bytes.is_empty() {
+        return Ok(false);
+    }
+    Ok(bytes[bytes.len() - 1] != 0)
+}
+
+fn nat_from_hex(value: &str) -> InternalResult<Nat> {
+    let bytes = parse_hex_bytes(value)?;
+    Ok(Nat::from(BigUint::from_bytes_be(&bytes)))
+}
+
+fn nat_from_hex_with_zero_default(value: &str) -> InternalResult<Nat> {
+    if value == "0x" {
+        Ok(Nat::from(0u32))
+    } else {
+        nat_from_hex(value)
+    }
+}
+
+fn nat_to_u128(value: &Nat) -> InternalResult<u128> {
+    value
+        .0
+        .to_u128()
+        .ok_or_else(|| RelayError::NumberOutOfRange {
+            field: "u128".into(),
+        })
+}
+
+fn scale_nat(value: &Nat, multiplier: f64) -> InternalResult<Nat> {
+    let base = nat_to_u128(value)?;
+    let scaled = (base as f64 * multiplier).ceil();
+    if scaled.is_nan() || scaled.is_infinite() || scaled < 0.0 {
+        return Err(RelayError::NumberOutOfRange {
+            field: "scaled nat".into(),
+        });
+    }
+    Ok(Nat::from(scaled as u128))
+}
+
+const RPC_RESPONSE_MAX_BYTES: u64 = 64 * 1024;
+const PRICE_RESPONSE_MAX_BYTES: u64 = 8 * 1024;
+static JSON_RPC_ID: AtomicU64 = AtomicU64::new(1);
+
+fn tokens_in_smallest_unit(cap_tokens: u64, decimals: u8) -> Option<Nat> {
+    if cap_tokens == 0 {
+        None
+    } else {
+        Some(Nat::from(
+            BigUint::from(cap_tokens) * BigUint::from(10u32).pow(decimals as u32),
+        ))
+    }
+}
+
+fn check_value_bounds(asset: &AssetConfig, value: &Nat) -> InternalResult<()> {
+    let Some(limits) = &asset.limits else {
+        return Ok(());
+    };
+    if let Some(min) = &limits.min_value {
+        if value < min {
+            return Err(RelayError::AmountBelowMinimum {
+                min: min.clone(),
+                actual: value.clone(),
+            });
+        }
+    }
+    if let Some(max) = &limits.max_value {
+        if value > max {
+            return Err(RelayError::AmountAboveMaximum {
+                max: max.clone(),
+                actual: value.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+enum DailyCapKind {
+    Sender,
+    Recipient,
+}
+
+struct DailyCap {
+    key: String,
+    cap: Nat,
+    kind: DailyCapKind,
+}
+
+/// Sender and recipient daily caps in force for this relay, keyed per asset.
+fn daily_caps(state: &RelayerState, subject: &RateLimitSubject) -> Vec<DailyCap> {
+    let asset = state.assets.get(&subject.asset);
+    let decimals = asset
+        .and_then(|cfg| cfg.decimals)
+        .unwrap_or(DEFAULT_TOKEN_DECIMALS);
+    let limits = asset.and_then(|cfg| cfg.limits.clone()).unwrap_or_default();
+    let sender_tokens = limits
+        .sender_daily_cap_tokens
+        .unwrap_or(state.rate_limit.daily_cap_token);
+    let recipient_tokens = limits.recipient_daily_cap_tokens.unwrap_or(0);
+
+    let mut caps = Vec::new();
+    if let Some(cap) = tokens_in_smallest_unit(sender_tokens, decimals) {
+        caps.push(DailyCap {
+            key: format!("from:{}:{}", subject.asset, subject.from),
+            cap,
+            kind: DailyCapKind::Sender,
+        });
+    }
+    if let Some(cap) = tokens_in_smallest_unit(recipient_tokens, decimals) {
+        caps.push(DailyCap {
+            key: format!("to:{}:{}", subject.asset, subject.to),
+            cap,
+            kind: DailyCapKind::Recipient,
+        });
+    }
+    caps
+}
+
+/// Sender counters used to be keyed by the bare `from` address and shared
+/// by all assets. Each is carried over to every registered asset's sender
+/// key so a day's spend survives the upgrade.
+fn migrate_daily_counters(state: &mut RelayerState) {
+    let counters = &mut state.rate_state.daily_counter;
+    let legacy: Vec<String> = counters
+        .keys()
+        .filter(|key| !key.contains(':'))
+        .cloned()
+        .collect();
+    for from in legacy {
+        let Some(counter) = counters.remove(&from) else {
+            continue;
+        };
+        for asset in state.assets.keys() {
+            counters
+                .entry(format!("from:{}:{}", asset, from))
+                .or_insert_with(|| counter.clone());
+        }
+    }
+}
+
+const TOKEN_MILLI: u64 = 1_000;
+
+struct RateLimitSubject<'a> {
+    caller: Principal,
+    from: &'a str,
+    to: &'a str,
+    asset: Principal,
+    /// Relays arriving over `POST /relay` draw from the `http` bucket.
+    via_http: bool,
+}
+
+/// Adds to the cycle meter of the task being polled, if it has one.
+fn charge_cycles(cycles: u128) {
+    CYCLE_METER.with(|meter| {
+        if let Some(total) = meter.get() {
+            meter.set(Some(
+                total.saturating_add(cycles.min(u64::MAX as u128) as u64),
+            ));
+        }
+    });
+}
+
+/// Installs its own cycle meter around every poll of `inner`, so cycles
+/// charged while this future runs are attributed to it even when other
+/// relays interleave at await points.
+struct Metered<F> {
+    inner: Pin<Box<F>>,
+    cycles: u64,
+}
+
+impl<F: Future> Future for Metered<F> {
+    type Output = (F::Output, u64);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let outer = CYCLE_METER.with(|meter| meter.replace(Some(self.cycles)));
+        let poll = self.inner.as_mut().poll(cx);
+        let own = CYCLE_METER.with(|meter| meter.replace(outer)).unwrap_or(0);
+        if let Some(outer) = outer {
+            CYCLE_METER.with(|meter| meter.set(Some(outer.saturating_add(own - self.cycles))));
+        }
+        self.cycles = own;
+        poll.map(|output| (output, self.cycles))
+    }
+}
+
+/// Per-stage timings of one relay, written to its log when dropped so that
+/// failed relays keep the stages they got through.
+struct StageRecorder {
+    log_id: u64,
+    stages: Vec<StageTiming>,
+}
+
+impl StageRecorder {
+    fn new(log_id: u64) -> Self {
+        Self {
+            log_id,
+            stages: Vec::new(),
+        }
+    }
+
+    async fn run<F: Future>(&mut self, stage: &str, fut: F) -> F::Output {
+        let started = time();
+        let (output, cycles) = Metered {
+            inner: Box::pin(fut),
+            cycles: 0,
+        }
+        .await;
+        let duration_ms = time().saturating_sub(started) / 1_000_000;
+        match self.stages.iter_mut().find(|timing| timing.stage == stage) {
+            Some(timing) => {
+                timing.duration_ms += duration_ms;
+                timing.cycles = timing.cycles.saturating_add(cycles);
+            }
+            None => self.stages.push(StageTiming {
+                stage: stage.to_string(),
+                duration_ms,
+                cycles,
+            }),
+        }
+        output
+    }
+}
+
+impl Drop for StageRecorder {
+    fn drop(&mut self) {
+        let stages = std::mem::take(&mut self.stages);
+        defer_state_update(DeferredUpdate::StageTimings(self.log_id, stages));
+    }
+}
+
+/// Nearest-rank percentile of an ascending slice.
+fn percentile(sorted: &[u64], pct: u64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (pct * sorted.len() as u64).div_ceil(100).max(1) as usize;
+    sorted[rank.min(sorted.len()) - 1]
+}
+
+/// Aggregates the stage timings of several relays; `total` sums every stage
+/// of a relay.
+fn stage_percentiles(relays: &[&[StageTiming]]) -> Vec<StagePercentiles> {
+    let mut samples: BTreeMap<String, (Vec<u64>, Vec<u64>)> = BTreeMap::new();
+    for stages in relays.iter().copied() {
+        if stages.is_empty() {
+            continue;
+        }
+        let mut total = (0u64, 0u64);
+        for timing in stages {
+            let entry = samples.entry(timing.stage.clone()).or_default();
+            entry.0.push(timing.duration_ms);
+            entry.1.push(timing.cycles);
+            total.0 += timing.duration_ms;
+            total.1 = total.1.saturating_add(timing.cycles);
+        }
+        let entry = samples.entry("total".to_string()).or_default();
+        entry.0.push(total.0);
+        entry.1.push(total.1);
+    }
+    samples
+        .into_iter()
+        .map(|(stage, (mut durations, mut cycles))| {
+            durations.sort_unstable();
+            cycles.sort_unstable();
+            StagePercentiles {
+                stage,
+                samples: durations.len() as u32,
+                p50_ms: percentile(&durations, 50),
+                p90_ms: percentile(&durations, 90),
+                p99_ms: percentile(&durations, 99),
+                max_ms: durations.last().copied().unwrap_or(0),
+                p50_cycles: percentile(&cycles, 50),
+                p90_cycles: percentile(&cycles, 90),
+                p99_cycles: percentile(&cycles, 99),
+                total_cycles: Nat::from(cycles.iter().map(|c| *c as u128).sum::<u128>()),
+            }
+        })
+        .collect()
+}
+
+/// Quota taken for one relay. Dropping it without `commit` hands the tokens
+/// and daily amount back, so relays that fail before broadcast cost nothing.
+struct QuotaReservation {
+    refund: QuotaRefund,
+    committed: bool,
+}
+
+#[derive(Default)]
+struct QuotaRefund {
+    buckets: Vec<(String, BucketLimit)>,
+    daily_keys: Vec<String>,
+    amount: Nat,
+    day: u64,
+}
+
+impl QuotaReservation {
+    fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        defer_state_update(DeferredUpdate::RefundQuota(std::mem::take(
+            &mut self.refund,
+        )));
+    }
+}
+
+fn legacy_limiter_config(config: &RateLimitConfig) -> RateLimiterConfig {
+    let per_from = (config.per_addr_per_min > 0).then_some(BucketLimit {
+        capacity: config.per_addr_per_min,
+        refill_per_min: config.per_addr_per_min,
+    });
+    RateLimiterConfig {
+        per_from,
+        ..RateLimiterConfig::default()
+    }
+}
+
+/// Limiter state, seeded from the init-time `per_addr_per_min` on first use.
+fn limiter_mut(state: &mut RelayerState) -> &mut RateLimiter {
+    let legacy = &state.rate_limit;
+    state.limiter.get_or_insert_with(|| RateLimiter {
+        config: legacy_limiter_config(legacy),
+        buckets: BTreeMap::new(),
+        gc: None,
+    })
+}
+
+const RATE_LIMIT_SCOPES: [&str; 6] = ["global", "principal", "http", "asset", "from", "to"];
+
+fn scope_limit(config: &RateLimiterConfig, scope: &str) -> Option<BucketLimit> {
+    match scope {
+        "global" => config.global,
+        "principal" => config.per_principal,
+        "asset" => config.per_asset,
+        "from" => config.per_from,
+        "to" => config.per_to,
+        "http" => config.per_http.or(config.per_principal),
+        "quote" => Some(config.per_principal.unwrap_or(DEFAULT_QUOTE_LIMIT)),
+        _ => None,
+    }
+}
+
+fn bucket_scope(key: &str) -> &str {
+    key.split(':').next().unwrap_or(key)
+}
+
+fn rate_limit_buckets(
+    config: &RateLimiterConfig,
+    subject: &RateLimitSubject,
+) -> Vec<(String, BucketLimit)> {
+    RATE_LIMIT_SCOPES
+        .iter()
+        .filter_map(|scope| {
+            let limit = scope_limit(config, scope)?;
+            let http_scope = *scope == "http";
+            if matches!(*scope, "principal" | "http") && http_scope != subject.via_http {
+                return None;
+            }
+            let key = match *scope {
+                "global" | "http" => scope.to_string(),
+                "principal" => format!("principal:{}", subject.caller),
+                "asset" => format!("asset:{}", subject.asset),
+                "from" => format!("from:{}", subject.from),
+                _ => format!("to:{}", subject.to),
+            };
+            Some((key, limit))
+        })
+        .collect()
+}
+
+fn refill_bucket(bucket: &mut TokenBucket, limit: &BucketLimit, now_sec: u64) {
+    let capacity = limit.capacity as u64 * TOKEN_MILLI;
+    let elapsed = now_sec.saturating_sub(bucket.updated_sec);
+    let refill = elapsed.saturating_mul(limit.refill_per_min as u64 * TOKEN_MILLI) / 60;
+    bucket.tokens_milli = bucket.tokens_milli.saturating_add(refill).min(capacity);
+    bucket.updated_sec = bucket.updated_sec.max(now_sec);
+}
+
+/// Takes one token from every bucket, or none if any of them is empty.
+fn take_bucket_tokens(
+    buckets: &mut BTreeMap<String, TokenBucket>,
+    limits: &[(String, BucketLimit)],
+    now_sec: u64,
+) -> InternalResult<()> {
+    for (key, limit) in limits {
+        let bucket = buckets.entry(key.clone()).or_insert(TokenBucket {
+            tokens_milli: limit.capacity as u64 * TOKEN_MILLI,
+            updated_sec: now_sec,
+        });
+        refill_bucket(bucket, limit, now_sec);
+        if bucket.tokens_milli < TOKEN_MILLI {
+            return Err(RelayError::RateLimited {
+                scope: bucket_scope(key).to_string(),
+            });
+        }
+    }
+    for (key, _) in limits {
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.tokens_milli -= TOKEN_MILLI;
+        }
+    }
+    Ok(())
+}
+
+fn enforce_rate_limits(
+    state: &mut RelayerState,
+    subject: RateLimitSubject,
+    amount: &Nat,
+    now_sec: u64,
+) -> InternalResult<QuotaReservation> {
+    let day = now_sec / 86_400;
+    let caps = daily_caps(state, &subject);
+    for daily in &caps {
+        let spent = state
+            .rate_state
+            .daily_counter
+            .get(&daily.key)
+            .filter(|counter| counter.window_start_sec == day)
+            .map(|counter| counter.amount.clone())
+            .unwrap_or_default();
+        if spent.clone() + amount.clone() > daily.cap {
+            let cap = daily.cap.clone();
+            return Err(match daily.kind {
+                DailyCapKind::Sender => RelayError::SenderDailyCapExceeded { cap, spent },
+                DailyCapKind::Recipient => RelayError::RecipientDailyCapExceeded { cap, spent },
+            });
+        }
+    }
+    let daily_keys: Vec<String> = caps.into_iter().map(|daily| daily.key).collect();
+
+    let limits = rate_limit_buckets(&limiter_mut(state).config, &subject);
+    ensure_tracking_capacity(state, &limits, &daily_keys, now_sec)?;
+    take_bucket_tokens(&mut limiter_mut(state).buckets, &limits, now_sec)?;
+
+    for key in &daily_keys {
+        let counter = state
+            .rate_state
+            .daily_counter
+            .entry(key.clone())
+            .or_default();
+        if counter.window_start_sec != day {
+            counter.window_start_sec = day;
+            counter.amount = Nat::from(0u32);
+            counter.hits = 0;
+        }
+        counter.hits += 1;
+        counter.amount = counter.amount.clone() + amount.clone();
+    }
+
+    Ok(QuotaReservation {
+        refund: QuotaRefund {
+            buckets: limits,
+            daily_keys,
+            amount: amount.clone(),
+            day,
+        },
+        committed: false,
+    })
+}
+
+/// Exponential moving average (1/8 weight) of the expected gas cost of a relay.
+fn record_relay_cost(state: &mut RelayerState, cost: &Nat) {
+    let monitor = state.gas_monitor.get_or_insert_with(GasMonitor::default);
+    monitor.avg_relay_cost_wei = Some(match &monitor.avg_relay_cost_wei {
+        Some(avg) => (avg.clone() * Nat::from(7u32) + cost.clone()) / Nat::from(8u32),
+        None => cost.clone(),
+    });
+}
+
+fn total_pool_gas(state: &RelayerState) -> Nat {
+    state
+        .wallet_pool
+        .iter()
+        .flat_map(|pool| pool.wallets.values())
+        .filter(|wallet| wallet.address.is_some())
+        .fold(Nat::from(0u32), |sum, wallet| sum + wallet.gas_wei.clone())
+}
+
+fn relays_since(state: &RelayerState, since_sec: u64) -> u64 {
+    state
+        .logs
+        .iter()
+        .rev()
+        .take_while(|log| log.ts_sec >= since_sec)
+        .filter(|log| matches!(log.kind, None | Some(PaymentKind::Relay)) && log.tx_hash.is_some())
+        .count() as u64
+}
+
+fn classify_gas_health(config: &GasMonitorConfig, total: &Nat) -> GasHealth {
+    if config
+        .hard_threshold_wei
+        .as_ref()
+        .is_some_and(|hard| total < hard)
+    {
+        GasHealth::Critical
+    } else if config
+        .soft_threshold_wei
+        .as_ref()
+        .is_some_and(|soft| total < soft)
+    {
+        GasHealth::Warning
+    } else {
+        GasHealth::Healthy
+    }
+}
+
+/// Relays the balance still covers, and how many hours that lasts at the
+/// current hourly relay rate.
+fn estimate_runway(
+    total: &Nat,
+    avg_cost: Option<&Nat>,
+    relays_per_hour: u64,
+) -> (Option<u64>, Option<f64>) {
+    let Some(avg_cost) = avg_cost.filter(|cost| cost.0.bits() > 0) else {
+        return (None, None);
+    };
+    let relays = (total.0.clone() / avg_cost.0.clone())
+        .to_u64()
+        .unwrap_or(u64::MAX);
+    let hours = (relays_per_hour > 0).then(|| relays as f64 / relays_per_hour as f64);
+    (Some(relays), hours)
+}
+
+fn gas_monitor_info(state: &RelayerState, now_sec: u64) -> GasMonitorInfo {
+    let monitor = state.gas_monitor.clone().unwrap_or_default();
+    let total = total_pool_gas(state);
+    let relays_last_hour = relays_since(state, now_sec.saturating_sub(3_600));
+    let (runway_relays, runway_hours) = estimate_runway(
+        &total,
+        monitor.avg_relay_cost_wei.as_ref(),
+        relays_last_hour,
+    );
+    GasMonitorInfo {
+        health: monitor.health,
+        total_gas_wei: total,
+        avg_relay_cost_wei: monitor.avg_relay_cost_wei,
+        relays_last_hour,
+        runway_relays,
+        runway_hours,
+        soft_threshold_wei: monitor.config.soft_threshold_wei,
+        hard_threshold_wei: monitor.config.hard_threshold_wei,
+        last_refresh_sec: monitor.last_refresh_sec,
+        last_error: monitor.last_error,
+        auto_paused: monitor.auto_paused,
+    }
+}
+
+/// Records the refresh outcome and moves between health states, pausing on
+/// `Critical` and lifting its own pause once the balance recovers.
+fn finish_gas_refresh(state: &mut RelayerState, error: Option<&RelayError>, now_sec: u64) {
+    let total = total_pool_gas(state);
+    let monitor = state.gas_monitor.get_or_insert_with(GasMonitor::default);
+    monitor.last_refresh_sec = now_sec;
+    monitor.last_error = error.map(|err| err.to_string());
+    if error.is_some() {
+        return;
+    }
+    let health = classify_gas_health(&monitor.config, &total);
+    monitor.health = health;
+    if health == GasHealth::Critical && monitor.config.auto_pause && !state.config.paused {
+        monitor.auto_paused = true;
+        state.config.paused = true;
+        record_audit(state, "gas_auto_pause", format!("total_gas_wei={}", total));
+    } else if health == GasHealth::Healthy && monitor.auto_paused {
+        monitor.auto_paused = false;
+        if state.config.paused {
+            state.config.paused = false;
+            record_audit(state, "gas_auto_resume", format!("total_gas_wei={}", total));
+        }
+    }
+}
+
+const MAX_MEMO_BYTES: usize = 256;
+const MAX_MEMO_SALT_BYTES: usize = 32;
+
+fn memo_bytes(memo: &Memo) -> &[u8] {
+    match memo {
+        Memo::Text(text) => text.as_bytes(),
+        Memo::Blob(bytes) => bytes,
+    }
+}
+
+fn memo_nonce(memo: &Memo, salt: &[u8]) -> [u8; 32] {
+    let mut preimage = b"jpycpay-memo-v1".to_vec();
+    preimage.extend_from_slice(&(memo_bytes(memo).len() as u32).to_be_bytes());
+    preimage.extend_from_slice(memo_bytes(memo));
+    preimage.extend_from_slice(salt);
+    keccak256(&preimage)
+}
+
+fn check_memo(req: &SubmitAuthorizationRequest) -> InternalResult<()> {
+    if req
+        .memo
+        .as_ref()
+        .is_some_and(|memo| memo_bytes(memo).len() > MAX_MEMO_BYTES)
+    {
+        return Err(RelayError::NumberOutOfRange {
+            field: "memo".into(),
+        });
+    }
+    if req
+        .memo_salt
+        .as_ref()
+        .is_some_and(|salt| salt.len() > MAX_MEMO_SALT_BYTES)
+    {
+        return Err(RelayError::NumberOutOfRange {
+            field: "memo_salt".into(),
+        });
+    }
+    match (&req.memo, &req.memo_salt) {
+        (_, None) => Ok(()),
+        (Some(memo), Some(salt)) if memo_nonce(memo, salt).as_slice() == req.nonce => Ok(()),
+        _ => Err(RelayError::MemoNotBound),
+    }
+}
+
+const MAX_INVOICE_REFERENCE_LEN: usize = 128;
+const MAX_OPEN_INVOICES_PER_MERCHANT: usize = 1_000;
+const MAX_INVOICES: usize = 50_000;
+/// How long paid, cancelled and expired invoices stay queryable.
+const INVOICE_RETENTION_SEC: u64 = 30 * 24 * 60 * 60;
+
+/// Assigns the invoice its id and nonce and stores it, pruning finished
+/// invoices first when the registry is full.
+fn open_invoice(
+    registry: &mut InvoiceRegistry,
+    mut invoice: Invoice,
+    seed: &[u8],
+    now_sec: u64,
+) -> Result<Invoice, String> {
+    if registry.invoices.len() >= MAX_INVOICES {
+        prune_invoices(registry, now_sec);
+        if registry.invoices.len() >= MAX_INVOICES {
+            return Err("invoice registry full".to_string());
+        }
+    }
+    let open = registry
+        .invoices
+        .values()
+        .filter(|existing| {
+            existing.merchant == invoice.merchant
+                && matches!(
+                    invoice_status(existing, now_sec),
+                    InvoiceStatus::Open | InvoiceStatus::Pending
+                )
+        })
+        .count();
+    if open >= MAX_OPEN_INVOICES_PER_MERCHANT {
+        return Err("too many open invoices".to_string());
+    }
+    registry.next_id += 1;
+    invoice.id = registry.next_id;
+    invoice.nonce = invoice_nonce(invoice.id, seed).to_vec();
+    registry.by_nonce.insert(invoice.nonce.clone(), invoice.id);
+    registry.invoices.insert(invoice.id, invoice.clone());
+    Ok(invoice)
+}
+
+/// Drops paid, cancelled and expired invoices once `INVOICE_RETENTION_SEC`
+/// has passed since they expired or were paid.
+fn prune_invoices(registry: &mut InvoiceRegistry, now_sec: u64) {
+    let stale: Vec<(u64, Vec<u8>)> = registry
+        .invoices
+        .values()
+        .filter(|invoice| {
+            matches!(
+                invoice_status(invoice, now_sec),
+                InvoiceStatus::Paid | InvoiceStatus::Cancelled | InvoiceStatus::Expired
+            )
+        })
+        .filter(|invoice| {
+            let finished_sec = invoice
+                .expires_sec
+                .max(invoice.paid_sec.unwrap_or_default());
+            now_sec >= finished_sec.saturating_add(INVOICE_RETENTION_SEC)
+        })
+        .map(|invoice| (invoice.id, invoice.nonce.clone()))
+        .collect();
+    for (id, nonce) in stale {
+        registry.invoices.remove(&id);
+        registry.by_nonce.remove(&nonce);
+    }
+}
+
+fn mark_invoice_pending(registry: &mut InvoiceRegistry, invoice_id: u64, log_id: u64) {
+    if let Some(invoice) = registry.invoices.get_mut(&invoice_id) {
+        invoice.status = InvoiceStatus::Pending;
+        invoice.log_id = Some(log_id);
+        registry
+            .by_log
+            .get_or_insert_with(BTreeMap::new)
+            .insert(log_id, invoice_id);
+    }
+}
+
+/// Rebuilds the log index for registries saved before it existed.
+fn migrate_invoices(state: &mut RelayerState) {
+    let Some(registry) = state.invoices.as_mut() else {
+        return;
+    };
+    if registry.by_log.is_none() {
+        registry.by_log = Some(
+            registry
+                .invoices
+                .values()
+                .filter(|invoice| invoice.status == InvoiceStatus::Pending)
+                .filter_map(|invoice| Some((invoice.log_id?, invoice.id)))
+                .collect(),
+        );
+    }
+}
+
+fn invoice_nonce(invoice_id: u64, salt: &[u8]) -> [u8; 32] {
+    let mut preimage = b"jpycpay-invoice-v1".to_vec();
+    preimage.extend_from_slice(&invoice_id.to_be_bytes());
+    preimage.extend_from_slice(salt);
+    keccak256(&preimage)
+}
+
+/// Stored status with expiry applied; open invoices expire lazily.
+fn invoice_status(invoice: &Invoice, now_sec: u64) -> InvoiceStatus {
+    if invoice.status == InvoiceStatus::Open && now_sec >= invoice.expires_sec {
+        InvoiceStatus::Expired
+    } else {
+        invoice.status
+    }
+}
+
+fn with_current_status(invoice: &Invoice, now_sec: u64) -> Invoice {
+    Invoice {
+        status: invoice_status(invoice, now_sec),
+        ..invoice.clone()
+    }
+}
+
+/// Finds the invoice an authorization pays, if its nonce belongs to one,
+/// and checks that it is still open and that the terms match.
+fn invoice_for_payment(
+    registry: &InvoiceRegistry,
+    req: &SubmitAuthorizationRequest,
+    to_hex: &str,
+    now_sec: u64,
+) -> InternalResult<Option<u64>> {
+    let Some(invoice) = registry
+        .by_nonce
+        .get(&req.nonce)
+        .and_then(|id| registry.invoices.get(id))
+    else {
+        return Ok(None);
+    };
+    let status = invoice_status(invoice, now_sec);
+    if status != InvoiceStatus::Open {
+        return Err(RelayError::InvoiceNotPayable {
+            id: invoice.id,
+            status: format!("{:?}", status).to_lowercase(),
+        });
+    }
+    let mismatch = if invoice.asset != req.asset {
+        Some("asset")
+    } else if invoice.recipient != to_hex {
+        Some("recipient")
+    } else if invoice.amount != req.value {
+        Some("amount")
+    } else {
+        None
+    };
+    match mismatch {
+        Some(field) => Err(RelayError::InvoiceMismatch {
+            id: invoice.id,
+            field: field.into(),
+        }),
+        None => Ok(Some(invoice.id)),
+    }
+}
+
+fn settle_invoice(registry: &mut InvoiceRegistry, log: &PaymentLog, now_sec: u64) {
+    if matches!(
+        log.status,
+        PaymentStatus::Accepted | PaymentStatus::Broadcasted
+    ) {
+        return;
+    }
+    let Some(invoice_id) = registry
+        .by_log
+        .as_mut()
+        .and_then(|by_log| by_log.remove(&log.id))
+    else {
+        return;
+    };
+    let Some(invoice) = registry
+        .invoices
+        .get_mut(&invoice_id)
+        .filter(|invoice| invoice.status == InvoiceStatus::Pending)
+    else {
+        return;
+    };
+    if log.status == PaymentStatus::Confirmed {
+        invoice.status = InvoiceStatus::Paid;
+        invoice.tx_hash = log.tx_hash.clone();
+        invoice.paid_sec = Some(now_sec);
+    } else {
+        invoice.status = InvoiceStatus::Open;
+        invoice.log_id = None;
+    }
+}
+
+const MAX_SUBSCRIPTIONS: usize = 500;
+const MAX_SUBSCRIPTIONS_PER_CALLER: usize = 10;
+const MAX_QUEUED_EVENTS: usize = 5_000;
+const MAX_EVENTS_PER_RUN: usize = 20;
+const MAX_SUBSCRIBER_FAILURES: u32 = 10;
+const SUBSCRIBER_CALL_TIMEOUT_SEC: u32 = 10;
+const EVENT_BASE_BACKOFF_SEC: u64 = 30;
+
+fn subscription_matches(filter: &SubscriptionFilter, log: &PaymentLog) -> bool {
+    filter.asset.is_none_or(|asset| asset == log.asset)
+        && filter
+            .recipient
+            .as_ref()
+            .is_none_or(|recipient| *recipient == log.to)
+        && filter
+            .memo
+            .as_ref()
+            .is_none_or(|memo| log.memo.as_ref() == Some(memo))
+}
+
+fn enqueue_subscription_events(state: &mut RelayerState, log: &PaymentLog, now_sec: u64) {
+    let Some(event) = payment_event_name(&log.status) else {
+        return;
+    };
+    let Some(registry) = state.subscriptions.as_mut() else {
+        return;
+    };
+    let payload = PaymentEvent {
+        log_id: log.id,
+        event: event.to_string(),
+        asset: log.asset,
+        from: log.from.clone(),
+        to: log.to.clone(),
+        value: log.value.clone(),
+        status: log.status.clone(),
+        tx_hash: log.tx_hash.clone(),
+        fail_reason: log.fail_reason.clone(),
+        memo: log.memo.clone(),
+        ts_sec: now_sec,
+    };
+    let matching: Vec<u64> = registry
+        .subscriptions
+        .values()
+        .filter(|sub| subscription_matches(&sub.filter, log))
+        .map(|sub| sub.id)
+        .collect();
+    for subscription_id in matching {
+        registry.queue.push(QueuedEvent {
+            subscription_id,
+            event: payload.clone(),
+            attempts: 0,
+            next_attempt_sec: now_sec,
+        });
+    }
+    if registry.queue.len() > MAX_QUEUED_EVENTS {
+        let excess = registry.queue.len() - MAX_QUEUED_EVENTS;
+        registry.queue.drain(..excess);
+    }
+}
+
+fn drop_subscription(registry: &mut SubscriptionRegistry, id: u64) {
+    registry.subscriptions.remove(&id);
+    registry.queue.retain(|queued| queued.subscription_id != id);
+}
+
+/// Books one delivery attempt. Failures back off per event and count
+/// against the subscriber; returns `true` when the subscriber was dropped.
+fn record_event_attempt(
+    registry: &mut SubscriptionRegistry,
+    subscription_id: u64,
+    log_id: u64,
+    event: &str,
+    result: Result<(), String>,
+    now_sec: u64,
+) -> bool {
+    let position = registry.queue.iter().position(|queued| {
+        queued.subscription_id == subscription_id
+            && queued.event.log_id == log_id
+            && queued.event.event == event
+    });
+    let Some(sub) = registry.subscriptions.get_mut(&subscription_id) else {
+        return false;
+    };
+    match result {
+        Ok(()) => {
+            sub.delivered += 1;
+            sub.consecutive_failures = 0;
+            sub.last_error = None;
+            if let Some(index) = position {
+                registry.queue.remove(index);
+            }
+            false
+        }
+        Err(err) => {
+            sub.consecutive_failures += 1;
+            sub.last_error = Some(err);
+            if sub.consecutive_failures >= MAX_SUBSCRIBER_FAILURES {
+                drop_subscription(registry, subscription_id);
+                return true;
+            }
+            if let Some(queued) = position.map(|index| &mut registry.queue[index]) {
+                queued.attempts += 1;
+                let backoff = EVENT_BASE_BACKOFF_SEC
+                    .saturating_mul(1 << (queued.attempts - 1).min(16))
+                    .min(WEBHOOK_MAX_BACKOFF_SEC);
+                queued.next_attempt_sec = now_sec + backoff;
+            }
+            false
+        }
+    }
+}
+
+/// Delivers due events. Events are held while their call is in flight; a
+/// reject or timeout counts as a failure against the subscriber.
+async fn process_subscription_queue(limit: usize) {
+    let now_sec = time() / 1_000_000_000;
+    let due: Vec<(u64, Principal, PaymentEvent)> = state_mut(|state| {
+        let Some(registry) = state.subscriptions.as_mut() else {
+            return Vec::new();
+        };
+        let subscribers: BTreeMap<u64, Principal> = registry
+            .subscriptions
+            .iter()
+            .map(|(id, sub)| (*id, sub.subscriber))
+            .collect();
+        registry
+            .queue
+            .iter_mut()
+            .filter(|queued| queued.next_attempt_sec <= now_sec)
+            .take(limit)
+            .filter_map(|queued| {
+                let subscriber = *subscribers.get(&queued.subscription_id)?;
+                queued.next_attempt_sec = now_sec + WEBHOOK_IN_FLIGHT_SEC;
+                Some((queued.subscription_id, subscriber, queued.event.clone()))
+            })
+            .collect()
+    });
+
+    for (subscription_id, subscriber, event) in due {
+        let result = Call::bounded_wait(subscriber, "on_payment_event")
+            .change_timeout(SUBSCRIBER_CALL_TIMEOUT_SEC)
+            .with_arg(&event)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string());
+        let finished_sec = time() / 1_000_000_000;
+        state_mut(|state| {
+            if let Some(registry) = state.subscriptions.as_mut() {
+                let dropped = record_event_attempt(
+                    registry,
+                    subscription_id,
+                    event.log_id,
+                    &event.event,
+                    result,
+                    finished_sec,
+                );
+                if dropped {
+                    record_audit(
+                        state,
+                        "drop_subscription",
+                        format!("{} {}", subscription_id, subscriber),
+                    );
+                }
+            }
+        });
+    }
+}
+
+const MAX_WEBHOOKS_PER_RUN: usize = 10;
+const MAX_WEBHOOK_DELIVERIES: usize = 2_000;
+const WEBHOOK_IN_FLIGHT_SEC: u64 = 300;
+const WEBHOOK_MAX_BACKOFF_SEC: u64 = 3_600;
+const WEBHOOK_RESPONSE_MAX_BYTES: u64 = 2 * 1024;
+
+fn validate_webhook_url(url: &str, allow_local: bool) -> Result<(), String> {
+    let local = ["http://localhost", "http://127.0.0.1"]
+        .iter()
+        .any(|prefix| url.starts_with(prefix));
+    if url.starts_with("https://") || (allow_local && local) {
+        Ok(())
+    } else {
+        Err("webhook url must start with https://".into())
+    }
+}
+
+fn payment_event_name(status: &PaymentStatus) -> Option<&'static str> {
+    match status {
+        PaymentStatus::Broadcasted => Some("payment.broadcasted"),
+        PaymentStatus::Confirmed => Some("payment.confirmed"),
+        PaymentStatus::Failed => Some("payment.failed"),
+        PaymentStatus::Accepted => None,
+    }
+}
+
+fn payment_event_json(log: &PaymentLog, event: &str, now_sec: u64) -> Value {
+    json!({
+        "event": event,
+        "created_at": now_sec,
+        "log_id": log.id,
+        "asset": log.asset.to_text(),
+        "from": log.from,
+        "to": log.to,
+        "value": log.value.0.to_string(),
+        "status": format!("{:?}", log.status),
+        "tx_hash": log.tx_hash,
+        "fail_reason": log.fail_reason,
+        "memo": log.memo.as_ref().map(|memo| match memo {
+            Memo::Text(text) => json!({ "text": text }),
+            Memo::Blob(bytes) => json!({ "hex": to_hex_prefixed(bytes) }),
+        }),
+        "memo_bound": log.memo_bound.unwrap_or(false),
+    })
+}
+
+fn enqueue_webhook(state: &mut RelayerState, log: &PaymentLog, now_sec: u64) {
+    let Some(event) = payment_event_name(&log.status) else {
+        return;
+    };
+    let Some(registry) = state.webhooks.as_mut() else {
+        return;
+    };
+    if !registry.endpoints.contains_key(&log.to) {
+        return;
+    }
+    registry.next_id += 1;
+    registry.deliveries.push(WebhookDelivery {
+        id: registry.next_id,
+        log_id: log.id,
+        recipient: log.to.clone(),
+        event: event.to_string(),
+        payload: payment_event_json(log, event, now_sec).to_string(),
+        status: DeliveryStatus::Pending,
+        attempts: 0,
+        next_attempt_sec: now_sec,
+        last_status_code: None,
+        last_error: None,
+        created_sec: now_sec,
+        delivered_sec: None,
+    });
+    if registry.deliveries.len() > MAX_WEBHOOK_DELIVERIES {
+        // Finished deliveries go first; when everything is still pending the
+        // oldest one is dropped so the log stays bounded.
+        let index = registry
+            .deliveries
+            .iter()
+            .position(|delivery| delivery.status != DeliveryStatus::Pending)
+            .unwrap_or(0);
+        registry.deliveries.remove(index);
+    }
+}
+
+/// `sha256=<hex>` HMAC over `"{timestamp}.{body}"`.
+fn webhook_signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Applies a delivery attempt: 2xx delivers, anything else retries with
+/// exponential backoff until `max_attempts`.
+fn record_webhook_attempt(
+    delivery: &mut WebhookDelivery,
+    config: &WebhookConfig,
+    result: Result<u16, String>,
+    now_sec: u64,
+) {
+    delivery.attempts += 1;
+    match result {
+        Ok(code) if (200..300).contains(&code) => {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.last_status_code = Some(code);
+            delivery.last_error = None;
+            delivery.delivered_sec = Some(now_sec);
+            return;
+        }
+        Ok(code) => {
+            delivery.last_status_code = Some(code);
+            delivery.last_error = Some(format!("HTTP {}", code));
+        }
+        Err(err) => delivery.last_error = Some(err),
+    }
+    if delivery.attempts >= config.max_attempts {
+        delivery.status = DeliveryStatus::Failed;
+    } else {
+        let backoff = config
+            .base_backoff_sec
+            .saturating_mul(1 << (delivery.attempts - 1).min(16))
+            .min(WEBHOOK_MAX_BACKOFF_SEC);
+        delivery.next_attempt_sec = now_sec + backoff;
+    }
+}
+
+async fn process_webhook_queue(limit: usize) -> u32 {
+    let now_sec = time() / 1_000_000_000;
+    let due: Vec<(u64, String, String, String)> = state_mut(|state| {
+        let Some(registry) = state.webhooks.as_mut() else {
+            return Vec::new();
+        };
+        let endpoints = registry.endpoints.clone();
+        registry
+            .deliveries
+            .iter_mut()
+            .filter(|delivery| {
+                delivery.status == DeliveryStatus::Pending && delivery.next_attempt_sec <= now_sec
+            })
+            .take(limit)
+            .filter_map(|delivery| {
+                let endpoint = endpoints.get(&delivery.recipient)?;
+                // Hold the slot so an overlapping run does not resend it.
+                delivery.next_attempt_sec = now_sec + WEBHOOK_IN_FLIGHT_SEC;
+                Some((
+                    delivery.id,
+                    endpoint.url.clone(),
+                    endpoint.secret.clone(),
+                    delivery.payload.clone(),
+                ))
+            })
+            .collect()
+    });
+
+    let mut delivered = 0;
+    for (id, url, secret, payload) in due {
+        let result = post_webhook(&url, &secret, &payload, now_sec).await;
+        let finished_sec = time() / 1_000_000_000;
+        state_mut(|state| {
+            if let Some(registry) = state.webhooks.as_mut() {
+                let config = registry.config.clone();
+                if let Some(delivery) = registry.deliveries.iter_mut().find(|d| d.id == id) {
+                    record_webhook_attempt(delivery, &config, result, finished_sec);
+                    if delivery.status == DeliveryStatus::Delivered {
+                        delivered += 1;
+                    }
+                }
+            }
+        });
+    }
+    delivered
+}
+
+async fn post_webhook(url: &str, secret: &str, payload: &str, now_sec: u64) -> Result<u16, String> {
+    let header = |name: &str, value: String| IcHttpHeader {
+        name: name.to_string(),
+        value,
+    };
+    let request = HttpRequestArgs {
+        url: url.to_string(),
+        method: HttpMethod::POST,
+        body: Some(payload.as_bytes().to_vec()),
+        max_response_bytes: Some(WEBHOOK_RESPONSE_MAX_BYTES),
+        headers: vec![
+            header("Content-Type", "application/json".into()),
+            header("X-Jpycpay-Timestamp", now_sec.to_string()),
+            header(
+                "X-Jpycpay-Signature",
+                webhook_signature(secret, now_sec, payload),
+            ),
+        ],
+        transform: Some(transform_context_from_query(
+            "transform_http".to_string(),
+            vec![],
+        )),
+        is_replicated: Some(false),
+    };
+    let response = perform_http_outcall(&request)
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(response.status.0.to_u64().unwrap_or(0).min(u16::MAX as u64) as u16)
+}
+
+const GLOBAL_SCOPE: &str = "global";
+const PROBE_TIMEOUT_SEC: u64 = 300;
+
+fn breaker_scope(asset: Option<Principal>) -> String {
+    asset
+        .map(|asset| asset.to_text())
+        .unwrap_or_else(|| GLOBAL_SCOPE.to_string())
+}
+
+/// Infrastructure failures the breaker reacts to; client errors are ignored.
+fn error_class(err: &RelayError) -> Option<ErrorClass> {
+    match err {
+        RelayError::SimulationFailed { .. } | RelayError::GasEstimateFailed { .. } => {
+            Some(ErrorClass::Simulation)
+        }
+        RelayError::RpcError { .. }
+        | RelayError::RpcTransportError { .. }
+        | RelayError::RpcResultTypeMismatch { .. }
+        | RelayError::JsonError { .. } => Some(ErrorClass::Rpc),
+        RelayError::GasBalanceLow { .. } | RelayError::NoHealthyWallet => Some(ErrorClass::Gas),
+        _ => None,
+    }
+}
+
+/// Rejects the relay while a circuit is open and hands out half-open probe
+/// slots; returns the scopes this relay is probing.
+fn admit_through_breaker(
+    state: &mut RelayerState,
+    asset: Principal,
+    now_sec: u64,
+) -> InternalResult<Vec<String>> {
+    let Some(breaker) = state.breaker.as_mut().filter(|b| b.config.enabled) else {
+        return Ok(Vec::new());
+    };
+    let cooldown = breaker.config.cooldown_sec;
+    let scopes = [breaker_scope(None), breaker_scope(Some(asset))];
+    let mut probes = Vec::new();
+    for scope in &scopes {
+        let Some(circuit) = breaker.circuits.get(scope) else {
+            continue;
+        };
+        let rejected = match circuit.phase {
+            CircuitPhase::Closed => false,
+            CircuitPhase::Open => now_sec.saturating_sub(circuit.tripped_sec) < cooldown,
+            CircuitPhase::HalfOpen => circuit
+                .probe_started_sec
+                .is_some_and(|started| now_sec.saturating_sub(started) < PROBE_TIMEOUT_SEC),
+        };
+        if rejected {
+            return Err(RelayError::CircuitOpen {
+                scope: scope.clone(),
+                reason: circuit.reason.clone().unwrap_or_default(),
+            });
+        }
+        if circuit.phase != CircuitPhase::Closed {
+            probes.push(scope.clone());
+        }
+    }
+    for scope in &probes {
+        if let Some(circuit) = breaker.circuits.get_mut(scope) {
+            if circuit.phase == CircuitPhase::Open {
+                circuit.phase = CircuitPhase::HalfOpen;
+                circuit.probe_successes = 0;
+            }
+            circuit.probe_started_sec = Some(now_sec);
+        }
+    }
+    Ok(probes)
+}
+
+/// `outcome` is `None` for relays that ended on a client error.
+fn record_breaker_outcome(
+    state: &mut RelayerState,
+    asset: Principal,
+    probes: &[String],
+    outcome: Option<Result<(), ErrorClass>>,
+    now_sec: u64,
+) {
+    let Some(breaker) = state.breaker.as_mut().filter(|b| b.config.enabled) else {
+        return;
+    };
+    let config = breaker.config.clone();
+    for scope in probes {
+        if let Some(circuit) = breaker.circuits.get_mut(scope) {
+            circuit.probe_started_sec = None;
+        }
+    }
+    let Some(outcome) = outcome else {
+        return;
+    };
+    let minute = now_sec / 60;
+    let oldest = now_sec.saturating_sub(config.window_sec) / 60;
+    let scopes = match outcome {
+        Ok(()) => vec![breaker_scope(None), breaker_scope(Some(asset))],
+        Err(ErrorClass::Simulation) => vec![breaker_scope(Some(asset))],
+        Err(_) => vec![breaker_scope(None)],
+    };
+    for scope in scopes {
+        let probing = probes.contains(&scope);
+        let circuit = breaker.circuits.entry(scope).or_default();
+        circuit.buckets.retain(|bucket| bucket.minute >= oldest);
+        if circuit
+            .buckets
+            .last()
+            .is_none_or(|bucket| bucket.minute != minute)
+        {
+            circuit.buckets.push(OutcomeBucket {
+                minute,
+                ..OutcomeBucket::default()
+            });
+        }
+        let bucket = circuit.buckets.last_mut().expect("bucket just ensured");
+        match outcome {
+            Ok(()) => bucket.successes += 1,
+            Err(class) => *bucket.failures.entry(class).or_default() += 1,
+        }
+
+        match (circuit.phase, outcome) {
+            (CircuitPhase::HalfOpen, Ok(())) if probing => {
+                circuit.probe_successes += 1;
+                if circuit.probe_successes >= config.probe_successes {
+                    *circuit = Circuit::default();
+                }
+            }
+            (CircuitPhase::HalfOpen, Err(class)) => {
+                trip_circuit(circuit, format!("probe failed ({:?})", class), now_sec);
+            }
+            (CircuitPhase::Closed, Err(ErrorClass::Gas)) if config.trip_on_low_gas => {
+                trip_circuit(circuit, "relayer gas low".into(), now_sec);
+            }
+            (CircuitPhase::Closed, Err(class)) => {
+                if let Some(rate) = failure_rate_bps(circuit, class, config.min_samples) {
+                    let threshold = config.thresholds_bps.get(&class).copied();
+                    if threshold.is_some_and(|threshold| rate >= threshold as u64) {
+                        let reason = format!(
+                            "{:?} failure rate {}bps over {}s",
+                            class, rate, config.window_sec
+                        );
+                        trip_circuit(circuit, reason, now_sec);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn failure_rate_bps(circuit: &Circuit, class: ErrorClass, min_samples: u32) -> Option<u64> {
+    let mut total = 0u64;
+    let mut failed = 0u64;
+    for bucket in &circuit.buckets {
+        total += bucket.successes as u64 + bucket.failures.values().map(|n| *n as u64).sum::<u64>();
+        failed += bucket.failures.get(&class).copied().unwrap_or(0) as u64;
+    }
+    (total >= min_samples as u64 && total > 0).then(|| failed * 10_000 / total)
+}
+
+fn trip_circuit(circuit: &mut Circuit, reason: String, now_sec: u64) {
+    circuit.phase = CircuitPhase::Open;
+    circuit.reason = Some(reason);
+    circuit.tripped_sec = now_sec;
+    circuit.probe_started_sec = None;
+    circuit.probe_successes = 0;
+}
+
+fn rollout_bucket(from: &[u8]) -> u16 {
+    let digest = keccak256(from);
+    let mut head = [0u8; 8];
+    head.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(head) % 10_000) as u16
+}
+
+fn check_rollout(
+    rollout: Option<&RolloutState>,
+    asset: Principal,
+    from: &[u8],
+    from_hex: &str,
+) -> InternalResult<()> {
+    let Some(rollout) = rollout else {
+        return Ok(());
+    };
+    let mode = rollout.asset_overrides.get(&asset).unwrap_or(&rollout.mode);
+    let allowlisted = rollout.allowlist.contains(from_hex);
+    let admitted = match mode {
+        RolloutMode::Paused => false,
+        RolloutMode::AllowlistOnly => allowlisted,
+        RolloutMode::Percentage { bps } => allowlisted || rollout_bucket(from) < *bps,
+        RolloutMode::Open => true,
+    };
+    if admitted {
+        Ok(())
+    } else {
+        Err(RelayError::RolloutRestricted {
+            mode: format!("{:?}", mode),
+        })
+    }
+}
+
+/// Applies every ramp step that is due and returns them.
+fn advance_rollout(state: &mut RelayerState, now_sec: u64) -> Vec<RampStep> {
+    let Some(rollout) = state.rollout.as_mut() else {
+        return Vec::new();
+    };
+    let (due, pending): (Vec<_>, Vec<_>) = rollout
+        .schedule
+        .drain(..)
+        .partition(|step| step.at_sec <= now_sec);
+    rollout.schedule = pending;
+    for step in &due {
+        match step.asset {
+            Some(asset) => {
+                rollout.asset_overrides.insert(asset, step.mode.clone());
+            }
+            None => rollout.mode = step.mode.clone(),
+        }
+    }
+    due
+}
+
+const KYC_ATTESTATION_DOMAIN: &[u8] = b"jpycpay-kyc-attestation-v2";
+
+/// Binds an attestation to one chain and one relayer canister so it cannot be
+/// replayed against another deployment.
+fn kyc_attestation_digest(
+    chain_id: u64,
+    relayer: Principal,
+    address: &[u8],
+    tier: u8,
+    expires_sec: u64,
+) -> [u8; 32] {
+    let mut message = KYC_ATTESTATION_DOMAIN.to_vec();
+    message.extend_from_slice(&chain_id.to_be_bytes());
+    message.push(relayer.as_slice().len() as u8);
+    message.extend_from_slice(relayer.as_slice());
+    message.extend_from_slice(address);
+    message.push(tier);
+    message.extend_from_slice(&expires_sec.to_be_bytes());
+    keccak256(&message)
+}
+
+enum ParsedAttestorKey {
+    Secp256k1(VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+fn parse_attestor_key(key: &KycAttestorKey) -> InternalResult<ParsedAttestorKey> {
+    let invalid = |message: &str| RelayError::InvalidKycAttestation {
+        message: message.to_string(),
+    };
+    match key {
+        KycAttestorKey::Secp256k1(bytes) => VerifyingKey::from_sec1_bytes(bytes)
+            .map(ParsedAttestorKey::Secp256k1)
+            .map_err(|_| invalid("bad secp256k1 key")),
+        KycAttestorKey::Ed25519(bytes) => {
+            let bytes: [u8; 32] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| invalid("ed25519 key must be 32 bytes"))?;
+            ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+                .map(ParsedAttestorKey::Ed25519)
+                .map_err(|_| invalid("bad ed25519 key"))
+        }
+    }
+}
+
+fn verify_kyc_signature(
+    key: &KycAttestorKey,
+    digest: &[u8; 32],
+    signature: &[u8],
+) -> InternalResult<()> {
+    let invalid = || RelayError::InvalidKycAttestation {
+        message: "signature verification failed".into(),
+    };
+    match parse_attestor_key(key)? {
+        ParsedAttestorKey::Secp256k1(key) => {
+            let rs = match signature.len() {
+                64 | 65 => &signature[..64],
+                _ => return Err(invalid()),
+            };
+            let sig = K256Signature::from_slice(rs).map_err(|_| invalid())?;
+            key.verify_prehash(digest, &sig).map_err(|_| invalid())
+        }
+        ParsedAttestorKey::Ed25519(key) => {
+            let sig = ed25519_dalek::Signature::from_slice(signature).map_err(|_| invalid())?;
+            key.verify_strict(digest, &sig).map_err(|_| invalid())
+        }
+    }
+}
+
+/// Verifies an attached attestation (caching it), then enforces the KYC
+/// threshold and tier limit for this relay.
+fn check_kyc(
+    state: &mut RelayerState,
+    relayer: Principal,
+    req: &SubmitAuthorizationRequest,
+    from_hex: &str,
+    decimals: u8,
+    now_sec: u64,
+) -> InternalResult<()> {
+    let chain_id = state.config.chain_id.as_ref().map(nat_to_u64);
+    let Some(kyc) = state.kyc.as_mut() else {
+        return Ok(());
+    };
+    if let Some(attestation) = &req.kyc {
+        if attestation.expires_sec <= now_sec {
+            return Err(RelayError::InvalidKycAttestation {
+                message: "attestation expired".into(),
+            });
+        }
+        let key = kyc.attestors.get(&attestation.attestor).ok_or_else(|| {
+            RelayError::InvalidKycAttestation {
+                message: "unknown attestor".into(),
+            }
+        })?;
+        let chain_id = chain_id.ok_or(RelayError::ConfigurationMissing {
+            field: "chain_id".into(),
+        })??;
+        let digest = kyc_attestation_digest(
+            chain_id,
+            relayer,
+            &req.from,
+            attestation.tier,
+            attestation.expires_sec,
+        );
+        verify_kyc_signature(key, &digest, &attestation.signature)?;
+        kyc.verified.insert(
+            from_hex.to_string(),
+            VerifiedKyc {
+                attestor: attestation.attestor.clone(),
+                tier: attestation.tier,
+                expires_sec: attestation.expires_sec,
+                verified_sec: now_sec,
+            },
+        );
+    }
+
+    let Some(policy) = &kyc.policy else {
+        return Ok(());
+    };
+    let Some(threshold) = tokens_in_smallest_unit(policy.threshold_tokens, decimals) else {
+        return Ok(());
+    };
+    if req.value <= threshold {
+        return Ok(());
+    }
+    let tier = kyc
+        .verified
+        .get(from_hex)
+        .filter(|verified| verified.expires_sec > now_sec)
+        .map(|verified| verified.tier)
+        .ok_or(RelayError::KycRequired {
+            threshold: threshold.clone(),
+        })?;
+    let max = policy
+        .tier_max_tokens
+        .get(&tier)
+        .and_then(|tokens| tokens_in_smallest_unit(*tokens, decimals))
+        .unwrap_or(threshold);
+    if req.value > max {
+        return Err(RelayError::KycTierLimitExceeded { tier, max });
+    }
+    Ok(())
+}
+
+fn screening_list(
+    screening: &ScreeningState,
+    list: ScreeningList,
+) -> &BTreeMap<String, ScreeningEntry> {
+    match list {
+        ScreeningList::DenyFrom => &screening.deny_from,
+        ScreeningList::DenyTo => &screening.deny_to,
+        ScreeningList::AllowFrom => &screening.allow_from,
+        ScreeningList::AllowTo => &screening.allow_to,
+    }
+}
+
+fn screening_list_mut(
+    state: &mut RelayerState,
+    list: ScreeningList,
+) -> &mut BTreeMap<String, ScreeningEntry> {
+    let screening = state.screening.get_or_insert_with(ScreeningState::default);
+    match list {
+        ScreeningList::DenyFrom => &mut screening.deny_from,
+        ScreeningList::DenyTo => &mut screening.deny_to,
+        ScreeningList::AllowFrom => &mut screening.allow_from,
+        ScreeningList::AllowTo => &mut screening.allow_to,
+    }
+}
+
+const MAX_SCREENING_IMPORT: usize = 10_000;
+
+fn insert_screening_entries(
+    state: &mut RelayerState,
+    list: ScreeningList,
+    entries: Vec<ScreeningEntryInput>,
+    now_sec: u64,
+) -> InternalResult<u32> {
+    if entries.len() > MAX_SCREENING_IMPORT {
+        return Err(RelayError::NumberOutOfRange {
+            field: "screening entries".into(),
+        });
+    }
+    let normalized = entries
+        .into_iter()
+        .map(|input| {
+            let address = normalize_evm_address(&input.address)?;
+            Ok((
+                address,
+                ScreeningEntry {
+                    reason: input.reason,
+                    expires_sec: input.expires_sec,
+                    added_sec: now_sec,
+                },
+            ))
+        })
+        .collect::<InternalResult<Vec<_>>>()?;
+    let count = normalized.len() as u32;
+    screening_list_mut(state, list).extend(normalized);
+    Ok(count)
+}
+
+fn parse_screening_import(
+    format: ImportFormat,
+    data: &str,
+) -> InternalResult<Vec<ScreeningEntryInput>> {
+    match format {
+        ImportFormat::Json => serde_json::from_str(data).map_err(|err| RelayError::JsonError {
+            message: err.to_string(),
+        }),
+        ImportFormat::Csv => {
+            data.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter(|line| !line.to_ascii_lowercase().starts_with("address"))
+                .map(|line| {
+                    let mut fields = line.split(',').map(str::trim);
+                    let address = fields.next().unwrap_or_default().to_string();
+                    let reason = fields.next().unwrap_or_default().to_string();
+                    let expires_sec = match fields.next().filter(|field| !field.is_empty()) {
+                        Some(field) => Some(field.parse::<u64>().map_err(|_| {
+                            RelayError::NumberOutOfRange {
+                                field: format!("expires_sec for {}", address),
+                            }
+                        })?),
+                        None => None,
+                    };
+                    Ok(ScreeningEntryInput {
+                        address,
+                        reason,
+                        expires_sec,
+                    })
+                })
+                .collect()
+        }
+    }
+}
+
+fn active_entry<'a>(
+    list: &'a BTreeMap<String, ScreeningEntry>,
+    address: &str,
+    now_sec: u64,
+) -> Option<&'a ScreeningEntry> {
+    list.get(address)
+        .filter(|entry| entry.expires_sec.is_none_or(|expiry| expiry > now_sec))
+}
+
+/// Checks `from`/`to` against the deny and (if enforced) allow lists.
+fn screen_addresses(
+    screening: Option<&ScreeningState>,
+    from: &str,
+    to: &str,
+    now_sec: u64,
+) -> InternalResult<()> {
+    let Some(screening) = screening else {
+        return Ok(());
+    };
+    let sides = [
+        (
+            "from",
+            from,
+            &screening.deny_from,
+            &screening.allow_from,
+            screening.enforce_allow_from,
+        ),
+        (
+            "to",
+            to,
+            &screening.deny_to,
+            &screening.allow_to,
+            screening.enforce_allow_to,
+        ),
+    ];
+    for (side, address, deny, allow, enforce_allow) in sides {
+        if let Some(entry) = active_entry(deny, address, now_sec) {
+            return Err(RelayError::AddressScreened {
+                side: side.into(),
+                reason: entry.reason.clone(),
+            });
+        }
+        if enforce_allow && active_entry(allow, address, now_sec).is_none() {
+            return Err(RelayError::AddressScreened {
+                side: side.into(),
+                reason: "not allowlisted".into(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn prune_expired_screening(state: &mut RelayerState, now_sec: u64) {
+    if let Some(screening) = state.screening.as_mut() {
+        for list in [
+            &mut screening.deny_from,
+            &mut screening.deny_to,
+            &mut screening.allow_from,
+            &mut screening.allow_to,
+        ] {
+            list.retain(|_, entry| entry.expires_sec.is_none_or(|expiry| expiry > now_sec));
+        }
+    }
+}
+
+const DEFAULT_MAX_TRACKED_KEYS: u64 = 50_000;
+
+/// Fails closed when admitting a request would push the number of tracked
+/// limiter keys past the cap, after first trying an eviction pass.
+fn ensure_tracking_capacity(
+    state: &mut RelayerState,
+    limits: &[(String, BucketLimit)],
+    daily_keys: &[String],
+    now_sec: u64,
+) -> InternalResult<()> {
+    for attempt in 0..2 {
+        let limiter = limiter_mut(state);
+        let max = limiter
+            .gc
+            .get_or_insert_with(RateLimitGc::default)
+            .max_tracked_keys;
+        let mut tracked = limiter.buckets.len() as u64;
+        let mut needed = limits
+            .iter()
+            .filter(|(key, _)| !limiter.buckets.contains_key(key))
+            .count() as u64;
+        tracked += state.rate_state.daily_counter.len() as u64;
+        needed += daily_keys
+            .iter()
+            .filter(|key| !state.rate_state.daily_counter.contains_key(*key))
+            .count() as u64;
+        if tracked + needed <= max {
+            return Ok(());
+        }
+        if attempt == 0 {
+            gc_rate_limit_state(state, now_sec);
+        }
+    }
+    Err(RelayError::RateLimited {
+        scope: "tracking_capacity".into(),
+    })
+}
+
+/// Drops full token buckets, buckets for scopes that are no longer limited
+/// and daily counters from past windows.
+fn gc_rate_limit_state(state: &mut RelayerState, now_sec: u64) {
+    let day = now_sec / 86_400;
+    let daily_before = state.rate_state.daily_counter.len();
+    state
+        .rate_state
+        .daily_counter
+        .retain(|_, counter| counter.window_start_sec == day);
+    let mut evicted = (daily_before - state.rate_state.daily_counter.len()) as u64;
+
+    let limiter = limiter_mut(state);
+    let config = limiter.config.clone();
+    let buckets_before = limiter.buckets.len();
+    limiter.buckets.retain(
+        |key, bucket| match scope_limit(&config, bucket_scope(key)) {
+            Some(limit) => {
+                refill_bucket(bucket, &limit, now_sec);
+                bucket.tokens_milli < limit.capacity as u64 * TOKEN_MILLI
+            }
+            None => false,
+        },
+    );
+    evicted += (buckets_before - limiter.buckets.len()) as u64;
+
+    let gc = limiter.gc.get_or_insert_with(RateLimitGc::default);
+    gc.evicted_total += evicted;
+    gc.last_evicted = evicted;
+    gc.last_run_sec = now_sec;
+}
+
+fn refund_quota(state: &mut RelayerState, quota: &QuotaRefund) {
+    if let Some(limiter) = state.limiter.as_mut() {
+        for (key, limit) in &quota.buckets {
+            if let Some(bucket) = limiter.buckets.get_mut(key) {
+                bucket.tokens_milli =
+                    (bucket.tokens_milli + TOKEN_MILLI).min(limit.capacity as u64 * TOKEN_MILLI);
+            }
+        }
+    }
+    for key in &quota.daily_keys {
+        if let Some(counter) = state.rate_state.daily_counter.get_mut(key) {
+            if counter.window_start_sec == quota.day && counter.amount >= quota.amount {
+                counter.amount = counter.amount.clone() - quota.amount.clone();
+                counter.hits = counter.hits.saturating_sub(1);
+            }
+        }
+    }
+}
+
+fn trim_leading_zeroes(data: &[u8]) -> Vec<u8> {
+    let mut index = 0;
+    while index < data.len() && data[index] == 0 {
+        index += 1;
+    }
+    if index >= data.len() {
+        Vec::new()
+    } else {
+        data[index..].to_vec()
+    }
+}
+
+fn nat_to_be_bytes(value: &Nat) -> Vec<u8> {
+    let bytes = value.0.to_bytes_be();
+    trim_leading_zeroes(&bytes)
+}
+
+fn length_to_bytes(len: usize) -> Vec<u8> {
+    let mut value = len;
+    let mut bytes = Vec::new();
+    while value > 0 {
+        bytes.push((value & 0xff) as u8);
+        value >>= 8;
+    }
+    bytes.reverse();
+    if bytes.is_empty() {
+        vec![0]
+    } else {
+        bytes
+    }
+}
+
+fn rlp_encode_bytes(data: &[u8]) -> Vec<u8> {
+    match data.len() {
+        0 => vec![0x80],
+        1 if data[0] < 0x80 => vec![data[0]],
+        len if len <= 55 => {
+            let mut out = Vec::with_capacity(1 + len);
+            out.push(0x80 + len as u8);
+            out.extend_from_slice(data);
+            out
+        }
+        len => {
+            let len_bytes = length_to_bytes(len);
+            let mut out = Vec::with_capacity(1 + len_bytes.len() + len);
+            out.push(0xB7 + len_bytes.len() as u8);
+            out.extend_from_slice(&len_bytes);
+            out.extend_from_slice(data);
+            out
+        }
+    }
+}
+
+fn rlp_encode_nat_value(value: &Nat) -> Vec<u8> {
+    let bytes = nat_to_be_bytes(value);
+    rlp_encode_bytes(&bytes)
+}
+
+fn rlp_encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let total_len: usize = items.iter().map(|item| item.len()).sum();
+    if total_len <= 55 {
+        let mut out = Vec::with_capacity(1 + total_len);
+        out.push(0xC0 + total_len as u8);
+        for item in items {
+            out.extend_from_slice(item);
+        }
+        out
+    } else {
+        let len_bytes = length_to_bytes(total_len);
+        let mut out = Vec::with_capacity(1 + len_bytes.len() + total_len);
+        out.push(0xF7 + len_bytes.len() as u8);
+        out.extend_from_slice(&len_bytes);
+        for item in items {
+            out.extend_from_slice(item);
+        }
+        out
+    }
+}
+
+const KECCAKF_ROUND_CONSTANTS: [u64; 24] = [
+    0x0000000000000001,
+    0x0000000000008082,
+    0x800000000000808a,
+    0x8000000080008000,
+    0x000000000000808b,
+    0x0000000080000001,
+    0x8000000080008081,
+    0x8000000000008009,
+    0x000000000000008a,
+    0x0000000000000088,
+    0x0000000080008009,
+    0x000000008000000a,
+    0x000000008000808b,
+    0x800000000000008b,
+    0x8000000000008089,
+    0x8000000000008003,
+    0x8000000000008002,
+    0x8000000000000080,
+    0x000000000000800a,
+    0x800000008000000a,
+    0x8000000080008081,
+    0x8000000000008080,
+    0x0000000080000001,
+    0x8000000080008008,
+];
+
+const KECCAKF_ROTATION: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+
+const KECCAKF_PERMUTATION: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+fn keccak_f1600(state: &mut [u64; 25]) {
+    let mut bc = [0u64; 5];
+    for &rc in KECCAKF_ROUND_CONSTANTS.iter() {
+        for i in 0..5 {
+            bc[i] = state[i] ^ state[i + 5] ^ state[i + 10] ^ state[i + 15] ^ state[i + 20];
+        }
+        for i in 0..5 {
+            let t = bc[(i + 4) % 5] ^ bc[(i + 1) % 5].rotate_left(1);
+            for j in (0..25).step_by(5) {
+                state[i + j] ^= t;
+            }
+        }
+        let mut t = state[1];
+        for i in 0..24 {
+            let j = KECCAKF_PERMUTATION[i];
+            let tmp = state[j];
+            state[j] = t.rotate_left(KECCAKF_ROTATION[i]);
+            t = tmp;
+        }
+        for j in (0..25).step_by(5) {
+            bc.copy_from_slice(&state[j..j + 5]);
+            for i in 0..5 {
+                state[j + i] ^= (!bc[(i + 1) % 5]) & bc[(i + 2) % 5];
+            }
+        }
+        state[0] ^= rc;
+    }
+}
+
+fn absorb_block(state: &mut [u64; 25], block: &[u8]) {
+    for (i, chunk) in block.chunks(8).enumerate() {
+        let mut lane_bytes = [0u8; 8];
+        lane_bytes[..chunk.len()].copy_from_slice(chunk);
+        state[i] ^= u64::from_le_bytes(lane_bytes);
+    }
+}
+
+fn state_to_bytes(state: &[u64; 25]) -> [u8; 200] {
+    let mut out = [0u8; 200];
+    for (i, lane) in state.iter().enumerate() {
+        out[i * 8..(i + 1) * 8].copy_from_slice(&lane.to_le_bytes());
+    }
+    out
+}
+
+fn keccak256(input: &[u8]) -> [u8; 32] {
+    const RATE: usize = 136;
+    let mut state = [0u64; 25];
+    let mut offset = 0;
+    while offset + RATE <= input.len() {
+        absorb_block(&mut state, &input[offset..offset + RATE]);
+        keccak_f1600(&mut state);
+        offset += RATE;
+    }
+
+    let mut block = [0u8; RATE];
+    let remainder = &input[offset..];
+    block[..remainder.len()].copy_from_slice(remainder);
+    block[remainder.len()] = 0x01;
+    block[RATE - 1] |= 0x80;
+    absorb_block(&mut state, &block);
+    keccak_f1600(&mut state);
+
+    let mut output = [0u8; 32];
+    let mut out_offset = 0;
+    loop {
+        let state_bytes = state_to_bytes(&state);
+        let take = std::cmp::min(32 - out_offset, RATE);
+        output[out_offset..out_offset + take].copy_from_slice(&state_bytes[..take]);
+        out_offset += take;
+        if out_offset >= 32 {
+            break;
+        }
+        keccak_f1600(&mut state);
+    }
+    output
+}
+
+fn function_selector(signature: &str) -> [u8; 4] {
+    let hash = keccak256(signature.as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+fn encode_erc20_transfer_call(to: &[u8; 20], amount: &Nat) -> InternalResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(4 + 32 * 2);
+    data.extend_from_slice(&function_selector("transfer(address,uint256)"));
+    data.extend_from_slice(&pad_left(to, 32));
+    data.extend_from_slice(&encode_uint_nat(amount)?);
+    Ok(data)
+}
+
+/// `aggregate3((address,bool,bytes)[])` with `allowFailure = false`, so any
+/// failing call reverts the whole batch.
+fn encode_multicall3_aggregate3(calls: &[([u8; 20], &[u8])]) -> Vec<u8> {
+    let encoded_calls: Vec<Vec<u8>> = calls
+        .iter()
+        .map(|(target, data)| {
+            let mut tuple = Vec::with_capacity(32 * 4 + data.len() + 32);
+            tuple.extend_from_slice(&pad_left(target, 32));
+            tuple.extend_from_slice(&encode_uint_u8(0)); // allowFailure
+            tuple.extend_from_slice(&pad_left(&(32u64 * 3).to_be_bytes(), 32));
+            tuple.extend_from_slice(&pad_left(&(data.len() as u64).to_be_bytes(), 32));
+            tuple.extend_from_slice(data);
+            let padding = (32 - data.len() % 32) % 32;
+            tuple.extend(std::iter::repeat_n(0u8, padding));
+            tuple
+        })
+        .collect();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&function_selector("aggregate3((address,bool,bytes)[])"));
+    out.extend_from_slice(&pad_left(&32u64.to_be_bytes(), 32));
+    out.extend_from_slice(&pad_left(&(calls.len() as u64).to_be_bytes(), 32));
+    let mut offset = 32 * calls.len() as u64;
+    for tuple in &encoded_calls {
+        out.extend_from_slice(&pad_left(&offset.to_be_bytes(), 32));
+        offset += tuple.len() as u64;
+    }
+    for tuple in encoded_calls {
+        out.extend_from_slice(&tuple);
+    }
+    out
+}
+
+fn encode_authorization_state_call(from: &[u8], nonce: &[u8]) -> InternalResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(4 + 32 * 2);
+    let selector = function_selector("authorizationState(address,bytes32)");
+    data.extend_from_slice(&selector);
+    data.extend_from_slice(&pad_left(from, 32));
+    data.extend_from_slice(&encode_bytes32(nonce)?);
+    Ok(data)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_transfer_with_authorization_call(
+    from: &[u8],
+    to: &[u8],
+    value: &Nat,
+    valid_after: &Nat,
+    valid_before: &Nat,
+    nonce: &[u8],
+    sig_v: u8,
+    sig_r: &[u8],
+    sig_s: &[u8],
+) -> InternalResult<Vec<u8>> {
+    if sig_r.len() != 32 {
+        return Err(RelayError::InvalidSignatureLength {
+            field: "sig_r".into(),
+            expected: 32,
+            actual: sig_r.len(),
+        });
+    }
+    if sig_s.len() != 32 {
+        return Err(RelayError::InvalidSignatureLength {
+            field: "sig_s".into(),
+            expected: 32,
+            actual: sig_s.len(),
+        });
+    }
+    let mut data = Vec::with_capacity(4 + 32 * 9);
+    let selector = function_selector("transferWithAuthorization(address,address,uint256,uint256,uint256,bytes32,uint8,bytes32,bytes32)");
+    data.extend_from_slice(&selector);
+    data.extend_from_slice(&pad_left(from, 32));
+    data.extend_from_slice(&pad_left(to, 32));
+    data.extend_from_slice(&encode_uint_nat(value)?);
+    data.extend_from_slice(&encode_uint_nat(valid_after)?);
+    data.extend_from_slice(&encode_uint_nat(valid_before)?);
+    data.extend_from_slice(&encode_bytes32(nonce)?);
+    data.extend_from_slice(&encode_uint_u8(sig_v));
+    data.extend_from_slice(sig_r);
+    data.extend_from_slice(sig_s);
+    Ok(data)
+}
+
+fn evm_address_bytes(address: &str) -> InternalResult<[u8; 20]> {
+    let bytes = parse_hex_bytes(address)?;
+    if bytes.len() != 20 {
+        return Err(RelayError::InvalidAddressLength {
+            field: "evm_address".into(),
+            expected: 20,
+            actual: bytes.len(),
+        });
+    }
+    let mut out = [0u8; 20];
+    out.copy_from_slice(&bytes);
+    Ok(out)
+}
+
+/// Unsigned EIP-1559 (type 0x02) transaction with an empty access list.
+struct Eip1559Tx {
+    chain_id: Nat,
+    nonce: Nat,
+    max_priority_fee_per_gas: Nat,
+    max_fee_per_gas: Nat,
+    gas_limit: Nat,
+    to: [u8; 20],
+    value: Nat,
+    data: Vec<u8>,
+}
+
+impl Eip1559Tx {
+    fn rlp_items(&self) -> Vec<Vec<u8>> {
+        vec![
+            rlp_encode_nat_value(&self.chain_id),
+            rlp_encode_nat_value(&self.nonce),
+            rlp_encode_nat_value(&self.max_priority_fee_per_gas),
+            rlp_encode_nat_value(&self.max_fee_per_gas),
+            rlp_encode_nat_value(&self.gas_limit),
+            rlp_encode_bytes(&self.to),
+            rlp_encode_nat_value(&self.value),
+            rlp_encode_bytes(&self.data),
+            rlp_encode_list(&[]), // access list
+        ]
+    }
+
+    fn signing_hash(&self) -> [u8; 32] {
+        let unsigned_rlp = rlp_encode_list(&self.rlp_items());
+        let mut signing_payload = Vec::with_capacity(1 + unsigned_rlp.len());
+        signing_payload.push(0x02);
+        signing_payload.extend_from_slice(&unsigned_rlp);
+        keccak256(&signing_payload)
+    }
+}
+
+struct FeeParams {
+    base_fee_per_gas: Nat,
+    max_priority_fee_per_gas: Nat,
+    max_fee_per_gas: Nat,
+}
+
+/// Signs `tx` with the tECDSA key at `derivation_path` and returns the raw
+/// typed transaction ready for `eth_sendRawTransaction`.
+async fn sign_eip1559_transaction(
+    tx: &Eip1559Tx,
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+    signer: &[u8; 20],
+) -> InternalResult<Vec<u8>> {
+    let sighash = tx.signing_hash();
+    let signature = sign_prehashed_message(key_name, derivation_path, &sighash, signer).await?;
+
+    let mut signed_items = tx.rlp_items();
+    signed_items.push(rlp_encode_nat_value(&Nat::from(signature.y_parity as u64)));
+    signed_items.push(rlp_encode_bytes(&signature.r));
+    signed_items.push(rlp_encode_bytes(&signature.s));
+
+    let signed_rlp = rlp_encode_list(&signed_items);
+    let mut raw_tx = Vec::with_capacity(1 + signed_rlp.len());
+    raw_tx.push(0x02);
+    raw_tx.extend_from_slice(&signed_rlp);
+    Ok(raw_tx)
+}
+
+/// Current base fee and priority fee scaled by the configured multipliers.
+async fn fetch_fee_params(
+    chain_id: u64,
+    priority_multiplier: f64,
+    max_fee_multiplier: f64,
+) -> InternalResult<FeeParams> {
+    let base_fee = fetch_base_fee(chain_id).await?;
+    let priority_fee = fetch_max_priority_fee(chain_id).await?;
+
+    let scaled_priority = scale_nat(&priority_fee, priority_multiplier)?;
+    let priority_fee_effective = if scaled_priority < priority_fee {
+        priority_fee
+    } else {
+        scaled_priority
+    };
+    let priority_fee_effective = if priority_fee_effective == 0u64 {
+        Nat::from(1_000_000_000u64)
+    } else {
+        priority_fee_effective
+    };
+
+    let scaled_base = scale_nat(&base_fee, max_fee_multiplier)?;
+    let base_fee_scaled = if scaled_base < base_fee {
+        base_fee.clone()
+    } else {
+        scaled_base
+    };
+
+    Ok(FeeParams {
+        base_fee_per_gas: base_fee,
+        max_fee_per_gas: base_fee_scaled + priority_fee_effective.clone(),
+        max_priority_fee_per_gas: priority_fee_effective,
+    })
+}
+
+async fn sign_prehashed_message(
+    key_name: &str,
+    derivation_path: &[Vec<u8>],
+    message_hash: &[u8; 32],
+    expected_address: &[u8; 20],
+) -> InternalResult<SignatureParts> {
+    let arg = SignWithEcdsaArgs {
+        message_hash: message_hash.to_vec(),
+        derivation_path: derivation_path.to_vec(),
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: key_name.to_string(),
+        },
+    };
+
+    charge_cycles(ic_cdk::management_canister::cost_sign_with_ecdsa(&arg).unwrap_or(0));
+    let SignWithEcdsaResult { signature } =
+        sign_with_ecdsa(&arg)
+            .await
+            .map_err(|err| RelayError::RpcTransportError {
+                code: "sign_with_ecdsa".into(),
+                message: err.to_string(),
+            })?;
+    let (r_bytes, s_bytes, y_parity) = match signature.len() {
+        65 => (
+            signature[0..32].to_vec(),
+            signature[32..64].to_vec(),
+            signature[64],
+        ),
+        64 => {
+            let mut rs = [0u8; 64];
+            rs.copy_from_slice(&signature);
+            let y = derive_y_parity(message_hash, &rs, expected_address)?;
+            (rs[0..32].to_vec(), rs[32..64].to_vec(), y)
+        }
+        actual => {
+            return Err(RelayError::InvalidSignatureLength {
+                field: "signature".into(),
+                expected: 64,
+                actual,
+            });
+        }
+    };
+
+    let mut r = trim_leading_zeroes(&r_bytes);
+    if r.is_empty() {
+        r.push(0);
+    }
+    let mut s = trim_leading_zeroes(&s_bytes);
+    if s.is_empty() {
+        s.push(0);
+    }
+
+    Ok(SignatureParts { y_parity, r, s })
+}
+
+fn derive_y_parity(
+    message_hash: &[u8; 32],
+    signature_rs: &[u8; 64],
+    expected_address: &[u8; 20],
+) -> InternalResult<u8> {
+    let sig = K256Signature::try_from(signature_rs.as_slice()).map_err(|err| {
+        RelayError::SignatureRecoveryFailed {
+            message: format!("invalid signature bytes: {}", err),
+        }
+    })?;
+
+    for recovery_byte in 0u8..=3u8 {
+        let recovery_id = RecoveryId::try_from(recovery_byte).map_err(|err| {
+            RelayError::SignatureRecoveryFailed {
+                message: format!("invalid recovery id candidate {}: {}", recovery_byte, err),
+            }
+        })?;
+
+        if let Ok(verifying_key) =
+            VerifyingKey::recover_from_prehash(message_hash, &sig, recovery_id)
+        {
+            let encoded = verifying_key.to_encoded_point(false);
+            let pubkey_bytes = encoded.as_bytes();
+            if pubkey_bytes.len() != 65 {
+                continue;
+            }
+            let hashed = keccak256(&pubkey_bytes[1..]);
+            if &hashed[12..] == expected_address.as_ref() {
+                return Ok(u8::from(recovery_id.is_y_odd()));
+            }
+        }
+    }
+
+    Err(RelayError::SignatureRecoveryFailed {
+        message: "no recovery id produced expected relayer address".into(),
+    })
+}
+
+async fn ensure_authorization_unused(
+    chain_id: u64,
+    asset_address: &str,
+    from: &[u8],
+    nonce: &[u8],
+) -> InternalResult<()> {
+    let data = encode_authorization_state_call(from, nonce)?;
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_call",
+        "params": [
+            {
+                "to": asset_address,
+                "data": to_hex_prefixed(&data),
+            },
+            "latest"
+        ],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
+        expected: "hex string",
+    })?;
+    let bytes = parse_hex_bytes(hex)?;
+    let used = decode_bool_abi(&bytes)?;
+    if used {
+        Err(RelayError::AuthorizationAlreadyUsed)
+    } else {
+        Ok(())
+    }
+}
+
+async fn simulate_transfer_call(
+    chain_id: u64,
+    asset_address: &str,
+    from_address: &str,
+    call_data: &[u8],
+) -> InternalResult<()> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_call",
+        "params": [
+            {
+                "from": from_address,
+                "to": asset_address,
+                "data": to_hex_prefixed(call_data),
+            },
+            "latest"
+        ],
+    });
+    match rpc_request(chain_id, payload).await {
+        Ok(_) => Ok(()),
+        Err(RelayError::RpcError { message, .. }) => Err(RelayError::SimulationFailed { message }),
+        Err(other) => Err(other),
+    }
+}
+
+async fn estimate_gas(
+    chain_id: u64,
+    asset_address: &str,
+    from_address: &str,
+    call_data: &[u8],
+) -> InternalResult<Nat> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_estimateGas",
+        "params": [
+            {
+                "from": from_address,
+                "to": asset_address,
+                "data": to_hex_prefixed(call_data),
+            }
+        ],
+    });
+    match rpc_request(chain_id, payload).await {
+        Ok(value) => {
+            let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
+                expected: "hex string",
+            })?;
+            nat_from_hex(hex).map_err(|err| match err {
+                RelayError::HexDecodeFailed { value } => {
+                    RelayError::GasEstimateFailed { message: value }
+                }
+                other => other,
+            })
+        }
+        Err(RelayError::RpcError { message, .. }) => Err(RelayError::GasEstimateFailed { message }),
+        Err(other) => Err(other),
+    }
+}
+
+async fn fetch_balance(chain_id: u64, address: &str) -> InternalResult<Nat> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_getBalance",
+        "params": [address, "latest"],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
+        expected: "hex string",
+    })?;
+    nat_from_hex_with_zero_default(hex)
+}
+
+async fn fetch_nonce(chain_id: u64, address: &str) -> InternalResult<Nat> {
+    fetch_transaction_count(chain_id, address, "pending").await
+}
+
+async fn fetch_transaction_count(
+    chain_id: u64,
+    address: &str,
+    block_tag: &str,
+) -> InternalResult<Nat> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_getTransactionCount",
+        "params": [address, block_tag],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
+        expected: "hex string",
+    })?;
+    nat_from_hex_with_zero_default(hex)
+}
+
+async fn fetch_max_priority_fee(chain_id: u64) -> InternalResult<Nat> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_maxPriorityFeePerGas",
+        "params": [],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    let hex = value.as_str().ok_or(RelayError::RpcResultTypeMismatch {
+        expected: "hex string",
+    })?;
+    nat_from_hex_with_zero_default(hex)
+}
+
+async fn fetch_base_fee(chain_id: u64) -> InternalResult<Nat> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_getBlockByNumber",
+        "params": ["latest", false],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    match value {
+        Value::Object(map) => {
+            if let Some(base_fee) = map.get("baseFeePerGas").and_then(Value::as_str) {
+                nat_from_hex_with_zero_default(base_fee)
+            } else {
+                Err(RelayError::RpcResultTypeMismatch {
+                    expected: "baseFeePerGas",
+                })
+            }
+        }
+        _ => Err(RelayError::RpcResultTypeMismatch {
+            expected: "block object",
+        }),
+    }
+}
+
+/// `None` while the transaction is still pending, otherwise whether it
+/// succeeded (`status == 0x1`).
+async fn fetch_transaction_receipt(chain_id: u64, tx_hash: &str) -> InternalResult<Option<bool>> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_getTransactionReceipt",
+        "params": [tx_hash],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    match value {
+        Value::Null => Ok(None),
+        Value::Object(map) => {
+            let status = map.get("status").and_then(Value::as_str).ok_or(
+                RelayError::RpcResultTypeMismatch {
+                    expected: "receipt status",
+                },
+            )?;
+            Ok(Some(nat_from_hex_with_zero_default(status)? == 1u64))
+        }
+        _ => Err(RelayError::RpcResultTypeMismatch {
+            expected: "receipt object",
+        }),
+    }
+}
+
+async fn send_raw_transaction(chain_id: u64, raw_tx: &[u8]) -> InternalResult<String> {
+    let payload = json!({
+        "jsonrpc": "2.0",
+        "id": next_json_rpc_id(),
+        "method": "eth_sendRawTransaction",
+        "params": [to_hex_prefixed(raw_tx)],
+    });
+    let value = rpc_request(chain_id, payload).await?;
+    value
+        .as_str()
+        .map(str::to_string)
+        .ok_or(RelayError::RpcResultTypeMismatch {
+            expected: "transaction hash",
+        })
+}
+
+fn next_json_rpc_id() -> u64 {
+    JSON_RPC_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn get_rpc_endpoint() -> InternalResult<String> {
+    state_ref(|state| state.config.rpc_endpoint.clone()).ok_or(RelayError::ConfigurationMissing {
+        field: "rpc_endpoint".into(),
+    })
+}
+
+async fn rpc_request(_chain_id: u64, payload: Value) -> InternalResult<Value> {
+    let endpoint = get_rpc_endpoint()?;
+    let payload_str = serde_json::to_string(&payload).map_err(|err| RelayError::JsonError {
+        message: err.to_string(),
+    })?;
+    let body_bytes = payload_str.into_bytes();
+
+    let headers = vec![
+        IcHttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        IcHttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: format!("relayer-{}", next_json_rpc_id()),
+        },
+    ];
+
+    let request = HttpRequestArgs {
+        url: endpoint,
+        method: HttpMethod::POST,
+        body: Some(body_bytes),
+        max_response_bytes: Some(RPC_RESPONSE_MAX_BYTES),
+        headers,
+        transform: Some(transform_context_from_query(
+            "transform_http".to_string(),
+            vec![],
+        )),
+        is_replicated: Some(false),
+    };
+
+    let result = perform_json_outcall(request).await;
+    let now_sec = time() / 1_000_000_000;
+    state_mut(|state| {
+        let health = state.rpc_health.get_or_insert_with(RpcHealth::default);
+        if result.is_ok() {
+            health.last_success_sec = Some(now_sec);
+            health.consecutive_failures = 0;
+        } else {
+            health.last_failure_sec = Some(now_sec);
+            health.consecutive_failures += 1;
+        }
+    });
+    let value = result?;
+
+    if let Some(error) = value.get("error") {
+        let code = error.get("code").and_then(Value::as_i64).unwrap_or(-32_000);
+        let message = error
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown error")
+            .to_string();
+        return Err(RelayError::RpcError { code, message });
+    }
+
+    value
+        .get("result")
+        .cloned()
+        .ok_or(RelayError::RpcResultTypeMismatch { expected: "result" })
+}
+
+async fn perform_http_outcall(request: &HttpRequestArgs) -> InternalResult<HttpRequestResult> {
+    let cycles = ic_cdk::management_canister::cost_http_request(request);
+    charge_cycles(cycles);
+    Call::unbounded_wait(Principal::management_canister(), "http_request")
+        .with_arg(request)
+        .with_cycles(cycles)
+        .await
+        .map_err(|err| RelayError::RpcTransportError {
+            code: format!("{:?}", err),
+            message: err.to_string(),
+        })?
+        .candid()
+        .map_err(|err| RelayError::RpcTransportError {
+            code: "CandidDecode".into(),
+            message: err.to_string(),
+        })
+}
+
+/// Sends `request` through the management canister and parses a 200 JSON
+/// response body.
+async fn perform_json_outcall(request: HttpRequestArgs) -> InternalResult<Value> {
+    let response = perform_http_outcall(&request).await?;
+
+    let status = response.status.0.to_u64().unwrap_or(0);
+
+    if status != 200 {
+        let body_text = String::from_utf8(response.body.clone())
+            .unwrap_or_else(|_| "<non-utf8 body>".to_string());
+        return Err(RelayError::RpcTransportError {
+            code: format!("HTTP {}", status),
+            message: body_text,
+        });
+    }
+
+    let body = String::from_utf8(response.body).map_err(|err| RelayError::JsonError {
+        message: format!("invalid utf8: {}", err),
+    })?;
+    serde_json::from_str(&body).map_err(|err| RelayError::JsonError {
+        message: err.to_string(),
+    })
+}
+
+async fn http_get_json(url: &str) -> InternalResult<Value> {
+    let request = HttpRequestArgs {
+        url: url.to_string(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(PRICE_RESPONSE_MAX_BYTES),
+        headers: vec![IcHttpHeader {
+            name: "Accept".to_string(),
+            value: "application/json".to_string(),
+        }],
+        transform: Some(transform_context_from_query(
+            "transform_http".to_string(),
+            vec![],
+        )),
+        is_replicated: Some(false),
+    };
+    perform_json_outcall(request).await
+}
+
+/// Serves `/metrics` in the Prometheus text format, `/health` as JSON (503
+/// while not ready) and a `/status` page, and upgrades `POST /relay` to
+/// `http_request_update`. Responses are not certified, so fetch them through
+/// the `raw` gateway domain.
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let response = route_http_query(&req);
+    with_cors(response, &req)
+}
+
+/// Relays a JSON-encoded EIP-3009 authorization; see `parse_http_relay`.
+#[update]
+async fn http_request_update(req: HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    let response = if req.method != "POST" || path != "/relay" {
+        http_text_response(404, "text/plain", "not found".into())
+    } else if req.body.len() > MAX_HTTP_RELAY_BODY_BYTES {
+        http_json_response(413, json!({ "error": "request body too large" }))
+    } else {
+        let parsed = state_ref(|state| {
+            parse_http_relay(&req.body, &state.assets, state.config.chain_id.as_ref())
+        });
+        match parsed {
+            Ok(relay) => {
+                let (log_id, result) = relay_authorization(relay, true).await;
+                match result {
+                    Ok(tx_hash) => http_json_response(
+                        200,
+                        json!({ "log_id": log_id, "tx_hash": tx_hash, "status": "broadcasted" }),
+                    ),
+                    Err(err) => http_json_response(422, json!({ "log_id": log_id, "error": err })),
+                }
+            }
+            Err(err) => http_json_response(400, json!({ "error": err })),
+        }
+    };
+    with_cors(response, &req)
+}
+
+#[update]
+fn set_http_gateway_config(config: HttpGatewayConfig) {
+    if let Err(err) = ensure_admin() {
+        ic_cdk::trap(err.to_string());
+    }
+    state_mut(|state| {
+        record_audit(state, "set_http_gateway_config", format!("{:?}", config));
+        state.http_gateway = Some(config);
+    });
+}
+
+const MAX_HTTP_RELAY_BODY_BYTES: usize = 16 * 1024;
+
+fn route_http_query(req: &HttpRequest) -> HttpResponse {
+    let path = req.url.split('?').next().unwrap_or_default();
+    match (req.method.as_str(), path) {
+        ("OPTIONS", _) => HttpResponse {
+            status_code: 204,
+            headers: Vec::new(),
+            body: serde_bytes::ByteBuf::new(),
+            upgrade: None,
+        },
+        ("POST", "/relay") => HttpResponse {
+            status_code: 200,
+            headers: Vec::new(),
+            body: serde_bytes::ByteBuf::new(),
+            upgrade: Some(true),
+        },
+        ("GET", _) => route_http_get(path),
+        _ => http_text_response(405, "text/plain", "method not allowed".into()),
+    }
+}
+
+fn route_http_get(path: &str) -> HttpResponse {
+    match path {
+        "/metrics" => {
+            let cycles = ic_cdk::api::canister_cycle_balance();
+            let stable_bytes = stable_size() * WASM_PAGE_BYTES;
+            let body = state_ref(|state| {
+                render_metrics(state, time() / 1_000_000_000, cycles, stable_bytes)
+            });
+            http_text_response(200, "text/plain; version=0.0.4", body)
+        }
+        "/health" => {
+            let report = state_ref(|state| health_report(state, time() / 1_000_000_000));
+            let status = if report.ready { 200 } else { 503 };
+            http_text_response(status, "application/json", health_json(&report).to_string())
+        }
+        "/status" => {
+            let report = state_ref(|state| health_report(state, time() / 1_000_000_000));
+            http_text_response(200, "text/html; charset=utf-8", status_html(&report))
+        }
+        _ => http_text_response(404, "text/plain", "not found".into()),
+    }
+}
+
+fn http_json_response(status_code: u16, body: Value) -> HttpResponse {
+    http_text_response(status_code, "application/json", body.to_string())
+}
+
+/// Adds CORS headers when the request's `Origin` is allowed.
+fn with_cors(mut response: HttpResponse, req: &HttpRequest) -> HttpResponse {
+    let Some(origin) = req
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("origin"))
+        .map(|(_, value)| value.clone())
+    else {
+        return response;
+    };
+    let allowed = state_ref(|state| {
+        state.http_gateway.as_ref().is_some_and(|gateway| {
+            gateway
+                .cors_allowed_origins
+                .iter()
+                .any(|allowed| allowed == "*" || *allowed == origin)
+        })
+    });
+    if allowed {
+        response.headers.extend([
+            ("Access-Control-Allow-Origin".to_string(), origin),
+            (
+                "Access-Control-Allow-Methods".to_string(),
+                "GET, POST, OPTIONS".to_string(),
+            ),
+            (
+                "Access-Control-Allow-Headers".to_string(),
+                "Content-Type".to_string(),
+            ),
+            ("Vary".to_string(), "Origin".to_string()),
+        ]);
+    }
+    response
+}
+
+fn json_nat(value: &Value, field: &str) -> Result<Nat, String> {
+    let invalid = || format!("invalid {}", field);
+    match value {
+        Value::Number(number) => number.as_u64().map(Nat::from).ok_or_else(invalid),
+        Value::String(text) => {
+            let text = text.trim();
+            let parsed = match text.strip_prefix("0x") {
+                Some(hex_digits) => BigUint::parse_bytes(hex_digits.as_bytes(), 16),
+                None => BigUint::parse_bytes(text.as_bytes(), 10),
+            };
+            parsed.map(Nat).ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn json_str<'a>(value: &'a Value, field: &str) -> Result<&'a str, String> {
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing {}", field))
+}
+
+/// Splits a 65-byte `r || s || v` signature; `v` may be 0/1 or 27/28.
+fn split_signature(signature: &str) -> Result<(u8, Vec<u8>, Vec<u8>), String> {
+    let bytes = parse_hex_bytes(signature).map_err(|err| err.to_string())?;
+    if bytes.len() != 65 {
+        return Err("signature must be 65 bytes".into());
+    }
+    let v = match bytes[64] {
+        0 | 1 => bytes[64] + 27,
+        v => v,
+    };
+    Ok((v, bytes[0..32].to_vec(), bytes[32..64].to_vec()))
+}
+
+/// The signed `TransferWithAuthorization` fields of an EIP-712 message.
+struct HttpAuthorization {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    value: Nat,
+    valid_after: Nat,
+    valid_before: Nat,
+    nonce: Vec<u8>,
+    sig_v: u8,
+    sig_r: Vec<u8>,
+    sig_s: Vec<u8>,
+}
+
+fn parse_http_authorization(body: &Value) -> Result<HttpAuthorization, String> {
+    let message = body.get("message").ok_or("missing message")?;
+    let field = |name: &str| message.get(name).ok_or_else(|| format!("missing {}", name));
+    let address = |name: &str| {
+        json_str(message, name)
+            .and_then(|text| evm_address_bytes(text).map_err(|err| err.to_string()))
+            .map(|bytes| bytes.to_vec())
+    };
+    let nonce = parse_hex_bytes(json_str(message, "nonce")?).map_err(|err| err.to_string())?;
+    if nonce.len() != 32 {
+        return Err("nonce must be 32 bytes".into());
+    }
+    let (sig_v, sig_r, sig_s) = split_signature(json_str(body, "signature")?)?;
+    Ok(HttpAuthorization {
+        from: address("from")?,
+        to: address("to")?,
+        value: json_nat(field("value")?, "value")?,
+        valid_after: json_nat(field("validAfter")?, "validAfter")?,
+        valid_before: json_nat(field("validBefore")?, "validBefore")?,
+        nonce,
+        sig_v,
+        sig_r,
+        sig_s,
+    })
+}
+
+/// Builds a relay request from the JSON body of `POST /relay`:
+///
+/// `{ "domain": {..}, "message": {from, to, value, validAfter, validBefore,
+/// nonce}, "signature": "0x..", "asset"?: principal, "fee"?: {message,
+/// signature}, "quote_id"?: n, "kyc"?: {attestor, tier, expires_sec,
+/// signature: "0x.."}, "memo"?: text, "memo_salt"?: "0x.." }`
+///
+/// `domain`, `types` and `primaryType` may be passed through unchanged from
+/// the typed data the wallet signed. Without `asset` the token is found by
+/// `domain.verifyingContract`.
+fn parse_http_relay(
+    body: &[u8],
+    assets: &BTreeMap<Principal, AssetConfig>,
+    chain_id: Option<&Nat>,
+) -> Result<SubmitAuthorizationRequest, String> {
+    let body: Value =
+        serde_json::from_slice(body).map_err(|err| format!("invalid json: {}", err))?;
+    let domain = body.get("domain");
+    if let (Some(expected), Some(claimed)) = (chain_id, domain.and_then(|d| d.get("chainId"))) {
+        if json_nat(claimed, "chainId")? != *expected {
+            return Err("domain chainId does not match the relayer".into());
+        }
+    }
+    let asset = match body.get("asset").and_then(Value::as_str) {
+        Some(text) => Principal::from_text(text).map_err(|err| err.to_string())?,
+        None => {
+            let contract = domain
+                .and_then(|d| d.get("verifyingContract"))
+                .and_then(Value::as_str)
+                .ok_or("missing asset or domain.verifyingContract")?;
+            let contract = normalize_evm_address(contract).map_err(|err| err.to_string())?;
+            assets
+                .iter()
+                .find(|(_, cfg)| cfg.evm_address.eq_ignore_ascii_case(&contract))
+                .map(|(asset, _)| *asset)
+                .ok_or("unknown verifyingContract")?
+        }
+    };
+    let payment = parse_http_authorization(&body)?;
+    let fee = match body.get("fee") {
+        Some(fee) => {
+            let fee = parse_http_authorization(fee)?;
+            if fee.from != payment.from {
+                return Err("fee authorization must come from the payer".into());
+            }
+            Some(FeeAuthorization {
+                value: fee.value,
+                valid_after: fee.valid_after,
+                valid_before: fee.valid_before,
+                nonce: fee.nonce,
+                sig_v: fee.sig_v,
+                sig_r: fee.sig_r,
+                sig_s: fee.sig_s,
+            })
+        }
+        None => None,
+    };
+    Ok(SubmitAuthorizationRequest {
+        asset,
+        from: payment.from,
+        to: payment.to,
+        value: payment.value,
+        valid_after: payment.valid_after,
+        valid_before: payment.valid_before,
+        nonce: payment.nonce,
+        sig_v: payment.sig_v,
+        sig_r: payment.sig_r,
+        sig_s: payment.sig_s,
+        fee,
+        quote_id: body.get("quote_id").and_then(Value::as_u64),
+        kyc: body.get("kyc").map(parse_http_kyc).transpose()?,
+        memo: body
+            .get("memo")
+            .and_then(Value::as_str)
+            .map(|memo| Memo::Text(memo.to_string())),
+        memo_salt: body
+            .get("memo_salt")
+            .and_then(Value::as_str)
+            .map(|salt| parse_hex_bytes(salt).map_err(|err| err.to_string()))
+            .transpose()?,
+    })
+}
+
+fn parse_http_kyc(kyc: &Value) -> Result<KycAttestation, String> {
+    let number = |name: &str| {
+        kyc.get(name)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| format!("invalid kyc.{}", name))
+    };
+    Ok(KycAttestation {
+        attestor: json_str(kyc, "attestor")?.to_string(),
+        tier: u8::try_from(number("tier")?).map_err(|_| "invalid kyc.tier".to_string())?,
+        expires_sec: number("expires_sec")?,
+        signature: parse_hex_bytes(json_str(kyc, "signature")?).map_err(|err| err.to_string())?,
+    })
+}
+
+fn http_text_response(status_code: u16, content_type: &str, body: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![
+            ("Content-Type".into(), content_type.into()),
+            ("Cache-Control".into(), "no-store".into()),
+        ],
+        body: serde_bytes::ByteBuf::from(body.into_bytes()),
+        upgrade: None,
+    }
+}
+
+fn breaker_infos(state: &RelayerState) -> Vec<BreakerInfo> {
+    state
+        .breaker
+        .iter()
+        .flat_map(|breaker| breaker.circuits.iter())
+        .map(|(scope, circuit)| BreakerInfo {
+            scope: scope.clone(),
+            phase: circuit.phase,
+            reason: circuit.reason.clone(),
+            tripped_sec: circuit.tripped_sec,
+        })
+        .collect()
+}
+
+/// Consecutive RPC transport failures after which the relayer reports
+/// itself not ready.
+const RPC_UNHEALTHY_FAILURES: u32 = 5;
+
+fn health_report(state: &RelayerState, now_sec: u64) -> HealthReport {
+    let gas = gas_monitor_info(state, now_sec);
+    let rpc = state.rpc_health.clone().unwrap_or_default();
+    let breakers = breaker_infos(state);
+    let mut reasons = Vec::new();
+    if state.config.paused {
+        reasons.push("paused".to_string());
+    }
+    if state.config.rpc_endpoint.is_none() || state.config.chain_id.is_none() {
+        reasons.push("not_configured".to_string());
+    }
+    if state.config.evm_addr.is_none() {
+        reasons.push("relayer_address_missing".to_string());
+    }
+    if breakers
+        .iter()
+        .any(|breaker| breaker.scope == GLOBAL_SCOPE && breaker.phase == CircuitPhase::Open)
+    {
+        reasons.push("circuit_open".to_string());
+    }
+    if matches!(gas.health, GasHealth::Critical) {
+        reasons.push("gas_critical".to_string());
+    }
+    if rpc.consecutive_failures >= RPC_UNHEALTHY_FAILURES {
+        reasons.push("rpc_unhealthy".to_string());
+    }
+    let last_success_sec = state
+        .logs
+        .iter()
+        .rev()
+        .find(|log| {
+            log.kind.clone().unwrap_or_default() == PaymentKind::Relay
+                && matches!(
+                    log.status,
+                    PaymentStatus::Broadcasted | PaymentStatus::Confirmed
+                )
+        })
+        .map(|log| log.ts_sec);
+    HealthReport {
+        ready: reasons.is_empty(),
+        reasons,
+        paused: state.config.paused,
+        gas_auto_paused: gas.auto_paused,
+        breakers,
+        gas_health: gas.health,
+        runway_relays: gas.runway_relays,
+        runway_hours: gas.runway_hours,
+        last_success_sec,
+        rpc,
+        now_sec,
+    }
+}
+
+fn health_json(report: &HealthReport) -> Value {
+    json!({
+        "status": if report.ready { "ok" } else { "unavailable" },
+        "ready": report.ready,
+        "reasons": report.reasons,
+        "paused": report.paused,
+        "gas_auto_paused": report.gas_auto_paused,
+        "breakers": report.breakers.iter().map(|breaker| json!({
+            "scope": breaker.scope,
+            "phase": format!("{:?}", breaker.phase),
+            "reason": breaker.reason,
+            "tripped_at": breaker.tripped_sec,
+        })).collect::<Vec<_>>(),
+        "gas": {
+            "health": format!("{:?}", report.gas_health),
+            "runway_relays": report.runway_relays,
+            "runway_hours": report.runway_hours,
+        },
+        "last_success_at": report.last_success_sec,
+        "rpc": {
+            "healthy": report.rpc.consecutive_failures < RPC_UNHEALTHY_FAILURES,
+            "last_success_at": report.rpc.last_success_sec,
+            "last_failure_at": report.rpc.last_failure_sec,
+            "consecutive_failures": report.rpc.consecutive_failures,
+        },
+        "time": report.now_sec,
+    })
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn status_html(report: &HealthReport) -> String {
+    let optional = |value: Option<u64>| value.map_or("-".to_string(), |v| v.to_string());
+    let mut rows = vec![
+        (
+            "Ready",
+            if report.ready {
+                "yes".to_string()
+            } else {
+                format!("no ({})", report.reasons.join(", "))
+            },
+        ),
+        ("Paused", report.paused.to_string()),
+        ("Gas", format!("{:?}", report.gas_health)),
+        ("Gas runway (relays)", optional(report.runway_relays)),
+        (
+            "Gas runway (hours)",
+            report
+                .runway_hours
+                .map_or("-".to_string(), |hours| format!("{:.1}", hours)),
+        ),
+        ("Last successful relay", optional(report.last_success_sec)),
+        (
+            "RPC consecutive failures",
+            report.rpc.consecutive_failures.to_string(),
+        ),
+        ("RPC last success", optional(report.rpc.last_success_sec)),
+    ];
+    for breaker in &report.breakers {
+        rows.push((
+            "Circuit",
+            format!(
+                "{} {:?} {}",
+                breaker.scope,
+                breaker.phase,
+                breaker.reason.clone().unwrap_or_default()
+            ),
+        ));
+    }
+    let rows: String = rows
+        .iter()
+        .map(|(label, value)| format!("<tr><th>{}</th><td>{}</td></tr>", label, html_escape(value)))
+        .collect();
+    format!(
+        "<!doctype html><html><head><meta charset=\"utf-8\"><title>Relayer status</title></head>\
+         <body><h1>Relayer {}</h1><table>{}</table><p>Times are Unix seconds; generated at {}.</p></body></html>",
+        if report.ready { "operational" } else { "unavailable" },
+        rows,
+        report.now_sec
+    )
+}
+
+fn count_relay_outcome(state: &mut RelayerState, outcome: &str) {
+    *state
+        .relay_outcomes
+        .get_or_insert_with(BTreeMap::new)
+        .entry(outcome.to_string())
+        .or_default() += 1;
+}
+
+/// Upper bounds of the latency histogram buckets, in milliseconds.
+const LATENCY_BUCKETS_MS: [u64; 9] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000];
+
+fn log_kind_label(log: &PaymentLog) -> &'static str {
+    match log.kind.clone().unwrap_or_default() {
+        PaymentKind::Relay => "relay",
+        PaymentKind::Payout => "payout",
+    }
+}
+
+fn log_status_label(status: &PaymentStatus) -> &'static str {
+    match status {
+        PaymentStatus::Accepted => "accepted",
+        PaymentStatus::Broadcasted => "broadcasted",
+        PaymentStatus::Confirmed => "confirmed",
+        PaymentStatus::Failed => "failed",
+    }
+}
+
+impl LogMetrics {
+    fn count_status(&mut self, log: &PaymentLog) {
+        let key = (
+            log_kind_label(log).to_string(),
+            log_status_label(&log.status).to_string(),
+        );
+        *self.by_status.entry(key).or_default() += 1;
+        if log.status == PaymentStatus::Confirmed {
+            *self
+                .confirmed_volume
+                .entry((log.asset.to_text(), log_kind_label(log).to_string()))
+                .or_default() += log.value.clone();
+        }
+    }
+
+    fn uncount_status(&mut self, log: &PaymentLog) {
+        let key = (
+            log_kind_label(log).to_string(),
+            log_status_label(&log.status).to_string(),
+        );
+        if let Some(count) = self.by_status.get_mut(&key) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    fn observe_stages(&mut self, stages: &[StageTiming]) {
+        if stages.is_empty() {
+            return;
+        }
+        let total = stages.iter().map(|timing| timing.duration_ms).sum();
+        let samples = stages
+            .iter()
+            .map(|timing| (timing.stage.as_str(), timing.duration_ms))
+            .chain([("total", total)]);
+        for (stage, duration_ms) in samples {
+            let histogram = self.stage_durations.entry(stage.to_string()).or_default();
+            histogram.buckets.resize(LATENCY_BUCKETS_MS.len(), 0);
+            for (count, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS_MS) {
+                if duration_ms <= bound {
+                    *count += 1;
+                }
+            }
+            histogram.count += 1;
+            histogram.sum_ms += duration_ms;
+        }
+    }
+}
+
+fn rebuild_log_metrics(logs: &[PaymentLog]) -> LogMetrics {
+    let mut metrics = LogMetrics::default();
+    for log in logs {
+        metrics.count_status(log);
+        if let Some(stages) = &log.stages {
+            metrics.observe_stages(stages);
+        }
+    }
+    metrics
+}
+
+/// Counts the log just pushed onto `state.logs`.
+fn count_new_log(state: &mut RelayerState) {
+    if let Some(log) = state.logs.last() {
+        state
+            .log_metrics
+            .get_or_insert_with(LogMetrics::default)
+            .count_status(log);
+    }
+}
+
+/// Moves a log to `status`, keeping the `/metrics` totals in step.
+fn set_log_status(
+    metrics: &mut Option<LogMetrics>,
+    index: &mut Option<LogIndex>,
+    log: &mut PaymentLog,
+    status: PaymentStatus,
+) {
+    let metrics = metrics.get_or_insert_with(LogMetrics::default);
+    metrics.uncount_status(log);
+    if let Some(by_status) = index.as_mut().and_then(|index| index.by_status.as_mut()) {
+        if let Some(ids) = by_status.get_mut(log_status_label(&log.status)) {
+            ids.remove(&log.id);
+        }
+        by_status
+            .entry(log_status_label(&status).to_string())
+            .or_default()
+            .insert(log.id);
+    }
+    log.status = status;
+    metrics.count_status(log);
+}
+
+struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        self.out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('"', "\\\"")))
+                .collect();
+            self.out.push_str(&format!("{{{}}}", labels.join(",")));
+        }
+        self.out.push_str(&format!(" {}\n", value));
+    }
+}
+
+fn render_metrics(state: &RelayerState, now_sec: u64, cycles: u128, stable_bytes: u64) -> String {
+    let mut w = MetricsWriter { out: String::new() };
+
+    w.header("relayer_paused", "gauge", "1 while relaying is paused.");
+    w.sample("relayer_paused", &[], state.config.paused as u8);
+
+    w.header(
+        "relayer_relays_total",
+        "counter",
+        "submit_authorization results by outcome or error class.",
+    );
+    for (outcome, count) in state.relay_outcomes.iter().flatten() {
+        w.sample("relayer_relays_total", &[("outcome", outcome)], count);
+    }
+
+    let empty = LogMetrics::default();
+    let metrics = state.log_metrics.as_ref().unwrap_or(&empty);
+    w.header("relayer_logs", "gauge", "Payment logs by kind and status.");
+    for ((kind, status), count) in &metrics.by_status {
+        w.sample("relayer_logs", &[("kind", kind), ("status", status)], count);
+    }
+    w.header(
+        "relayer_confirmed_volume",
+        "gauge",
+        "Confirmed transfer volume per asset in the token's smallest unit.",
+    );
+    for ((asset, kind), total) in &metrics.confirmed_volume {
+        w.sample(
+            "relayer_confirmed_volume",
+            &[("asset", asset), ("kind", kind)],
+            &total.0,
+        );
+    }
+
+    w.header(
+        "relayer_stage_duration_seconds",
+        "histogram",
+        "Relay stage latency; stage=\"total\" covers the whole relay.",
+    );
+    for (stage, histogram) in &metrics.stage_durations {
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&histogram.buckets) {
+            let le = format!("{}", *bound as f64 / 1_000.0);
+            w.sample(
+                "relayer_stage_duration_seconds_bucket",
+                &[("stage", stage), ("le", &le)],
+                count,
+            );
+        }
+        w.sample(
+            "relayer_stage_duration_seconds_bucket",
+            &[("stage", stage), ("le", "+Inf")],
+            histogram.count,
+        );
+        w.sample(
+            "relayer_stage_duration_seconds_sum",
+            &[("stage", stage)],
+            histogram.sum_ms as f64 / 1_000.0,
+        );
+        w.sample(
+            "relayer_stage_duration_seconds_count",
+            &[("stage", stage)],
+            histogram.count,
+        );
+    }
+
+    let wallets: Vec<(String, &RelayerWallet)> = state
+        .wallet_pool
+        .iter()
+        .flat_map(|pool| pool.wallets.iter())
+        .map(|(id, wallet)| (id.to_string(), wallet))
+        .collect();
+    w.header(
+        "relayer_wallet_gas_wei",
+        "gauge",
+        "Native gas balance per relayer wallet.",
+    );
+    for (id, wallet) in &wallets {
+        let address = wallet.address.clone().unwrap_or_default();
+        let labels = [("wallet", id.as_str()), ("address", address.as_str())];
+        w.sample("relayer_wallet_gas_wei", &labels, &wallet.gas_wei.0);
+    }
+    w.header(
+        "relayer_wallet_nonce_gap",
+        "gauge",
+        "Nonces assigned locally beyond the chain's pending nonce at last check.",
+    );
+    for (id, wallet) in &wallets {
+        let (Some(next), Some(chain)) = (&wallet.next_nonce, &wallet.chain_nonce) else {
+            continue;
+        };
+        let gap = if next.0 > chain.0 {
+            &next.0 - &chain.0
+        } else {
+            BigUint::from(0u32)
+        };
+        let address = wallet.address.clone().unwrap_or_default();
+        let labels = [("wallet", id.as_str()), ("address", address.as_str())];
+        w.sample("relayer_wallet_nonce_gap", &labels, gap);
+    }
+    w.header(
+        "relayer_gas_wei",
+        "gauge",
+        "Last known gas balance of the primary wallet.",
+    );
+    w.sample("relayer_gas_wei", &[], &state.last_known_gas.0);
+
+    w.header(
+        "relayer_queue_depth",
+        "gauge",
+        "Work waiting in each background queue.",
+    );
+    let webhooks = state.webhooks.as_ref().map_or(0, |registry| {
+        registry
+            .deliveries
+            .iter()
+            .filter(|delivery| delivery.status == DeliveryStatus::Pending)
+            .count()
+    });
+    let events = state
+        .subscriptions
+        .as_ref()
+        .map_or(0, |registry| registry.queue.len());
+    let payouts = state.payouts.as_ref().map_or(0, |registry| {
+        registry
+            .payouts
+            .iter()
+            .filter(|payout| payout.status == PayoutStatus::Queued)
+            .count()
+    });
+    let awaiting_receipt: u64 = metrics
+        .by_status
+        .iter()
+        .filter(|((_, status), _)| status == "broadcasted")
+        .map(|(_, count)| count)
+        .sum();
+    w.sample(
+        "relayer_queue_depth",
+        &[("queue", "receipts")],
+        awaiting_receipt,
+    );
+    w.sample("relayer_queue_depth", &[("queue", "webhooks")], webhooks);
+    w.sample("relayer_queue_depth", &[("queue", "subscriptions")], events);
+    w.sample("relayer_queue_depth", &[("queue", "payouts")], payouts);
+
+    w.header("relayer_cycles_balance", "gauge", "Canister cycle balance.");
+    w.sample("relayer_cycles_balance", &[], cycles);
+    w.header(
+        "relayer_stable_memory_bytes",
+        "gauge",
+        "Stable memory in use.",
+    );
+    w.sample("relayer_stable_memory_bytes", &[], stable_bytes);
+    #[cfg(target_arch = "wasm32")]
+    {
+        w.header("relayer_heap_memory_bytes", "gauge", "Wasm heap size.");
+        w.sample(
+            "relayer_heap_memory_bytes",
+            &[],
+            core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_BYTES,
+        );
+    }
+    w.header(
+        "relayer_scrape_timestamp_seconds",
+        "gauge",
+        "Canister time of this scrape.",
+    );
+    w.sample("relayer_scrape_timestamp_seconds", &[], now_sec);
+    w.out
+}
+
+#[query]
+fn transform_http(args: TransformArgs) -> HttpRequestResult {
+    let mut response = args.response;
+    response.headers.clear();
+    response
+}
+
+candid::export_service!();
+
+#[query(name = "__get_candid_interface_tmp_hack")]
+fn export_service() -> String {
+    __export_service()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_conversion() {
+        let bytes = vec![0u8; 20];
+        let hex = to_hex_address(&bytes).unwrap();
+        assert_eq!(hex.len(), 42);
+        assert!(hex.starts_with("0x"));
+    }
+
+    #[test]
+    fn nat_helpers() {
+        let n32 = Nat::from(100_u32);
+        assert_eq!(nat_to_u32(&n32).unwrap(), 100_u32);
+
+        let n64 = Nat::from(1_000_000_u64);
+        assert_eq!(nat_to_u64(&n64).unwrap(), 1_000_000_u64);
+    }
+
+    #[test]
+    fn wallet_pool_prefers_least_loaded_wallet() {
+        let mut state = RelayerState::default();
+        state.config.evm_addr = Some(format!("0x{}", "11".repeat(20)));
+        sync_primary_wallet(&mut state);
+        wallet_pool_mut(&mut state).wallets.insert(
+            1,
+            RelayerWallet {
+                address: Some(format!("0x{}", "22".repeat(20))),
+                ..RelayerWallet::default()
+            },
+        );
+
+        let first = acquire_wallet(&mut state).unwrap();
+        let second = acquire_wallet(&mut state).unwrap();
+        assert_ne!(first.id, second.id);
+
+        wallet_pool_mut(&mut state)
+            .wallets
+            .get_mut(&second.id)
+            .unwrap()
+            .status = WalletStatus::Draining;
+        let third = acquire_wallet(&mut state).unwrap();
+        assert_eq!(third.id, first.id);
+
+        let pending = Nat::from(7u32);
+        assert_eq!(
+            allocate_wallet_nonce(&mut state, first.id, &pending),
+            pending
+        );
+        assert_eq!(
+            allocate_wallet_nonce(&mut state, first.id, &pending),
+            Nat::from(8u32)
+        );
+        reset_wallet_nonce(&mut state, first.id);
+        assert_eq!(
+            allocate_wallet_nonce(&mut state, first.id, &pending),
+            pending
+        );
+    }
+
+    #[test]
+    fn wallet_lease_dropped_while_borrowed_is_released_later() {
+        let mut state = RelayerState::default();
+        state.config.evm_addr = Some(format!("0x{}", "11".repeat(20)));
+        sync_primary_wallet(&mut state);
+        STATE.with(|cell| *cell.borrow_mut() = Some(state));
+
+        let in_flight = |state: &RelayerState| {
+            state.wallet_pool.as_ref().unwrap().wallets[&PRIMARY_WALLET_ID].in_flight
+        };
+        state_mut(|state| {
+            let lease = acquire_wallet(state).unwrap();
+            drop(lease);
+            assert_eq!(in_flight(state), 1);
+        });
+        assert_eq!(state_mut(|state| in_flight(state)), 0);
+
+        let lease = state_mut(acquire_wallet).unwrap();
+        drop(lease);
+        assert_eq!(state_ref(in_flight), 0);
+        STATE.with(|cell| *cell.borrow_mut() = None);
+    }
+
+    #[test]
+    fn quota_dropped_while_borrowed_is_refunded_later() {
+        let limit = BucketLimit {
+            capacity: 2,
+            refill_per_min: 1,
+        };
+        let state = RelayerState {
+            limiter: Some(RateLimiter {
+                config: RateLimiterConfig {
+                    per_from: Some(limit),
+                    ..RateLimiterConfig::default()
+                },
+                buckets: BTreeMap::new(),
+                gc: None,
+            }),
+            ..RelayerState::default()
+        };
+        STATE.with(|cell| *cell.borrow_mut() = Some(state));
+
+        let tokens = |state: &RelayerState| {
+            state.limiter.as_ref().unwrap().buckets["from:0xabc"].tokens_milli
+        };
+        let subject = RateLimitSubject {
+            caller: Principal::anonymous(),
+            from: "0xabc",
+            to: "0xdef",
+            asset: Principal::management_canister(),
+            via_http: false,
+        };
+        state_mut(|state| {
+            let quota = enforce_rate_limits(state, subject, &Nat::from(1u32), 1_000).unwrap();
+            drop(quota);
+            assert_eq!(tokens(state), TOKEN_MILLI);
+        });
+        assert_eq!(state_mut(|state| tokens(state)), 2 * TOKEN_MILLI);
+        STATE.with(|cell| *cell.borrow_mut() = None);
+    }
+
+    #[test]
+    fn key_rotation_waits_for_confirmed_sweep() {
+        let old = format!("0x{}", "11".repeat(20));
+        let new = format!("0x{}", "33".repeat(20));
+        let mut state = RelayerState::default();
+        state.config.evm_addr = Some(old.clone());
+        sync_primary_wallet(&mut state);
+        let wallet_status = |state: &RelayerState| {
+            state.wallet_pool.as_ref().unwrap().wallets[&PRIMARY_WALLET_ID]
+                .status
+                .clone()
+        };
+
+        let rotation = begin_rotation(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            vec![vec![1]],
+            new.clone(),
+            100,
+        )
+        .unwrap();
+        assert_eq!(rotation.phase, RotationPhase::AwaitingSettlement);
+        assert_eq!(wallet_status(&state), WalletStatus::Draining);
+        assert!(begin_rotation(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            vec![vec![2]],
+            new.clone(),
+            100
+        )
+        .is_err());
+        assert!(begin_rotation(&mut state, 9, vec![vec![2]], new.clone(), 100).is_err());
+
+        // Not settled yet: back to waiting, then a failed sweep is recorded.
+        assert_eq!(
+            claim_rotation_step(&mut state, rotation.id, 100)
+                .unwrap()
+                .phase,
+            RotationPhase::Sweeping
+        );
+        assert!(claim_rotation_step(&mut state, rotation.id, 100).is_err());
+        retry_rotation_sweep(&mut state, rotation.id, Some("rpc down".to_string()));
+        claim_rotation_step(&mut state, rotation.id, 100).unwrap();
+
+        let sweep = |hash: &str| SweepResult {
+            tx_hash: Some(hash.to_string()),
+            swept_wei: Nat::from(5u32),
+        };
+        let pending = record_rotation_sweep(&mut state, rotation.id, sweep("0xaa"), 110);
+        assert_eq!(pending.phase, RotationPhase::SweepPending);
+        assert!(pending.last_error.is_none());
+        assert_eq!(state.config.evm_addr.as_deref(), Some(old.as_str()));
+        assert_eq!(
+            claim_rotation_step(&mut state, rotation.id, 100)
+                .unwrap()
+                .phase,
+            RotationPhase::SweepPending
+        );
+
+        // A reverted sweep is retried from settlement.
+        let reverted = apply_sweep_receipt(&mut state, rotation.id, false, None, 120).unwrap();
+        assert_eq!(reverted.phase, RotationPhase::AwaitingSettlement);
+        assert!(reverted.last_error.unwrap().contains("0xaa"));
+        claim_rotation_step(&mut state, rotation.id, 100).unwrap();
+        record_rotation_sweep(&mut state, rotation.id, sweep("0xbb"), 130);
+
+        let done =
+            apply_sweep_receipt(&mut state, rotation.id, true, Some(Nat::from(3u32)), 140).unwrap();
+        assert_eq!(done.phase, RotationPhase::Completed);
+        assert_eq!(done.sweep_tx.as_deref(), Some("0xbb"));
+        assert_eq!(done.residual_wei, Some(Nat::from(3u32)));
+        assert_eq!(done.finished_sec, Some(140));
+        assert_eq!(state.config.evm_addr.as_deref(), Some(new.as_str()));
+        assert_eq!(state.config.ecdsa_derivation_path, vec![vec![1]]);
+        assert_eq!(wallet_status(&state), WalletStatus::Active);
+        assert!(apply_sweep_receipt(&mut state, rotation.id, true, None, 150).is_err());
+
+        // Nothing to sweep completes without waiting for a receipt.
+        let second = begin_rotation(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            vec![vec![2]],
+            old.clone(),
+            200,
+        )
+        .unwrap();
+        claim_rotation_step(&mut state, second.id, 100).unwrap();
+        let empty = SweepResult {
+            tx_hash: None,
+            swept_wei: Nat::from(0u32),
+        };
+        let done = record_rotation_sweep(&mut state, second.id, empty, 210);
+        assert_eq!(done.phase, RotationPhase::Completed);
+        assert_eq!(state.config.evm_addr.as_deref(), Some(old.as_str()));
+
+        // A sweep that never confirms can be aborted once it is stuck.
+        let third = begin_rotation(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            vec![vec![3]],
+            new.clone(),
+            300,
+        )
+        .unwrap();
+        claim_rotation_step(&mut state, third.id, 300).unwrap();
+        record_rotation_sweep(&mut state, third.id, sweep("0xcc"), 310);
+        assert!(abort_rotation(&mut state, third.id, 320).is_err());
+        let aborted = abort_rotation(&mut state, third.id, 310 + ROTATION_STUCK_SEC).unwrap();
+        assert_eq!(aborted.phase, RotationPhase::Aborted);
+        assert_eq!(wallet_status(&state), WalletStatus::Active);
+        assert_eq!(state.config.evm_addr.as_deref(), Some(old.as_str()));
+
+        let gas = Nat::from(NATIVE_TRANSFER_GAS);
+        assert_eq!(
+            sweep_amount(Nat::from(30_000u32), &Nat::from(1u32), &gas),
+            Some(Nat::from(9_000u32))
+        );
+        assert_eq!(
+            sweep_amount(Nat::from(21_000u32), &Nat::from(1u32), &gas),
+            None
+        );
+    }
+
+    #[test]
+    fn withdrawals_respect_timelock_caps_and_refunds() {
+        let to = format!("0x{}", "44".repeat(20));
+        let owner = Principal::anonymous();
+        let mut state = RelayerState {
+            withdrawals: Some(WithdrawalRegistry {
+                policy: WithdrawalPolicy {
+                    timelock_sec: 60,
+                    daily_cap_native_wei: Nat::from(100u32),
+                    daily_cap_erc20: BTreeMap::new(),
+                },
+                ..WithdrawalRegistry::default()
+            }),
+            ..RelayerState::default()
+        };
+        state.config.evm_addr = Some(format!("0x{}", "11".repeat(20)));
+        sync_primary_wallet(&mut state);
+        let used = |state: &RelayerState| {
+            state.withdrawals.as_ref().unwrap().daily_counter["native"]
+                .amount
+                .clone()
+        };
+        let status = |state: &RelayerState, id: u64| {
+            state.withdrawals.as_ref().unwrap().requests[id as usize - 1]
+                .status
+                .clone()
+        };
+
+        let token = WithdrawalKind::Erc20 {
+            token: format!("0x{}", "55".repeat(20)),
+        };
+        assert!(new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            token,
+            to.clone(),
+            Nat::from(1u32),
+            owner,
+            0
+        )
+        .is_err());
+
+        let first = new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            WithdrawalKind::Native,
+            to.clone(),
+            Nat::from(60u32),
+            owner,
+            1_000,
+        )
+        .unwrap();
+        let second = new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            WithdrawalKind::Native,
+            to.clone(),
+            Nat::from(50u32),
+            owner,
+            1_000,
+        )
+        .unwrap();
+        assert_eq!(first.executable_after_sec, 1_060);
+        assert!(claim_withdrawal(&mut state, first.id, 1_059)
+            .unwrap_err()
+            .contains("timelocked"));
+        let outbound = claim_withdrawal(&mut state, first.id, 1_060).unwrap();
+        assert_eq!(outbound.value, Nat::from(60u32));
+        assert_eq!(status(&state, first.id), WithdrawalStatus::Executing);
+        assert_eq!(used(&state), Nat::from(60u32));
+        assert!(claim_withdrawal(&mut state, first.id, 1_060).is_err());
+
+        // The claimed amount counts against the cap until it is refunded.
+        assert!(claim_withdrawal(&mut state, second.id, 1_060)
+            .unwrap_err()
+            .contains("cap"));
+        let failed = finish_withdrawal(
+            &mut state,
+            first.id,
+            &Err(RelayError::RpcError {
+                code: -32000,
+                message: "nonce too low".into(),
+            }),
+            1_070,
+        );
+        assert_eq!(failed.status, WithdrawalStatus::Failed);
+        assert_eq!(used(&state), Nat::from(0u32));
+        claim_withdrawal(&mut state, second.id, 1_080).unwrap();
+        let done = finish_withdrawal(&mut state, second.id, &Ok("0xabc".into()), 1_090);
+        assert_eq!(done.status, WithdrawalStatus::Executed);
+        assert_eq!(used(&state), Nat::from(50u32));
+        assert!(new_withdrawal_request(
+            &mut state,
+            7,
+            WithdrawalKind::Native,
+            to.clone(),
+            Nat::from(1u32),
+            owner,
+            1_090
+        )
+        .is_err());
+
+        let third = new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            WithdrawalKind::Native,
+            to.clone(),
+            Nat::from(10u32),
+            owner,
+            1_100,
+        )
+        .unwrap();
+        assert_eq!(
+            cancel_withdrawal_request(&mut state, third.id, 1_110)
+                .unwrap()
+                .status,
+            WithdrawalStatus::Cancelled
+        );
+        assert!(cancel_withdrawal_request(&mut state, third.id, 1_110).is_err());
+        assert!(claim_withdrawal(&mut state, third.id, 2_000).is_err());
+
+        // A bad destination fails before anything is counted or claimed.
+        let bad = new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            WithdrawalKind::Native,
+            "0x12".to_string(),
+            Nat::from(10u32),
+            owner,
+            1_100,
+        )
+        .unwrap();
+        assert!(claim_withdrawal(&mut state, bad.id, 2_000).is_err());
+        assert_eq!(status(&state, bad.id), WithdrawalStatus::Pending);
+        assert_eq!(used(&state), Nat::from(50u32));
+
+        // A claim that never finishes stalls and is refunded when resolved.
+        let stuck = new_withdrawal_request(
+            &mut state,
+            PRIMARY_WALLET_ID,
+            WithdrawalKind::Native,
+            to.clone(),
+            Nat::from(40u32),
+            owner,
+            86_400,
+        )
+        .unwrap();
+        claim_withdrawal(&mut state, stuck.id, 86_460).unwrap();
+        assert!(
+            stall_stuck_withdrawals(&mut state, 86_459 + WITHDRAWAL_EXECUTING_TIMEOUT_SEC)
+                .is_empty()
+        );
+        assert_eq!(
+            stall_stuck_withdrawals(&mut state, 86_460 + WITHDRAWAL_EXECUTING_TIMEOUT_SEC),
+            vec![stuck.id]
+        );
+        let resolved = finish_withdrawal(
+            &mut state,
+            stuck.id,
+            &Err(RelayError::RpcTransportError {
+                code: "stalled".into(),
+                message: "not sent".into(),
+            }),
+            90_000,
+        );
+        assert_eq!(resolved.status, WithdrawalStatus::Failed);
+        assert_eq!(used(&state), Nat::from(0u32));
+    }
+
+    #[test]
+    fn payouts_debit_budget_and_drain_in_order() {
+        let canister = Principal::from_slice(&[7]);
+        let asset = Principal::from_slice(&[9]);
+        let token = format!("0x{}", "11".repeat(20));
+        let to = format!("0x{}", "22".repeat(20));
+        let mut state = RelayerState::default();
+        state.assets.insert(
+            asset,
+            AssetConfig {
+                evm_address: token.clone(),
+                status: AssetStatus::Active,
+                fee_bps: 0,
+                version: 1,
+                decimals: Some(18),
+                limits: None,
+                domain: None,
+            },
+        );
+        let mut caller = PayoutCaller::default();
+        caller.budgets.insert(asset, Nat::from(100u32));
+        state
+            .payouts
+            .get_or_insert_with(PayoutRegistry::default)
+            .callers
+            .insert(canister, caller);
+        let budget = |state: &RelayerState| {
+            state.payouts.as_ref().unwrap().callers[&canister].budgets[&asset].clone()
+        };
+        let status =
+            |state: &RelayerState, id: u64| payout_by_id(state, id).unwrap().status.clone();
+
+        let stranger = Principal::from_slice(&[8]);
+        assert!(matches!(
+            enqueue_payout(
+                &mut state,
+                stranger,
+                asset,
+                to.clone(),
+                Nat::from(1u32),
+                None,
+                0
+            ),
+            Err(RelayError::NotAuthorized)
+        ));
+        let first = enqueue_payout(
+            &mut state,
+            canister,
+            asset,
+            to.clone(),
+            Nat::from(60u32),
+            None,
+            10,
+        )
+        .unwrap();
+        let second = enqueue_payout(
+            &mut state,
+            canister,
+            asset,
+            to.clone(),
+            Nat::from(40u32),
+            None,
+            11,
+        )
+        .unwrap();
+        assert_eq!(budget(&state), Nat::from(0u32));
+        assert!(matches!(
+            enqueue_payout(
+                &mut state,
+                canister,
+                asset,
+                to.clone(),
+                Nat::from(1u32),
+                None,
+                12
+            ),
+            Err(RelayError::PayoutBudgetExceeded)
+        ));
+
+        let (claimed, address) = claim_next_payout(&mut state, 20).unwrap();
+        assert_eq!(claimed.id, first);
+        assert_eq!(address.as_deref(), Some(token.as_str()));
+        assert_eq!(status(&state, first), PayoutStatus::Sending);
+        let (claimed, _) = claim_next_payout(&mut state, 20).unwrap();
+        assert_eq!(claimed.id, second);
+        assert!(claim_next_payout(&mut state, 20).is_none());
+
+        finish_payout(&mut state, first, Ok("0xaa".to_string()), 30);
+        let sent = payout_by_id(&state, first).unwrap();
+        assert_eq!(sent.status, PayoutStatus::Broadcasted);
+        assert_eq!(
+            find_log(&state.logs, sent.log_id).unwrap().status,
+            PaymentStatus::Broadcasted
+        );
+        assert_eq!(budget(&state), Nat::from(0u32));
+
+        // A send that never returned stalls without giving the budget back.
+        assert!(stall_stuck_payouts(&mut state, 20 + PAYOUT_SENDING_TIMEOUT_SEC - 1).is_empty());
+        assert_eq!(
+            stall_stuck_payouts(&mut state, 20 + PAYOUT_SENDING_TIMEOUT_SEC),
+            vec![second]
+        );
+        assert_eq!(status(&state, second), PayoutStatus::Stalled);
+        assert_eq!(budget(&state), Nat::from(0u32));
+
+        let not_sent = RelayError::RpcTransportError {
+            code: "stalled".into(),
+            message: "not sent".into(),
+        };
+        finish_payout(&mut state, second, Err(not_sent), 700);
+        let failed = payout_by_id(&state, second).unwrap();
+        assert_eq!(failed.status, PayoutStatus::Failed);
+        assert_eq!(
+            find_log(&state.logs, failed.log_id).unwrap().status,
+            PaymentStatus::Failed
+        );
+        assert_eq!(budget(&state), Nat::from(40u32));
+
+        // Finished payouts are not touched again.
+        finish_payout(&mut state, first, Err(RelayError::AssetNotRegistered), 800);
+        assert_eq!(status(&state, first), PayoutStatus::Broadcasted);
+        assert_eq!(budget(&state), Nat::from(40u32));
+    }
+
+    #[test]
+    fn fees_require_batch_contract() {
+        let recipient = format!("0x{}", "66".repeat(20));
+        let mut settings = FeeSettings::default();
+        assert!(fee_recipient_for(false, &settings).unwrap().is_none());
+        assert!(matches!(
+            fee_recipient_for(true, &settings),
+            Err(RelayError::ConfigurationMissing { field }) if field == "fee_recipient"
+        ));
+        settings.recipient = Some(recipient);
+        assert!(matches!(
+            fee_recipient_for(true, &settings),
+            Err(RelayError::ConfigurationMissing { field }) if field == "batch_contract"
+        ));
+        settings.batch_contract = Some(format!("0x{}", "77".repeat(20)));
+        assert_eq!(
+            fee_recipient_for(true, &settings).unwrap(),
+            Some([0x66; 20])
+        );
+    }
+
+    #[test]
+    fn quotes_are_capped_bound_to_payer_and_held_until_broadcast() {
+        let asset = Principal::anonymous();
+        let payer = format!("0x{}", "11".repeat(20));
+        let other = format!("0x{}", "22".repeat(20));
+        let stranger = Principal::management_canister();
+        let quote = |from: &str, expires_sec: u64, requested_by: Principal| IssuedQuote {
+            asset,
+            value: Nat::from(500u64),
+            fee_value: Nat::from(7u64),
+            expires_sec,
+            from: Some(from.to_string()),
+            log_id: None,
+            requested_by: Some(requested_by),
+        };
+        let mut pricing = PricingState::default();
+        pricing.config.enabled = true;
+        // A stranger filling quotes for the payer only evicts its own.
+        let first = issue_quote(&mut pricing, quote(&payer, 100, stranger), 10).unwrap();
+        for _ in 1..MAX_QUOTES_PER_CALLER {
+            issue_quote(&mut pricing, quote(&payer, 100, stranger), 10).unwrap();
+        }
+        issue_quote(&mut pricing, quote(&payer, 100, stranger), 10).unwrap();
+        assert!(!pricing.quotes.contains_key(&first));
+        assert_eq!(pricing.quotes.len(), MAX_QUOTES_PER_CALLER);
+        let other_id = issue_quote(&mut pricing, quote(&other, 100, asset), 10).unwrap();
+        assert_eq!(pricing.quotes.len(), MAX_QUOTES_PER_CALLER + 1);
+        // Quotes held by a relay are never evicted.
+        for quote in pricing.quotes.values_mut() {
+            quote.log_id = Some(1);
+        }
+        pricing.quotes.get_mut(&other_id).unwrap().log_id = None;
+        assert!(issue_quote(&mut pricing, quote(&payer, 100, stranger), 10).is_err());
+        // Expired quotes are dropped.
+        let quote_id = issue_quote(&mut pricing, quote(&payer, 200, asset), 100).unwrap();
+        assert_eq!(pricing.quotes.len(), 1);
+        assert!(!pricing.quotes.contains_key(&other_id));
+
+        let mut buckets_state = RelayerState::default();
+        for _ in 0..DEFAULT_QUOTE_LIMIT.capacity {
+            take_quote_token(&mut buckets_state, stranger, 10).unwrap();
+        }
+        assert!(matches!(
+            take_quote_token(&mut buckets_state, stranger, 10),
+            Err(RelayError::RateLimited { scope }) if scope == "quote"
+        ));
+        assert!(take_quote_token(&mut buckets_state, asset, 10).is_ok());
+
+        let mut state = RelayerState {
+            pricing: Some(pricing),
+            ..RelayerState::default()
+        };
+        let req = SubmitAuthorizationRequest {
+            asset,
+            from: vec![0x11; 20],
+            to: vec![0x33; 20],
+            value: Nat::from(500u64),
+            valid_after: Nat::from(0u64),
+            valid_before: Nat::from(u64::MAX),
+            nonce: vec![0; 32],
+            sig_v: 27,
+            sig_r: vec![0; 32],
+            sig_s: vec![0; 32],
+            fee: None,
+            quote_id: Some(quote_id),
+            kyc: None,
+            memo: None,
+            memo_salt: None,
+        };
+        assert_eq!(
+            resolve_required_fee(&state, &req, &payer, 0, 150).unwrap(),
+            Nat::from(7u64)
+        );
+        assert!(resolve_required_fee(&state, &req, &other, 0, 150).is_err());
+
+        let held = |state: &mut RelayerState, log_id: Option<u64>| {
+            state
+                .pricing
+                .as_mut()
+                .unwrap()
+                .quotes
+                .get_mut(&quote_id)
+                .unwrap()
+                .log_id = log_id;
+        };
+        held(&mut state, Some(4));
+        assert!(resolve_required_fee(&state, &req, &payer, 0, 150).is_err());
+        settle_quote(&mut state, 4, false);
+        assert!(resolve_required_fee(&state, &req, &payer, 0, 150).is_ok());
+        held(&mut state, Some(5));
+        settle_quote(&mut state, 5, true);
+        assert!(state.pricing.as_ref().unwrap().quotes.is_empty());
+    }
+
+    #[test]
+    fn legacy_daily_counters_move_to_asset_keys() {
+        let from = format!("0x{}", "11".repeat(20));
+        let asset = Principal::anonymous();
+        let mut state = RelayerState::default();
+        state.assets.insert(
+            asset,
+            AssetConfig {
+                evm_address: format!("0x{}", "22".repeat(20)),
+                status: AssetStatus::Active,
+                fee_bps: 0,
+                version: 1,
+                decimals: Some(18),
+                limits: None,
+                domain: None,
+            },
+        );
+        let counters = &mut state.rate_state.daily_counter;
+        counters.insert(
+            from.clone(),
+            RateWindowCounter {
+                window_start_sec: 20_000,
+                hits: 2,
+                amount: Nat::from(300u32),
+            },
+        );
+        let current = format!("to:{}:{}", asset, from);
+        counters.insert(current.clone(), RateWindowCounter::default());
+
+        migrate_daily_counters(&mut state);
+        let counters = &state.rate_state.daily_counter;
+        assert!(!counters.contains_key(&from));
+        assert!(counters.contains_key(&current));
+        let migrated = &counters[&format!("from:{}:{}", asset, from)];
+        assert_eq!(migrated.window_start_sec, 20_000);
+        assert_eq!(migrated.amount, Nat::from(300u32));
+    }
+
+    #[test]
+    fn manual_pause_overrides_gas_auto_pause() {
+        let mut state = RelayerState::default();
+        state.config.paused = true;
+        state
+            .gas_monitor
+            .get_or_insert_with(GasMonitor::default)
+            .auto_paused = true;
+
+        set_paused(&mut state, true);
+        assert!(state.config.paused);
+        assert!(!state.gas_monitor.as_ref().unwrap().auto_paused);
+        // A healthy refresh no longer resumes what the operator paused.
+        finish_gas_refresh(&mut state, None, 100);
+        assert!(state.config.paused);
+    }
+
+    #[test]
+    fn erc20_transfer_calldata() {
+        let to = [0x11u8; 20];
+        let data = encode_erc20_transfer_call(&to, &Nat::from(1_000u32)).unwrap();
+        assert_eq!(hex::encode(&data[..4]), "a9059cbb");
+        assert_eq!(data.len(), 4 + 64);
+        assert_eq!(&data[16..36], &to);
+        assert_eq!(&data[66..68], &[0x03, 0xe8]);
+    }
+
+    #[test]
+    fn fee_rounds_up() {
+        assert_eq!(required_fee(&Nat::from(10_000u32), 30), Nat::from(30u32));
+        assert_eq!(required_fee(&Nat::from(10_001u32), 30), Nat::from(31u32));
+        assert_eq!(required_fee(&Nat::from(1u32), 1), Nat::from(1u32));
+        assert_eq!(required_fee(&Nat::from(1_000u32), 0), Nat::from(0u32));
+    }
+
+    #[test]
+    fn multicall3_aggregate3_layout() {
+        let data = encode_multicall3_aggregate3(&[([0xaa; 20], &[1, 2, 3]), ([0xbb; 20], &[])]);
+        assert_eq!(hex::encode(&data[..4]), "82ad56cb");
+        let word = |i: usize| &data[4 + 32 * i..4 + 32 * (i + 1)];
+        assert_eq!(word(0)[31], 0x20);
+        assert_eq!(word(1)[31], 2);
+        assert_eq!(word(2)[31], 0x40);
+        // first tuple: head (3 words) + length + one padded data word
+        assert_eq!(word(3)[31], 0x40 + 5 * 32);
+        assert_eq!(&word(4)[12..], &[0xaa; 20]);
+        assert_eq!(word(6)[31], 0x60);
+        assert_eq!(word(7)[31], 3);
+        assert_eq!(&word(8)[..3], &[1, 2, 3]);
+        assert_eq!(&word(9)[12..], &[0xbb; 20]);
+        assert_eq!(word(12)[31], 0);
+        assert_eq!(data.len(), 4 + 32 * 13);
+    }
+
+    #[test]
+    fn gas_fee_conversion() {
+        // 100k gas at 50 gwei = 0.005 POL; at 80 JPY/POL that is 0.4 JPY.
+        let gas_price = Nat::from(50_000_000_000u64);
+        let fee = gas_fee_in_token_units(100_000, &gas_price, 80_000_000, 18, 0);
+        assert_eq!(fee, Nat::from(400_000_000_000_000_000u64));
+        let with_margin = gas_fee_in_token_units(100_000, &gas_price, 80_000_000, 18, 2_500);
+        assert_eq!(with_margin, Nat::from(500_000_000_000_000_000u64));
+        let six_decimals = gas_fee_in_token_units(100_000, &gas_price, 80_000_000, 6, 0);
+        assert_eq!(six_decimals, Nat::from(400_000u64));
+    }
+
+    #[test]
+    fn price_parsing() {
+        let body = json!({"matic-network": {"jpy": 81.25}});
+        let path = vec!["matic-network".to_string(), "jpy".to_string()];
+        assert_eq!(parse_price_micro(&body, &path).unwrap(), 81_250_000);
+        let text = json!({"price": "0.5"});
+        assert_eq!(
+            parse_price_micro(&text, &["price".to_string()]).unwrap(),
+            500_000
+        );
+        assert!(parse_price_micro(&body, &["missing".to_string()]).is_err());
+    }
+
+    #[test]
+    fn token_bucket_refills_and_blocks() {
+        let limit = BucketLimit {
+            capacity: 2,
+            refill_per_min: 1,
+        };
+        let limits = vec![("from:0xabc".to_string(), limit)];
+        let mut buckets = BTreeMap::new();
+        assert!(take_bucket_tokens(&mut buckets, &limits, 1_000).is_ok());
+        assert!(take_bucket_tokens(&mut buckets, &limits, 1_000).is_ok());
+        match take_bucket_tokens(&mut buckets, &limits, 1_030) {
+            Err(RelayError::RateLimited { scope }) => assert_eq!(scope, "from"),
+            other => panic!("expected rate limit, got {:?}", other.err()),
+        }
+        assert!(take_bucket_tokens(&mut buckets, &limits, 1_060).is_ok());
+        assert!(take_bucket_tokens(&mut buckets, &limits, 1_061).is_err());
+
+        let config = RateLimiterConfig {
+            per_principal: Some(limit),
+            per_from: Some(limit),
+            ..RateLimiterConfig::default()
+        };
+        let subject = |caller, via_http| RateLimitSubject {
+            caller,
+            from: "0xabc",
+            to: "0xdef",
+            asset: Principal::management_canister(),
+            via_http,
+        };
+        let keys = |caller, via_http| -> Vec<String> {
+            rate_limit_buckets(&config, &subject(caller, via_http))
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+        assert_eq!(
+            keys(Principal::anonymous(), false),
+            vec!["principal:2vxsx-fae", "from:0xabc"]
+        );
+        assert_eq!(
+            keys(Principal::anonymous(), true),
+            vec!["http", "from:0xabc"]
+        );
+        assert_eq!(keys(Principal::management_canister(), false).len(), 2);
+    }
+
+    #[test]
+    fn gc_evicts_idle_rate_limit_entries() {
+        let mut state = RelayerState {
+            limiter: Some(RateLimiter {
+                config: RateLimiterConfig {
+                    per_from: Some(BucketLimit {
+                        capacity: 2,
+                        refill_per_min: 2,
+                    }),
+                    ..RateLimiterConfig::default()
+                },
+                buckets: BTreeMap::new(),
+                gc: None,
+            }),
+            ..RelayerState::default()
+        };
+        let limits = |from: &str| {
+            vec![(
+                format!("from:{}", from),
+                BucketLimit {
+                    capacity: 2,
+                    refill_per_min: 2,
+                },
+            )]
+        };
+        let buckets = &mut state.limiter.as_mut().unwrap().buckets;
+        take_bucket_tokens(buckets, &limits("0xaa"), 100_000).unwrap();
+        take_bucket_tokens(buckets, &limits("0xbb"), 100_050).unwrap();
+        state.rate_state.daily_counter.insert(
+            "0xaa".into(),
+            RateWindowCounter {
+                window_start_sec: 0,
+                ..RateWindowCounter::default()
+            },
+        );
+
+        gc_rate_limit_state(&mut state, 100_040);
+        let limiter = state.limiter.as_ref().unwrap();
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.buckets.contains_key("from:0xbb"));
+        assert!(state.rate_state.daily_counter.is_empty());
+        assert_eq!(limiter.gc.as_ref().unwrap().evicted_total, 2);
+
+        limiter_mut(&mut state)
+            .gc
+            .get_or_insert_with(RateLimitGc::default)
+            .max_tracked_keys = 1;
+        let result = ensure_tracking_capacity(&mut state, &limits("0xcc"), &[], 100_041);
+        assert!(matches!(result, Err(RelayError::RateLimited { .. })));
+    }
+
+    #[test]
+    fn daily_caps_scale_with_decimals() {
+        assert_eq!(
+            tokens_in_smallest_unit(10_000, 18),
+            Some(Nat::from(10_000u128 * 1_000_000_000_000_000_000))
+        );
+        assert_eq!(
+            tokens_in_smallest_unit(10_000, 6),
+            Some(Nat::from(10_000_000_000u64))
+        );
+        assert_eq!(tokens_in_smallest_unit(0, 6), None);
+
+        let asset = AssetConfig {
+            evm_address: format!("0x{}", "11".repeat(20)),
+            status: AssetStatus::Active,
+            fee_bps: 0,
+            version: 1,
+            decimals: Some(6),
+            limits: Some(AssetLimits {
+                min_value: Some(Nat::from(1_000u64)),
+                max_value: Some(Nat::from(5_000u64)),
+                ..AssetLimits::default()
+            }),
+            domain: None,
+        };
+        assert!(matches!(
+            check_value_bounds(&asset, &Nat::from(999u64)),
+            Err(RelayError::AmountBelowMinimum { .. })
+        ));
+        assert!(matches!(
+            check_value_bounds(&asset, &Nat::from(5_001u64)),
+            Err(RelayError::AmountAboveMaximum { .. })
+        ));
+        assert!(check_value_bounds(&asset, &Nat::from(5_000u64)).is_ok());
+    }
+
+    #[test]
+    fn screening_blocks_denied_and_unlisted_addresses() {
+        let csv = "address,reason,expires_sec\n\
+                   0x00000000000000000000000000000000000000AA,OFAC,\n\
+                   0x00000000000000000000000000000000000000bb,fraud,500\n";
+        let entries = parse_screening_import(ImportFormat::Csv, csv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].expires_sec, Some(500));
+
+        let mut state = RelayerState::default();
+        insert_screening_entries(&mut state, ScreeningList::DenyFrom, entries, 0).unwrap();
+        let aa = format!("0x{}aa", "00".repeat(19));
+        let bb = format!("0x{}bb", "00".repeat(19));
+        let cc = format!("0x{}cc", "00".repeat(19));
+
+        match screen_addresses(state.screening.as_ref(), &aa, &cc, 100) {
+            Err(RelayError::AddressScreened { side, reason }) => {
+                assert_eq!(side, "from");
+                assert_eq!(reason, "OFAC");
             }
+            other => panic!("expected screening hit, got {:?}", other),
         }
-        _ => Err(RelayError::RpcResultTypeMismatch {
-            expected: "block object",
-        }),
+        assert!(screen_addresses(state.screening.as_ref(), &bb, &cc, 100).is_err());
+        assert!(screen_addresses(state.screening.as_ref(), &bb, &cc, 500).is_ok());
+
+        state.screening.as_mut().unwrap().enforce_allow_to = true;
+        assert!(screen_addresses(state.screening.as_ref(), &cc, &cc, 100).is_err());
+        let json = format!(
+            r#"[{{"address":"{}","reason":"merchant","expires_sec":null}}]"#,
+            cc
+        );
+        let allow = parse_screening_import(ImportFormat::Json, &json).unwrap();
+        insert_screening_entries(&mut state, ScreeningList::AllowTo, allow, 0).unwrap();
+        assert!(screen_addresses(state.screening.as_ref(), &cc, &cc, 100).is_ok());
+
+        let err = screen_addresses(state.screening.as_ref(), &aa, &cc, 100).unwrap_err();
+        let req = SubmitAuthorizationRequest {
+            asset: Principal::anonymous(),
+            from: vec![0; 20],
+            to: vec![0; 20],
+            value: Nat::from(500u64),
+            valid_after: Nat::from(0u64),
+            valid_before: Nat::from(u64::MAX),
+            nonce: vec![0; 32],
+            sig_v: 27,
+            sig_r: vec![0; 32],
+            sig_s: vec![0; 32],
+            fee: None,
+            quote_id: None,
+            kyc: None,
+            memo: None,
+            memo_salt: None,
+        };
+        let log_id = log_screened_relay(
+            &mut state,
+            Principal::anonymous(),
+            &req,
+            &aa,
+            &cc,
+            &err,
+            100,
+        );
+        let log = state.logs.iter().find(|log| log.id == log_id).unwrap();
+        assert!(matches!(log.status, PaymentStatus::Failed));
+        assert_eq!(
+            log.fail_reason.as_deref(),
+            Some("screening: from address blocked (OFAC)")
+        );
     }
-}
 
-async fn send_raw_transaction(chain_id: u64, raw_tx: &[u8]) -> InternalResult<String> {
-    let payload = json!({
-        "jsonrpc": "2.0",
-        "id": next_json_rpc_id(),
-        "method": "eth_sendRawTransaction",
-        "params": [to_hex_prefixed(raw_tx)],
-    });
-    let value = rpc_request(chain_id, payload).await?;
-    value
-        .as_str()
-        .map(str::to_string)
-        .ok_or(RelayError::RpcResultTypeMismatch {
-            expected: "transaction hash",
-        })
-}
+    #[test]
+    fn kyc_attestations_gate_high_value_relays() {
+        use ed25519_dalek::Signer;
+        use k256::ecdsa::SigningKey;
+
+        let from = [0x11u8; 20];
+        let from_hex = format!("0x{}", "11".repeat(20));
+        let relayer = Principal::from_slice(&[1, 2, 3]);
+        let secp = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let ed = ed25519_dalek::SigningKey::from_bytes(&[9u8; 32]);
+        let mut state = RelayerState {
+            kyc: Some(KycState {
+                policy: Some(KycPolicy {
+                    threshold_tokens: 100,
+                    tier_max_tokens: BTreeMap::from([(1, 1_000)]),
+                }),
+                attestors: BTreeMap::from([
+                    (
+                        "secp".to_string(),
+                        KycAttestorKey::Secp256k1(
+                            secp.verifying_key()
+                                .to_encoded_point(true)
+                                .as_bytes()
+                                .to_vec(),
+                        ),
+                    ),
+                    (
+                        "ed".to_string(),
+                        KycAttestorKey::Ed25519(ed.verifying_key().to_bytes().to_vec()),
+                    ),
+                ]),
+                verified: BTreeMap::new(),
+            }),
+            ..RelayerState::default()
+        };
+        state.config.chain_id = Some(Nat::from(137u32));
+        let mut req = SubmitAuthorizationRequest {
+            asset: Principal::anonymous(),
+            from: from.to_vec(),
+            to: vec![0x22; 20],
+            value: Nat::from(500u64),
+            valid_after: Nat::from(0u64),
+            valid_before: Nat::from(u64::MAX),
+            nonce: vec![0; 32],
+            sig_v: 27,
+            sig_r: vec![0; 32],
+            sig_s: vec![0; 32],
+            fee: None,
+            quote_id: None,
+            kyc: None,
+            memo: None,
+            memo_salt: None,
+        };
+
+        assert!(matches!(
+            check_kyc(&mut state, relayer, &req, &from_hex, 0, 10),
+            Err(RelayError::KycRequired { .. })
+        ));
+
+        let digest = kyc_attestation_digest(137, relayer, &from, 1, 1_000);
+        let (sig, _) = secp.sign_prehash_recoverable(&digest).unwrap();
+        req.kyc = Some(KycAttestation {
+            attestor: "secp".into(),
+            tier: 1,
+            expires_sec: 1_000,
+            signature: sig.to_bytes().to_vec(),
+        });
+        let mut padded = req.clone();
+        padded
+            .kyc
+            .as_mut()
+            .unwrap()
+            .signature
+            .extend_from_slice(&[0, 0]);
+        assert!(check_kyc(&mut state, relayer, &padded, &from_hex, 0, 10).is_err());
+        let other_relayer = Principal::from_slice(&[9]);
+        assert!(check_kyc(&mut state, other_relayer, &req, &from_hex, 0, 10).is_err());
+        assert!(check_kyc(&mut state, relayer, &req, &from_hex, 0, 10).is_ok());
+
+        // Cached verification is reused without an attestation.
+        req.kyc = None;
+        assert!(check_kyc(&mut state, relayer, &req, &from_hex, 0, 20).is_ok());
+        req.value = Nat::from(1_001u64);
+        assert!(matches!(
+            check_kyc(&mut state, relayer, &req, &from_hex, 0, 20),
+            Err(RelayError::KycTierLimitExceeded { tier: 1, .. })
+        ));
+        req.value = Nat::from(500u64);
+        assert!(check_kyc(&mut state, relayer, &req, &from_hex, 0, 1_000).is_err());
+
+        let ed_sig = ed.sign(&kyc_attestation_digest(137, relayer, &from, 1, 5_000));
+        req.kyc = Some(KycAttestation {
+            attestor: "ed".into(),
+            tier: 1,
+            expires_sec: 5_000,
+            signature: ed_sig.to_bytes().to_vec(),
+        });
+        assert!(check_kyc(&mut state, relayer, &req, &from_hex, 0, 1_000).is_ok());
+        req.kyc.as_mut().unwrap().tier = 2;
+        assert!(matches!(
+            check_kyc(&mut state, relayer, &req, &from_hex, 0, 1_000),
+            Err(RelayError::InvalidKycAttestation { .. })
+        ));
+    }
 
-fn next_json_rpc_id() -> u64 {
-    JSON_RPC_ID.fetch_add(1, Ordering::Relaxed)
-}
+    #[test]
+    fn rollout_ramps_by_schedule_and_hash() {
+        let asset = Principal::anonymous();
+        let senders: Vec<[u8; 20]> = (0u8..200).map(|i| [i; 20]).collect();
+        let mut state = RelayerState {
+            rollout: Some(RolloutState {
+                mode: RolloutMode::AllowlistOnly,
+                allowlist: BTreeSet::from([format!("0x{}", "05".repeat(20))]),
+                schedule: vec![
+                    RampStep {
+                        at_sec: 100,
+                        asset: None,
+                        mode: RolloutMode::Percentage { bps: 1_000 },
+                    },
+                    RampStep {
+                        at_sec: 200,
+                        asset: None,
+                        mode: RolloutMode::Open,
+                    },
+                ],
+                ..RolloutState::default()
+            }),
+            ..RelayerState::default()
+        };
+        let admitted = |state: &RelayerState| {
+            senders
+                .iter()
+                .filter(|from| {
+                    let hex = format!("0x{}", hex::encode(from));
+                    check_rollout(state.rollout.as_ref(), asset, from.as_slice(), &hex).is_ok()
+                })
+                .count()
+        };
+
+        assert_eq!(admitted(&state), 1);
+        assert!(advance_rollout(&mut state, 50).is_empty());
+        assert_eq!(advance_rollout(&mut state, 150).len(), 1);
+        let partial = admitted(&state);
+        assert!(partial > 1 && partial < 60, "admitted {}", partial);
+        // The same senders stay admitted while the percentage holds.
+        assert_eq!(admitted(&state), partial);
+        advance_rollout(&mut state, 250);
+        assert_eq!(admitted(&state), senders.len());
+
+        state
+            .rollout
+            .as_mut()
+            .unwrap()
+            .asset_overrides
+            .insert(asset, RolloutMode::Paused);
+        assert_eq!(admitted(&state), 0);
+    }
 
-fn get_rpc_endpoint() -> InternalResult<String> {
-    state_ref(|state| state.config.rpc_endpoint.clone()).ok_or(RelayError::ConfigurationMissing {
-        field: "rpc_endpoint".into(),
-    })
-}
+    #[test]
+    fn breaker_trips_and_recovers_through_probes() {
+        let asset = Principal::anonymous();
+        let mut state = RelayerState {
+            breaker: Some(BreakerState {
+                config: BreakerConfig {
+                    min_samples: 4,
+                    probe_successes: 2,
+                    ..BreakerConfig::default()
+                },
+                circuits: BTreeMap::new(),
+            }),
+            ..RelayerState::default()
+        };
+        let mut now = 10_000;
+        for outcome in [Ok(()), Err(ErrorClass::Rpc), Ok(()), Err(ErrorClass::Rpc)] {
+            let probes = admit_through_breaker(&mut state, asset, now).unwrap();
+            record_breaker_outcome(&mut state, asset, &probes, Some(outcome), now);
+            now += 1;
+        }
+        match admit_through_breaker(&mut state, asset, now) {
+            Err(RelayError::CircuitOpen { scope, .. }) => assert_eq!(scope, GLOBAL_SCOPE),
+            other => panic!("expected open circuit, got {:?}", other),
+        }
 
-async fn rpc_request(_chain_id: u64, payload: Value) -> InternalResult<Value> {
-    let endpoint = get_rpc_endpoint()?;
-    let payload_str = serde_json::to_string(&payload).map_err(|err| RelayError::JsonError {
-        message: err.to_string(),
-    })?;
-    let body_bytes = payload_str.into_bytes();
+        // After the cooldown a single probe is let through at a time.
+        now += 300;
+        let probes = admit_through_breaker(&mut state, asset, now).unwrap();
+        assert_eq!(probes, vec![GLOBAL_SCOPE.to_string()]);
+        assert!(admit_through_breaker(&mut state, asset, now).is_err());
+        record_breaker_outcome(&mut state, asset, &probes, Some(Ok(())), now);
+        let probes = admit_through_breaker(&mut state, asset, now).unwrap();
+        record_breaker_outcome(&mut state, asset, &probes, Some(Ok(())), now);
+        let circuit = &state.breaker.as_ref().unwrap().circuits[GLOBAL_SCOPE];
+        assert_eq!(circuit.phase, CircuitPhase::Closed);
+
+        // Low gas trips immediately; client errors are neutral.
+        let probes = admit_through_breaker(&mut state, asset, now).unwrap();
+        record_breaker_outcome(&mut state, asset, &probes, None, now);
+        assert!(admit_through_breaker(&mut state, asset, now).is_ok());
+        record_breaker_outcome(&mut state, asset, &[], Some(Err(ErrorClass::Gas)), now);
+        assert!(admit_through_breaker(&mut state, asset, now).is_err());
+    }
 
-    let headers = vec![
-        IcHttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        },
-        IcHttpHeader {
-            name: "Idempotency-Key".to_string(),
-            value: format!("relayer-{}", next_json_rpc_id()),
-        },
-    ];
+    #[test]
+    fn gas_runway_and_health() {
+        let config = GasMonitorConfig {
+            soft_threshold_wei: Some(Nat::from(1_000u64)),
+            hard_threshold_wei: Some(Nat::from(100u64)),
+            ..GasMonitorConfig::default()
+        };
+        assert_eq!(
+            classify_gas_health(&config, &Nat::from(5_000u64)),
+            GasHealth::Healthy
+        );
+        assert_eq!(
+            classify_gas_health(&config, &Nat::from(999u64)),
+            GasHealth::Warning
+        );
+        assert_eq!(
+            classify_gas_health(&config, &Nat::from(99u64)),
+            GasHealth::Critical
+        );
 
-    let request = HttpRequestArgs {
-        url: endpoint,
-        method: HttpMethod::POST,
-        body: Some(body_bytes),
-        max_response_bytes: Some(RPC_RESPONSE_MAX_BYTES),
-        headers,
-        transform: Some(transform_context_from_query(
-            "transform_http".to_string(),
-            vec![],
-        )),
-        is_replicated: Some(false),
-    };
+        let avg = Nat::from(50u64);
+        assert_eq!(
+            estimate_runway(&Nat::from(1_000u64), Some(&avg), 4),
+            (Some(20), Some(5.0))
+        );
+        assert_eq!(
+            estimate_runway(&Nat::from(1_000u64), Some(&avg), 0),
+            (Some(20), None)
+        );
+        assert_eq!(estimate_runway(&Nat::from(1_000u64), None, 4), (None, None));
+    }
 
-    let call = Call::unbounded_wait(Principal::management_canister(), "http_request")
-        .with_arg(&request)
-        .with_cycles(ic_cdk::management_canister::cost_http_request(&request))
-        .await
-        .map_err(|err| RelayError::RpcTransportError {
-            code: format!("{:?}", err),
-            message: err.to_string(),
-        })?
-        .candid()
-        .map_err(|err| RelayError::RpcTransportError {
-            code: "CandidDecode".into(),
-            message: err.to_string(),
-        })?;
-    let response: HttpRequestResult = call;
+    #[test]
+    fn webhook_signing_and_backoff() {
+        let signature = webhook_signature("0123456789abcdef", 1_700_000_000, r#"{"ok":true}"#);
+        assert_eq!(
+            signature,
+            "sha256=ed9ca58e4ef0cc7f1adfb065c2922b70d963a47298466f8ea44a290876b0704b"
+        );
 
-    let status = response
-        .status
-        .0
-        .to_u64()
-        .unwrap_or(0);
+        let config = WebhookConfig {
+            max_attempts: 3,
+            base_backoff_sec: 30,
+            allow_insecure_localhost: true,
+        };
+        let mut delivery = WebhookDelivery {
+            id: 1,
+            log_id: 1,
+            recipient: "0x".into(),
+            event: "payment.confirmed".into(),
+            payload: "{}".into(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_sec: 0,
+            last_status_code: None,
+            last_error: None,
+            created_sec: 0,
+            delivered_sec: None,
+        };
+        record_webhook_attempt(&mut delivery, &config, Ok(500), 100);
+        assert_eq!(delivery.next_attempt_sec, 130);
+        record_webhook_attempt(&mut delivery, &config, Err("timeout".into()), 130);
+        assert_eq!(delivery.next_attempt_sec, 190);
+        let mut retried = delivery.clone();
+        record_webhook_attempt(&mut retried, &config, Ok(204), 190);
+        assert_eq!(retried.status, DeliveryStatus::Delivered);
+        record_webhook_attempt(&mut delivery, &config, Ok(502), 190);
+        assert_eq!(delivery.status, DeliveryStatus::Failed);
+
+        assert!(validate_webhook_url("http://localhost:8787/hook", true).is_ok());
+        assert!(validate_webhook_url("http://localhost:8787/hook", false).is_err());
+        assert!(validate_webhook_url("https://merchant.example/hook", false).is_ok());
+
+        // A full log of pending deliveries still evicts the oldest.
+        let recipient = format!("0x{}", "33".repeat(20));
+        let mut state = RelayerState {
+            webhooks: Some(WebhookRegistry {
+                config,
+                endpoints: BTreeMap::from([(
+                    recipient.clone(),
+                    WebhookEndpoint {
+                        url: "https://merchant.example/hook".into(),
+                        secret: "0123456789abcdef".into(),
+                        created_sec: 0,
+                    },
+                )]),
+                deliveries: (1..=MAX_WEBHOOK_DELIVERIES as u64)
+                    .map(|id| WebhookDelivery {
+                        id,
+                        status: DeliveryStatus::Pending,
+                        ..delivery.clone()
+                    })
+                    .collect(),
+                next_id: MAX_WEBHOOK_DELIVERIES as u64,
+            }),
+            ..RelayerState::default()
+        };
+        let log = PaymentLog {
+            id: 7,
+            ts_sec: 0,
+            asset: Principal::anonymous(),
+            from: format!("0x{}", "11".repeat(20)),
+            to: recipient,
+            value: Nat::from(1u32),
+            status: PaymentStatus::Broadcasted,
+            tx_hash: Some("0xaa".into()),
+            fail_reason: None,
+            kind: Some(PaymentKind::Relay),
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: None,
+        };
+        enqueue_webhook(&mut state, &log, 200);
+        let deliveries = &state.webhooks.as_ref().unwrap().deliveries;
+        assert_eq!(deliveries.len(), MAX_WEBHOOK_DELIVERIES);
+        assert_eq!(deliveries[0].id, 2);
+        assert_eq!(deliveries.last().unwrap().log_id, 7);
+    }
 
-    if status != 200 {
-        let body_text = String::from_utf8(response.body.clone())
-            .unwrap_or_else(|_| "<non-utf8 body>".to_string());
-        return Err(RelayError::RpcTransportError {
-            code: format!("HTTP {}", status),
-            message: body_text,
-        });
+    #[test]
+    fn subscriptions_filter_and_drop_failing_subscribers() {
+        let asset = Principal::anonymous();
+        let merchant = format!("0x{}", "22".repeat(20));
+        let mut state = RelayerState {
+            subscriptions: Some(SubscriptionRegistry::default()),
+            ..RelayerState::default()
+        };
+        let registry = state.subscriptions.as_mut().unwrap();
+        for (id, filter) in [
+            (
+                1,
+                SubscriptionFilter {
+                    recipient: Some(merchant.clone()),
+                    ..SubscriptionFilter::default()
+                },
+            ),
+            (
+                2,
+                SubscriptionFilter {
+                    asset: Some(Principal::management_canister()),
+                    ..SubscriptionFilter::default()
+                },
+            ),
+        ] {
+            registry.subscriptions.insert(
+                id,
+                Subscription {
+                    id,
+                    subscriber: Principal::management_canister(),
+                    filter,
+                    created_sec: 0,
+                    delivered: 0,
+                    consecutive_failures: 0,
+                    last_error: None,
+                },
+            );
+        }
+        let log = PaymentLog {
+            id: 7,
+            ts_sec: 0,
+            asset,
+            from: format!("0x{}", "11".repeat(20)),
+            to: merchant,
+            value: Nat::from(1u32),
+            status: PaymentStatus::Confirmed,
+            tx_hash: Some("0xabc".into()),
+            fail_reason: None,
+            kind: None,
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: None,
+        };
+        enqueue_subscription_events(&mut state, &log, 100);
+        let registry = state.subscriptions.as_mut().unwrap();
+        assert_eq!(registry.queue.len(), 1);
+        assert_eq!(registry.queue[0].subscription_id, 1);
+
+        assert!(!record_event_attempt(
+            registry,
+            1,
+            7,
+            "payment.confirmed",
+            Err("timeout".into()),
+            100
+        ));
+        assert_eq!(registry.queue[0].next_attempt_sec, 130);
+        let mut dropped = false;
+        for _ in 1..MAX_SUBSCRIBER_FAILURES {
+            dropped = record_event_attempt(
+                registry,
+                1,
+                7,
+                "payment.confirmed",
+                Err("rejected".into()),
+                200,
+            );
+        }
+        assert!(dropped);
+        assert!(!registry.subscriptions.contains_key(&1));
+        assert!(registry.queue.is_empty());
+        let subscriber = Principal::management_canister();
+        assert!(
+            register_subscription(registry, subscriber, SubscriptionFilter::default(), 300)
+                .is_err()
+        );
+        registry.allowed = Some(BTreeSet::from([subscriber]));
+        assert!(
+            register_subscription(registry, subscriber, SubscriptionFilter::default(), 300).is_ok()
+        );
     }
 
-    let body = String::from_utf8(response.body).map_err(|err| RelayError::JsonError {
-        message: format!("invalid utf8: {}", err),
-    })?;
-    let value: Value = serde_json::from_str(&body).map_err(|err| RelayError::JsonError {
-        message: err.to_string(),
-    })?;
+    #[test]
+    fn invoices_link_matching_payments_and_settle() {
+        let asset = Principal::anonymous();
+        let merchant = format!("0x{}", "22".repeat(20));
+        let nonce = invoice_nonce(1, b"salt").to_vec();
+        assert_ne!(nonce, invoice_nonce(2, b"salt").to_vec());
+        let mut registry = InvoiceRegistry {
+            next_id: 1,
+            ..InvoiceRegistry::default()
+        };
+        registry.by_nonce.insert(nonce.clone(), 1);
+        registry.invoices.insert(
+            1,
+            Invoice {
+                id: 1,
+                merchant: Principal::management_canister(),
+                asset,
+                recipient: merchant.clone(),
+                amount: Nat::from(500u32),
+                expires_sec: 1_000,
+                reference: "order-1".into(),
+                nonce: nonce.clone(),
+                status: InvoiceStatus::Open,
+                log_id: None,
+                tx_hash: None,
+                created_sec: 0,
+                paid_sec: None,
+            },
+        );
+        let mut req = SubmitAuthorizationRequest {
+            asset,
+            from: vec![0x11; 20],
+            to: vec![0x22; 20],
+            value: Nat::from(400u32),
+            valid_after: Nat::from(0u32),
+            valid_before: Nat::from(2_000u32),
+            nonce,
+            sig_v: 27,
+            sig_r: vec![0; 32],
+            sig_s: vec![0; 32],
+            fee: None,
+            quote_id: None,
+            kyc: None,
+            memo: None,
+            memo_salt: None,
+        };
+        assert!(matches!(
+            invoice_for_payment(&registry, &req, &merchant, 10),
+            Err(RelayError::InvoiceMismatch { ref field, .. }) if field == "amount"
+        ));
+        req.value = Nat::from(500u32);
+        assert_eq!(
+            invoice_for_payment(&registry, &req, &merchant, 10).unwrap(),
+            Some(1)
+        );
+        assert!(matches!(
+            invoice_for_payment(&registry, &req, &merchant, 1_000),
+            Err(RelayError::InvoiceNotPayable { .. })
+        ));
+
+        mark_invoice_pending(&mut registry, 1, 9);
+        let mut log = PaymentLog {
+            id: 9,
+            ts_sec: 10,
+            asset,
+            from: format!("0x{}", "11".repeat(20)),
+            to: merchant,
+            value: Nat::from(500u32),
+            status: PaymentStatus::Failed,
+            tx_hash: None,
+            fail_reason: Some("reverted".into()),
+            kind: None,
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: None,
+        };
+        settle_invoice(&mut registry, &log, 20);
+        assert_eq!(registry.invoices[&1].status, InvoiceStatus::Open);
+
+        mark_invoice_pending(&mut registry, 1, 9);
+        log.status = PaymentStatus::Confirmed;
+        log.tx_hash = Some("0xabc".into());
+        settle_invoice(&mut registry, &log, 30);
+        let invoice = &registry.invoices[&1];
+        assert_eq!(invoice.status, InvoiceStatus::Paid);
+        assert_eq!(invoice.paid_sec, Some(30));
+        assert!(registry.by_log.as_ref().unwrap().is_empty());
+
+        let paid = registry.invoices[&1].clone();
+        let second = open_invoice(
+            &mut registry,
+            Invoice {
+                id: 0,
+                reference: "order-2".into(),
+                nonce: Vec::new(),
+                status: InvoiceStatus::Open,
+                tx_hash: None,
+                paid_sec: None,
+                log_id: None,
+                ..paid
+            },
+            b"salt",
+            40,
+        )
+        .unwrap();
+        assert_eq!(second.nonce, invoice_nonce(2, b"salt").to_vec());
+        prune_invoices(&mut registry, 1_000 + INVOICE_RETENTION_SEC);
+        assert!(registry.invoices.is_empty());
+        assert!(registry.by_nonce.is_empty());
+    }
 
-    if let Some(error) = value.get("error") {
-        let code = error.get("code").and_then(Value::as_i64).unwrap_or(-32_000);
-        let message = error
-            .get("message")
-            .and_then(Value::as_str)
-            .unwrap_or("unknown error")
-            .to_string();
-        return Err(RelayError::RpcError { code, message });
+    #[test]
+    fn memo_binding_requires_matching_nonce() {
+        let memo = Memo::Text("order-42".into());
+        let salt = vec![7u8; 32];
+        let mut req = SubmitAuthorizationRequest {
+            asset: Principal::anonymous(),
+            from: vec![0x11; 20],
+            to: vec![0x22; 20],
+            value: Nat::from(1u32),
+            valid_after: Nat::from(0u32),
+            valid_before: Nat::from(1u32),
+            nonce: vec![0; 32],
+            sig_v: 27,
+            sig_r: vec![0; 32],
+            sig_s: vec![0; 32],
+            fee: None,
+            quote_id: None,
+            kyc: None,
+            memo: Some(memo.clone()),
+            memo_salt: None,
+        };
+        assert!(check_memo(&req).is_ok());
+        req.memo_salt = Some(salt.clone());
+        assert!(matches!(check_memo(&req), Err(RelayError::MemoNotBound)));
+        req.nonce = memo_nonce(&memo, &salt).to_vec();
+        assert!(check_memo(&req).is_ok());
+        req.memo = Some(Memo::Text("order-43".into()));
+        assert!(matches!(check_memo(&req), Err(RelayError::MemoNotBound)));
+        req.memo = Some(Memo::Blob(vec![0; MAX_MEMO_BYTES + 1]));
+        assert!(matches!(
+            check_memo(&req),
+            Err(RelayError::NumberOutOfRange { .. })
+        ));
+        req.memo = Some(memo.clone());
+        req.memo_salt = Some(vec![7u8; MAX_MEMO_SALT_BYTES + 1]);
+        req.nonce = memo_nonce(&memo, req.memo_salt.as_ref().unwrap()).to_vec();
+        assert!(matches!(
+            check_memo(&req),
+            Err(RelayError::NumberOutOfRange { ref field }) if field == "memo_salt"
+        ));
     }
 
-    value
-        .get("result")
-        .cloned()
-        .ok_or(RelayError::RpcResultTypeMismatch { expected: "result" })
-}
+    #[test]
+    fn query_logs_filters_and_pages_both_ways() {
+        let alice = format!("0x{}", "11".repeat(20));
+        let bob = format!("0x{}", "22".repeat(20));
+        let mut state = RelayerState::default();
+        for id in 1..=6u64 {
+            state.logs.push(PaymentLog {
+                id,
+                ts_sec: id * 10,
+                asset: Principal::anonymous(),
+                from: if id % 2 == 0 {
+                    alice.clone()
+                } else {
+                    bob.clone()
+                },
+                to: bob.clone(),
+                value: Nat::from(id * 100),
+                status: PaymentStatus::Confirmed,
+                tx_hash: Some(format!("0x{:02x}", id)),
+                fail_reason: None,
+                kind: None,
+                fee_value: None,
+                fee_tx_hash: None,
+                memo: None,
+                memo_bound: None,
+                chain_id: None,
+                valid_after: None,
+                valid_before: None,
+                auth_nonce: None,
+                tx_nonce: None,
+                gas_limit: None,
+                max_fee_per_gas: None,
+                max_priority_fee_per_gas: None,
+                caller: Some(Principal::management_canister()),
+                stages: None,
+            });
+            index_log(&mut state, id);
+        }
 
-#[query]
-fn transform_http(args: TransformArgs) -> HttpRequestResult {
-    let mut response = args.response;
-    response.headers.clear();
-    response
-}
+        let mut query = LogQuery {
+            from: Some(alice.clone()),
+            limit: 2,
+            ..LogQuery::default()
+        };
+        let page = query_log_page(&state.logs, state.log_index.as_ref(), &query);
+        assert_eq!(page.total, 3);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![6, 4]
+        );
+        query.cursor = page.next_cursor;
+        let page = query_log_page(&state.logs, state.log_index.as_ref(), &query);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert_eq!(page.next_cursor, None);
+
+        let query = LogQuery {
+            since_sec: Some(20),
+            until_sec: Some(50),
+            min_value: Some(Nat::from(300u32)),
+            direction: Some(LogDirection::OldestFirst),
+            limit: 10,
+            ..LogQuery::default()
+        };
+        let mut page = query_log_page(&state.logs, None, &query);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        redact_callers(&mut page.entries);
+        assert!(page.entries.iter().all(|e| e.caller.is_none()));
+
+        let query = LogQuery {
+            tx_hash: Some("0x07".into()),
+            limit: 10,
+            ..LogQuery::default()
+        };
+        assert_eq!(
+            query_log_page(&state.logs, state.log_index.as_ref(), &query).total,
+            0
+        );
 
-candid::export_service!();
+        let log = find_log_mut(&mut state.logs, 6).unwrap();
+        set_log_status(
+            &mut state.log_metrics,
+            &mut state.log_index,
+            log,
+            PaymentStatus::Failed,
+        );
+        let mut query = LogQuery {
+            status: Some(PaymentStatus::Confirmed),
+            limit: 2,
+            ..LogQuery::default()
+        };
+        let page = query_log_page(&state.logs, state.log_index.as_ref(), &query);
+        assert_eq!(page.total, 5);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![5, 4]
+        );
+        query.status = Some(PaymentStatus::Failed);
+        let page = query_log_page(&state.logs, state.log_index.as_ref(), &query);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.entries[0].id, 6);
+
+        let query = LogQuery {
+            cursor: Some(3),
+            limit: 10,
+            ..LogQuery::default()
+        };
+        let page = query_log_page(&state.logs, state.log_index.as_ref(), &query);
+        assert_eq!(page.total, 6);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+    }
 
-#[query(name = "__get_candid_interface_tmp_hack")]
-fn export_service() -> String {
-    __export_service()
-}
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn legacy_logs_migrate_once() {
+        let mut state = RelayerState::default();
+        state.config.chain_id = Some(Nat::from(137u32));
+        state.logs.push(PaymentLog {
+            id: 1,
+            ts_sec: 0,
+            asset: Principal::anonymous(),
+            from: String::new(),
+            to: String::new(),
+            value: Nat::from(1u32),
+            status: PaymentStatus::Confirmed,
+            tx_hash: None,
+            fail_reason: None,
+            kind: None,
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: None,
+        });
+        migrate_logs(&mut state);
+        assert_eq!(state.logs[0].kind, Some(PaymentKind::Relay));
+        assert_eq!(state.logs[0].chain_id, None);
+        assert_eq!(state.log_schema, Some(LOG_SCHEMA_VERSION));
+
+        state.logs[0].kind = None;
+        migrate_logs(&mut state);
+        assert_eq!(state.logs[0].kind, None);
+    }
 
     #[test]
-    fn hex_conversion() {
-        let bytes = vec![0u8; 20];
-        let hex = to_hex_address(&bytes).unwrap();
-        assert_eq!(hex.len(), 42);
-        assert!(hex.starts_with("0x"));
+    fn stage_percentiles_use_nearest_rank() {
+        assert_eq!(percentile(&[], 50), 0);
+        let sorted: Vec<u64> = (1..=10).collect();
+        assert_eq!(percentile(&sorted, 50), 5);
+        assert_eq!(percentile(&sorted, 90), 9);
+        assert_eq!(percentile(&sorted, 99), 10);
+
+        let timing = |stage: &str, duration_ms: u64, cycles: u64| StageTiming {
+            stage: stage.into(),
+            duration_ms,
+            cycles,
+        };
+        let relays: Vec<Vec<StageTiming>> = [100u64, 300, 200]
+            .into_iter()
+            .map(|sign_ms| {
+                vec![
+                    timing("simulate", 50, 1_000),
+                    timing("sign", sign_ms, 26_000),
+                ]
+            })
+            .collect();
+        let relays: Vec<&[StageTiming]> = relays.iter().map(Vec::as_slice).collect();
+        let stats = stage_percentiles(&relays);
+        let sign = stats.iter().find(|s| s.stage == "sign").unwrap();
+        assert_eq!((sign.samples, sign.p50_ms, sign.max_ms), (3, 200, 300));
+        let total = stats.iter().find(|s| s.stage == "total").unwrap();
+        assert_eq!(total.p50_ms, 250);
+        assert_eq!(total.total_cycles, Nat::from(81_000u32));
+
+        let mut state = RelayerState::default();
+        state.logs.push(PaymentLog {
+            id: 1,
+            ts_sec: 0,
+            asset: Principal::anonymous(),
+            from: String::new(),
+            to: String::new(),
+            value: Nat::from(1u32),
+            status: PaymentStatus::Failed,
+            tx_hash: None,
+            fail_reason: None,
+            kind: None,
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: None,
+        });
+        STATE.with(|cell| *cell.borrow_mut() = Some(state));
+        state_mut(|state| {
+            let mut recorder = StageRecorder::new(1);
+            recorder.stages.push(timing("sign", 10, 0));
+            drop(recorder);
+            assert!(state.logs[0].stages.is_none());
+        });
+        assert_eq!(
+            state_mut(|state| state.logs[0].stages.clone()).map(|stages| stages.len()),
+            Some(1)
+        );
+        STATE.with(|cell| *cell.borrow_mut() = None);
     }
 
     #[test]
-    fn nat_helpers() {
-        let n32 = Nat::from(100_u32);
-        assert_eq!(nat_to_u32(&n32).unwrap(), 100_u32);
+    fn metrics_render_prometheus_text() {
+        let mut state = RelayerState::default();
+        count_relay_outcome(&mut state, "ok");
+        count_relay_outcome(&mut state, "ok");
+        count_relay_outcome(&mut state, "rpc");
+        state.logs.push(PaymentLog {
+            id: 1,
+            ts_sec: 0,
+            asset: Principal::anonymous(),
+            from: String::new(),
+            to: String::new(),
+            value: Nat::from(1_500u32),
+            status: PaymentStatus::Confirmed,
+            tx_hash: None,
+            fail_reason: None,
+            kind: None,
+            fee_value: None,
+            fee_tx_hash: None,
+            memo: None,
+            memo_bound: None,
+            chain_id: None,
+            valid_after: None,
+            valid_before: None,
+            auth_nonce: None,
+            tx_nonce: None,
+            gas_limit: None,
+            max_fee_per_gas: None,
+            max_priority_fee_per_gas: None,
+            caller: None,
+            stages: Some(vec![StageTiming {
+                stage: "send".into(),
+                duration_ms: 400,
+                cycles: 0,
+            }]),
+        });
+        state.log_metrics = Some(rebuild_log_metrics(&state.logs));
+        let mut pending = state.logs[0].clone();
+        pending.id = 2;
+        pending.status = PaymentStatus::Accepted;
+        pending.stages = None;
+        state.logs.push(pending);
+        count_new_log(&mut state);
+        set_log_status(
+            &mut state.log_metrics,
+            &mut state.log_index,
+            &mut state.logs[1],
+            PaymentStatus::Broadcasted,
+        );
+        state.config.evm_addr = Some(format!("0x{}", "11".repeat(20)));
+        sync_primary_wallet(&mut state);
+        let pool = state.wallet_pool.as_mut().unwrap();
+        let mut second = pool.wallets[&PRIMARY_WALLET_ID].clone();
+        second.next_nonce = Some(Nat::from(5u32));
+        second.chain_nonce = Some(Nat::from(3u32));
+        pool.wallets.insert(PRIMARY_WALLET_ID + 1, second);
+        let text = render_metrics(&state, 42, 1_000, 65_536);
+        assert!(text.contains("relayer_relays_total{outcome=\"ok\"} 2\n"));
+        assert!(text.contains("relayer_logs{kind=\"relay\",status=\"confirmed\"} 1\n"));
+        assert!(
+            text.contains("relayer_confirmed_volume{asset=\"2vxsx-fae\",kind=\"relay\"} 1500\n")
+        );
+        assert!(
+            text.contains("relayer_stage_duration_seconds_bucket{stage=\"send\",le=\"0.25\"} 0\n")
+        );
+        assert!(
+            text.contains("relayer_stage_duration_seconds_bucket{stage=\"send\",le=\"0.5\"} 1\n")
+        );
+        assert!(text.contains("relayer_stable_memory_bytes 65536\n"));
+        assert!(text.contains("relayer_logs{kind=\"relay\",status=\"accepted\"} 0\n"));
+        assert!(text.contains("relayer_queue_depth{queue=\"receipts\"} 1\n"));
+        let last_gas = text.rfind("relayer_wallet_gas_wei{").unwrap();
+        let gap_help = text.find("# HELP relayer_wallet_nonce_gap").unwrap();
+        assert!(last_gas < gap_help);
+        assert!(text.contains("relayer_wallet_nonce_gap{wallet=\"1\""));
+    }
 
-        let n64 = Nat::from(1_000_000_u64);
-        assert_eq!(nat_to_u64(&n64).unwrap(), 1_000_000_u64);
+    #[test]
+    fn readiness_reports_blocking_conditions() {
+        let mut state = RelayerState::default();
+        state.config.rpc_endpoint = Some("https://rpc.example/secret-key".into());
+        state.config.chain_id = Some(Nat::from(137u32));
+        state.config.evm_addr = Some(format!("0x{}", "11".repeat(20)));
+        let report = health_report(&state, 100);
+        assert!(report.ready, "{:?}", report.reasons);
+
+        state.config.paused = true;
+        state.rpc_health = Some(RpcHealth {
+            last_success_sec: Some(10),
+            last_failure_sec: Some(90),
+            consecutive_failures: RPC_UNHEALTHY_FAILURES,
+        });
+        let report = health_report(&state, 100);
+        assert!(!report.ready);
+        assert_eq!(report.reasons, vec!["paused", "rpc_unhealthy"]);
+        let body = health_json(&report).to_string();
+        assert!(!body.contains("secret-key"));
+        assert!(status_html(&report).contains("no (paused, rpc_unhealthy)"));
+    }
+
+    #[test]
+    fn http_relay_body_parses_signed_typed_data() {
+        let asset = Principal::management_canister();
+        let contract = format!("0x{}", "ab".repeat(20));
+        let mut assets = BTreeMap::new();
+        assets.insert(
+            asset,
+            AssetConfig {
+                evm_address: contract.clone(),
+                status: AssetStatus::Active,
+                fee_bps: 0,
+                version: 2,
+                decimals: None,
+                limits: None,
+                domain: None,
+            },
+        );
+        let signature = format!("0x{}{}01", "11".repeat(32), "22".repeat(32));
+        let body = json!({
+            "primaryType": "TransferWithAuthorization",
+            "domain": { "name": "JPY Coin", "version": "1", "chainId": 137, "verifyingContract": contract },
+            "message": {
+                "from": format!("0x{}", "01".repeat(20)),
+                "to": format!("0x{}", "02".repeat(20)),
+                "value": "1000000000000000000000",
+                "validAfter": 0,
+                "validBefore": "1900000000",
+                "nonce": format!("0x{}", "33".repeat(32)),
+            },
+            "signature": signature,
+            "memo": "order-7",
+            "memo_salt": format!("0x{}", "07".repeat(32)),
+            "kyc": { "attestor": "ops", "tier": 2, "expires_sec": 1_900_000_000u64, "signature": "0xabcd" },
+        })
+        .to_string();
+        let chain_id = Nat::from(137u32);
+        let req = parse_http_relay(body.as_bytes(), &assets, Some(&chain_id)).unwrap();
+        assert_eq!(req.asset, asset);
+        assert_eq!(req.from, vec![0x01; 20]);
+        assert_eq!(req.value, Nat::from(1_000_000_000_000_000_000_000u128));
+        assert_eq!(req.valid_before, Nat::from(1_900_000_000u64));
+        assert_eq!((req.sig_v, req.sig_r[0], req.sig_s[0]), (28, 0x11, 0x22));
+        assert_eq!(req.memo, Some(Memo::Text("order-7".into())));
+        assert_eq!(req.memo_salt, Some(vec![7u8; 32]));
+        let kyc = req.kyc.unwrap();
+        assert_eq!((kyc.tier, kyc.signature), (2, vec![0xab, 0xcd]));
+
+        let other_chain = Nat::from(1u32);
+        assert!(parse_http_relay(body.as_bytes(), &assets, Some(&other_chain)).is_err());
+    }
+
+    #[test]
+    fn prepared_authorization_round_trips_through_http_relay() {
+        let asset = Principal::management_canister();
+        let contract = format!("0x{}", "ab".repeat(20));
+        let domain = TokenDomain {
+            name: "JPY Coin".into(),
+            version: "1".into(),
+        };
+        let from = format!("0x{}", "01".repeat(20));
+        let to = format!("0x{}", "02".repeat(20));
+        let value = Nat::from(5_000u32);
+        let nonce = authorization_nonce(b"seed", asset, &from, &to, &value, 1);
+        assert_ne!(
+            nonce,
+            authorization_nonce(b"seed", asset, &from, &to, &value, 2)
+        );
+
+        let mut typed = transfer_authorization_typed_data(
+            &domain,
+            137,
+            &contract,
+            TypedTransfer {
+                from: &from,
+                to: &to,
+                value: &value,
+                valid_after: 0,
+                valid_before: 1_900_000_000,
+                nonce: &nonce,
+            },
+        );
+        assert_eq!(typed["domain"]["name"], "JPY Coin");
+        assert_eq!(typed["domain"]["chainId"], 137);
+
+        typed["signature"] = json!(format!("0x{}{}1b", "11".repeat(32), "22".repeat(32)));
+        let mut assets = BTreeMap::new();
+        assets.insert(
+            asset,
+            AssetConfig {
+                evm_address: contract,
+                status: AssetStatus::Active,
+                fee_bps: 0,
+                version: 1,
+                decimals: None,
+                limits: None,
+                domain: Some(domain),
+            },
+        );
+        let body = typed.to_string();
+        let req = parse_http_relay(body.as_bytes(), &assets, Some(&Nat::from(137u32))).unwrap();
+        assert_eq!(req.asset, asset);
+        assert_eq!(req.nonce, nonce.to_vec());
+        assert_eq!(req.value, value);
+        assert_eq!(req.valid_before, Nat::from(1_900_000_000u64));
     }
 
     #[test]
@@ -0,0 +1,60 @@
+#!/usr/bin/env node
+
+// Local stand-in for a merchant webhook endpoint.
+//
+//   WEBHOOK_SECRET=0123456789abcdef node scripts/webhook_sink.js
+//
+// Register it on a local replica with
+//   dfx canister call relayer set_webhook_config '(record { max_attempts = 8; base_backoff_sec = 30; allow_insecure_localhost = true })'
+//   dfx canister call relayer set_webhook '("0x<recipient>", "http://localhost:8787/hook", "0123456789abcdef")'
+//
+// FAIL_FIRST=n answers the first n requests with 500 to exercise retries.
+
+const crypto = require('crypto');
+const http = require('http');
+
+const port = Number(process.env.PORT || 8787);
+const secret = process.env.WEBHOOK_SECRET || '';
+let failFirst = Number(process.env.FAIL_FIRST || 0);
+
+function expectedSignature(timestamp, body) {
+  const mac = crypto.createHmac('sha256', secret);
+  mac.update(`${timestamp}.${body}`);
+  return `sha256=${mac.digest('hex')}`;
+}
+
+const server = http.createServer((req, res) => {
+  let body = '';
+  req.on('data', (chunk) => {
+    body += chunk;
+  });
+  req.on('end', () => {
+    const timestamp = req.headers['x-jpycpay-timestamp'];
+    const signature = req.headers['x-jpycpay-signature'];
+    const valid =
+      typeof signature === 'string' &&
+      signature.length === expectedSignature(timestamp, body).length &&
+      crypto.timingSafeEqual(Buffer.from(signature), Buffer.from(expectedSignature(timestamp, body)));
+
+    console.log(`[${new Date().toISOString()}] ${req.method} ${req.url} signature=${valid ? 'ok' : 'INVALID'}`);
+    console.log(body);
+
+    if (!valid) {
+      res.writeHead(401);
+      res.end('invalid signature');
+      return;
+    }
+    if (failFirst > 0) {
+      failFirst -= 1;
+      res.writeHead(500);
+      res.end('simulated failure');
+      return;
+    }
+    res.writeHead(204);
+    res.end();
+  });
+});
+
+server.listen(port, () => {
+  console.log(`webhook sink listening on http://localhost:${port}`);
+});